@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE transactions DROP COLUMN wallet_tx_cancelled;
+ALTER TABLE transactions DROP COLUMN cancel_attempts;
+ALTER TABLE transactions DROP COLUMN next_cancel_attempt;
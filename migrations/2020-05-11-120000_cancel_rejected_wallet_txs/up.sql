@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE transactions ADD COLUMN wallet_tx_cancelled BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE transactions ADD COLUMN cancel_attempts INT NOT NULL DEFAULT 0;
+ALTER TABLE transactions ADD COLUMN next_cancel_attempt TIMESTAMP;
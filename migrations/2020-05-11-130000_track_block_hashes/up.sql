@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE blocks (
+	height BIGINT PRIMARY KEY,
+	hash TEXT NOT NULL,
+	previous TEXT NOT NULL
+);
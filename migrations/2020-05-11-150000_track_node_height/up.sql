@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE current_height ADD COLUMN node_height BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE transactions DROP COLUMN stuck_since;
+ALTER TABLE transactions DROP COLUMN mempool_changed_at;
+ALTER TABLE transactions DROP COLUMN in_mempool;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE transactions ADD COLUMN in_mempool BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE transactions ADD COLUMN mempool_changed_at TIMESTAMP;
+ALTER TABLE transactions ADD COLUMN stuck_since TIMESTAMP;
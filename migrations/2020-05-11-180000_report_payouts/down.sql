@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+
+-- nothing to undo, payouts were never reported before
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+-- merchants are notified about payouts from now on, don't report old ones
+UPDATE transactions SET reported = true
+	WHERE transaction_type = 'payout'
+	AND status IN ('in_chain', 'confirmed', 'rejected')
+	AND reported = false;
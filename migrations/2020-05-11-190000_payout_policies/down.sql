@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE merchants DROP COLUMN auto_payout_error;
+ALTER TABLE merchants DROP COLUMN last_auto_payout_at;
+ALTER TABLE merchants DROP COLUMN payout_threshold;
+ALTER TABLE merchants DROP COLUMN payout_policy;
+DROP TYPE payout_policy;
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+
+CREATE TYPE payout_policy AS ENUM (
+	'manual',
+	'immediate',
+	'daily',
+	'weekly',
+	'threshold'
+);
+
+ALTER TABLE merchants ADD COLUMN payout_policy payout_policy NOT NULL DEFAULT 'manual';
+ALTER TABLE merchants ADD COLUMN payout_threshold BIGINT;
+ALTER TABLE merchants ADD COLUMN last_auto_payout_at TIMESTAMP;
+ALTER TABLE merchants ADD COLUMN auto_payout_error TEXT;
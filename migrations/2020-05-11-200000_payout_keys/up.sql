@@ -0,0 +1,3 @@
+-- Your SQL goes here
+
+ALTER TABLE merchants ADD COLUMN payout_key_hash TEXT;
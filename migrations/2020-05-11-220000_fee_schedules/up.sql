@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE fee_schedules (
+  merchant_id TEXT PRIMARY KEY REFERENCES merchants(id),
+  schedule JSONB NOT NULL,
+  updated_at TIMESTAMP NOT NULL
+);
+
+ALTER TABLE transactions ADD COLUMN fee_schedule JSONB;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM fee_schedules WHERE merchant_id = 'knockturn';
+DELETE FROM merchants WHERE id = 'knockturn';
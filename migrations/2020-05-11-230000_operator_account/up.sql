@@ -0,0 +1,20 @@
+-- Your SQL goes here
+
+-- The id is reserved for the operator account, a merchant who took it
+-- would get the operator's revenue
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM merchants WHERE id = 'knockturn') THEN
+        RAISE EXCEPTION 'merchant knockturn already exists, rename it before the upgrade: knockturn is reserved for the operator account';
+    END IF;
+END
+$$;
+
+-- Operator account keeps revenue of knockturn. Nobody can log in with it,
+-- the password is not a valid hash
+INSERT INTO merchants (id, email, password, created_at, token, confirmed_2fa, payout_policy)
+VALUES ('knockturn', '', '!', now(), md5(random()::text), false, 'manual');
+
+-- Withdrawing revenue isn't charged
+INSERT INTO fee_schedules (merchant_id, schedule, updated_at)
+VALUES ('knockturn', '{"payout": {"share": 0.0}}', now());
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE balances;
+DROP TABLE ledger_entries;
+DROP FUNCTION forbid_ledger_changes();
+DROP TYPE balance_bucket;
+DROP TYPE ledger_entry_kind;
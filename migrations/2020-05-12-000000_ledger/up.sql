@@ -0,0 +1,50 @@
+-- Your SQL goes here
+
+CREATE TYPE ledger_entry_kind AS ENUM (
+	'payment_received',
+	'payment_cleared',
+	'refund',
+	'fee',
+	'payout',
+	'payout_sent',
+	'network_fee',
+	'fee_credit',
+	'fee_loss',
+	'adjustment'
+);
+
+CREATE TYPE balance_bucket AS ENUM ('available', 'pending', 'locked');
+
+CREATE TABLE ledger_entries (
+	id UUID PRIMARY KEY,
+	-- entries written together, their amounts sum up to zero
+	posting_id UUID NOT NULL,
+	transaction_id UUID REFERENCES transactions(id),
+	kind ledger_entry_kind NOT NULL,
+	account TEXT NOT NULL,
+	bucket balance_bucket NOT NULL,
+	amount BIGINT NOT NULL,
+	note TEXT,
+	created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX ledger_entries_transaction_id_idx ON ledger_entries (transaction_id);
+CREATE INDEX ledger_entries_account_idx ON ledger_entries (account, bucket);
+
+CREATE FUNCTION forbid_ledger_changes() RETURNS trigger AS $$
+BEGIN
+	RAISE EXCEPTION 'ledger entries are immutable';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER ledger_entries_immutable BEFORE UPDATE OR DELETE ON ledger_entries
+	FOR EACH ROW EXECUTE PROCEDURE forbid_ledger_changes();
+
+-- Running totals of ledger entries
+CREATE TABLE balances (
+	account TEXT NOT NULL,
+	bucket balance_bucket NOT NULL,
+	amount BIGINT NOT NULL,
+	updated_at TIMESTAMP NOT NULL,
+	PRIMARY KEY (account, bucket)
+);
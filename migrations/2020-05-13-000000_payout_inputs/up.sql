@@ -0,0 +1,3 @@
+-- Your SQL goes here
+
+ALTER TABLE transactions ADD COLUMN input_commits TEXT[];
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+CREATE TABLE paused_jobs (
+  job TEXT PRIMARY KEY,
+  paused_at TIMESTAMP NOT NULL
+);
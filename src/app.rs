@@ -1,73 +1,77 @@
+use crate::clock::Clock;
+use crate::cron::HORIZON_HEIGHT;
 use crate::db::{get_current_height, DbExecutor};
 use crate::errors::Error;
 use crate::fsm::Fsm;
 use crate::fsm_payout::FsmPayout;
 use crate::handlers::*;
-use crate::node::Node;
-use crate::wallet::Wallet;
+use crate::node::{NodeApi, NodeApiVersion};
+use crate::scheduler::{JobRegistry, Schedule};
+use crate::wallet::WalletApi;
 use crate::Pool;
 use actix::prelude::*;
 use actix_web::web;
 use diesel::pg::PgConnection;
-use diesel::{self, prelude::*};
 use futures::future::Future;
 use log::*;
-
-const HORIZON_HEIGHT: i64 = 60 * 24 * 5; // approximate number of blocks generated in 5 days
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct AppCfg {
+    /// Comma separated list of nodes
     pub node_url: String,
     pub node_user: String,
     pub node_pass: String,
+    pub node_api_version: NodeApiVersion,
+    /// How many nodes must agree on the chain tip
+    pub node_quorum: usize,
     pub wallet_url: String,
     pub wallet_user: String,
     pub wallet_pass: String,
+    pub wallet_password: String,
     pub database_url: String,
+    pub operator_token: Option<String>,
+    /// Intervals of background jobs
+    pub job_schedule: Schedule,
 }
 
 pub struct AppState {
     pub db: Addr<DbExecutor>,
-    pub wallet: Wallet,
+    pub wallet: Arc<dyn WalletApi>,
     pub pool: Pool,
     pub fsm: Addr<Fsm>,
     pub fsm_payout: Addr<FsmPayout>,
+    pub clock: Arc<dyn Clock>,
+    pub node: Arc<dyn NodeApi>,
+    pub operator_token: Option<String>,
+    pub jobs: JobRegistry,
 }
 
-pub async fn check_node_horizon(node: &Node, pool: &Pool) -> Result<(), Error> {
+pub async fn check_node_horizon(node: &dyn NodeApi, pool: &Pool) -> Result<(), Error> {
     info!("Try to check how differ height on node and in DB");
-    let pool = pool.clone();
-
     let node_height = node.current_height().await?;
 
-    let _ = web::block::<_, _, Error>({
+    let last_height = web::block::<_, _, Error>({
         let pool = pool.clone();
         move || {
             let conn: &PgConnection = &pool.get().unwrap();
-            let last_height = get_current_height(conn).or_else(|e| match e {
+            get_current_height(conn).or_else(|e| match e {
                 Error::EntityNotFound(_) => Ok(0),
                 _ => Err(e),
-            })?;
-            info!(
-                "Node height: {}, local height: {}",
-                node_height, last_height
-            );
-            if node_height - last_height > HORIZON_HEIGHT {
-                warn!(
-                    "Current height {} is outdated! Reset to current node height {}",
-                    last_height, node_height
-                );
-                use crate::schema::current_height::dsl::*;
-                diesel::update(current_height)
-                    .set(height.eq(node_height as i64))
-                    .execute(conn)
-                    .map(|_| ())
-                    .map_err::<Error, _>(|e| e.into())?;
-            }
-            Ok(())
+            })
         }
     })
     .await?;
+    info!(
+        "Node height: {}, local height: {}",
+        node_height, last_height
+    );
+    if node_height - last_height > HORIZON_HEIGHT {
+        warn!(
+            "Current height {} is outdated! Only open transactions will be looked up in blocks up to node height {}",
+            last_height, node_height
+        );
+    }
 
     Ok(())
 }
@@ -88,6 +92,24 @@ pub fn routing(cfg: &mut web::ServiceConfig) {
             web::resource("/merchants/{merchant_id}/payments/{transaction_id}/status")
                 .route(web::get().to(payment::get_payment_status)),
         )
+        .service(
+            web::resource("/merchants/{merchant_id}/payouts")
+                .route(web::get().to(payout_api::get_payouts))
+                .route(web::post().to(payout_api::create_payout)),
+        )
+        .service(
+            web::resource("/merchants/{merchant_id}/payouts/{transaction_id}")
+                .route(web::get().to(payout_api::get_payout)),
+        )
+        .service(
+            web::resource("/merchants/{merchant_id}/payouts/{transaction_id}/slate")
+                .route(web::get().to(payout_api::get_slate))
+                .route(web::post().to(payout_api::accept_slate)),
+        )
+        .service(
+            web::resource("/merchants/{merchant_id}/payouts/{transaction_id}/cancel")
+                .route(web::post().to(payout_api::cancel_payout)),
+        )
         .service(
             web::resource("/merchants/{merchant_id}/payments/{transaction_id}/{grin_path:.*}")
                 .route(web::post().to(payment::wallet_jsonrpc)),
@@ -117,6 +139,15 @@ pub fn routing(cfg: &mut web::ServiceConfig) {
         .service(
             web::resource("/withdraw/confirm").route(web::post().to(payout::withdraw_confirmation)),
         )
+        .service(
+            web::resource("/withdraw/policy").route(web::post().to(payout::set_payout_policy)),
+        )
+        .service(
+            web::resource("/withdraw/key").route(web::post().to(payout::generate_payout_key)),
+        )
+        .service(
+            web::resource("/payouts/{id}/cancel").route(web::post().to(payout::cancel_payout)),
+        )
         .service(
             web::resource("/payouts/{id}")
                 .route(web::get().to(payout::get_payout))
@@ -139,5 +170,44 @@ pub fn routing(cfg: &mut web::ServiceConfig) {
         )
         .service(
             web::resource("/transactions").route(web::get().to(transaction::get_transactions)),
-        );
+        )
+        .service(
+            web::resource("/statement").route(web::get().to(statement::get_merchant_statement)),
+        )
+        .service(web::resource("/admin/rescan").route(web::post().to(admin::rescan)))
+        .service(web::resource("/admin/jobs").route(web::get().to(admin::get_jobs)))
+        .service(web::resource("/admin/jobs/{job}/pause").route(web::post().to(admin::pause_job)))
+        .service(web::resource("/admin/jobs/{job}/resume").route(web::post().to(admin::resume_job)))
+        .service(
+            web::resource("/admin/merchants/{merchant_id}/fee_schedule")
+                .route(web::get().to(admin::get_fee_schedule))
+                .route(web::put().to(admin::set_fee_schedule)),
+        )
+        .service(
+            web::resource("/admin/merchants/{merchant_id}/balances")
+                .route(web::get().to(admin::get_merchant_balances)),
+        )
+        .service(
+            web::resource("/admin/merchants/{merchant_id}/adjustments")
+                .route(web::post().to(admin::adjust_merchant_balance)),
+        )
+        .service(web::resource("/admin/revenue").route(web::get().to(admin::get_revenue_report)))
+        .service(web::resource("/admin/balances").route(web::get().to(admin::get_balances)))
+        .service(
+            web::resource("/admin/payouts")
+                .route(web::get().to(admin::get_operator_payouts))
+                .route(web::post().to(admin::create_operator_payout)),
+        )
+        .service(
+            web::resource("/admin/payouts/{transaction_id}/slate")
+                .route(web::get().to(admin::get_operator_payout_slate))
+                .route(web::post().to(admin::accept_operator_payout_slate)),
+        )
+        .service(
+            web::resource("/admin/payouts/{transaction_id}/cancel")
+                .route(web::post().to(admin::cancel_operator_payout)),
+        )
+        .service(web::resource("/health").route(web::get().to(health::health)))
+        .service(web::resource("/ready").route(web::get().to(health::ready)))
+        .service(web::resource("/metrics").route(web::get().to(metrics::metrics)));
 }
@@ -0,0 +1,97 @@
+//! Operator's command line tool, talks to the admin API of running knockturn.
+//!
+//! Usage: knockturn-admin rescan <from_height> <to_height> [--apply]
+//!
+//! Without `--apply` rescan only reports which transactions would change state.
+//! Needs OPERATOR_TOKEN, knockturn address is taken from KNOCKTURN_URL
+//! (http://localhost:3000 by default).
+use actix_web::client::Client;
+use dotenv::dotenv;
+use knockturn::handlers::admin::{RescanRequest, RescanResponse};
+use std::env;
+use std::process::exit;
+use std::time::Duration;
+
+const USAGE: &'static str = "Usage: knockturn-admin rescan <from_height> <to_height> [--apply]";
+
+fn parse_height(arg: Option<&String>) -> i64 {
+    match arg.and_then(|h| h.parse().ok()) {
+        Some(height) => height,
+        None => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    }
+}
+
+#[actix_rt::main]
+async fn main() {
+    dotenv().ok();
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(|cmd| cmd.as_str()) != Some("rescan") {
+        eprintln!("{}", USAGE);
+        exit(2);
+    }
+    let rescan_req = RescanRequest {
+        from_height: parse_height(args.get(2)),
+        to_height: parse_height(args.get(3)),
+        apply: args.get(4).map(|flag| flag.as_str()) == Some("--apply"),
+    };
+
+    let url = env::var("KNOCKTURN_URL").unwrap_or("http://localhost:3000".to_owned());
+    let token = env::var("OPERATOR_TOKEN").expect("OPERATOR_TOKEN must be set");
+    let mut resp = Client::build()
+        .timeout(Duration::from_secs(600))
+        .finish()
+        .post(format!("{}/admin/rescan", url.trim_end_matches('/')))
+        .basic_auth("operator", Some(&token))
+        .send_json(&rescan_req)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot call knockturn: {}", e);
+            exit(1);
+        });
+    if !resp.status().is_success() {
+        let body = resp.body().await.unwrap_or_default();
+        eprintln!(
+            "Rescan failed with {}: {}",
+            resp.status(),
+            String::from_utf8_lossy(&body)
+        );
+        exit(1);
+    }
+    let report: RescanResponse = resp
+        .json()
+        .limit(10 * 1024 * 1024)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot decode response: {}", e);
+            exit(1);
+        });
+
+    println!(
+        "Blocks {}-{}: {} transactions found",
+        report.from_height,
+        report.to_height,
+        report.changes.len()
+    );
+    for change in &report.changes {
+        let new_status = change
+            .new_status
+            .map(|status| status.to_string())
+            .unwrap_or("needs manual review".to_owned());
+        println!(
+            "{} {} of {} at height {}: {} -> {}{}",
+            change.transaction_id,
+            change.transaction_type,
+            change.merchant_id,
+            change.height,
+            change.status,
+            new_status,
+            if change.applied { " (applied)" } else { "" }
+        );
+    }
+    if !rescan_req.apply && report.changes.iter().any(|c| c.new_status.is_some()) {
+        println!("Run again with --apply to change state of these transactions");
+    }
+}
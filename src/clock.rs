@@ -0,0 +1,84 @@
+//! Source of the current time.
+//!
+//! Expiration, retry backoff and transaction timestamps take time from a `Clock`
+//! instead of calling `Utc::now()` directly, so tests can move time forward.
+//! All timestamps are naive UTC.
+#[cfg(any(test, feature = "fakes"))]
+use chrono::Duration;
+use chrono::{NaiveDateTime, Utc};
+#[cfg(any(test, feature = "fakes"))]
+use parking_lot::Mutex;
+#[cfg(any(test, feature = "fakes"))]
+use std::sync::Arc;
+
+pub trait Clock: Send + Sync {
+    /// Current time in UTC
+    fn now(&self) -> NaiveDateTime;
+}
+
+/// Wall clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Utc::now().naive_utc()
+    }
+}
+
+/// Clock which stands still until it's moved explicitly
+#[cfg(any(test, feature = "fakes"))]
+#[derive(Debug, Clone)]
+pub struct TestClock {
+    now: Arc<Mutex<NaiveDateTime>>,
+}
+
+#[cfg(any(test, feature = "fakes"))]
+impl TestClock {
+    pub fn new(now: NaiveDateTime) -> Self {
+        TestClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: NaiveDateTime) {
+        *self.now.lock() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock();
+        *now = *now + duration;
+    }
+}
+
+#[cfg(any(test, feature = "fakes"))]
+impl Default for TestClock {
+    fn default() -> Self {
+        TestClock::new(SystemClock.now())
+    }
+}
+
+#[cfg(any(test, feature = "fakes"))]
+impl Clock for TestClock {
+    fn now(&self) -> NaiveDateTime {
+        *self.now.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_test() {
+        let start = NaiveDateTime::from_timestamp(1_500_000_000, 0);
+        let clock = TestClock::new(start);
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::minutes(15));
+        assert_eq!(clock.now() - start, Duration::minutes(15));
+
+        let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+        clock.set(start);
+        assert_eq!(shared.now(), start);
+    }
+}
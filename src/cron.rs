@@ -1,30 +1,51 @@
-use crate::db::{get_current_height, DbExecutor, RejectExpiredPayments};
+use crate::clock::Clock;
+use crate::db::{
+    advance_current_height, confirm_payments, get_blocks, get_current_height,
+    get_pending_transactions, get_watched_transactions, mark_in_chain, rollback_chain,
+    set_node_height, store_blocks, update_mempool_status, DbExecutor, RejectExpiredPayments,
+};
 use crate::errors::Error;
 use crate::fsm::{
-    get_unreported_confirmed_payments, get_unreported_rejected_payments, Fsm, Payment,
-    PendingPayment, RejectPayment, ReportPayment,
+    get_uncancelled_rejected_payments, get_unreported_confirmed_payments,
+    get_unreported_rejected_payments, CancelWalletTx, Fsm, Payment, PendingPayment, RejectPayment,
+    ReportPayment,
 };
-use crate::models::{Transaction, TransactionStatus};
-use crate::node::Node;
+use crate::models::{self, Transaction};
+use crate::node::{self, NodeApi, PoolTransaction};
 use crate::rates::RatesFetcher;
+use crate::scheduler::{self, JobRegistry};
+use crate::session::delete_expired_sessions;
 use crate::Pool;
 use actix::prelude::*;
 use actix_web::web::block;
+use chrono::Duration;
 use diesel::pg::PgConnection;
 use diesel::{self, prelude::*};
-use futures::future::{join_all, Future, FutureExt, TryFutureExt};
+use futures::future::{join_all, try_join_all, Future, TryFutureExt};
+use futures::stream::{self, StreamExt};
 
 use log::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
 
-const REQUEST_BLOCKS_FROM_NODE: i64 = 10;
+pub const HORIZON_HEIGHT: i64 = 60 * 24 * 5; // approximate number of blocks generated in 5 days
+const MIN_BLOCKS_PER_REQUEST: i64 = 10;
+const MAX_BLOCKS_PER_REQUEST: i64 = 100;
+const MAX_CONCURRENT_REQUESTS: i64 = 4;
+const OUTPUTS_PER_REQUEST: usize = 100;
+const MAX_CONCURRENT_KERNEL_LOOKUPS: usize = 8;
+const MAX_REORG_DEPTH: i64 = 100; // number of recent blocks we remember to detect forks
+const WATCH_REJECTED_HOURS: i64 = 24; // how long we look for rejected transactions in chain
 
 #[derive(Clone)]
 pub struct Cron {
     db: Addr<DbExecutor>,
-    node: Node,
+    node: Arc<dyn NodeApi>,
     fsm: Addr<Fsm>,
     pool: Pool,
+    clock: Arc<dyn Clock>,
+    jobs: JobRegistry,
 }
 
 impl Actor for Cron {
@@ -32,37 +53,44 @@ impl Actor for Cron {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("Starting cron process");
-        let rates = RatesFetcher::new(self.pool.clone());
-        ctx.run_interval(
-            std::time::Duration::new(5, 0),
-            move |_instance: &mut Cron, _ctx: &mut Context<Self>| {
-                let rates = rates.clone();
-                actix::spawn(async move {
-                    rates
-                        .fetch()
-                        .map(|r| {
-                            if let Err(e) = r {
-                                error!("Couldn't fetch rates {}", e);
-                            }
-                            ()
-                        })
-                        .await
-                });
-            },
+        let rates = RatesFetcher::new(self.pool.clone(), self.clock.clone());
+        self.schedule(ctx, "fetch_rates", move |_| {
+            let rates = rates.clone();
+            async move { rates.fetch().await.map(|_| ()) }
+        });
+        self.schedule(ctx, "reject_expired_payments", |cron| async move {
+            cron.reject_expired_payments().await
+        });
+        self.schedule(ctx, "process_pending_payments", |cron| async move {
+            cron.process_pending_payments().await
+        });
+        self.schedule(
+            ctx,
+            "process_unreported_confirmed_payments",
+            |cron| async move { cron.process_unreported_confirmed_payments().await },
         );
-        ctx.run_interval(std::time::Duration::new(5, 0), reject_expired_payments);
-        ctx.run_interval(std::time::Duration::new(5, 0), process_pending_payments);
-
-        ctx.run_interval(
-            std::time::Duration::new(5, 0),
-            process_unreported_confirmed_payments,
+        self.schedule(
+            ctx,
+            "process_unreported_rejected_payments",
+            |cron| async move { cron.process_unreported_rejected_payments().await },
         );
-        ctx.run_interval(
-            std::time::Duration::new(5, 0),
-            process_unreported_rejected_payments,
+        self.schedule(
+            ctx,
+            "process_uncancelled_rejected_payments",
+            |cron| async move { cron.process_uncancelled_rejected_payments().await },
         );
-        ctx.run_interval(std::time::Duration::new(5, 0), sync_with_node);
-        ctx.run_interval(std::time::Duration::new(5, 0), autoconfirmation);
+        self.schedule(ctx, "sync_with_node", |cron| async move {
+            cron.sync_with_node().await
+        });
+        self.schedule(ctx, "autoconfirmation", |cron| async move {
+            cron.autoconfirmation().await
+        });
+        self.schedule(ctx, "watch_mempool", |cron| async move {
+            cron.watch_mempool().await
+        });
+        self.schedule(ctx, "delete_expired_sessions", |cron| async move {
+            cron.delete_expired_sessions().await
+        });
     }
 
     fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
@@ -72,20 +100,64 @@ impl Actor for Cron {
 }
 
 impl Cron {
-    pub fn new(db: Addr<DbExecutor>, fsm: Addr<Fsm>, node: Node, pool: Pool) -> Self {
+    pub fn new(
+        db: Addr<DbExecutor>,
+        fsm: Addr<Fsm>,
+        node: Arc<dyn NodeApi>,
+        pool: Pool,
+        clock: Arc<dyn Clock>,
+        jobs: JobRegistry,
+    ) -> Self {
         Cron {
             db,
             fsm,
             node,
             pool,
+            clock,
+            jobs,
         }
     }
+
+    fn schedule<F, Fut>(&self, ctx: &mut Context<Self>, name: &'static str, job: F)
+    where
+        F: Fn(Cron) -> Fut + 'static,
+        Fut: Future<Output = Result<(), Error>> + 'static,
+    {
+        scheduler::schedule(ctx, self.jobs.clone(), self.clock.clone(), name, job);
+    }
+
+    async fn reject_expired_payments(&self) -> Result<(), Error> {
+        self.db
+            .send(RejectExpiredPayments {
+                now: self.clock.now(),
+            })
+            .await??;
+        Ok(())
+    }
+
+    async fn delete_expired_sessions(&self) -> Result<(), Error> {
+        let now = self.clock.now();
+        let deleted = block::<_, _, Error>({
+            let pool = self.pool.clone();
+            move || {
+                let conn: &PgConnection = &pool.get().unwrap();
+                delete_expired_sessions(now, conn)
+            }
+        })
+        .await?;
+        if deleted > 0 {
+            debug!("Deleted {} expired sessions", deleted);
+        }
+        Ok(())
+    }
+
     async fn process_pending_payments(&self) -> Result<(), Error> {
         debug!("run process_pending_payments");
         let pool = self.pool.clone();
         let payments: Vec<PendingPayment> = Payment::list(pool).await?;
+        let now = self.clock.now();
         for payment in payments {
-            if payment.is_expired() {
+            if payment.is_expired(now) {
                 debug!("payment {} expired: try to reject it", payment.id);
                 let res: Result<(), Error> = self
                     .fsm
@@ -109,7 +181,8 @@ impl Cron {
     }
 
     async fn process_unreported_confirmed_payments(&self) -> Result<(), Error> {
-        let payments = get_unreported_confirmed_payments(self.pool.clone()).await?;
+        let payments =
+            get_unreported_confirmed_payments(self.pool.clone(), self.clock.now()).await?;
         for payment in payments {
             let payment_id = payment.id;
             let res: Result<(), Error> = self
@@ -133,7 +206,8 @@ impl Cron {
     }
 
     async fn process_unreported_rejected_payments(&self) -> Result<(), Error> {
-        let payments = get_unreported_rejected_payments(self.pool.clone()).await?;
+        let payments =
+            get_unreported_rejected_payments(self.pool.clone(), self.clock.now()).await?;
         for payment in payments {
             let payment_id = payment.id;
             let res: Result<(), Error> = self
@@ -155,23 +229,63 @@ impl Cron {
         }
         Ok(())
     }
+
+    async fn process_uncancelled_rejected_payments(&self) -> Result<(), Error> {
+        let payments =
+            get_uncancelled_rejected_payments(self.pool.clone(), self.clock.now()).await?;
+        for payment in payments {
+            let payment_id = payment.id;
+            let res: Result<(), Error> = self
+                .fsm
+                .send(CancelWalletTx { payment })
+                .await
+                .map_err(|e| Error::General(s!(e)))
+                .and_then(|db_response| {
+                    db_response?;
+                    Ok(())
+                })
+                .or_else({
+                    move |e| {
+                        warn!("Couldn't cancel wallet tx of payment {}: {}", payment_id, e);
+                        Ok(())
+                    }
+                });
+            res?;
+        }
+        Ok(())
+    }
+
     async fn sync_with_node(&self) -> Result<(), Error> {
         debug!("run sync_with_node");
 
-        let last_height = block::<_, _, Error>({
+        let node_height = self.node.current_height().await?;
+        let mut last_height = block::<_, _, Error>({
             let pool = self.pool.clone();
             move || {
                 let conn: &PgConnection = &pool.get().unwrap();
+                set_node_height(node_height, conn)?;
                 let last_height = get_current_height(conn)?;
                 Ok(last_height)
             }
         })
         .await?;
 
-        let blocks = self
-            .node
-            .blocks(last_height + 1, last_height + 1 + REQUEST_BLOCKS_FROM_NODE)
+        if node_height - last_height > HORIZON_HEIGHT {
+            return self.skip_to(last_height, node_height).await;
+        }
+
+        let mut blocks = self
+            .fetch_blocks(&sync_ranges(last_height, node_height))
             .await?;
+        if let Some(fork_height) = self.find_fork(last_height, &blocks).await? {
+            self.rollback(fork_height).await?;
+            last_height = fork_height - 1;
+            blocks = self
+                .fetch_blocks(&sync_ranges(last_height, node_height))
+                .await?;
+        }
+        blocks.retain(|block| block.header.height as i64 > last_height);
+
         let new_height = blocks
             .iter()
             .fold(last_height as u64, |current_height, block| {
@@ -180,7 +294,7 @@ impl Cron {
                 } else {
                     current_height
                 }
-            });
+            }) as i64;
         let commits: HashMap<String, i64> = blocks
             .iter()
             .flat_map(|block| block.outputs.iter())
@@ -189,174 +303,351 @@ impl Cron {
             .map(|o| (o.commit.clone(), o.block_height.unwrap() as i64))
             .collect();
         info!("Found {} non coinbase outputs", commits.len());
+        let watched = self.watched_transactions().await?;
+        // kernel is unambiguous, an output could be sender's change
+        let mut found =
+            find_kernels(self.node.as_ref(), &watched, last_height + 1, new_height).await?;
+        let new_blocks: Vec<models::Block> = blocks
+            .iter()
+            .map(|block| models::Block {
+                height: block.header.height as i64,
+                hash: block.header.hash.clone(),
+                previous: block.header.previous.clone(),
+            })
+            .collect();
         block({
             let pool = self.pool.clone();
+            let now = self.clock.now();
             move || {
                 use crate::schema::transactions::dsl::*;
                 let conn: &PgConnection = &pool.get().unwrap();
                 conn.transaction(move || {
-                    let txs = transactions
+                    debug!("Set new last_height = {}", new_height);
+                    advance_current_height(last_height, new_height, conn)?;
+                    let txs: Vec<(Uuid, Option<String>)> = transactions
                         .filter(commit.eq_any(commits.keys()))
-                        .load::<Transaction>(conn)?;
-
-                    if txs.len() > 0 {
-                        debug!("Found {} transactions which got into chain", txs.len());
-                    }
-                    for tx in txs {
-                        let query = diesel::update(transactions.filter(id.eq(tx.id.clone())));
-
-                        match tx.status {
-                            TransactionStatus::Pending => query.set((
-                                status.eq(TransactionStatus::InChain),
-                                height.eq(commits.get(&tx.commit.unwrap()).unwrap()),
-                            )),
-                            TransactionStatus::Rejected => query.set((
-                                status.eq(TransactionStatus::Refund),
-                                height.eq(commits.get(&tx.commit.unwrap()).unwrap()),
-                            )),
-                            _ => {
-                                return Err(Error::General(format!(
-                                    "Transaction {} in chain although it has status {}",
-                                    tx.id.clone(),
-                                    tx.status
-                                )))
-                            }
-                        }
-                        .get_result(conn)
-                        .map(|_: Transaction| ())
-                        .map_err::<Error, _>(|e| e.into())?;
-                    }
-                    {
-                        debug!("Set new last_height = {}", new_height);
-                        use crate::schema::current_height::dsl::*;
-                        diesel::update(current_height)
-                            .set(height.eq(new_height as i64))
-                            .execute(conn)
-                            .map(|_| ())
-                            .map_err::<Error, _>(|e| e.into())?;
+                        .select((id, commit))
+                        .load(conn)?;
+                    for (tx_id, tx_commit) in txs {
+                        let tx_height = commits[&tx_commit.unwrap()];
+                        found.entry(tx_id).or_insert(tx_height);
                     }
+                    mark_in_chain(&found, now, conn)?;
+                    store_blocks(&new_blocks, new_height - MAX_REORG_DEPTH, conn)?;
                     Ok(())
                 })
             }
         })
         .await?;
 
+        if node_height - new_height > MIN_BLOCKS_PER_REQUEST {
+            info!(
+                "Catching up with node: synced to height {} of {}",
+                new_height, node_height
+            );
+        }
         Ok(())
     }
 
-    async fn autoconfirmation(&self) -> Result<(), Error> {
-        debug!("run autoconfirmation");
+    /// Node is too far ahead to fetch all blocks in reasonable time. Instead
+    /// look up only transactions we wait for and jump to the node's height
+    async fn skip_to(&self, last_height: i64, node_height: i64) -> Result<(), Error> {
+        warn!(
+            "Current height {} is {} blocks behind the node, look up only open transactions",
+            last_height,
+            node_height - last_height
+        );
+        let watched = self.watched_transactions().await?;
+        let found =
+            scan_transactions(self.node.as_ref(), &watched, last_height + 1, node_height).await?;
+        let found_count = found.len();
         block::<_, _, Error>({
             let pool = self.pool.clone();
+            let now = self.clock.now();
             move || {
                 let conn: &PgConnection = &pool.get().unwrap();
-                let last_height = {
-                    use crate::schema::current_height::dsl::*;
-                    let last_height: i64 = current_height.select(height).first(conn)?;
-                    last_height
-                };
-
-                use diesel::sql_query;
-                sql_query(format!(
-                    "UPDATE transactions SET status = 'confirmed' WHERE
-            status = 'in_chain' and confirmations < {} - height",
-                    last_height
-                ))
-                .execute(conn)?;
-                Ok(())
+                conn.transaction(|| {
+                    advance_current_height(last_height, node_height, conn)?;
+                    mark_in_chain(&found, now, conn)?;
+                    // we don't know hashes of skipped blocks
+                    store_blocks(&[], node_height - MAX_REORG_DEPTH, conn)
+                })
             }
         })
         .await?;
+        info!(
+            "Skipped to height {}, {} of {} open transactions found in chain",
+            node_height,
+            found_count,
+            watched.len()
+        );
         Ok(())
     }
-}
 
-fn reject_expired_payments(cron: &mut Cron, _: &mut Context<Cron>) {
-    debug!("run process_expired_payments");
+    /// Requests blocks in `ranges` concurrently, returns them ordered by height
+    async fn fetch_blocks(&self, ranges: &[(i64, i64)]) -> Result<Vec<node::Block>, Error> {
+        let requests = ranges
+            .iter()
+            .map(|(start, end)| self.node.blocks(*start, *end));
+        let mut blocks: Vec<node::Block> = try_join_all(requests)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+        blocks.sort_by_key(|block| block.header.height);
+        Ok(blocks)
+    }
 
-    let db = cron.db.clone();
+    /// Transactions which could get into chain
+    async fn watched_transactions(&self) -> Result<Vec<Transaction>, Error> {
+        let rejected_since = self.clock.now() - Duration::hours(WATCH_REJECTED_HOURS);
+        let txs = block::<_, _, Error>({
+            let pool = self.pool.clone();
+            move || {
+                let conn: &PgConnection = &pool.get().unwrap();
+                get_watched_transactions(rejected_since, conn)
+            }
+        })
+        .await?;
+        Ok(txs)
+    }
 
-    let fut = async move {
-        db.send(RejectExpiredPayments)
-            .await
-            .map_err(|e| Error::from(e))
-            .and_then(|db_response| {
-                db_response?;
-                Ok(())
-            })
-            .map_err(|e| error!("Got an error in rejecting exprired payments {}", e));
-    };
+    /// Returns height of the first block which is not in the node's chain anymore
+    async fn find_fork(
+        &self,
+        last_height: i64,
+        blocks: &[node::Block],
+    ) -> Result<Option<i64>, Error> {
+        let known_blocks = block::<_, _, Error>({
+            let pool = self.pool.clone();
+            move || {
+                let conn: &PgConnection = &pool.get().unwrap();
+                get_blocks(last_height - MAX_REORG_DEPTH, last_height, conn)
+            }
+        })
+        .await?;
+        let last_block = match known_blocks.first() {
+            Some(last_block) if last_block.height == last_height => last_block,
+            // we don't know hash of the last block, nothing to compare with
+            _ => return Ok(None),
+        };
+        match blocks.first() {
+            Some(block) if block.header.height as i64 == last_height => {
+                if block.header.hash == last_block.hash {
+                    return Ok(None);
+                }
+            }
+            // node is behind us
+            _ => return Ok(None),
+        }
 
-    actix::spawn(fut);
-}
+        let lowest_height = known_blocks.last().unwrap().height;
+        warn!(
+            "Node's chain diverged from ours at height {}, looking for common ancestor",
+            last_height
+        );
+        let node_hashes: HashMap<i64, String> = self
+            .node
+            .blocks(lowest_height, last_height)
+            .await?
+            .into_iter()
+            .map(|block| (block.header.height as i64, block.header.hash))
+            .collect();
+        for known_block in &known_blocks {
+            if node_hashes.get(&known_block.height) == Some(&known_block.hash) {
+                return Ok(Some(known_block.height + 1));
+            }
+        }
+        error!(
+            "Fork is deeper than {} blocks we remember, rollback to height {}",
+            MAX_REORG_DEPTH, lowest_height
+        );
+        Ok(Some(lowest_height))
+    }
 
-fn process_pending_payments(cron: &mut Cron, _: &mut Context<Cron>) {
-    let cron = cron.clone();
-    actix::spawn(async move {
-        cron.process_pending_payments()
-            .map(|r| {
-                if let Err(e) = r {
-                    error!("Couldn't process penging payments: {}", e);
-                }
-                ()
-            })
-            .await
-    });
+    async fn rollback(&self, fork_height: i64) -> Result<(), Error> {
+        let now = self.clock.now();
+        let rolled_back = block::<_, _, Error>({
+            let pool = self.pool.clone();
+            move || {
+                let conn: &PgConnection = &pool.get().unwrap();
+                rollback_chain(fork_height, now, conn)
+            }
+        })
+        .await?;
+        warn!(
+            "Chain reorganization from height {}: {} transactions rolled back",
+            fork_height,
+            rolled_back.len()
+        );
+        for tx in rolled_back {
+            warn!(
+                "Transaction {} is not in chain anymore, new status {}",
+                tx.id, tx.status
+            );
+        }
+        Ok(())
+    }
+
+    /// Tracks pending transactions in node's pool and raises an alert when
+    /// a transaction is neither in the pool nor in chain for too long
+    async fn watch_mempool(&self) -> Result<(), Error> {
+        debug!("run watch_mempool");
+        let pool_txs = match self.node.unconfirmed_transactions().await? {
+            Some(pool_txs) => pool_txs,
+            None => return Ok(()),
+        };
+        let now = self.clock.now();
+        let stuck = block::<_, _, Error>({
+            let pool = self.pool.clone();
+            move || {
+                let conn: &PgConnection = &pool.get().unwrap();
+                let txs = get_pending_transactions(conn)?;
+                let in_pool = find_in_pool(&txs, &pool_txs);
+                update_mempool_status(&txs, &in_pool, now, conn)
+            }
+        })
+        .await?;
+        for tx in stuck {
+            error!(
+                "ALERT: {} {} of merchant {} is pending since {} but it is neither in node's pool nor in chain",
+                tx.transaction_type, tx.id, tx.merchant_id, tx.updated_at
+            );
+        }
+        Ok(())
+    }
+
+    async fn autoconfirmation(&self) -> Result<(), Error> {
+        debug!("run autoconfirmation");
+        let confirmed = block::<_, _, Error>({
+            let pool = self.pool.clone();
+            let now = self.clock.now();
+            move || {
+                let conn: &PgConnection = &pool.get().unwrap();
+                let last_height = get_current_height(conn)?;
+                confirm_payments(last_height, now, conn)
+            }
+        })
+        .await?;
+        if confirmed > 0 {
+            debug!("Confirmed {} payments", confirmed);
+        }
+        Ok(())
+    }
 }
 
-fn process_unreported_confirmed_payments(cron: &mut Cron, _: &mut Context<Cron>) {
-    let cron = cron.clone();
-    actix::spawn(async move {
-        cron.process_unreported_confirmed_payments()
-            .map(|r| {
-                if let Err(e) = r {
-                    error!("Couldn't process unreported payments: {}", e);
-                }
-                ()
-            })
-            .await
-    });
+/// Splits blocks from `last_height` up to `node_height` into ranges which are
+/// requested concurrently. The last known block is requested again to check
+/// that node is still on the same chain. The further we are behind the node,
+/// the bigger the ranges.
+fn sync_ranges(last_height: i64, node_height: i64) -> Vec<(i64, i64)> {
+    let node_height = node_height.max(last_height);
+    let batch = ((node_height - last_height) / MAX_CONCURRENT_REQUESTS)
+        .max(MIN_BLOCKS_PER_REQUEST)
+        .min(MAX_BLOCKS_PER_REQUEST);
+    let mut ranges = vec![];
+    let mut start = last_height;
+    loop {
+        let end = (start + batch).min(node_height);
+        ranges.push((start, end));
+        if end == node_height || ranges.len() as i64 == MAX_CONCURRENT_REQUESTS {
+            return ranges;
+        }
+        start = end + 1;
+    }
 }
 
-fn process_unreported_rejected_payments(cron: &mut Cron, _: &mut Context<Cron>) {
-    let cron = cron.clone();
-    actix::spawn(async move {
-        cron.process_unreported_rejected_payments()
-            .map(|r| {
-                if let Err(e) = r {
-                    error!("Couldn't process unreported rejected payments: {}", e);
-                }
-                ()
-            })
-            .await
+/// Looks up kernels of `txs` in blocks [min_height, max_height].
+/// Returns heights of found ones by transaction id
+pub(crate) async fn find_kernels(
+    node: &dyn NodeApi,
+    txs: &[Transaction],
+    min_height: i64,
+    max_height: i64,
+) -> Result<HashMap<Uuid, i64>, Error> {
+    let mut found = HashMap::new();
+    if max_height < min_height {
+        return Ok(found);
+    }
+    let lookups = txs.iter().filter_map(|tx| {
+        tx.kernel_excess.as_ref().map(|excess| async move {
+            node.kernel(excess, min_height, max_height)
+                .await
+                .map(|height| (tx.id, excess, height))
+        })
     });
+    let results: Vec<_> = stream::iter(lookups)
+        .buffer_unordered(MAX_CONCURRENT_KERNEL_LOOKUPS)
+        .collect()
+        .await;
+    for result in results {
+        if let (tx_id, excess, Some(height)) = result? {
+            debug!("Found kernel {} of transaction {}", excess, tx_id);
+            found.insert(tx_id, height);
+        }
+    }
+    Ok(found)
 }
 
-fn sync_with_node(cron: &mut Cron, _: &mut Context<Cron>) {
-    let cron = cron.clone();
-    actix::spawn(async move {
-        cron.sync_with_node()
-            .map(|r| {
-                if let Err(e) = r {
-                    error!("Couldn't sync with a node: {}", e);
-                }
+/// Looks up `txs` in blocks [min_height, max_height] without fetching the blocks:
+/// by kernel and, if it's not found, by output commitment.
+/// Returns heights of found ones by transaction id
+async fn scan_transactions(
+    node: &dyn NodeApi,
+    txs: &[Transaction],
+    min_height: i64,
+    max_height: i64,
+) -> Result<HashMap<Uuid, i64>, Error> {
+    let mut found = find_kernels(node, txs, min_height, max_height).await?;
+    let commits: HashMap<String, Uuid> = txs
+        .iter()
+        .filter(|tx| !found.contains_key(&tx.id))
+        .filter_map(|tx| tx.commit.clone().map(|commit| (commit, tx.id)))
+        .collect();
+    let commit_list: Vec<String> = commits.keys().cloned().collect();
+    for chunk in commit_list.chunks(OUTPUTS_PER_REQUEST) {
+        for output in node.outputs(chunk).await? {
+            let height = output.height as i64;
+            if height < min_height || height > max_height {
+                continue;
+            }
+            if let Some(tx_id) = commits.get(&output.commit) {
+                debug!("Found output {} of transaction {}", output.commit, tx_id);
+                found.insert(*tx_id, height);
+            }
+        }
+    }
+    Ok(found)
+}
 
-                ()
-            })
-            .await
-    });
+/// Transactions from `txs` which are in node's pool, by kernel or by our output
+fn find_in_pool(txs: &[Transaction], pool_txs: &[PoolTransaction]) -> HashSet<Uuid> {
+    let kernels: HashSet<&String> = pool_txs.iter().flat_map(|tx| &tx.kernels).collect();
+    let outputs: HashSet<&String> = pool_txs.iter().flat_map(|tx| &tx.outputs).collect();
+    txs.iter()
+        .filter(|tx| {
+            tx.kernel_excess.as_ref().map(|k| kernels.contains(k)) == Some(true)
+                || tx.commit.as_ref().map(|c| outputs.contains(c)) == Some(true)
+        })
+        .map(|tx| tx.id)
+        .collect()
 }
 
-fn autoconfirmation(cron: &mut Cron, _: &mut Context<Cron>) {
-    let cron = cron.clone();
-    actix::spawn(async move {
-        cron.autoconfirmation()
-            .map(|r| {
-                if let Err(e) = r {
-                    error!("Error in autoconfirmation: {}", e);
-                }
-                ()
-            })
-            .await
-    });
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_ranges_test() {
+        assert_eq!(sync_ranges(100, 100), vec![(100, 100)]);
+        // node is behind us
+        assert_eq!(sync_ranges(100, 90), vec![(100, 100)]);
+        assert_eq!(sync_ranges(100, 105), vec![(100, 105)]);
+        assert_eq!(
+            sync_ranges(100, 140),
+            vec![(100, 110), (111, 121), (122, 132), (133, 140)]
+        );
+        let ranges = sync_ranges(0, 100_000);
+        assert_eq!(ranges, vec![(0, 100), (101, 201), (202, 302), (303, 403)]);
+    }
 }
@@ -1,23 +1,54 @@
+use crate::clock::Clock;
 use crate::errors::Error;
 use crate::fsm_payout::{
-    FsmPayout, GetExpiredInitializedPayouts, GetExpiredNewPayouts, GetPendingPayouts, RejectPayout,
+    CancelPayoutTx, ConfirmPayout, FsmPayout, GetConfirmablePayouts, GetDueAutoPayouts,
+    GetExpiredInitializedPayouts, GetExpiredNewPayouts, GetPendingPayouts, GetSeenInChainPayouts,
+    GetUnreportedPayouts, PendingPayout, RejectPayout, ReportPayout, SeenInChainPayout,
 };
-use crate::rates::RatesFetcher;
-use crate::Pool;
+use crate::node::NodeApi;
+use crate::scheduler::{self, JobRegistry};
 use actix::prelude::*;
-use futures::future::{join_all, Future, FutureExt};
+use futures::future::Future;
 use log::*;
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use std::sync::Arc;
+use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct CronPayout {
     fsm: Addr<FsmPayout>,
-    pool: Pool,
+    node: Arc<dyn NodeApi>,
+    clock: Arc<dyn Clock>,
+    jobs: JobRegistry,
+    /// Expired payouts which need a manual check, they are reported once
+    stuck_payouts: Arc<Mutex<HashSet<Uuid>>>,
 }
 
 impl CronPayout {
-    pub fn new(fsm: Addr<FsmPayout>, pool: Pool) -> Self {
-        CronPayout { fsm, pool }
+    pub fn new(
+        fsm: Addr<FsmPayout>,
+        node: Arc<dyn NodeApi>,
+        clock: Arc<dyn Clock>,
+        jobs: JobRegistry,
+    ) -> Self {
+        CronPayout {
+            fsm,
+            node,
+            clock,
+            jobs,
+            stuck_payouts: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    fn schedule<F, Fut>(&self, ctx: &mut Context<Self>, name: &'static str, job: F)
+    where
+        F: Fn(CronPayout) -> Fut + 'static,
+        Fut: Future<Output = Result<(), Error>> + 'static,
+    {
+        scheduler::schedule(ctx, self.jobs.clone(), self.clock.clone(), name, job);
     }
+
     async fn process_expired_new_payouts(&self) -> Result<(), Error> {
         debug!("run process_expired_new_payouts");
         let payouts = self
@@ -84,24 +115,200 @@ impl CronPayout {
             .await
             .map_err(|e| Error::General(s!(e)))??;
         debug!("Found {} pending payouts", payouts.len());
+        let now = self.clock.now();
+        // payouts found in chain are moved to InChain by process_in_chain_payouts
+        for payout in payouts
+            .into_iter()
+            .filter(|payout| payout.height.is_none() && payout.is_expired(now))
+        {
+            let payout_id = payout.id;
+            if let Err(e) = self.expire_pending_payout(payout).await {
+                error!("Cannot reject payout {}: {}", payout_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// The payout's transaction was posted, so it can still get into the
+    /// chain. Reject it only after our wallet cancelled the transaction and
+    /// the node confirms that all its inputs are unspent.
+    async fn expire_pending_payout(&self, payout: PendingPayout) -> Result<(), Error> {
+        let inputs = match payout.input_commits.clone() {
+            Some(inputs) if !inputs.is_empty() => inputs,
+            _ => return self.expire_payout_by_kernel(payout).await,
+        };
+        let payout = self.cancel_payout_tx(payout).await?;
+        let unspent = self.node.outputs(&inputs).await?;
+        if !inputs
+            .iter()
+            .all(|input| unspent.iter().any(|output| &output.commit == input))
+        {
+            if self.first_warning(payout.id) {
+                warn!(
+                    "Inputs of expired payout {} are spent, it should be found in chain",
+                    payout.id
+                );
+            }
+            return Ok(());
+        }
+        self.fsm
+            .send(RejectPayout { payout })
+            .await
+            .map_err(|e| Error::General(s!(e)))??;
+        Ok(())
+    }
+
+    /// Payouts posted before their inputs were recorded. Once our wallet
+    /// cancelled the transaction, the payout is rejected if its kernel isn't
+    /// in the blocks mined since it was created.
+    async fn expire_payout_by_kernel(&self, payout: PendingPayout) -> Result<(), Error> {
+        let excess = match payout.kernel_excess.clone() {
+            Some(excess) => excess,
+            None => {
+                if self.first_warning(payout.id) {
+                    warn!(
+                        "Inputs and kernel of expired payout {} are unknown, check it manually",
+                        payout.id
+                    );
+                }
+                return Ok(());
+            }
+        };
+        let payout = self.cancel_payout_tx(payout).await?;
+        let max_height = self.node.current_height().await?;
+        // a block is mined every minute on average, look twice as far back
+        // as the payout exists
+        let elapsed_blocks = (self.clock.now() - payout.created_at).num_minutes() + 1;
+        let min_height = (max_height - 2 * elapsed_blocks).max(0);
+        match self.node.kernel(&excess, min_height, max_height).await? {
+            Some(height) => {
+                self.fsm
+                    .send(SeenInChainPayout { payout, height })
+                    .await
+                    .map_err(|e| Error::General(s!(e)))??;
+            }
+            None => {
+                self.fsm
+                    .send(RejectPayout { payout })
+                    .await
+                    .map_err(|e| Error::General(s!(e)))??;
+            }
+        }
+        Ok(())
+    }
+
+    async fn cancel_payout_tx(&self, payout: PendingPayout) -> Result<PendingPayout, Error> {
+        if payout.wallet_tx_cancelled {
+            return Ok(payout);
+        }
+        self.fsm
+            .send(CancelPayoutTx { payout })
+            .await
+            .map_err(|e| Error::General(s!(e)))?
+    }
+
+    /// Whether the payout isn't reported as stuck yet
+    fn first_warning(&self, payout_id: Uuid) -> bool {
+        self.stuck_payouts.lock().insert(payout_id)
+    }
+
+    async fn process_in_chain_payouts(&self) -> Result<(), Error> {
+        debug!("run process_in_chain_payouts");
+        let payouts = self
+            .fsm
+            .send(GetSeenInChainPayouts)
+            .await
+            .map_err(|e| Error::General(s!(e)))??;
+        debug!("Found {} payouts which got into chain", payouts.len());
         for payout in payouts {
             let payout_id = payout.id;
-            let res: Result<(), Error> = self
+            let height = match payout.height {
+                Some(height) => height,
+                None => continue,
+            };
+            let res = self
                 .fsm
-                .send(RejectPayout {
-                    payout: payout.clone(),
-                })
+                .send(SeenInChainPayout { payout, height })
                 .await
                 .map_err(|e| Error::General(s!(e)))
-                .and_then(|db_response| {
-                    db_response?;
-                    Ok(())
-                })
-                .or_else(move |e| {
-                    error!("Cannot reject payout {}: {}", payout_id, e);
-                    Ok(())
-                });
-            res?;
+                .and_then(|db_response| db_response);
+            if let Err(e) = res {
+                error!("Cannot move payout {} to InChain: {}", payout_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn confirm_payouts(&self) -> Result<(), Error> {
+        debug!("run confirm_payouts");
+        let payouts = self
+            .fsm
+            .send(GetConfirmablePayouts)
+            .await
+            .map_err(|e| Error::General(s!(e)))??;
+        debug!("Found {} payouts with enough confirmations", payouts.len());
+        for payout in payouts {
+            let payout_id = payout.id;
+            let res = self
+                .fsm
+                .send(ConfirmPayout { payout })
+                .await
+                .map_err(|e| Error::General(s!(e)))
+                .and_then(|db_response| db_response);
+            if let Err(e) = res {
+                error!("Cannot confirm payout {}: {}", payout_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn report_payouts(&self) -> Result<(), Error> {
+        debug!("run report_payouts");
+        let payouts = self
+            .fsm
+            .send(GetUnreportedPayouts)
+            .await
+            .map_err(|e| Error::General(s!(e)))??;
+        for payout in payouts {
+            let payout_id = payout.id;
+            let res = self
+                .fsm
+                .send(ReportPayout { payout })
+                .await
+                .map_err(|e| Error::General(s!(e)))
+                .and_then(|db_response| db_response);
+            if let Err(e) = res {
+                warn!("Couldn't report payout {}: {}", payout_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn auto_payouts(&self) -> Result<(), Error> {
+        debug!("run auto_payouts");
+        let due = self
+            .fsm
+            .send(GetDueAutoPayouts)
+            .await
+            .map_err(|e| Error::General(s!(e)))??;
+        for auto_payout in due {
+            let merchant_id = auto_payout.merchant.id.clone();
+            let res = self
+                .fsm
+                .send(auto_payout)
+                .await
+                .map_err(|e| Error::General(s!(e)))
+                .and_then(|db_response| db_response);
+            match res {
+                Ok(payout) => info!(
+                    "Sent payout {} to wallet of merchant {}",
+                    payout.id, merchant_id
+                ),
+                Err(e) => error!(
+                    "Automatic payout for merchant {} failed: {}",
+                    merchant_id, e
+                ),
+            }
         }
         Ok(())
     }
@@ -112,37 +319,32 @@ impl Actor for CronPayout {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("Starting cron process");
-        let rates = RatesFetcher::new(self.pool.clone());
-        ctx.run_interval(
-            std::time::Duration::new(5, 0),
-            move |_instance: &mut CronPayout, _ctx: &mut Context<Self>| {
-                rates.fetch();
-            },
+        self.schedule(ctx, "process_expired_new_payouts", |cron| async move {
+            cron.process_expired_new_payouts().await
+        });
+        self.schedule(
+            ctx,
+            "process_expired_initialized_payouts",
+            |cron| async move { cron.process_expired_initialized_payouts().await },
         );
-        ctx.run_interval(std::time::Duration::new(5, 0), process_expired_new_payouts);
-        ctx.run_interval(
-            std::time::Duration::new(5, 0),
-            process_expired_initialized_payouts,
-        );
-        ctx.run_interval(std::time::Duration::new(5, 0), process_pending_payouts);
+        self.schedule(ctx, "process_pending_payouts", |cron| async move {
+            cron.process_pending_payouts().await
+        });
+        self.schedule(ctx, "process_in_chain_payouts", |cron| async move {
+            cron.process_in_chain_payouts().await
+        });
+        self.schedule(ctx, "confirm_payouts", |cron| async move {
+            cron.confirm_payouts().await
+        });
+        self.schedule(ctx, "report_payouts", |cron| async move {
+            cron.report_payouts().await
+        });
+        self.schedule(ctx, "auto_payouts", |cron| async move {
+            cron.auto_payouts().await
+        });
     }
 
     fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
         Running::Stop
     }
 }
-
-fn process_expired_new_payouts(cron: &mut CronPayout, _: &mut Context<CronPayout>) {
-    let cron = cron.clone();
-    actix::spawn(async move { cron.process_expired_new_payouts().map(|_| ()).await });
-}
-
-fn process_expired_initialized_payouts(cron: &mut CronPayout, _: &mut Context<CronPayout>) {
-    let cron = cron.clone();
-    actix::spawn(async move { cron.process_expired_initialized_payouts().map(|_| ()).await });
-}
-
-fn process_pending_payouts(cron: &mut CronPayout, _: &mut Context<CronPayout>) {
-    let cron = cron.clone();
-    actix::spawn(async move { cron.process_pending_payouts().map(|_| ()).await });
-}
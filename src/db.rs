@@ -1,23 +1,25 @@
 use crate::errors::*;
+use crate::fees::applied_fee_schedule;
+use crate::ledger::{get_balances, record_transaction, RESERVED_ACCOUNTS};
 use crate::models::{
-    Currency, Merchant, Money, Rate, Transaction, TransactionStatus, TransactionType,
-    NEW_PAYMENT_TTL_SECONDS,
+    Block, Currency, CurrentHeight, Merchant, Money, PayoutPolicy, Rate, Transaction,
+    TransactionStatus, TransactionType, NEW_PAYMENT_TTL_SECONDS,
 };
+use crate::ser;
 use crate::Pool;
 use actix::{Actor, SyncContext};
 use actix::{Handler, Message};
-use bigdecimal::{BigDecimal, ToPrimitive};
+use chrono::Duration;
 use chrono::NaiveDateTime;
-use chrono::{Duration, Local, Utc};
 use data_encoding::BASE32;
-use diesel::dsl::sum;
 use diesel::pg::PgConnection;
 use diesel::{self, prelude::*};
-use log::info;
+use log::{debug, error, info};
 use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
+use ring::digest::{digest, SHA256};
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use uuid::Uuid;
 
 pub struct DbExecutor(pub Pool);
@@ -64,7 +66,7 @@ pub struct GetPayoutsByStatus(pub TransactionStatus);
 #[derive(Debug, Deserialize)]
 pub struct ReportAttempt {
     pub transaction_id: Uuid,
-    pub next_attempt: Option<NaiveDateTime>,
+    pub next_attempt: NaiveDateTime,
 }
 
 #[derive(Debug, Deserialize)]
@@ -78,7 +80,9 @@ pub struct Reset2FA {
 }
 
 #[derive(Debug, Deserialize)]
-pub struct RejectExpiredPayments;
+pub struct RejectExpiredPayments {
+    pub now: NaiveDateTime,
+}
 
 impl Message for GetMerchant {
     type Result = Result<Merchant, Error>;
@@ -108,29 +112,47 @@ impl Message for RejectExpiredPayments {
     type Result = Result<(), Error>;
 }
 
-pub fn create_merchant(m: CreateMerchant, conn: &PgConnection) -> Result<Merchant, Error> {
-    use crate::schema::merchants;
-    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+const TOKEN_CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
     abcdefghijklmnopqrstuvwxyz\
     0123456789";
+
+fn random_token() -> Option<String> {
+    let mut rng = thread_rng();
+    (0..64)
+        .map(|_| Some(*TOKEN_CHARSET.choose(&mut rng)? as char))
+        .collect()
+}
+
+pub fn create_merchant(
+    m: CreateMerchant,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<Merchant, Error> {
+    use crate::schema::merchants;
+    if RESERVED_ACCOUNTS.contains(&m.id.as_str()) {
+        return Err(Error::AlreadyExists(format!("merchant {}", m.id)));
+    }
     let password =
         bcrypt::hash(&m.password, bcrypt::DEFAULT_COST).map_err(|e| Error::General(s!(e)))?;
 
     let mut rng = thread_rng();
-    let new_token: Option<String> = (0..64)
-        .map(|_| Some(*CHARSET.choose(&mut rng)? as char))
-        .collect();
+    let new_token = random_token();
     let new_token_2fa = BASE32.encode(&rng.gen::<[u8; 10]>());
     let new_merchant = Merchant {
         id: m.id,
         email: m.email,
         password: password,
         wallet_url: m.wallet_url,
-        created_at: Local::now().naive_local() + Duration::hours(24),
+        created_at: now,
         callback_url: m.callback_url,
         token: new_token.ok_or(Error::General(s!("cannot generate rangom token")))?,
         token_2fa: Some(new_token_2fa),
         confirmed_2fa: false,
+        payout_policy: PayoutPolicy::Manual,
+        payout_threshold: None,
+        last_auto_payout_at: None,
+        auto_payout_error: None,
+        payout_key_hash: None,
     };
 
     diesel::insert_into(merchants::table)
@@ -139,6 +161,21 @@ pub fn create_merchant(m: CreateMerchant, conn: &PgConnection) -> Result<Merchan
         .map_err(|e| e.into())
 }
 
+/// Only a hash of payout key is stored, the key itself is shown to merchant once
+pub fn hash_payout_key(key: &str) -> String {
+    ser::to_hex(digest(&SHA256, key.as_bytes()).as_ref().to_vec())
+}
+
+/// Generates a new payout key for merchant, the old one stops working
+pub fn new_payout_key(merch_id: &str, conn: &PgConnection) -> Result<String, Error> {
+    use crate::schema::merchants::dsl::*;
+    let key = random_token().ok_or(Error::General(s!("cannot generate random key")))?;
+    diesel::update(merchants.filter(id.eq(merch_id)))
+        .set(payout_key_hash.eq(hash_payout_key(&key)))
+        .get_result::<Merchant>(conn)?;
+    Ok(key)
+}
+
 impl Handler<GetMerchant> for DbExecutor {
     type Result = Result<Merchant, Error>;
 
@@ -176,6 +213,7 @@ impl Handler<GetPayoutsByStatus> for DbExecutor {
 
 pub fn create_transaction(
     tx: CreateTransaction,
+    now: NaiveDateTime,
     conn: &PgConnection,
 ) -> Result<Transaction, Error> {
     use crate::schema::merchants::dsl::*;
@@ -200,6 +238,16 @@ pub fn create_transaction(
     };
 
     let grins = tx.amount.convert_to(Currency::GRIN, exch_rate.rate);
+    let (payment_fee, applied_schedule) = match tx.transaction_type {
+        TransactionType::Payment => {
+            let applied_schedule = applied_fee_schedule(&tx.merchant_id, now, conn)?;
+            (
+                Some(applied_schedule.payment_fee(grins.amount)),
+                Some(applied_schedule),
+            )
+        }
+        TransactionType::Payout => (None, None),
+    };
 
     let new_transaction = Transaction {
         id: uuid::Uuid::new_v4(),
@@ -210,8 +258,8 @@ pub fn create_transaction(
         grin_amount: grins.amount,
         status: TransactionStatus::New,
         confirmations: tx.confirmations,
-        created_at: Local::now().naive_local(),
-        updated_at: Local::now().naive_local(),
+        created_at: now,
+        updated_at: now,
         report_attempts: 0,
         next_report_attempt: None,
         reported: false,
@@ -220,40 +268,61 @@ pub fn create_transaction(
         message: tx.message,
         slate_messages: None,
         transfer_fee: None,
-        knockturn_fee: None,
+        knockturn_fee: payment_fee,
         real_transfer_fee: None,
         transaction_type: tx.transaction_type,
         height: None,
         commit: None,
         redirect_url: tx.redirect_url,
+        wallet_tx_cancelled: false,
+        cancel_attempts: 0,
+        next_cancel_attempt: None,
+        kernel_excess: None,
+        in_mempool: false,
+        mempool_changed_at: None,
+        stuck_since: None,
+        transfer_fee_credit: None,
+        fee_schedule: applied_schedule,
+        input_commits: None,
     };
 
-    diesel::insert_into(transactions)
-        .values(&new_transaction)
-        .get_result(conn)
-        .map_err(|e| e.into())
+    conn.transaction(|| {
+        let transaction = diesel::insert_into(transactions)
+            .values(&new_transaction)
+            .get_result(conn)?;
+        record_transaction(&transaction, now, conn)?;
+        Ok(transaction)
+    })
 }
 
 pub fn update_transaction_status(
     tx_id: Uuid,
     tx_status: TransactionStatus,
+    now: NaiveDateTime,
     conn: &PgConnection,
 ) -> Result<Transaction, Error> {
     use crate::schema::transactions::dsl::*;
-    diesel::update(transactions.filter(id.eq(tx_id)))
-        .set((status.eq(tx_status), updated_at.eq(Utc::now().naive_utc())))
-        .get_result(conn)
-        .map_err(|e| e.into())
+    conn.transaction(|| {
+        let transaction = diesel::update(transactions.filter(id.eq(tx_id)))
+            .set((status.eq(tx_status), updated_at.eq(now)))
+            .get_result(conn)?;
+        record_transaction(&transaction, now, conn)?;
+        Ok(transaction)
+    })
 }
 
-pub fn register_rate(rates_map: HashMap<String, f64>, conn: &PgConnection) -> Result<(), Error> {
+pub fn register_rate(
+    rates_map: HashMap<String, f64>,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<(), Error> {
     use crate::schema::rates::dsl::*;
 
     for (currency, new_rate) in rates_map {
         let new_rate = Rate {
             id: currency.to_uppercase(),
             rate: new_rate,
-            updated_at: Local::now().naive_local(),
+            updated_at: now,
         };
 
         diesel::insert_into(rates)
@@ -270,10 +339,9 @@ pub fn register_rate(rates_map: HashMap<String, f64>, conn: &PgConnection) -> Re
 pub fn report_attempt(
     conn: &PgConnection,
     transaction_id: Uuid,
-    next_attempt: Option<NaiveDateTime>,
+    next_attempt: NaiveDateTime,
 ) -> Result<(), Error> {
     use crate::schema::transactions::dsl::*;
-    let next_attempt = next_attempt.unwrap_or(Utc::now().naive_utc() + Duration::seconds(10));
     diesel::update(transactions.filter(id.eq(transaction_id)))
         .set((
             report_attempts.eq(report_attempts + 1),
@@ -284,6 +352,35 @@ pub fn report_attempt(
         .map(|_: Transaction| ())
 }
 
+pub fn cancel_attempt(
+    conn: &PgConnection,
+    transaction_id: Uuid,
+    next_attempt: NaiveDateTime,
+) -> Result<Transaction, Error> {
+    use crate::schema::transactions::dsl::*;
+    diesel::update(transactions.filter(id.eq(transaction_id)))
+        .set((
+            cancel_attempts.eq(cancel_attempts + 1),
+            next_cancel_attempt.eq(next_attempt),
+        ))
+        .get_result(conn)
+        .map_err(|e| e.into())
+}
+
+pub fn mark_wallet_tx_cancelled(
+    transaction_id: Uuid,
+    conn: &PgConnection,
+) -> Result<Transaction, Error> {
+    use crate::schema::transactions::dsl::*;
+    diesel::update(transactions.filter(id.eq(transaction_id)))
+        .set((
+            wallet_tx_cancelled.eq(true),
+            cancel_attempts.eq(cancel_attempts + 1),
+        ))
+        .get_result(conn)
+        .map_err(|e| e.into())
+}
+
 impl Handler<ReportAttempt> for DbExecutor {
     type Result = Result<(), Error>;
 
@@ -327,26 +424,29 @@ impl Handler<Reset2FA> for DbExecutor {
 impl Handler<RejectExpiredPayments> for DbExecutor {
     type Result = Result<(), Error>;
 
-    fn handle(&mut self, _: RejectExpiredPayments, _: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: RejectExpiredPayments, _: &mut Self::Context) -> Self::Result {
         use crate::schema::transactions::dsl::*;
         let conn: &PgConnection = &self.0.get().unwrap();
-        diesel::update(
-            transactions
+        conn.transaction(|| {
+            let expired: Vec<Transaction> = transactions
                 .filter(status.eq(TransactionStatus::New))
                 .filter(transaction_type.eq(TransactionType::Payment))
-                .filter(
-                    created_at
-                        .lt(Utc::now().naive_utc() - Duration::seconds(NEW_PAYMENT_TTL_SECONDS)),
-                ),
-        )
-        .set(status.eq(TransactionStatus::Rejected))
-        .execute(conn)
-        .map_err(|e| e.into())
-        .map(|n| {
-            if n > 0 {
-                info!("Rejected {} expired new payments", n);
+                .filter(created_at.lt(msg.now - Duration::seconds(NEW_PAYMENT_TTL_SECONDS)))
+                .for_update()
+                .load(conn)?;
+            for tx in &expired {
+                let tx = diesel::update(transactions.filter(id.eq(tx.id)))
+                    .set((
+                        status.eq(TransactionStatus::Rejected),
+                        updated_at.eq(msg.now),
+                    ))
+                    .get_result(conn)?;
+                record_transaction(&tx, msg.now, conn)?;
+            }
+            if !expired.is_empty() {
+                info!("Rejected {} expired new payments", expired.len());
             }
-            ()
+            Ok(())
         })
     }
 }
@@ -358,62 +458,474 @@ pub fn get_current_height(conn: &PgConnection) -> Result<i64, Error> {
         .map_err(|e| e.into())
 }
 
-//
-//  SELECT (
-//              SELECT coalesce(sum(grin_amount), 0)
-//                  FROM transactions
-//                  WHERE merchant_id = 'id' AND transaction_type = 'payment' AND ( status = 'refund' OR status = 'refunded_manually' OR  ( status = 'confirmed' and reported = true))
-//          )
-//          -
-//          (
-//              SELECT coalesce(sum(grin_amount), 0)
-//                  FROM transactions
-//                  WHERE merchant_id = 'id' AND transaction_type = 'payout' AND status <> 'rejected'
-//          );
-//
-pub fn get_balance(merch_id: &str, conn: &PgConnection) -> Result<i64, Error> {
+pub fn set_current_height(new_height: i64, conn: &PgConnection) -> Result<(), Error> {
+    use crate::schema::current_height::dsl::*;
+    diesel::update(current_height)
+        .set(height.eq(new_height))
+        .execute(conn)
+        .map(|_| ())
+        .map_err(|e| e.into())
+}
+
+/// Move current height from `from_height` to `to_height`. Fails if it was
+/// changed meanwhile by somebody else, e.g. by an overlapping sync
+pub fn advance_current_height(
+    from_height: i64,
+    to_height: i64,
+    conn: &PgConnection,
+) -> Result<(), Error> {
+    use crate::schema::current_height::dsl::*;
+    let updated = diesel::update(current_height.filter(height.eq(from_height)))
+        .set(height.eq(to_height))
+        .execute(conn)?;
+    if updated == 0 {
+        return Err(Error::General(format!(
+            "Current height is not {} anymore",
+            from_height
+        )));
+    }
+    Ok(())
+}
+
+pub fn get_sync_status(conn: &PgConnection) -> Result<CurrentHeight, Error> {
+    use crate::schema::current_height::dsl::*;
+    current_height.first(conn).map_err(|e| e.into())
+}
+
+pub fn set_node_height(new_height: i64, conn: &PgConnection) -> Result<(), Error> {
+    use crate::schema::current_height::dsl::*;
+    diesel::update(current_height)
+        .set(node_height.eq(new_height))
+        .execute(conn)
+        .map(|_| ())
+        .map_err(|e| e.into())
+}
+
+/// Transactions which could appear in chain: pending ones and ones rejected
+/// after `rejected_since`, their wallet tx could be posted anyway
+pub fn get_watched_transactions(
+    rejected_since: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<Vec<Transaction>, Error> {
     use crate::schema::transactions::dsl::*;
-    let payments = transactions
-        .select(sum(grin_amount))
-        .filter(merchant_id.eq(merch_id))
+    transactions
         .filter(
-            // As a valid we consider a payments with
-            // Status=Confirmed and reported to merchant (which means that user got his goods)
-            // or Status = Refund (which means that we took user's money, but couldn't report to merchant)
             status
-                .eq(TransactionStatus::Refund)
-                .or(status.eq(TransactionStatus::RefundedManually))
+                .eq(TransactionStatus::Pending)
+                .and(height.is_null())
                 .or(status
-                    .eq(TransactionStatus::Confirmed)
-                    .and(reported.eq(true))),
+                    .eq(TransactionStatus::Rejected)
+                    .and(updated_at.gt(rejected_since))),
         )
-        .filter(transaction_type.eq(TransactionType::Payment))
-        .first::<Option<BigDecimal>>(conn)
-        .map_err::<Error, _>(|e| e.into())?
-        .and_then(|b| b.to_i64())
-        .unwrap_or(0);
+        .load::<Transaction>(conn)
+        .map_err(|e| e.into())
+}
+
+/// Pending transactions which are not found in chain yet
+pub fn get_pending_transactions(conn: &PgConnection) -> Result<Vec<Transaction>, Error> {
+    use crate::schema::transactions::dsl::*;
+    transactions
+        .filter(status.eq(TransactionStatus::Pending))
+        .filter(height.is_null())
+        .load::<Transaction>(conn)
+        .map_err(|e| e.into())
+}
+
+/// Records which of pending transactions `txs` are in node's pool now. Returns
+/// the ones which are missing from the pool for too long and are marked stuck
+pub fn update_mempool_status(
+    txs: &[Transaction],
+    in_pool: &HashSet<Uuid>,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<Vec<Transaction>, Error> {
+    use crate::schema::transactions::dsl::*;
+    let mut stuck = vec![];
+    for tx in txs {
+        let query = diesel::update(
+            transactions
+                .filter(id.eq(tx.id))
+                .filter(status.eq(TransactionStatus::Pending)),
+        );
+        if in_pool.contains(&tx.id) != tx.in_mempool {
+            query
+                .set((
+                    in_mempool.eq(!tx.in_mempool),
+                    mempool_changed_at.eq(now),
+                    stuck_since.eq(None::<NaiveDateTime>),
+                ))
+                .execute(conn)?;
+        } else if tx.stuck_since.is_none() && tx.is_missing_from_mempool(now) {
+            stuck.extend(
+                query
+                    .set(stuck_since.eq(now))
+                    .get_result::<Transaction>(conn)
+                    .optional()?,
+            );
+        }
+    }
+    Ok(stuck)
+}
+
+/// Mark transactions found in chain. `found` maps transaction id to height of
+/// the block where it was found. Payouts only get the height, they are moved to
+/// InChain by the payout state machine
+pub fn mark_in_chain(
+    found: &HashMap<Uuid, i64>,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<(), Error> {
+    use crate::schema::transactions::dsl::*;
+    let txs = transactions
+        .filter(id.eq_any(found.keys()))
+        .load::<Transaction>(conn)?;
+    if txs.len() > 0 {
+        debug!("Found {} transactions which got into chain", txs.len());
+    }
+    for tx in txs {
+        let tx_height = found[&tx.id];
+        let query = diesel::update(transactions.filter(id.eq(tx.id.clone())));
+
+        match (tx.transaction_type, tx.status) {
+            (TransactionType::Payout, TransactionStatus::Pending) => {
+                query.set((status.eq(TransactionStatus::Pending), height.eq(tx_height)))
+            }
+            (TransactionType::Payout, TransactionStatus::Rejected) => {
+                // the amount returned to merchant's balance has left our wallet,
+                // lock it again
+                error!(
+                    "Rejected payout {} got into chain at height {}",
+                    tx.id, tx_height
+                );
+                let tx = query
+                    .set((
+                        status.eq(TransactionStatus::InChain),
+                        height.eq(tx_height),
+                        updated_at.eq(now),
+                        reported.eq(false),
+                    ))
+                    .get_result(conn)?;
+                record_transaction(&tx, now, conn)?;
+                continue;
+            }
+            (_, TransactionStatus::Pending) => {
+                query.set((status.eq(TransactionStatus::InChain), height.eq(tx_height)))
+            }
+            (_, TransactionStatus::Rejected) => {
+                query.set((status.eq(TransactionStatus::Refund), height.eq(tx_height)))
+            }
+            _ => {
+                return Err(Error::General(format!(
+                    "Transaction {} in chain although it has status {}",
+                    tx.id.clone(),
+                    tx.status
+                )))
+            }
+        }
+        .get_result(conn)
+        .map_err::<Error, _>(|e| e.into())
+        .and_then(|tx: Transaction| record_transaction(&tx, now, conn))?;
+    }
+    Ok(())
+}
 
-    let payouts = transactions
-        .select(sum(grin_amount))
-        .filter(merchant_id.eq(merch_id))
-        .filter(status.ne(TransactionStatus::Rejected))
-        .filter(transaction_type.eq(TransactionType::Payout))
-        .first::<Option<BigDecimal>>(conn)
-        .map_err::<Error, _>(|e| e.into())?
-        .and_then(|b| b.to_i64())
-        .unwrap_or(0);
+/// Confirms payments which got enough confirmations at `last_height`,
+/// returns how many of them
+pub fn confirm_payments(
+    last_height: i64,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<usize, Error> {
+    use crate::schema::transactions::dsl::*;
+    conn.transaction(|| {
+        let confirmable: Vec<Transaction> = transactions
+            .filter(status.eq(TransactionStatus::InChain))
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .for_update()
+            .load::<Transaction>(conn)?
+            .into_iter()
+            .filter(|tx| match tx.height {
+                Some(tx_height) => tx.confirmations < last_height - tx_height,
+                None => false,
+            })
+            .collect();
+        for tx in &confirmable {
+            let tx = diesel::update(transactions.filter(id.eq(tx.id)))
+                .set((status.eq(TransactionStatus::Confirmed), updated_at.eq(now)))
+                .get_result(conn)?;
+            record_transaction(&tx, now, conn)?;
+        }
+        Ok(confirmable.len())
+    })
+}
+
+/// Known blocks in range [from_height, to_height], the highest first
+pub fn get_blocks(
+    from_height: i64,
+    to_height: i64,
+    conn: &PgConnection,
+) -> Result<Vec<Block>, Error> {
+    use crate::schema::blocks::dsl::*;
+    blocks
+        .filter(height.ge(from_height))
+        .filter(height.le(to_height))
+        .order(height.desc())
+        .load(conn)
+        .map_err(|e| e.into())
+}
 
-    Ok(payments - payouts)
+/// Remember new blocks and forget ones below `keep_from`
+pub fn store_blocks(
+    new_blocks: &[Block],
+    keep_from: i64,
+    conn: &PgConnection,
+) -> Result<(), Error> {
+    use crate::schema::blocks::dsl::*;
+    if let Some(lowest) = new_blocks.iter().map(|b| b.height).min() {
+        diesel::delete(blocks.filter(height.ge(lowest))).execute(conn)?;
+        diesel::insert_into(blocks)
+            .values(new_blocks)
+            .execute(conn)?;
+    }
+    diesel::delete(blocks.filter(height.lt(keep_from))).execute(conn)?;
+    Ok(())
+}
+
+/// Undo everything we learned from blocks starting at `fork_height`, which
+/// are not in the chain anymore. Transactions which were seen in these blocks
+/// go back to the state they had before, so they can be found again in the new branch.
+pub fn rollback_chain(
+    fork_height: i64,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<Vec<Transaction>, Error> {
+    conn.transaction(|| {
+        use crate::schema::transactions::dsl::*;
+        let mut rolled_back: Vec<Transaction> = diesel::update(
+            transactions
+                .filter(height.ge(fork_height))
+                .filter(status.eq_any(vec![
+                    TransactionStatus::InChain,
+                    TransactionStatus::Confirmed,
+                ])),
+        )
+        .set((
+            status.eq(TransactionStatus::Pending),
+            height.eq(None::<i64>),
+            updated_at.eq(now),
+            reported.eq(false),
+            report_attempts.eq(0),
+            next_report_attempt.eq(None::<NaiveDateTime>),
+        ))
+        .get_results(conn)?;
+
+        let refunds: Vec<Transaction> = diesel::update(
+            transactions
+                .filter(height.ge(fork_height))
+                .filter(status.eq(TransactionStatus::Refund)),
+        )
+        .set((
+            status.eq(TransactionStatus::Rejected),
+            height.eq(None::<i64>),
+            updated_at.eq(now),
+        ))
+        .get_results(conn)?;
+        rolled_back.extend(refunds);
+
+        // payouts which were found in chain but not moved to InChain yet
+        let unseen: Vec<Transaction> = diesel::update(
+            transactions
+                .filter(height.ge(fork_height))
+                .filter(status.eq(TransactionStatus::Pending)),
+        )
+        .set((height.eq(None::<i64>), updated_at.eq(now)))
+        .get_results(conn)?;
+        rolled_back.extend(unseen);
+
+        {
+            use crate::schema::blocks::dsl::*;
+            diesel::delete(blocks.filter(height.ge(fork_height))).execute(conn)?;
+        }
+        for tx in &rolled_back {
+            record_transaction(tx, now, conn)?;
+        }
+        set_current_height(fork_height - 1, conn)?;
+        Ok(rolled_back)
+    })
+}
+
+/// Balance which merchant may withdraw
+pub fn get_balance(merch_id: &str, conn: &PgConnection) -> Result<i64, Error> {
+    Ok(get_balances(merch_id, conn)?.available)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::errors::Error;
+    use crate::ledger::sync_ledger;
+    use crate::statement::get_statement;
     use crate::test_utils::{get_test_pool, run_migrations};
+    use chrono::Utc;
     use diesel::Connection;
     use diesel::{self, prelude::*};
 
+    #[test]
+    fn reserved_accounts_test() {
+        let pool = get_test_pool();
+
+        let conn = pool.get().unwrap();
+        conn.test_transaction::<(), Error, _>(|| {
+            run_migrations(&conn);
+            let now = Utc::now().naive_utc();
+            for reserved in RESERVED_ACCOUNTS {
+                assert!(create_merchant(
+                    CreateMerchant {
+                        id: s!(reserved),
+                        ..Default::default()
+                    },
+                    now,
+                    &conn,
+                )
+                .is_err());
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn confirm_payments_test() {
+        let pool = get_test_pool();
+
+        let conn = pool.get().unwrap();
+        conn.test_transaction::<(), Error, _>(|| {
+            run_migrations(&conn);
+            let now = Utc::now().naive_utc();
+            create_merchant(
+                CreateMerchant {
+                    id: s!("user"),
+                    ..Default::default()
+                },
+                now,
+                &conn,
+            )
+            .unwrap();
+            let mut rates = HashMap::new();
+            rates.insert(s!("grin"), 1.0);
+            register_rate(rates, now, &conn).unwrap();
+            let payment = create_transaction(
+                CreateTransaction {
+                    merchant_id: s!("user"),
+                    external_id: s!("1"),
+                    amount: Money::from_grin(1),
+                    confirmations: 10,
+                    ..Default::default()
+                },
+                now,
+                &conn,
+            )
+            .unwrap();
+            let mut found = HashMap::new();
+            found.insert(payment.id, 100);
+            update_transaction_status(payment.id, TransactionStatus::Pending, now, &conn).unwrap();
+            mark_in_chain(&found, now, &conn).unwrap();
+            assert!(get_balances("user", &conn).unwrap().pending == 1);
+
+            // not enough confirmations yet
+            assert_eq!(confirm_payments(110, now, &conn).unwrap(), 0);
+            assert_eq!(confirm_payments(111, now, &conn).unwrap(), 1);
+            use crate::schema::transactions::dsl::*;
+            let confirmed = diesel::update(transactions.filter(id.eq(payment.id)))
+                .set(reported.eq(true))
+                .get_result::<Transaction>(&conn)
+                .unwrap();
+            assert_eq!(confirmed.status, TransactionStatus::Confirmed);
+            record_transaction(&confirmed, now, &conn).unwrap();
+            let balances = get_balances("user", &conn).unwrap();
+            assert!(balances.pending == 0);
+            assert!(balances.available == 1);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn sync_ledger_test() {
+        let pool = get_test_pool();
+
+        let conn = pool.get().unwrap();
+        conn.test_transaction::<(), Error, _>(|| {
+            run_migrations(&conn);
+            let now = Utc::now().naive_utc();
+            let paid_at = now - Duration::days(60);
+            create_merchant(
+                CreateMerchant {
+                    id: s!("user"),
+                    ..Default::default()
+                },
+                paid_at,
+                &conn,
+            )
+            .unwrap();
+            let mut rates = HashMap::new();
+            rates.insert(s!("grin"), 1.0);
+            register_rate(rates, now, &conn).unwrap();
+            let payment = create_transaction(
+                CreateTransaction {
+                    merchant_id: s!("user"),
+                    external_id: s!("1"),
+                    amount: Money::from_grin(1),
+                    ..Default::default()
+                },
+                paid_at,
+                &conn,
+            )
+            .unwrap();
+            update_transaction_status(payment.id, TransactionStatus::Pending, paid_at, &conn)
+                .unwrap();
+            let confirmed_at = paid_at + Duration::hours(1);
+            update_transaction_status(
+                payment.id,
+                TransactionStatus::Confirmed,
+                confirmed_at,
+                &conn,
+            )
+            .unwrap();
+            {
+                use crate::schema::transactions::dsl::*;
+                diesel::update(transactions.filter(id.eq(payment.id)))
+                    .set(reported.eq(true))
+                    .execute(&conn)
+                    .unwrap();
+            }
+            // as if the payment was made before the ledger
+            diesel::delete(crate::schema::ledger_entries::table)
+                .execute(&conn)
+                .unwrap();
+            diesel::delete(crate::schema::balances::table)
+                .execute(&conn)
+                .unwrap();
+
+            assert!(sync_ledger(&conn).unwrap() >= 1);
+            let statement = get_statement(
+                "user",
+                paid_at - Duration::days(1),
+                paid_at + Duration::days(1),
+                &conn,
+            )
+            .unwrap();
+            assert_eq!(statement.opening_balance, 0);
+            assert_eq!(statement.lines.len(), 1);
+            assert_eq!(statement.lines[0].at, confirmed_at);
+            assert_eq!(statement.closing_balance, 1);
+            assert_eq!(get_balances("user", &conn).unwrap().available, 1);
+            // nothing happened since
+            let statement = get_statement("user", now - Duration::days(1), now, &conn).unwrap();
+            assert_eq!(statement.opening_balance, 1);
+            assert!(statement.lines.is_empty());
+            Ok(())
+        });
+    }
+
     #[test]
     fn balance_test() {
         let pool = get_test_pool();
@@ -421,18 +933,20 @@ mod tests {
         let conn = pool.get().unwrap();
         conn.test_transaction::<(), Error, _>(|| {
             run_migrations(&conn);
+            let now = Utc::now().naive_utc();
             create_merchant(
                 CreateMerchant {
                     id: s!("user"),
                     ..Default::default()
                 },
+                now,
                 &conn,
             )
             .unwrap();
             assert!(get_balance("user", &conn).unwrap() == 0);
             let mut rates = HashMap::new();
             rates.insert(s!("grin"), 1.0);
-            register_rate(rates, &conn).unwrap();
+            register_rate(rates, now, &conn).unwrap();
             create_transaction(
                 CreateTransaction {
                     merchant_id: s!("user"),
@@ -440,6 +954,7 @@ mod tests {
                     amount: Money::from_grin(1),
                     ..Default::default()
                 },
+                now,
                 &conn,
             )
             .unwrap();
@@ -453,29 +968,34 @@ mod tests {
                     amount: Money::from_grin(1),
                     ..Default::default()
                 },
+                now,
                 &conn,
             )
             .unwrap();
             assert!(get_balance("user", &conn).unwrap() == 0);
 
-            update_transaction_status(tx.id, TransactionStatus::Pending, &conn).unwrap();
+            update_transaction_status(tx.id, TransactionStatus::Pending, now, &conn).unwrap();
             assert!(get_balance("user", &conn).unwrap() == 0);
-            update_transaction_status(tx.id, TransactionStatus::Rejected, &conn).unwrap();
+            assert!(get_balances("user", &conn).unwrap().pending == 1);
+            update_transaction_status(tx.id, TransactionStatus::Rejected, now, &conn).unwrap();
             assert!(get_balance("user", &conn).unwrap() == 0);
-            update_transaction_status(tx.id, TransactionStatus::InChain, &conn).unwrap();
+            assert!(get_balances("user", &conn).unwrap().pending == 0);
+            update_transaction_status(tx.id, TransactionStatus::InChain, now, &conn).unwrap();
             assert!(get_balance("user", &conn).unwrap() == 0);
 
             // test that Confirmed payment increases balance only if reported
-            update_transaction_status(tx.id, TransactionStatus::Confirmed, &conn).unwrap();
+            update_transaction_status(tx.id, TransactionStatus::Confirmed, now, &conn).unwrap();
             assert!(get_balance("user", &conn).unwrap() == 0);
 
             use crate::schema::transactions::dsl::*;
-            diesel::update(transactions.filter(id.eq(tx.id)))
+            let reported_tx = diesel::update(transactions.filter(id.eq(tx.id)))
                 .set(reported.eq(true))
                 .get_result::<Transaction>(&conn)
                 .unwrap();
+            record_transaction(&reported_tx, now, &conn).unwrap();
 
             assert!(get_balance("user", &conn).unwrap() == 1);
+            assert!(get_balances("user", &conn).unwrap().pending == 0);
 
             //text that Refund added to balance
             let tx2 = create_transaction(
@@ -485,12 +1005,13 @@ mod tests {
                     amount: Money::from_grin(1),
                     ..Default::default()
                 },
+                now,
                 &conn,
             )
             .unwrap();
             assert!(get_balance("user", &conn).unwrap() == 1);
 
-            update_transaction_status(tx2.id, TransactionStatus::Refund, &conn).unwrap();
+            update_transaction_status(tx2.id, TransactionStatus::Refund, now, &conn).unwrap();
             assert!(get_balance("user", &conn).unwrap() == 2);
 
             // test that payouts reduces balance
@@ -502,17 +1023,46 @@ mod tests {
                     amount: Money::from_grin(1),
                     ..Default::default()
                 },
+                now,
                 &conn,
             )
             .unwrap();
             assert!(get_balance("user", &conn).unwrap() == 1);
+            assert!(get_balances("user", &conn).unwrap().locked == 1);
 
-            update_transaction_status(payout.id, TransactionStatus::Confirmed, &conn).unwrap();
+            update_transaction_status(payout.id, TransactionStatus::Confirmed, now, &conn).unwrap();
             assert!(get_balance("user", &conn).unwrap() == 1);
+            assert!(get_balances("user", &conn).unwrap().locked == 0);
+
+            // test that overcharged transfer fee returns to balance
+            let credited_payout = diesel::update(transactions.filter(id.eq(payout.id)))
+                .set(transfer_fee_credit.eq(1))
+                .get_result::<Transaction>(&conn)
+                .unwrap();
+            record_transaction(&credited_payout, now, &conn).unwrap();
+            assert!(get_balance("user", &conn).unwrap() == 2);
 
             // test that Rejected payouts ignored
-            update_transaction_status(payout.id, TransactionStatus::Rejected, &conn).unwrap();
+            update_transaction_status(payout.id, TransactionStatus::Rejected, now, &conn).unwrap();
             assert!(get_balance("user", &conn).unwrap() == 2);
+            assert!(crate::ledger::check_balances(&conn).unwrap().is_empty());
+
+            // test that Rejected payout found in chain leaves the balance again
+            diesel::update(transactions.filter(id.eq(payout.id)))
+                .set(transfer_fee_credit.eq::<Option<i64>>(None))
+                .execute(&conn)
+                .unwrap();
+            let mut found = HashMap::new();
+            found.insert(payout.id, 10);
+            mark_in_chain(&found, now, &conn).unwrap();
+            let in_chain = transactions
+                .filter(id.eq(payout.id))
+                .first::<Transaction>(&conn)
+                .unwrap();
+            assert_eq!(in_chain.status, TransactionStatus::InChain);
+            assert_eq!(in_chain.height, Some(10));
+            assert!(get_balance("user", &conn).unwrap() == 1);
+            assert!(crate::ledger::check_balances(&conn).unwrap().is_empty());
 
             Ok(())
         });
@@ -1,5 +1,5 @@
 use crate::app::AppState;
-use crate::db::GetMerchant;
+use crate::db::{hash_payout_key, GetMerchant};
 use crate::errors::*;
 use crate::jsonrpc;
 use crate::models::Merchant;
@@ -9,6 +9,7 @@ use actix_web::dev;
 use actix_web::{FromRequest, HttpRequest};
 use actix_web_httpauth::extractors::basic;
 use bytes::BytesMut;
+use consistenttime::ct_u8_slice_eq;
 use derive_deref::Deref;
 use futures::future::{err, ok};
 use futures::future::{Future, FutureExt, TryFutureExt};
@@ -57,6 +58,83 @@ impl FromRequest for BasicAuth<Merchant> {
     }
 }
 
+/// Merchant authorized to manage payouts, either with the API token or with
+/// the payout key. Only the payout key allows to request a payout without 2fa code
+#[derive(Debug, Clone)]
+pub struct PayoutAuth {
+    pub merchant: Merchant,
+    pub with_payout_key: bool,
+}
+
+impl FromRequest for BasicAuth<PayoutAuth> {
+    type Config = BasicAuthConfig;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>> + 'static>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        let req = req.clone();
+
+        async move {
+            let bauth = basic::BasicAuth::extract(&req)
+                .await
+                .map_err::<Error, _>(|_| Error::NotAuthorized)?;
+            let data = req.app_data::<AppState>().unwrap();
+            let username = bauth.user_id().to_string();
+            let password = bauth.password().map(|p| p.to_string()).unwrap_or(s!(""));
+            let merchant = data
+                .db
+                .send(GetMerchant { id: username })
+                .await?
+                .map_err(|_| Error::NotAuthorized)?;
+            let with_payout_key = match merchant.payout_key_hash {
+                Some(ref key_hash) => {
+                    ct_u8_slice_eq(hash_payout_key(&password).as_bytes(), key_hash.as_bytes())
+                }
+                None => false,
+            };
+            if with_payout_key || merchant.token == password {
+                Ok(BasicAuth(PayoutAuth {
+                    merchant,
+                    with_payout_key,
+                }))
+            } else {
+                Err(Error::NotAuthorized)
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Operator of the service, authorized by `OPERATOR_TOKEN`
+#[derive(Debug, Clone)]
+pub struct Operator;
+
+impl FromRequest for BasicAuth<Operator> {
+    type Config = BasicAuthConfig;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>> + 'static>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        let req = req.clone();
+
+        async move {
+            let bauth = basic::BasicAuth::extract(&req)
+                .await
+                .map_err::<Error, _>(|_| Error::NotAuthorized)?;
+            let data = req.app_data::<AppState>().unwrap();
+            // admin API is disabled without token
+            let token = data.operator_token.as_ref().ok_or(Error::NotAuthorized)?;
+            let password = bauth.password().map(|p| p.to_string()).unwrap_or(s!(""));
+            if ct_u8_slice_eq(password.as_bytes(), token.as_bytes()) {
+                Ok(BasicAuth(Operator))
+            } else {
+                Err(Error::NotAuthorized)
+            }
+        }
+        .boxed_local()
+    }
+}
+
 /// Session extractor
 #[derive(Debug, Deref, Clone)]
 pub struct Session<T>(pub T);
@@ -0,0 +1,753 @@
+//! In-memory implementations of `WalletApi` and `NodeApi`.
+//!
+//! They allow to run the whole payment and payout lifecycle on one machine
+//! without grin node and wallet: the wallet receives and creates slates and
+//! keeps a tx log, posted transactions go to the node mempool and get into
+//! the chain when a test mines a block. A test can also replace the top of
+//! the chain to simulate a reorganization.
+use crate::errors::Error;
+use crate::jsonrpc;
+use crate::node::{
+    Block, Header, LocatedOutput, NodeApi, NodeFuture, Output as NodeOutput, PoolTransaction,
+};
+use crate::ser;
+use crate::wallet::{
+    Input, Output, ParticipantMessageData, ParticipantMessages, Slate, Transaction,
+    TransactionBody, TxKernel, TxLogEntry, WalletApi, WalletFuture, WalletInfo,
+};
+use futures::future::ready;
+use parking_lot::Mutex;
+use rand::{thread_rng, Rng};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub const FAKE_FEE: u64 = 8_000_000;
+
+/// Random commitment, formatted like a real one
+pub fn random_commit() -> Vec<u8> {
+    let mut commit = vec![0x08];
+    commit.extend_from_slice(&thread_rng().gen::<[u8; 32]>());
+    commit
+}
+
+struct FakeBlock {
+    hash: String,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    kernels: Vec<String>,
+}
+
+#[derive(Default)]
+struct NodeState {
+    height: i64,
+    blocks: BTreeMap<i64, FakeBlock>,
+    /// Outputs confirmed before the node was created
+    utxos: Vec<String>,
+    mempool_inputs: Vec<String>,
+    mempool: Vec<String>,
+    mempool_kernels: Vec<String>,
+    offline: bool,
+}
+
+impl NodeState {
+    fn hash(&self, height: i64) -> String {
+        match self.blocks.get(&height) {
+            Some(block) => block.hash.clone(),
+            // blocks which existed before the node was created
+            None => format!("{:064x}", height),
+        }
+    }
+}
+
+/// Chain which grows only when a test asks to mine a block
+#[derive(Clone, Default)]
+pub struct FakeNode {
+    state: Arc<Mutex<NodeState>>,
+}
+
+impl FakeNode {
+    pub fn new(height: i64) -> Self {
+        let node = FakeNode::default();
+        node.state.lock().height = height;
+        node
+    }
+
+    pub fn height(&self) -> i64 {
+        self.state.lock().height
+    }
+
+    /// Add an output confirmed before the node was created, e.g. wallet's funds
+    pub fn add_utxo(&self, commit: &[u8]) {
+        self.state.lock().utxos.push(ser::to_hex(commit.to_vec()));
+    }
+
+    /// Put an output into mempool, it gets into the next mined block
+    pub fn post_output(&self, commit: &[u8]) {
+        self.state.lock().mempool.push(ser::to_hex(commit.to_vec()));
+    }
+
+    /// Put a kernel into mempool, it gets into the next mined block
+    pub fn post_kernel(&self, excess: &[u8]) {
+        self.state
+            .lock()
+            .mempool_kernels
+            .push(ser::to_hex(excess.to_vec()));
+    }
+
+    /// Put all inputs, outputs and kernels of transaction into mempool
+    pub fn post_tx(&self, tx: &Transaction) {
+        self.state
+            .lock()
+            .mempool_inputs
+            .extend(tx.input_commitments().into_iter().map(ser::to_hex));
+        for commit in tx.output_commitments() {
+            self.post_output(&commit);
+        }
+        for kernel in &tx.body.kernels {
+            self.post_kernel(&kernel.excess);
+        }
+    }
+
+    /// Mine a block with all outputs from mempool, returns its height
+    pub fn mine_block(&self) -> i64 {
+        FakeNode::mine(&mut self.state.lock())
+    }
+
+    /// Replace `depth` blocks on top of the chain with a longer branch.
+    /// Outputs and kernels of the replaced blocks are lost, returns the new height
+    pub fn reorg(&self, depth: i64) -> i64 {
+        let mut state = self.state.lock();
+        let fork_height = state.height - depth + 1;
+        state.blocks.split_off(&fork_height);
+        state.height = fork_height - 1;
+        for _ in 0..depth + 1 {
+            FakeNode::mine(&mut state);
+        }
+        state.height
+    }
+
+    /// Mine `n` blocks at once, nobody sees the chain in between
+    pub fn mine_blocks(&self, n: i64) -> i64 {
+        let mut state = self.state.lock();
+        for _ in 0..n {
+            FakeNode::mine(&mut state);
+        }
+        state.height
+    }
+
+    /// Whether a mined block spends the output
+    fn is_spent(&self, commit: &[u8]) -> bool {
+        let commit = ser::to_hex(commit.to_vec());
+        self.state
+            .lock()
+            .blocks
+            .values()
+            .any(|block| block.inputs.contains(&commit))
+    }
+
+    /// Drop all transactions from mempool as if they were evicted
+    pub fn clear_mempool(&self) {
+        let mut state = self.state.lock();
+        state.mempool_inputs.clear();
+        state.mempool.clear();
+        state.mempool_kernels.clear();
+    }
+
+    /// Make all requests fail as if the node is unreachable
+    pub fn set_offline(&self, offline: bool) {
+        self.state.lock().offline = offline;
+    }
+
+    fn mine(state: &mut NodeState) -> i64 {
+        state.height += 1;
+        let inputs = state.mempool_inputs.drain(..).collect();
+        let outputs = state.mempool.drain(..).collect();
+        let kernels = state.mempool_kernels.drain(..).collect();
+        let height = state.height;
+        let hash = ser::to_hex(thread_rng().gen::<[u8; 32]>().to_vec());
+        state.blocks.insert(
+            height,
+            FakeBlock {
+                hash,
+                inputs,
+                outputs,
+                kernels,
+            },
+        );
+        height
+    }
+
+    fn block(state: &NodeState, height: i64) -> Block {
+        let mut outputs = vec![NodeOutput {
+            output_type: s!("Coinbase"),
+            commit: ser::to_hex(random_commit()),
+            block_height: Some(height as u64),
+        }];
+        if let Some(block) = state.blocks.get(&height) {
+            outputs.extend(block.outputs.iter().map(|commit| NodeOutput {
+                output_type: s!("Transaction"),
+                commit: commit.clone(),
+                block_height: Some(height as u64),
+            }));
+        }
+        Block {
+            header: Header {
+                hash: state.hash(height),
+                height: height as u64,
+                previous: state.hash(height - 1),
+            },
+            outputs,
+        }
+    }
+}
+
+impl NodeApi for FakeNode {
+    fn blocks(&self, start: i64, end: i64) -> NodeFuture<Vec<Block>> {
+        let state = self.state.lock();
+        if state.offline {
+            return Box::pin(ready(Err(Error::NodeAPIError(s!("Node is offline")))));
+        }
+        let blocks = (start.max(0)..=end.min(state.height))
+            .map(|height| FakeNode::block(&state, height))
+            .collect();
+        Box::pin(ready(Ok(blocks)))
+    }
+
+    fn kernel(&self, excess: &str, min_height: i64, max_height: i64) -> NodeFuture<Option<i64>> {
+        let state = self.state.lock();
+        if state.offline {
+            return Box::pin(ready(Err(Error::NodeAPIError(s!("Node is offline")))));
+        }
+        let height = state
+            .blocks
+            .range(min_height..=max_height)
+            .find(|(_, block)| block.kernels.iter().any(|kernel| kernel == excess))
+            .map(|(height, _)| *height);
+        Box::pin(ready(Ok(height)))
+    }
+
+    fn outputs(&self, commits: &[String]) -> NodeFuture<Vec<LocatedOutput>> {
+        let state = self.state.lock();
+        if state.offline {
+            return Box::pin(ready(Err(Error::NodeAPIError(s!("Node is offline")))));
+        }
+        let spent: Vec<&String> = state
+            .blocks
+            .values()
+            .flat_map(|block| block.inputs.iter())
+            .collect();
+        let outputs = state
+            .utxos
+            .iter()
+            .map(|commit| (0, commit))
+            .chain(state.blocks.iter().flat_map(|(height, block)| {
+                block.outputs.iter().map(move |commit| (*height, commit))
+            }))
+            .filter(|(_, commit)| commits.contains(commit) && !spent.contains(commit))
+            .map(|(height, commit)| LocatedOutput {
+                commit: commit.clone(),
+                height: height as u64,
+                mmr_index: 0,
+            })
+            .collect();
+        Box::pin(ready(Ok(outputs)))
+    }
+
+    fn current_height(&self) -> NodeFuture<i64> {
+        let state = self.state.lock();
+        if state.offline {
+            return Box::pin(ready(Err(Error::NodeAPIError(s!("Node is offline")))));
+        }
+        Box::pin(ready(Ok(state.height)))
+    }
+
+    fn unconfirmed_transactions(&self) -> NodeFuture<Option<Vec<PoolTransaction>>> {
+        let state = self.state.lock();
+        if state.offline {
+            return Box::pin(ready(Err(Error::NodeAPIError(s!("Node is offline")))));
+        }
+        let pool_tx = PoolTransaction {
+            kernels: state.mempool_kernels.clone(),
+            outputs: state.mempool.clone(),
+        };
+        Box::pin(ready(Ok(Some(vec![pool_tx]))))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FakeTxStatus {
+    Received,
+    Sent,
+    Finalized,
+    Posted,
+    Cancelled,
+}
+
+#[derive(Debug, Clone)]
+struct FakeTx {
+    entry: TxLogEntry,
+    slate: Slate,
+    status: FakeTxStatus,
+}
+
+struct WalletState {
+    balance: u64,
+    fee: u64,
+    next_id: u32,
+    txs: HashMap<String, FakeTx>,
+    offline: bool,
+}
+
+/// Wallet which keeps its tx log in memory. If it's connected to a `FakeNode`
+/// posted transactions go to the node mempool.
+#[derive(Clone)]
+pub struct FakeWallet {
+    state: Arc<Mutex<WalletState>>,
+    node: Option<FakeNode>,
+}
+
+impl FakeWallet {
+    pub fn new(balance: u64) -> Self {
+        FakeWallet {
+            state: Arc::new(Mutex::new(WalletState {
+                balance,
+                fee: FAKE_FEE,
+                next_id: 0,
+                txs: HashMap::new(),
+                offline: false,
+            })),
+            node: None,
+        }
+    }
+
+    pub fn with_node(balance: u64, node: FakeNode) -> Self {
+        FakeWallet {
+            node: Some(node),
+            ..FakeWallet::new(balance)
+        }
+    }
+
+    pub fn balance(&self) -> u64 {
+        self.state.lock().balance
+    }
+
+    pub fn tx_status(&self, tx_slate_id: &str) -> Option<FakeTxStatus> {
+        self.state
+            .lock()
+            .txs
+            .get(tx_slate_id)
+            .map(|tx| tx.status.clone())
+    }
+
+    /// Fee of transactions created from now on
+    pub fn set_fee(&self, fee: u64) {
+        self.state.lock().fee = fee;
+    }
+
+    /// Make all requests fail as if the wallet is unreachable
+    pub fn set_offline(&self, offline: bool) {
+        self.state.lock().offline = offline;
+    }
+
+    /// Build a slate as a customer's wallet would do to pay `amount`.
+    /// Unlike a real one, its kernel excess is known from the start
+    pub fn payer_slate(amount: u64) -> Slate {
+        Slate {
+            id: Uuid::new_v4(),
+            tx: Transaction {
+                body: TransactionBody {
+                    inputs: vec![],
+                    outputs: vec![],
+                    kernels: vec![TxKernel {
+                        excess: random_commit(),
+                        other: serde_json::Map::new(),
+                    }],
+                    other: serde_json::Map::new(),
+                },
+                other: serde_json::Map::new(),
+            },
+            amount,
+            fee: FAKE_FEE,
+            other: serde_json::Map::new(),
+        }
+    }
+
+    fn check_online(&self) -> Result<(), Error> {
+        if self.state.lock().offline {
+            return Err(Error::WalletAPIError(s!("Wallet is offline")));
+        }
+        Ok(())
+    }
+
+    fn add_tx(&self, slate: Slate, status: FakeTxStatus, message: Option<String>) -> TxLogEntry {
+        let height = self.node.as_ref().map(|node| node.height() as u64);
+        let mut state = self.state.lock();
+        state.next_id += 1;
+        let entry = TxLogEntry {
+            id: state.next_id,
+            tx_slate_id: Some(slate.id.hyphenated().to_string()),
+            fee: Some(slate.fee),
+            messages: message.map(|message| ParticipantMessages {
+                messages: vec![ParticipantMessageData {
+                    id: 0,
+                    public_key: String::new(),
+                    message: Some(message),
+                    message_sig: None,
+                }],
+            }),
+            kernel_excess: slate.tx.kernel_excess().map(ser::to_hex),
+            kernel_lookup_min_height: height,
+        };
+        state.txs.insert(
+            slate.id.hyphenated().to_string(),
+            FakeTx {
+                entry: entry.clone(),
+                slate,
+                status,
+            },
+        );
+        entry
+    }
+
+    fn do_receive(&self, slate: &Slate) -> Result<Slate, Error> {
+        self.check_online()?;
+        let slate_id = slate.id.hyphenated().to_string();
+        if self.state.lock().txs.contains_key(&slate_id) {
+            return Err(Error::WalletAPIError(format!(
+                "Slate {} was already received",
+                slate_id
+            )));
+        }
+        let mut slate = slate.clone();
+        slate.tx.body.outputs.push(Output {
+            commit: random_commit(),
+            other: serde_json::Map::new(),
+        });
+        self.add_tx(slate.clone(), FakeTxStatus::Received, None);
+        Ok(slate)
+    }
+
+    fn do_estimate_fee(&self, amount: u64) -> Result<u64, Error> {
+        self.check_online()?;
+        let state = self.state.lock();
+        if state.balance < amount + state.fee {
+            return Err(Error::WalletAPIError(format!(
+                "Not enough funds: required {}, available {}",
+                amount + state.fee,
+                state.balance
+            )));
+        }
+        Ok(state.fee)
+    }
+
+    fn do_create_slate(&self, amount: u64, message: String) -> Result<Slate, Error> {
+        let fee = self.do_estimate_fee(amount)?;
+        self.state.lock().balance -= amount + fee;
+        let mut slate = FakeWallet::payer_slate(amount);
+        slate.fee = fee;
+        let input = random_commit();
+        if let Some(node) = &self.node {
+            node.add_utxo(&input);
+        }
+        slate.tx.body.inputs.push(Input {
+            commit: input,
+            other: serde_json::Map::new(),
+        });
+        // change output
+        slate.tx.body.outputs.push(Output {
+            commit: random_commit(),
+            other: serde_json::Map::new(),
+        });
+        self.add_tx(slate.clone(), FakeTxStatus::Sent, Some(message));
+        Ok(slate)
+    }
+
+    fn do_finalize(&self, slate: &Slate) -> Result<Slate, Error> {
+        self.check_online()?;
+        let slate_id = slate.id.hyphenated().to_string();
+        let mut state = self.state.lock();
+        let tx = state
+            .txs
+            .get_mut(&slate_id)
+            .ok_or(Error::WalletAPIError(format!(
+                "Transaction with slate_id {} not found",
+                slate_id
+            )))?;
+        if tx.status != FakeTxStatus::Sent {
+            return Err(Error::WalletAPIError(format!(
+                "Cannot finalize transaction {} with status {:?}",
+                slate_id, tx.status
+            )));
+        }
+        tx.status = FakeTxStatus::Finalized;
+        tx.slate = slate.clone();
+        Ok(slate.clone())
+    }
+
+    fn do_post_tx(&self, tx: &Transaction) -> Result<(), Error> {
+        self.check_online()?;
+        let commits = tx.output_commitments();
+        {
+            let mut state = self.state.lock();
+            if let Some(posted) = state
+                .txs
+                .values_mut()
+                .find(|fake_tx| fake_tx.slate.tx.output_commitments() == commits)
+            {
+                posted.status = FakeTxStatus::Posted;
+            }
+        }
+        if let Some(node) = &self.node {
+            node.post_tx(tx);
+        }
+        Ok(())
+    }
+
+    /// Posted transaction can be cancelled until it gets into the chain
+    fn is_confirmed(&self, tx: &Transaction) -> bool {
+        match &self.node {
+            Some(node) => tx
+                .input_commitments()
+                .iter()
+                .any(|commit| node.is_spent(commit)),
+            None => false,
+        }
+    }
+
+    fn do_cancel_tx(&self, tx_slate_id: &str) -> Result<(), Error> {
+        self.check_online()?;
+        let mut state = self.state.lock();
+        let tx = state
+            .txs
+            .get_mut(tx_slate_id)
+            .ok_or(Error::WalletAPIError(format!(
+                "Transaction with slate_id {} not found",
+                tx_slate_id
+            )))?;
+        let unlocked = match tx.status {
+            FakeTxStatus::Cancelled => {
+                return Err(Error::WalletAPIError(format!(
+                    "Cannot cancel transaction {} with status {:?}",
+                    tx_slate_id, tx.status
+                )))
+            }
+            FakeTxStatus::Received => 0,
+            FakeTxStatus::Posted if self.is_confirmed(&tx.slate.tx) => {
+                return Err(Error::WalletAPIError(format!(
+                    "Transaction {} is already confirmed",
+                    tx_slate_id
+                )))
+            }
+            FakeTxStatus::Sent | FakeTxStatus::Finalized | FakeTxStatus::Posted => {
+                tx.slate.amount + tx.slate.fee
+            }
+        };
+        tx.status = FakeTxStatus::Cancelled;
+        state.balance += unlocked;
+        Ok(())
+    }
+
+    fn do_foreign_request(&self, req: jsonrpc::Request) -> Result<jsonrpc::Response, Error> {
+        self.check_online()?;
+        let mut resp = jsonrpc::Response::with_id(req.id.clone());
+        match req.method.as_str() {
+            "check_version" => {
+                resp.result = json!({"Ok": {
+                    "foreign_api_version": 2,
+                    "supported_slate_versions": ["V2"],
+                }});
+            }
+            "receive_tx" => {
+                let slate: Slate = serde_json::from_value(req.params[0].clone())?;
+                resp.result = json!({ "Ok": self.do_receive(&slate)? });
+            }
+            _ => resp.error = Some(jsonrpc::ErrorData::std(-32601)),
+        }
+        Ok(resp)
+    }
+}
+
+impl WalletApi for FakeWallet {
+    fn foreign_request(&self, req: jsonrpc::Request) -> WalletFuture<jsonrpc::Response> {
+        Box::pin(ready(self.do_foreign_request(req)))
+    }
+
+    fn get_tx(&self, tx_id: &str) -> WalletFuture<TxLogEntry> {
+        let res = self.check_online().and_then(|_| {
+            self.state
+                .lock()
+                .txs
+                .get(tx_id)
+                .map(|tx| tx.entry.clone())
+                .ok_or(Error::WalletAPIError(format!(
+                    "Transaction with slate_id {} not found",
+                    tx_id
+                )))
+        });
+        Box::pin(ready(res))
+    }
+
+    fn receive(&self, slate: &Slate) -> WalletFuture<Slate> {
+        Box::pin(ready(self.do_receive(slate)))
+    }
+
+    fn finalize(&self, slate: &Slate) -> WalletFuture<Slate> {
+        Box::pin(ready(self.do_finalize(slate)))
+    }
+
+    fn post_tx(&self, tx: &Transaction, _fluff: bool) -> WalletFuture<()> {
+        Box::pin(ready(self.do_post_tx(tx)))
+    }
+
+    fn cancel_tx(&self, tx_slate_id: &str) -> WalletFuture<()> {
+        Box::pin(ready(self.do_cancel_tx(tx_slate_id)))
+    }
+
+    fn retrieve_summary_info(&self, minimum_confirmations: u64) -> WalletFuture<WalletInfo> {
+        let res = self.check_online().map(|_| {
+            let balance = self.state.lock().balance;
+            let height = self.node.as_ref().map(|node| node.height()).unwrap_or(0);
+            WalletInfo {
+                last_confirmed_height: height as u64,
+                minimum_confirmations,
+                total: balance,
+                amount_awaiting_finalization: 0,
+                amount_awaiting_confirmation: 0,
+                amount_immature: 0,
+                amount_currently_spendable: balance,
+                amount_locked: 0,
+            }
+        });
+        Box::pin(ready(res))
+    }
+
+    fn create_slate(&self, amount: u64, message: String) -> WalletFuture<Slate> {
+        Box::pin(ready(self.do_create_slate(amount, message)))
+    }
+
+    fn estimate_fee(&self, amount: u64) -> WalletFuture<u64> {
+        Box::pin(ready(self.do_estimate_fee(amount)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn fake_node_test() {
+        let node = FakeNode::new(10);
+        let commit = random_commit();
+        let excess = random_commit();
+        node.post_output(&commit);
+        node.post_kernel(&excess);
+        assert_eq!(node.mine_block(), 11);
+        let excess = ser::to_hex(excess);
+        assert_eq!(block_on(node.kernel(&excess, 0, 11)).unwrap(), Some(11));
+        assert_eq!(block_on(node.kernel(&excess, 0, 10)).unwrap(), None);
+        let commits = vec![ser::to_hex(commit.clone()), ser::to_hex(random_commit())];
+        let outputs = block_on(node.outputs(&commits)).unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].height, 11);
+        let blocks = block_on(node.blocks(10, 20)).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks[1]
+            .outputs
+            .iter()
+            .any(|o| !o.is_coinbase() && o.commit == ser::to_hex(commit.clone())));
+        assert_eq!(block_on(node.current_height()).unwrap(), 11);
+
+        node.set_offline(true);
+        assert!(block_on(node.current_height()).is_err());
+    }
+
+    #[test]
+    fn fake_node_reorg_test() {
+        let node = FakeNode::new(10);
+        node.post_output(&random_commit());
+        node.mine_blocks(2);
+        let before = block_on(node.blocks(10, 12)).unwrap();
+        assert_eq!(before[1].header.previous, before[0].header.hash);
+        assert_eq!(before[2].header.previous, before[1].header.hash);
+
+        assert_eq!(node.reorg(2), 13);
+        let after = block_on(node.blocks(10, 13)).unwrap();
+        assert_eq!(after[0].header.hash, before[0].header.hash);
+        assert_ne!(after[1].header.hash, before[1].header.hash);
+        assert_eq!(after[1].header.previous, before[0].header.hash);
+        assert!(after
+            .iter()
+            .flat_map(|b| &b.outputs)
+            .all(|o| o.is_coinbase()));
+    }
+
+    #[test]
+    fn fake_wallet_receive_test() {
+        let wallet = FakeWallet::new(0);
+        let slate = FakeWallet::payer_slate(1_000_000_000);
+        let received = block_on(wallet.receive(&slate)).unwrap();
+        assert_eq!(received.tx.output_commitments().len(), 1);
+        let slate_id = slate.id.hyphenated().to_string();
+        let tx = block_on(wallet.get_tx(&slate_id)).unwrap();
+        assert_eq!(tx.tx_slate_id, Some(slate_id.clone()));
+        assert!(block_on(wallet.receive(&slate)).is_err());
+
+        block_on(wallet.cancel_tx(&slate_id)).unwrap();
+        assert_eq!(wallet.tx_status(&slate_id), Some(FakeTxStatus::Cancelled));
+        assert!(block_on(wallet.cancel_tx(&slate_id)).is_err());
+    }
+
+    #[test]
+    fn fake_wallet_payout_test() {
+        let node = FakeNode::new(0);
+        let wallet = FakeWallet::with_node(10_000_000_000, node.clone());
+        let slate = block_on(wallet.create_slate(1_000_000_000, s!("payout"))).unwrap();
+        assert_eq!(wallet.balance(), 9_000_000_000 - FAKE_FEE);
+
+        let finalized = block_on(wallet.finalize(&slate)).unwrap();
+        block_on(wallet.post_tx(&finalized.tx, false)).unwrap();
+        let slate_id = slate.id.hyphenated().to_string();
+        assert_eq!(wallet.tx_status(&slate_id), Some(FakeTxStatus::Posted));
+
+        let height = node.mine_block();
+        let blocks = block_on(node.blocks(height, height)).unwrap();
+        assert_eq!(blocks[0].outputs.len(), 2);
+        let inputs: Vec<String> = slate
+            .tx
+            .input_commitments()
+            .into_iter()
+            .map(ser::to_hex)
+            .collect();
+        assert!(block_on(node.outputs(&inputs)).unwrap().is_empty());
+        assert!(block_on(wallet.cancel_tx(&slate_id)).is_err());
+
+        assert!(block_on(wallet.create_slate(100_000_000_000, s!("too much"))).is_err());
+    }
+
+    #[test]
+    fn fake_wallet_cancel_unlocks_funds_test() {
+        let wallet = FakeWallet::new(10_000_000_000);
+        let slate = block_on(wallet.create_slate(1_000_000_000, s!("payout"))).unwrap();
+        block_on(wallet.cancel_tx(&slate.id.hyphenated().to_string())).unwrap();
+        assert_eq!(wallet.balance(), 10_000_000_000);
+    }
+
+    #[test]
+    fn fake_wallet_estimate_fee_test() {
+        let wallet = FakeWallet::new(10_000_000_000);
+        wallet.set_fee(4_000_000);
+        assert_eq!(
+            block_on(wallet.estimate_fee(1_000_000_000)).unwrap(),
+            4_000_000
+        );
+        // nothing is locked
+        assert_eq!(wallet.balance(), 10_000_000_000);
+        let slate = block_on(wallet.create_slate(1_000_000_000, s!("payout"))).unwrap();
+        assert_eq!(slate.fee, 4_000_000);
+        assert_eq!(wallet.balance(), 9_000_000_000 - 4_000_000);
+        assert!(block_on(wallet.estimate_fee(10_000_000_000)).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! Fees merchants pay to knockturn.
+//!
+//! Every merchant has a fee schedule, the default one takes 1% of payouts and
+//! nothing of payments. Operator may set a schedule per merchant with
+//! a percentage, a fixed part and a minimum for payments and payouts, and
+//! volume tiers which change the percentage once merchant's payments over the
+//! last `VOLUME_PERIOD_DAYS` reach some volume. The schedule applied to a
+//! transaction is stored with it, so fees of existing transactions don't
+//! change with the schedule.
+use crate::errors::Error;
+use crate::models::{TransactionStatus, TransactionType};
+use bigdecimal::{BigDecimal, ToPrimitive};
+use chrono::{Duration, NaiveDateTime};
+use diesel::deserialize::{self, FromSql};
+use diesel::dsl::sum;
+use diesel::pg::{Pg, PgConnection};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Jsonb;
+use diesel::{self, prelude::*};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PAYOUT_SHARE: f64 = 0.01;
+/// Network fee of a payout we charge if the wallet can't estimate it
+pub const TRANSFER_FEE: i64 = 8_000_000;
+/// Smallest balance a merchant can withdraw
+pub const MINIMAL_WITHDRAW: i64 = 1_000_000_000;
+pub const VOLUME_PERIOD_DAYS: i64 = 30;
+
+/// How the fee of one kind of transactions is calculated
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(default)]
+pub struct FeeRule {
+    /// Part of the amount, 0.01 is 1%
+    pub share: f64,
+    /// Added to the percentage, in nanogrins
+    pub fixed: i64,
+    /// The fee is never lower than this, in nanogrins
+    pub minimum: i64,
+}
+
+impl FeeRule {
+    pub fn fee(&self, amount: i64) -> i64 {
+        ((amount as f64 * self.share) as i64 + self.fixed).max(self.minimum)
+    }
+
+    fn validate(&self, kind: &str) -> Result<(), Error> {
+        if !(0.0..1.0).contains(&self.share) {
+            return Err(Error::InvalidEntity(format!(
+                "{} share must be at least 0 and less than 1",
+                kind
+            )));
+        }
+        if self.fixed < 0 || self.minimum < 0 {
+            return Err(Error::InvalidEntity(format!(
+                "{} fixed fee and minimum can't be negative",
+                kind
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Shares which replace the base ones once merchant's volume reaches `min_volume`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct FeeTier {
+    pub min_volume: i64,
+    pub payout_share: Option<f64>,
+    pub payment_share: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, AsExpression, FromSqlRow, Clone, PartialEq)]
+#[sql_type = "Jsonb"]
+pub struct FeeSchedule {
+    pub payout: FeeRule,
+    #[serde(default)]
+    pub payment: FeeRule,
+    #[serde(default)]
+    pub tiers: Vec<FeeTier>,
+}
+
+impl Default for FeeSchedule {
+    fn default() -> Self {
+        FeeSchedule {
+            payout: FeeRule {
+                share: DEFAULT_PAYOUT_SHARE,
+                ..Default::default()
+            },
+            payment: FeeRule::default(),
+            tiers: vec![],
+        }
+    }
+}
+
+impl FeeSchedule {
+    /// Schedule with shares of the highest tier the volume reaches. Tiers are
+    /// already applied in the result, so it has none.
+    pub fn for_volume(&self, volume: i64) -> FeeSchedule {
+        let mut applied = FeeSchedule {
+            tiers: vec![],
+            ..self.clone()
+        };
+        if let Some(tier) = self
+            .tiers
+            .iter()
+            .filter(|tier| tier.min_volume <= volume)
+            .max_by_key(|tier| tier.min_volume)
+        {
+            if let Some(share) = tier.payout_share {
+                applied.payout.share = share;
+            }
+            if let Some(share) = tier.payment_share {
+                applied.payment.share = share;
+            }
+        }
+        applied
+    }
+
+    pub fn payout_fee(&self, amount: i64) -> i64 {
+        self.payout.fee(amount)
+    }
+
+    /// Fee of an incoming payment, never more than the payment itself
+    pub fn payment_fee(&self, amount: i64) -> i64 {
+        self.payment.fee(amount).min(amount)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        self.payout.validate("payout")?;
+        self.payment.validate("payment")?;
+        for tier in &self.tiers {
+            let tier_rule = |share: Option<f64>| FeeRule {
+                share: share.unwrap_or(0.0),
+                ..Default::default()
+            };
+            tier_rule(tier.payout_share).validate("tier payout")?;
+            tier_rule(tier.payment_share).validate("tier payment")?;
+            if tier.min_volume < 0 {
+                return Err(Error::InvalidEntity(s!("tier volume can't be negative")));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ToSql<Jsonb, Pg> for FeeSchedule {
+    fn to_sql<W: std::io::Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(&[1])?;
+        serde_json::to_writer(out, self)
+            .map(|_| serialize::IsNull::No)
+            .map_err(Into::into)
+    }
+}
+
+impl FromSql<Jsonb, Pg> for FeeSchedule {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let bytes = not_none!(bytes);
+        if bytes[0] != 1 {
+            return Err("Unsupported JSONB encoding version".into());
+        }
+        serde_json::from_slice(&bytes[1..]).map_err(Into::into)
+    }
+}
+
+/// Schedule set for the merchant or the default one
+pub fn get_fee_schedule(merch_id: &str, conn: &PgConnection) -> Result<FeeSchedule, Error> {
+    use crate::schema::fee_schedules::dsl::*;
+    Ok(fee_schedules
+        .find(merch_id)
+        .select(schedule)
+        .first::<FeeSchedule>(conn)
+        .optional()?
+        .unwrap_or_default())
+}
+
+pub fn set_fee_schedule(
+    merch_id: &str,
+    new_schedule: &FeeSchedule,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<(), Error> {
+    use crate::schema::fee_schedules::dsl::*;
+    use crate::schema::merchants;
+    new_schedule.validate()?;
+    merchants::table
+        .find(merch_id)
+        .select(merchants::id)
+        .first::<String>(conn)
+        .optional()?
+        .ok_or(Error::EntityNotFound(format!("merchant {}", merch_id)))?;
+    diesel::insert_into(fee_schedules)
+        .values((
+            merchant_id.eq(merch_id),
+            schedule.eq(new_schedule),
+            updated_at.eq(now),
+        ))
+        .on_conflict(merchant_id)
+        .do_update()
+        .set((schedule.eq(new_schedule), updated_at.eq(now)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Amount of confirmed payments of the merchant created since `since`
+pub fn payments_volume(
+    merch_id: &str,
+    since: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<i64, Error> {
+    use crate::schema::transactions::dsl::*;
+    Ok(transactions
+        .select(sum(grin_amount))
+        .filter(merchant_id.eq(merch_id))
+        .filter(transaction_type.eq(TransactionType::Payment))
+        .filter(status.eq(TransactionStatus::Confirmed))
+        .filter(created_at.ge(since))
+        .first::<Option<BigDecimal>>(conn)?
+        .and_then(|volume| volume.to_i64())
+        .unwrap_or(0))
+}
+
+/// Schedule to apply to a new transaction of the merchant
+pub fn applied_fee_schedule(
+    merch_id: &str,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<FeeSchedule, Error> {
+    let fee_schedule = get_fee_schedule(merch_id, conn)?;
+    if fee_schedule.tiers.is_empty() {
+        return Ok(fee_schedule);
+    }
+    let since = now - Duration::days(VOLUME_PERIOD_DAYS);
+    let volume = payments_volume(merch_id, since, conn)?;
+    Ok(fee_schedule.for_volume(volume))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule() -> FeeSchedule {
+        FeeSchedule {
+            payout: FeeRule {
+                share: 0.02,
+                fixed: 1_000_000,
+                minimum: 50_000_000,
+            },
+            payment: FeeRule {
+                share: 0.01,
+                fixed: 0,
+                minimum: 0,
+            },
+            tiers: vec![
+                FeeTier {
+                    min_volume: 100_000_000_000,
+                    payout_share: Some(0.005),
+                    payment_share: None,
+                },
+                FeeTier {
+                    min_volume: 10_000_000_000,
+                    payout_share: Some(0.01),
+                    payment_share: Some(0.0),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_default_schedule() {
+        let schedule = FeeSchedule::default();
+        assert_eq!(schedule.payout_fee(1_000_000_000), 10_000_000);
+        assert_eq!(schedule.payment_fee(1_000_000_000), 0);
+    }
+
+    #[test]
+    fn test_fee_rule() {
+        let schedule = schedule();
+        assert_eq!(schedule.payout_fee(10_000_000_000), 201_000_000);
+        // minimum
+        assert_eq!(schedule.payout_fee(1_000_000_000), 50_000_000);
+        assert_eq!(schedule.payment_fee(1_000_000_000), 10_000_000);
+        let flat = FeeRule {
+            minimum: 5_000_000,
+            ..Default::default()
+        };
+        assert_eq!(flat.fee(1_000_000_000), 5_000_000);
+    }
+
+    #[test]
+    fn test_payment_fee_is_capped() {
+        let schedule = FeeSchedule {
+            payment: FeeRule {
+                share: 0.0,
+                fixed: 10_000_000,
+                minimum: 0,
+            },
+            ..Default::default()
+        };
+        assert_eq!(schedule.payment_fee(1_000_000), 1_000_000);
+    }
+
+    #[test]
+    fn test_volume_tiers() {
+        let schedule = schedule();
+        let applied = schedule.for_volume(0);
+        assert_eq!(applied.payout_fee(10_000_000_000), 201_000_000);
+        assert!(applied.tiers.is_empty());
+
+        let applied = schedule.for_volume(10_000_000_000);
+        assert_eq!(applied.payout_fee(10_000_000_000), 101_000_000);
+        assert_eq!(applied.payment_fee(10_000_000_000), 0);
+        // the rest of the rule stays
+        assert_eq!(applied.payout_fee(1_000_000_000), 50_000_000);
+
+        let applied = schedule.for_volume(500_000_000_000);
+        assert_eq!(applied.payout_fee(10_000_000_000), 51_000_000);
+        assert_eq!(applied.payment_fee(10_000_000_000), 100_000_000);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(schedule().validate().is_ok());
+        let mut wrong = schedule();
+        wrong.payout.share = 1.5;
+        assert!(wrong.validate().is_err());
+        let mut wrong = schedule();
+        wrong.payment.fixed = -1;
+        assert!(wrong.validate().is_err());
+        let mut wrong = schedule();
+        wrong.tiers[0].payout_share = Some(-0.1);
+        assert!(wrong.validate().is_err());
+    }
+
+    #[test]
+    fn test_schedule_json() {
+        let schedule: FeeSchedule =
+            serde_json::from_str(r#"{"payout": {"share": 0.02}, "tiers": []}"#).unwrap();
+        assert_eq!(schedule.payout_fee(1_000_000_000), 20_000_000);
+        assert_eq!(schedule.payment, FeeRule::default());
+    }
+}
@@ -1,4 +1,5 @@
 use crate::models::Money;
+use crate::statement::format_grin;
 use askama::Error;
 use chrono::{Duration, NaiveDateTime};
 use chrono_humanize::{Accuracy, HumanTime, Tense};
@@ -6,6 +7,11 @@ pub fn grin(nanogrins: &i64) -> Result<String, Error> {
     Ok(Money::from_grin(*nanogrins).to_string())
 }
 
+/// Amount to a nanogrin, for statements
+pub fn grin_exact(nanogrins: &i64) -> Result<String, Error> {
+    Ok(format_grin(*nanogrins))
+}
+
 pub fn pretty_date(date: &NaiveDateTime) -> Result<String, Error> {
     Ok(date.format("%d.%m.%Y %H:%M:%S").to_string())
 }
@@ -1,36 +1,39 @@
+use crate::clock::Clock;
 use crate::db::{
-    create_transaction, update_transaction_status, CreateTransaction, DbExecutor, GetMerchant,
-    ReportAttempt,
+    cancel_attempt, create_transaction, mark_wallet_tx_cancelled, update_transaction_status,
+    CreateTransaction, DbExecutor, GetMerchant, ReportAttempt,
 };
 use crate::errors::Error;
+use crate::ledger::record_transaction;
+use crate::metrics::{self, TrackTransition};
 use crate::models::{Confirmation, Money, Transaction, TransactionStatus, TransactionType};
 use crate::ser;
 use crate::wallet::TxLogEntry;
-use crate::wallet::Wallet;
+use crate::wallet::WalletApi;
 use crate::Pool;
 use actix::{Actor, Addr, Context, Handler, Message, ResponseFuture};
 use actix_web::client::Client;
 use actix_web::web::block;
-use chrono::{Duration, Utc};
+use chrono::{Duration, NaiveDateTime};
 use derive_deref::Deref;
 use diesel::pg::PgConnection;
 use diesel::{self, prelude::*};
 use futures::future::{ok, Either, FutureExt, TryFutureExt};
-use log::{debug, error};
+use log::{debug, error, warn};
 use serde::{Deserialize, Serialize};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use uuid::Uuid;
 
-pub const MINIMAL_WITHDRAW: i64 = 1_000_000_000;
-pub const KNOCKTURN_SHARE: f64 = 0.01;
-pub const TRANSFER_FEE: i64 = 8_000_000;
-const MAX_REPORT_ATTEMPTS: i32 = 10; //Number or attemps we try to run merchant's callback
+pub(crate) const MAX_REPORT_ATTEMPTS: i32 = 10; //Number or attemps we try to run merchant's callback
+const MAX_CANCEL_ATTEMPTS: i32 = 10; //Number or attemps we try to cancel tx of rejected payment in wallet
 
 pub struct Fsm {
     pub db: Addr<DbExecutor>,
-    pub wallet: Wallet,
+    pub wallet: Arc<dyn WalletApi>,
     pub pool: Pool,
+    pub clock: Arc<dyn Clock>,
 }
 
 impl Actor for Fsm {
@@ -156,6 +159,15 @@ impl Message for ReportPayment<RejectedPayment> {
     type Result = Result<(), Error>;
 }
 
+#[derive(Debug, Deserialize)]
+pub struct CancelWalletTx {
+    pub payment: RejectedPayment,
+}
+
+impl Message for CancelWalletTx {
+    type Result = Result<RejectedPayment, Error>;
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ManuallyRefundPayment {
     pub payment: RefundPayment,
@@ -182,13 +194,14 @@ impl Handler<CreatePayment> for Fsm {
         };
 
         let pool = self.pool.clone();
+        let now = self.clock.now();
 
         let res = block::<_, _, Error>(move || {
             let conn: &PgConnection = &pool.get().unwrap();
-            create_transaction(tx, conn).map(|transaction| NewPayment(transaction))
+            create_transaction(tx, now, conn).map(|transaction| NewPayment(transaction))
         })
         .map_err(|e| e.into());
-        Box::pin(res)
+        res.track_transition("create_payment")
     }
 }
 
@@ -216,21 +229,54 @@ async fn get_payment(
     .map_err(|e| e.into())
 }
 
-pub async fn get_unreported_confirmed_payments(pool: Pool) -> Result<Vec<ConfirmedPayment>, Error> {
-    get_unreported_payments(TransactionStatus::Confirmed, pool)
+pub async fn get_unreported_confirmed_payments(
+    pool: Pool,
+    now: NaiveDateTime,
+) -> Result<Vec<ConfirmedPayment>, Error> {
+    get_unreported_payments(TransactionStatus::Confirmed, pool, now)
         .await
         .map(|list| list.into_iter().map(ConfirmedPayment).collect())
 }
 
-pub async fn get_unreported_rejected_payments(pool: Pool) -> Result<Vec<RejectedPayment>, Error> {
-    get_unreported_payments(TransactionStatus::Confirmed, pool)
+pub async fn get_unreported_rejected_payments(
+    pool: Pool,
+    now: NaiveDateTime,
+) -> Result<Vec<RejectedPayment>, Error> {
+    get_unreported_payments(TransactionStatus::Rejected, pool, now)
         .await
         .map(|list| list.into_iter().map(RejectedPayment).collect())
 }
 
+pub async fn get_uncancelled_rejected_payments(
+    pool: Pool,
+    now: NaiveDateTime,
+) -> Result<Vec<RejectedPayment>, Error> {
+    block::<_, _, Error>(move || {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &pool.get().unwrap();
+        transactions
+            .filter(status.eq(TransactionStatus::Rejected))
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .filter(wallet_tx_slate_id.is_not_null())
+            .filter(wallet_tx_cancelled.ne(true))
+            .filter(cancel_attempts.lt(MAX_CANCEL_ATTEMPTS))
+            .filter(
+                next_cancel_attempt
+                    .le(now)
+                    .or(next_cancel_attempt.is_null()),
+            )
+            .load::<Transaction>(conn)
+            .map_err(|e| e.into())
+    })
+    .await
+    .map(|list| list.into_iter().map(RejectedPayment).collect())
+    .map_err(|e| e.into())
+}
+
 async fn get_unreported_payments(
     tx_status: TransactionStatus,
     pool: Pool,
+    now: NaiveDateTime,
 ) -> Result<Vec<Transaction>, Error> {
     block::<_, _, Error>(move || {
         use crate::schema::transactions::dsl::*;
@@ -242,7 +288,7 @@ async fn get_unreported_payments(
             .filter(report_attempts.lt(MAX_REPORT_ATTEMPTS))
             .filter(
                 next_report_attempt
-                    .le(Utc::now().naive_utc())
+                    .le(now)
                     .or(next_report_attempt.is_null()),
             )
             .load::<Transaction>(conn)
@@ -287,6 +333,13 @@ impl Payment for ConfirmedPayment {
     }
 }
 
+impl Payment for RejectedPayment {
+    const STATUS: TransactionStatus = TransactionStatus::Rejected;
+    fn new(tx: Transaction) -> Self {
+        Self(tx)
+    }
+}
+
 impl Payment for RefundPayment {
     const STATUS: TransactionStatus = TransactionStatus::Refund;
     fn new(tx: Transaction) -> Self {
@@ -309,27 +362,32 @@ impl Handler<MakePayment> for Fsm {
         });
 
         let pool = self.pool.clone();
+        let now = self.clock.now();
 
         let res = block::<_, _, Error>(move || {
             use crate::schema::transactions::dsl::*;
             let conn: &PgConnection = &pool.get().unwrap();
 
-            let transaction = diesel::update(transactions.filter(id.eq(transaction_id.clone())))
-                .set((
-                    wallet_tx_id.eq(msg.wallet_tx.id as i64),
-                    wallet_tx_slate_id.eq(msg.wallet_tx.tx_slate_id.unwrap()),
-                    slate_messages.eq(messages),
-                    real_transfer_fee.eq(msg.wallet_tx.fee.map(|fee| fee as i64)),
-                    status.eq(TransactionStatus::Pending),
-                    commit.eq(ser::to_hex(msg.commit)),
-                ))
-                .get_result(conn)
-                .map_err::<Error, _>(|e| e.into())?;
-            Ok(PendingPayment(transaction))
+            conn.transaction(|| {
+                let transaction =
+                    diesel::update(transactions.filter(id.eq(transaction_id.clone())))
+                        .set((
+                            wallet_tx_id.eq(msg.wallet_tx.id as i64),
+                            wallet_tx_slate_id.eq(msg.wallet_tx.tx_slate_id.unwrap()),
+                            slate_messages.eq(messages),
+                            real_transfer_fee.eq(msg.wallet_tx.fee.map(|fee| fee as i64)),
+                            status.eq(TransactionStatus::Pending),
+                            commit.eq(ser::to_hex(msg.commit)),
+                            kernel_excess.eq(msg.wallet_tx.kernel_excess),
+                        ))
+                        .get_result(conn)?;
+                record_transaction(&transaction, now, conn)?;
+                Ok(PendingPayment(transaction))
+            })
         })
         .map_err(|e| e.into());
 
-        Box::pin(res)
+        res.track_transition("make_payment")
     }
 }
 
@@ -344,20 +402,22 @@ impl Handler<SeenInChainPayment<PendingPayment>> for Fsm {
         Box::pin(
             block::<_, _, Error>({
                 let pool = self.pool.clone();
+                let now = self.clock.now();
                 move || {
                     use crate::schema::transactions::dsl::*;
                     let conn: &PgConnection = &pool.get().unwrap();
-                    Ok(
-                        diesel::update(transactions.filter(id.eq(msg.payment.id.clone())))
+                    conn.transaction(|| {
+                        let tx = diesel::update(transactions.filter(id.eq(msg.payment.id.clone())))
                             .set((height.eq(msg.height), status.eq(TransactionStatus::InChain)))
-                            .get_result(conn)
-                            .map(|tx: Transaction| InChainPayment(tx))
-                            .map_err::<Error, _>(|e| e.into())?,
-                    )
+                            .get_result(conn)?;
+                        record_transaction(&tx, now, conn)?;
+                        Ok(InChainPayment(tx))
+                    })
                 }
             })
             .map_err(|e| e.into()),
         )
+        .track_transition("payment_in_chain")
     }
 }
 
@@ -372,20 +432,22 @@ impl Handler<SeenInChainPayment<RejectedPayment>> for Fsm {
         Box::pin(
             block::<_, _, Error>({
                 let pool = self.pool.clone();
+                let now = self.clock.now();
                 move || {
                     use crate::schema::transactions::dsl::*;
                     let conn: &PgConnection = &pool.get().unwrap();
-                    Ok(
-                        diesel::update(transactions.filter(id.eq(msg.payment.id.clone())))
-                            .set(status.eq(TransactionStatus::Refund))
-                            .get_result(conn)
-                            .map(|tx: Transaction| RefundPayment(tx))
-                            .map_err::<Error, _>(|e| e.into())?,
-                    )
+                    conn.transaction(|| {
+                        let tx = diesel::update(transactions.filter(id.eq(msg.payment.id.clone())))
+                            .set((height.eq(msg.height), status.eq(TransactionStatus::Refund)))
+                            .get_result(conn)?;
+                        record_transaction(&tx, now, conn)?;
+                        Ok(RefundPayment(tx))
+                    })
                 }
             })
             .map_err(|e| e.into()),
         )
+        .track_transition("refund_payment")
     }
 }
 
@@ -396,21 +458,23 @@ impl Handler<ConfirmPayment> for Fsm {
         Box::pin(
             block::<_, _, Error>({
                 let pool = self.pool.clone();
+                let now = self.clock.now();
                 move || {
                     use crate::schema::transactions::dsl::*;
                     let conn: &PgConnection = &pool.get().unwrap();
 
-                    let tx = diesel::update(transactions.filter(id.eq(msg.payment.id)))
-                        .set((
-                            status.eq(TransactionStatus::Confirmed),
-                            updated_at.eq(Utc::now().naive_utc()),
-                        ))
-                        .get_result(conn)?;
-                    Ok(ConfirmedPayment(tx))
+                    conn.transaction(|| {
+                        let tx = diesel::update(transactions.filter(id.eq(msg.payment.id)))
+                            .set((status.eq(TransactionStatus::Confirmed), updated_at.eq(now)))
+                            .get_result(conn)?;
+                        record_transaction(&tx, now, conn)?;
+                        Ok(ConfirmedPayment(tx))
+                    })
                 }
             })
             .map_err(|e| e.into()),
         )
+        .track_transition("confirm_payment")
     }
 }
 
@@ -503,27 +567,31 @@ impl Handler<ManuallyRefundPayment> for Fsm {
                 let merch_id = msg.merchant_id.clone();
                 let pool = self.pool.clone();
                 let transaction_id = msg.payment.id.clone();
+                let now = self.clock.now();
                 move || {
                     use crate::schema::transactions::dsl::*;
                     let conn: &PgConnection = &pool.get().unwrap();
 
-                    diesel::update(
-                        transactions
-                            .filter(id.eq(transaction_id))
-                            .filter(merchant_id.eq(merch_id))
-                            .filter(status.eq(TransactionStatus::Refund)),
-                    )
-                    .set((
-                        status.eq(TransactionStatus::RefundedManually),
-                        updated_at.eq(Utc::now().naive_utc()),
-                    ))
-                    .get_result::<Transaction>(conn)
-                    .map_err::<Error, _>(|e| e.into())
-                    .map(ManuallyRefundedPayment)
+                    conn.transaction(|| {
+                        let tx = diesel::update(
+                            transactions
+                                .filter(id.eq(transaction_id))
+                                .filter(merchant_id.eq(merch_id))
+                                .filter(status.eq(TransactionStatus::Refund)),
+                        )
+                        .set((
+                            status.eq(TransactionStatus::RefundedManually),
+                            updated_at.eq(now),
+                        ))
+                        .get_result::<Transaction>(conn)?;
+                        record_transaction(&tx, now, conn)?;
+                        Ok(ManuallyRefundedPayment(tx))
+                    })
                 }
             })
             .map_err(|e| e.into()),
         )
+        .track_transition("manually_refund_payment")
     }
 }
 
@@ -532,9 +600,10 @@ impl Handler<RejectPayment<NewPayment>> for Fsm {
 
     fn handle(&mut self, msg: RejectPayment<NewPayment>, _: &mut Self::Context) -> Self::Result {
         Box::pin(
-            reject_transaction(self.pool.clone(), msg.payment.id.clone())
+            reject_transaction(self.pool.clone(), msg.payment.id.clone(), self.clock.now())
                 .map(|res| res.map(RejectedPayment)),
         )
+        .track_transition("reject_new_payment")
     }
 }
 
@@ -546,17 +615,86 @@ impl Handler<RejectPayment<PendingPayment>> for Fsm {
         msg: RejectPayment<PendingPayment>,
         _: &mut Self::Context,
     ) -> Self::Result {
+        let wallet = self.wallet.clone();
+        let pool = self.pool.clone();
+        let clock = self.clock.clone();
+        async move {
+            let tx = reject_transaction(pool.clone(), msg.payment.id.clone(), clock.now()).await?;
+            // Payment is rejected no matter if we managed to cancel tx in wallet or not,
+            // a failed cancellation will be retried by cron
+            let tx = cancel_wallet_tx(wallet, pool, clock, tx).await?;
+            Ok(RejectedPayment(tx))
+        }
+        .track_transition("reject_pending_payment")
+    }
+}
+
+impl Handler<CancelWalletTx> for Fsm {
+    type Result = ResponseFuture<Result<RejectedPayment, Error>>;
+
+    fn handle(&mut self, msg: CancelWalletTx, _: &mut Self::Context) -> Self::Result {
         Box::pin(
-            reject_transaction(self.pool.clone(), msg.payment.id.clone())
-                .map(|res| res.map(RejectedPayment)),
+            cancel_wallet_tx(
+                self.wallet.clone(),
+                self.pool.clone(),
+                self.clock.clone(),
+                msg.payment.0,
+            )
+            .map(|res| res.map(RejectedPayment)),
         )
+        .track_transition("cancel_wallet_tx")
     }
 }
 
-async fn reject_transaction(pool: Pool, id: Uuid) -> Result<Transaction, Error> {
+async fn cancel_wallet_tx(
+    wallet: Arc<dyn WalletApi>,
+    pool: Pool,
+    clock: Arc<dyn Clock>,
+    transaction: Transaction,
+) -> Result<Transaction, Error> {
+    let slate_id = match transaction.wallet_tx_slate_id.clone() {
+        Some(slate_id) => slate_id,
+        None => return Ok(transaction),
+    };
+    debug!(
+        "Try to cancel wallet tx {} of payment {}",
+        slate_id, transaction.id
+    );
+
+    let cancelled = match wallet.cancel_tx(&slate_id).await {
+        Ok(_) => true,
+        Err(e) => {
+            warn!(
+                "Cannot cancel wallet tx {} of payment {}: {}",
+                slate_id, transaction.id, e
+            );
+            false
+        }
+    };
+
+    let now = clock.now();
     block::<_, _, Error>(move || {
         let conn: &PgConnection = &pool.get().unwrap();
-        update_transaction_status(id, TransactionStatus::Rejected, conn)
+        if cancelled {
+            mark_wallet_tx_cancelled(transaction.id, conn)
+        } else {
+            let next_attempt =
+                now + Duration::seconds(10 * (transaction.cancel_attempts + 1).pow(2) as i64);
+            cancel_attempt(conn, transaction.id, next_attempt)
+        }
+    })
+    .await
+    .map_err(|e| e.into())
+}
+
+async fn reject_transaction(
+    pool: Pool,
+    id: Uuid,
+    now: NaiveDateTime,
+) -> Result<Transaction, Error> {
+    block::<_, _, Error>(move || {
+        let conn: &PgConnection = &pool.get().unwrap();
+        update_transaction_status(id, TransactionStatus::Rejected, now, conn)
     })
     .await
     .map_err(|e| e.into())
@@ -570,25 +708,29 @@ impl Handler<ReportPayment<ConfirmedPayment>> for Fsm {
         msg: ReportPayment<ConfirmedPayment>,
         _: &mut Self::Context,
     ) -> Self::Result {
+        let now = self.clock.now();
         Box::pin(
-            report_transaction(self.db.clone(), msg.payment.0.clone()).and_then({
+            report_transaction(self.db.clone(), now, msg.payment.0.clone()).and_then({
                 let pool = self.pool.clone();
                 move |_| {
                     block::<_, _, Error>({
                         move || {
                             let conn: &PgConnection = &pool.get().unwrap();
                             use crate::schema::transactions::dsl::*;
-                            diesel::update(transactions.filter(id.eq(msg.payment.id)))
-                                .set(reported.eq(true))
-                                .get_result::<Transaction>(conn)
-                                .map_err::<Error, _>(|e| e.into())?;
-                            Ok(())
+                            // reported payment becomes available to merchant
+                            conn.transaction(|| {
+                                let tx = diesel::update(transactions.filter(id.eq(msg.payment.id)))
+                                    .set(reported.eq(true))
+                                    .get_result::<Transaction>(conn)?;
+                                record_transaction(&tx, now, conn)
+                            })
                         }
                     })
                     .map_err(|e| e.into())
                 }
             }),
         )
+        .track_transition("report_confirmed_payment")
     }
 }
 
@@ -600,8 +742,9 @@ impl Handler<ReportPayment<RejectedPayment>> for Fsm {
         msg: ReportPayment<RejectedPayment>,
         _: &mut Self::Context,
     ) -> Self::Result {
+        let now = self.clock.now();
         Box::pin(
-            report_transaction(self.db.clone(), msg.payment.0.clone()).and_then({
+            report_transaction(self.db.clone(), now, msg.payment.0.clone()).and_then({
                 let pool = self.pool.clone();
                 move |_| {
                     block::<_, _, Error>({
@@ -622,10 +765,15 @@ impl Handler<ReportPayment<RejectedPayment>> for Fsm {
                 }
             }),
         )
+        .track_transition("report_rejected_payment")
     }
 }
 
-async fn report_transaction(db: Addr<DbExecutor>, transaction: Transaction) -> Result<(), Error> {
+pub(crate) async fn report_transaction(
+    db: Addr<DbExecutor>,
+    now: NaiveDateTime,
+    transaction: Transaction,
+) -> Result<(), Error> {
     debug!("Try to report transaction {}", transaction.id);
 
     let merchant = db
@@ -637,16 +785,16 @@ async fn report_transaction(db: Addr<DbExecutor>, transaction: Transaction) -> R
     if let Some(callback_url) = merchant.callback_url.clone() {
         debug!("Run callback for merchant {}", merchant.email);
 
-        if let Err(callback_err) = run_callback(&callback_url, &merchant.token, &transaction).await
-        {
+        let res = run_callback(&callback_url, &merchant.token, &transaction).await;
+        metrics::callback(&res, transaction.report_attempts + 1);
+        if let Err(callback_err) = res {
             let report_attempts = transaction.report_attempts.clone();
             let transaction_id = transaction.id.clone();
-            let next_attempt = Utc::now().naive_utc()
-                + Duration::seconds(10 * (report_attempts + 1).pow(2) as i64);
+            let next_attempt = now + Duration::seconds(10 * (report_attempts + 1).pow(2) as i64);
             let dbres = db
                 .send(ReportAttempt {
                     transaction_id: transaction_id,
-                    next_attempt: Some(next_attempt),
+                    next_attempt: next_attempt,
                 })
                 .await
                 .map_err(|e| Error::General(s!(e)))
@@ -1,32 +1,38 @@
-use crate::db::{self, get_balance, update_transaction_status, DbExecutor};
+use crate::clock::Clock;
+use crate::db::{self, get_balance, get_current_height, DbExecutor};
 use crate::errors::Error;
-use crate::models::Merchant;
+use crate::fees::{applied_fee_schedule, FeeSchedule, MINIMAL_WITHDRAW, TRANSFER_FEE};
+use crate::fsm::{report_transaction, MAX_REPORT_ATTEMPTS};
+use crate::ledger::{payment_cleared_since, record_transaction};
+use crate::metrics::TrackTransition;
+use crate::models::{Merchant, PayoutPolicy};
 use crate::models::{Money, Transaction, TransactionStatus, TransactionType};
 use crate::models::{NEW_PAYOUT_TTL_SECONDS, PENDING_PAYOUT_TTL_SECONDS};
 use crate::ser;
 use crate::wallet::TxLogEntry;
-use crate::wallet::Wallet;
+use crate::wallet::{send_to_merchant_wallet, WalletApi};
 use crate::Pool;
-use actix::{Actor, Addr, Context, Handler, Message, ResponseFuture};
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, ResponseFuture};
 use actix_web::web::block;
-use chrono::{Duration, Utc};
+use chrono::{Duration, NaiveDateTime};
 use derive_deref::Deref;
 use diesel::pg::PgConnection;
 use diesel::{self, prelude::*};
 use futures::future::Future;
 use futures::future::{FutureExt, TryFutureExt};
-use log::warn;
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use uuid::Uuid;
 
-pub const MINIMAL_WITHDRAW: i64 = 1_000_000_000;
-pub const KNOCKTURN_SHARE: f64 = 0.01;
-pub const TRANSFER_FEE: i64 = 8_000_000;
+pub const DEFAULT_PAYOUT_CONFIRMATIONS: i64 = 10;
+const MAX_FINALIZE_ATTEMPTS: i32 = 3; //Number of attempts we try to record posted auto payout
 
 pub struct FsmPayout {
     pub db: Addr<DbExecutor>,
-    pub wallet: Wallet,
+    pub wallet: Arc<dyn WalletApi>,
     pub pool: Pool,
+    pub clock: Arc<dyn Clock>,
 }
 
 impl Actor for FsmPayout {
@@ -70,7 +76,7 @@ impl Message for CreatePayout {
 pub struct InitializePayout {
     pub new_payout: NewPayout,
     pub wallet_tx: TxLogEntry,
-    pub commit: Vec<u8>,
+    pub commit: Option<Vec<u8>>,
 }
 
 impl Message for InitializePayout {
@@ -80,12 +86,24 @@ impl Message for InitializePayout {
 #[derive(Debug, Deserialize)]
 pub struct FinalizePayout {
     pub initialized_payout: InitializedPayout,
+    pub kernel_excess: Option<String>,
+    pub input_commits: Vec<String>,
 }
 
 impl Message for FinalizePayout {
     type Result = Result<PendingPayout, Error>;
 }
 
+#[derive(Debug, Deserialize)]
+pub struct SeenInChainPayout {
+    pub payout: PendingPayout,
+    pub height: i64,
+}
+
+impl Message for SeenInChainPayout {
+    type Result = Result<InChainPayout, Error>;
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ConfirmPayout {
     pub payout: InChainPayout,
@@ -112,6 +130,30 @@ impl Message for RejectPayout<PendingPayout> {
     type Result = Result<RejectedPayout, Error>;
 }
 
+/// Cancels the transaction of an expired pending payout in our wallet, so
+/// the wallet can spend its inputs again. The payout is rejected later, once
+/// the node confirms the inputs are still unspent
+#[derive(Debug, Deserialize)]
+pub struct CancelPayoutTx {
+    pub payout: PendingPayout,
+}
+
+impl Message for CancelPayoutTx {
+    type Result = Result<PendingPayout, Error>;
+}
+
+/// Merchant cancels the payout which isn't posted yet, the amount returns to
+/// the balance immediately
+#[derive(Debug, Deserialize)]
+pub struct CancelPayout {
+    pub merchant_id: String,
+    pub transaction_id: Uuid,
+}
+
+impl Message for CancelPayout {
+    type Result = Result<RejectedPayout, Error>;
+}
+
 #[derive(Debug, Deserialize)]
 pub struct GetPayout {
     pub merchant_id: String,
@@ -165,26 +207,95 @@ impl Message for GetExpiredInitializedPayouts {
     type Result = Result<Vec<InitializedPayout>, Error>;
 }
 
+/// Pending payouts which were found in chain
+#[derive(Debug, Deserialize)]
+pub struct GetSeenInChainPayouts;
+
+impl Message for GetSeenInChainPayouts {
+    type Result = Result<Vec<PendingPayout>, Error>;
+}
+
+/// Payouts in chain which got enough confirmations
+#[derive(Debug, Deserialize)]
+pub struct GetConfirmablePayouts;
+
+impl Message for GetConfirmablePayouts {
+    type Result = Result<Vec<InChainPayout>, Error>;
+}
+
+/// Payouts which changed status since merchant was notified last time
+#[derive(Debug, Deserialize)]
+pub struct GetUnreportedPayouts;
+
+impl Message for GetUnreportedPayouts {
+    type Result = Result<Vec<Transaction>, Error>;
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportPayout {
+    pub payout: Transaction,
+}
+
+impl Message for ReportPayout {
+    type Result = Result<(), Error>;
+}
+
+/// Merchants whose balance has to be sent to their wallets now
+#[derive(Debug, Deserialize)]
+pub struct GetDueAutoPayouts;
+
+impl Message for GetDueAutoPayouts {
+    type Result = Result<Vec<AutoPayout>, Error>;
+}
+
+/// Send `amount` to merchant's listening wallet without merchant's
+/// interaction. If it fails the merchant is switched to manual payouts
+#[derive(Debug)]
+pub struct AutoPayout {
+    pub merchant: Merchant,
+    pub amount: i64,
+}
+
+impl Message for AutoPayout {
+    type Result = Result<PendingPayout, Error>;
+}
+
 impl Handler<CreatePayout> for FsmPayout {
     type Result = ResponseFuture<Result<NewPayout, Error>>;
 
     fn handle(&mut self, msg: CreatePayout, _: &mut Self::Context) -> Self::Result {
-        block::<_, _, Error>({
-            let pool = self.pool.clone();
-            let merchant_id = msg.merchant_id.clone();
-            move || {
+        let wallet = self.wallet.clone();
+        let pool = self.pool.clone();
+        let now = self.clock.now();
+        async move {
+            let fee_schedule = block::<_, _, Error>({
+                let pool = pool.clone();
+                let merchant_id = msg.merchant_id.clone();
+                move || {
+                    let conn: &PgConnection = &pool.get().unwrap();
+                    applied_fee_schedule(&merchant_id, now, conn)
+                }
+            })
+            .await?;
+            let quote = quote_payout(&*wallet, &fee_schedule, msg.amount).await;
+            block::<_, _, Error>(move || {
+                let merchant_id = msg.merchant_id.clone();
                 use crate::schema::merchants::dsl::*;
                 let conn: &PgConnection = &pool.get().unwrap();
                 let tx = conn.transaction(|| {
-                    let merchant: Merchant =
-                        merchants.find(merchant_id.clone()).get_result(conn)?;
+                    // concurrent payouts wait here, so they can't both pass
+                    // the balance check
+                    let merchant: Merchant = merchants
+                        .find(merchant_id.clone())
+                        .for_update()
+                        .get_result(conn)?;
                     let balance = get_balance(&merchant_id, &conn)?;
                     if balance < msg.amount {
                         return Err(Error::NotEnoughFunds);
                     }
 
                     //check if fees are not too high
-                    msg.amount.reminder()?;
+                    quote.reminder()?;
 
                     let amount = Money::from_grin(msg.amount);
                     let new_id = uuid::Uuid::new_v4();
@@ -197,8 +308,8 @@ impl Handler<CreatePayout> for FsmPayout {
                         grin_amount: msg.amount,
                         status: TransactionStatus::New,
                         confirmations: msg.confirmations,
-                        created_at: Utc::now().naive_utc(),
-                        updated_at: Utc::now().naive_utc(),
+                        created_at: now,
+                        updated_at: now,
                         report_attempts: 0,
                         next_report_attempt: None,
                         reported: false,
@@ -210,27 +321,39 @@ impl Handler<CreatePayout> for FsmPayout {
                             merchant_id.clone()
                         ),
                         slate_messages: None,
-                        transfer_fee: Some(msg.amount.transfer_fee()),
-                        knockturn_fee: Some(msg.amount.knockturn_fee()),
+                        transfer_fee: Some(quote.transfer_fee()),
+                        knockturn_fee: Some(quote.knockturn_fee()),
                         real_transfer_fee: None,
                         transaction_type: TransactionType::Payout,
                         height: None,
                         commit: None,
                         redirect_url: None,
+                        wallet_tx_cancelled: false,
+                        cancel_attempts: 0,
+                        next_cancel_attempt: None,
+                        kernel_excess: None,
+                        in_mempool: false,
+                        mempool_changed_at: None,
+                        stuck_since: None,
+                        transfer_fee_credit: None,
+                        fee_schedule: Some(fee_schedule),
+                        input_commits: None,
                     };
 
                     use crate::schema::transactions;
                     let tx = diesel::insert_into(transactions::table)
                         .values(&new_transaction)
                         .get_result::<Transaction>(conn)?;
+                    record_transaction(&tx, now, conn)?;
                     Ok(tx)
                 })?;
 
                 Ok(NewPayout(tx))
-            }
-        })
-        .map_err(|e| e.into())
-        .boxed()
+            })
+            .await
+            .map_err(|e| e.into())
+        }
+        .track_transition("create_payout")
     }
 }
 
@@ -272,26 +395,30 @@ impl Handler<InitializePayout> for FsmPayout {
         });
 
         let pool = self.pool.clone();
+        let now = self.clock.now();
 
         block::<_, _, Error>(move || {
             use crate::schema::transactions::dsl::*;
             let conn: &PgConnection = &pool.get().unwrap();
 
-            let transaction = diesel::update(transactions.filter(id.eq(transaction_id.clone())))
-                .set((
-                    wallet_tx_id.eq(msg.wallet_tx.id as i64),
-                    wallet_tx_slate_id.eq(msg.wallet_tx.tx_slate_id.unwrap()),
-                    slate_messages.eq(messages),
-                    real_transfer_fee.eq(msg.wallet_tx.fee.map(|fee| fee as i64)),
-                    status.eq(TransactionStatus::Initialized),
-                    commit.eq(ser::to_hex(msg.commit)),
-                ))
-                .get_result(conn)
-                .map_err::<Error, _>(|e| e.into())?;
-            Ok(InitializedPayout(transaction))
+            conn.transaction(|| {
+                let transaction =
+                    diesel::update(transactions.filter(id.eq(transaction_id.clone())))
+                        .set((
+                            wallet_tx_id.eq(msg.wallet_tx.id as i64),
+                            wallet_tx_slate_id.eq(msg.wallet_tx.tx_slate_id.unwrap()),
+                            slate_messages.eq(messages),
+                            real_transfer_fee.eq(msg.wallet_tx.fee.map(|fee| fee as i64)),
+                            status.eq(TransactionStatus::Initialized),
+                            commit.eq(msg.commit.map(ser::to_hex)),
+                        ))
+                        .get_result(conn)?;
+                record_transaction(&transaction, now, conn)?;
+                Ok(InitializedPayout(transaction))
+            })
         })
         .map_err(|e| e.into())
-        .boxed()
+        .track_transition("initialize_payout")
     }
 }
 
@@ -347,17 +474,33 @@ impl Handler<FinalizePayout> for FsmPayout {
 
     fn handle(&mut self, msg: FinalizePayout, _: &mut Self::Context) -> Self::Result {
         let pool = self.pool.clone();
+        let now = self.clock.now();
         block::<_, _, Error>(move || {
+            use crate::schema::transactions::dsl::*;
             let conn: &PgConnection = &pool.get().unwrap();
-            update_transaction_status(
-                msg.initialized_payout.id.clone(),
-                TransactionStatus::Pending,
-                &conn,
-            )
-            .map(PendingPayout)
+            let credit = fee_overcharge(&msg.initialized_payout);
+            conn.transaction(|| {
+                let tx: Transaction = diesel::update(
+                    transactions
+                        .filter(id.eq(msg.initialized_payout.id))
+                        .filter(status.eq(TransactionStatus::Initialized)),
+                )
+                .set((
+                    status.eq(TransactionStatus::Pending),
+                    updated_at.eq(now),
+                    kernel_excess.eq(msg.kernel_excess),
+                    transfer_fee_credit.eq(credit),
+                    input_commits.eq(msg.input_commits),
+                ))
+                .get_result(conn)
+                .optional()?
+                .ok_or(Error::InvalidEntity(s!("payout has changed its status")))?;
+                record_transaction(&tx, now, conn)?;
+                Ok(PendingPayout(tx))
+            })
         })
         .map_err(|e| e.into())
-        .boxed()
+        .track_transition("finalize_payout")
     }
 }
 
@@ -367,21 +510,68 @@ impl Handler<ConfirmPayout> for FsmPayout {
     fn handle(&mut self, msg: ConfirmPayout, _: &mut Self::Context) -> Self::Result {
         block::<_, _, Error>({
             let pool = self.pool.clone();
+            let now = self.clock.now();
             move || {
                 use crate::schema::transactions::dsl::*;
                 let conn: &PgConnection = &pool.get().unwrap();
 
-                let tx = diesel::update(transactions.filter(id.eq(msg.payout.0.id)))
+                conn.transaction(|| {
+                    let tx = diesel::update(
+                        transactions
+                            .filter(id.eq(msg.payout.0.id))
+                            .filter(status.eq(TransactionStatus::InChain)),
+                    )
                     .set((
                         status.eq(TransactionStatus::Confirmed),
-                        updated_at.eq(Utc::now().naive_utc()),
+                        updated_at.eq(now),
+                        reported.eq(false),
+                        report_attempts.eq(0),
+                        next_report_attempt.eq(None::<NaiveDateTime>),
                     ))
                     .get_result(conn)?;
-                Ok(ConfirmedPayout(tx))
+                    record_transaction(&tx, now, conn)?;
+                    Ok(ConfirmedPayout(tx))
+                })
             }
         })
         .map_err(|e| e.into())
-        .boxed()
+        .track_transition("confirm_payout")
+    }
+}
+
+impl Handler<SeenInChainPayout> for FsmPayout {
+    type Result = ResponseFuture<Result<InChainPayout, Error>>;
+
+    fn handle(&mut self, msg: SeenInChainPayout, _: &mut Self::Context) -> Self::Result {
+        block::<_, _, Error>({
+            let pool = self.pool.clone();
+            let now = self.clock.now();
+            move || {
+                use crate::schema::transactions::dsl::*;
+                let conn: &PgConnection = &pool.get().unwrap();
+
+                conn.transaction(|| {
+                    let tx = diesel::update(
+                        transactions
+                            .filter(id.eq(msg.payout.0.id))
+                            .filter(status.eq(TransactionStatus::Pending)),
+                    )
+                    .set((
+                        status.eq(TransactionStatus::InChain),
+                        height.eq(msg.height),
+                        updated_at.eq(now),
+                        reported.eq(false),
+                        report_attempts.eq(0),
+                        next_report_attempt.eq(None::<NaiveDateTime>),
+                    ))
+                    .get_result(conn)?;
+                    record_transaction(&tx, now, conn)?;
+                    Ok(InChainPayout(tx))
+                })
+            }
+        })
+        .map_err(|e| e.into())
+        .track_transition("payout_in_chain")
     }
 }
 
@@ -401,22 +591,334 @@ impl Handler<GetPendingPayouts> for FsmPayout {
     }
 }
 
+impl Handler<GetSeenInChainPayouts> for FsmPayout {
+    type Result = ResponseFuture<Result<Vec<PendingPayout>, Error>>;
+
+    fn handle(&mut self, _: GetSeenInChainPayouts, _: &mut Self::Context) -> Self::Result {
+        block::<_, _, Error>({
+            let pool = self.pool.clone();
+            move || {
+                use crate::schema::transactions::dsl::*;
+                let conn: &PgConnection = &pool.get().unwrap();
+                transactions
+                    .filter(status.eq(TransactionStatus::Pending))
+                    .filter(transaction_type.eq(TransactionType::Payout))
+                    .filter(height.is_not_null())
+                    .load::<Transaction>(conn)
+                    .map_err(|e| e.into())
+                    .map(|txs| txs.into_iter().map(PendingPayout).collect())
+            }
+        })
+        .map_err(|e| e.into())
+        .boxed()
+    }
+}
+
+impl Handler<GetConfirmablePayouts> for FsmPayout {
+    type Result = ResponseFuture<Result<Vec<InChainPayout>, Error>>;
+
+    fn handle(&mut self, _: GetConfirmablePayouts, _: &mut Self::Context) -> Self::Result {
+        block::<_, _, Error>({
+            let pool = self.pool.clone();
+            move || {
+                use crate::schema::transactions::dsl::*;
+                let conn: &PgConnection = &pool.get().unwrap();
+                let last_height = get_current_height(conn)?;
+                transactions
+                    .filter(status.eq(TransactionStatus::InChain))
+                    .filter(transaction_type.eq(TransactionType::Payout))
+                    .load::<Transaction>(conn)
+                    .map_err(|e| e.into())
+                    .map(|txs| {
+                        txs.into_iter()
+                            .filter(|tx| match tx.height {
+                                Some(tx_height) => tx.confirmations < last_height - tx_height,
+                                None => false,
+                            })
+                            .map(InChainPayout)
+                            .collect()
+                    })
+            }
+        })
+        .map_err(|e| e.into())
+        .boxed()
+    }
+}
+
+impl Handler<GetUnreportedPayouts> for FsmPayout {
+    type Result = ResponseFuture<Result<Vec<Transaction>, Error>>;
+
+    fn handle(&mut self, _: GetUnreportedPayouts, _: &mut Self::Context) -> Self::Result {
+        block::<_, _, Error>({
+            let pool = self.pool.clone();
+            let now = self.clock.now();
+            move || {
+                use crate::schema::transactions::dsl::*;
+                let conn: &PgConnection = &pool.get().unwrap();
+                transactions
+                    .filter(reported.ne(true))
+                    .filter(status.eq_any(vec![
+                        TransactionStatus::InChain,
+                        TransactionStatus::Confirmed,
+                        TransactionStatus::Rejected,
+                    ]))
+                    .filter(transaction_type.eq(TransactionType::Payout))
+                    .filter(report_attempts.lt(MAX_REPORT_ATTEMPTS))
+                    .filter(
+                        next_report_attempt
+                            .le(now)
+                            .or(next_report_attempt.is_null()),
+                    )
+                    .load::<Transaction>(conn)
+                    .map_err(|e| e.into())
+            }
+        })
+        .map_err(|e| e.into())
+        .boxed()
+    }
+}
+
+impl Handler<ReportPayout> for FsmPayout {
+    type Result = ResponseFuture<Result<(), Error>>;
+
+    fn handle(&mut self, msg: ReportPayout, _: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let pool = self.pool.clone();
+        let now = self.clock.now();
+        async move {
+            report_transaction(db, now, msg.payout.clone()).await?;
+            block::<_, _, Error>(move || {
+                use crate::schema::transactions::dsl::*;
+                let conn: &PgConnection = &pool.get().unwrap();
+                // status could change while we were calling merchant, then
+                // the new one has to be reported as well
+                diesel::update(
+                    transactions
+                        .filter(id.eq(msg.payout.id))
+                        .filter(status.eq(msg.payout.status)),
+                )
+                .set(reported.eq(true))
+                .execute(conn)?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| e.into())
+        }
+        .track_transition("report_payout")
+    }
+}
+
+impl Handler<GetDueAutoPayouts> for FsmPayout {
+    type Result = ResponseFuture<Result<Vec<AutoPayout>, Error>>;
+
+    fn handle(&mut self, _: GetDueAutoPayouts, _: &mut Self::Context) -> Self::Result {
+        block::<_, _, Error>({
+            let pool = self.pool.clone();
+            let now = self.clock.now();
+            move || {
+                use crate::schema::merchants::dsl::*;
+                let conn: &PgConnection = &pool.get().unwrap();
+                let mut due = vec![];
+                for merchant in merchants
+                    .filter(payout_policy.ne(PayoutPolicy::Manual))
+                    .load::<Merchant>(conn)?
+                {
+                    let balance = get_balance(&merchant.id, conn)?;
+                    let payment_cleared = merchant.payout_policy == PayoutPolicy::Immediate
+                        && payment_cleared_since(&merchant.id, merchant.last_auto_payout_at, conn)?;
+                    if balance >= MINIMAL_WITHDRAW
+                        && merchant.is_auto_payout_due(balance, payment_cleared, now)
+                    {
+                        due.push(AutoPayout {
+                            merchant,
+                            amount: balance,
+                        });
+                    }
+                }
+                Ok(due)
+            }
+        })
+        .map_err(|e| e.into())
+        .boxed()
+    }
+}
+
+impl Handler<AutoPayout> for FsmPayout {
+    type Result = ResponseFuture<Result<PendingPayout, Error>>;
+
+    fn handle(&mut self, msg: AutoPayout, ctx: &mut Self::Context) -> Self::Result {
+        let fsm = ctx.address();
+        let wallet = self.wallet.clone();
+        let pool = self.pool.clone();
+        let now = self.clock.now();
+        async move {
+            let merchant_id = msg.merchant.id.clone();
+            let res = send_auto_payout(fsm, wallet, msg.merchant, msg.amount).await;
+            let error = res.as_ref().err().map(|e| s!(e));
+            block::<_, _, Error>(move || {
+                use crate::schema::merchants::dsl::*;
+                let conn: &PgConnection = &pool.get().unwrap();
+                let merchant = merchants.filter(id.eq(merchant_id));
+                match error {
+                    None => diesel::update(merchant)
+                        .set((
+                            last_auto_payout_at.eq(now),
+                            auto_payout_error.eq(None::<String>),
+                        ))
+                        .execute(conn)?,
+                    Some(error) => {
+                        warn!(
+                            "Automatic payout failed, switch to manual payouts: {}",
+                            error
+                        );
+                        diesel::update(merchant)
+                            .set((
+                                payout_policy.eq(PayoutPolicy::Manual),
+                                auto_payout_error.eq(Some(error)),
+                            ))
+                            .execute(conn)?
+                    }
+                };
+                Ok(())
+            })
+            .await?;
+            res
+        }
+        .track_transition("auto_payout")
+    }
+}
+
+/// Goes through the same transitions as a manual payout, but the slate is
+/// signed by merchant's wallet instead of merchant
+async fn send_auto_payout(
+    fsm: Addr<FsmPayout>,
+    wallet: Arc<dyn WalletApi>,
+    merchant: Merchant,
+    amount: i64,
+) -> Result<PendingPayout, Error> {
+    let wallet_url = merchant
+        .wallet_url
+        .clone()
+        .ok_or(Error::General(s!("Merchant has no wallet url")))?;
+    let new_payout = fsm
+        .send(CreatePayout {
+            merchant_id: merchant.id.clone(),
+            amount,
+            confirmations: DEFAULT_PAYOUT_CONFIRMATIONS,
+        })
+        .await??;
+    let payout_id = new_payout.id;
+    let res = async {
+        let slate = wallet
+            .create_slate(new_payout.reminder()? as u64, new_payout.message.clone())
+            .await?;
+        let wallet_tx = wallet.get_tx(&slate.id.hyphenated().to_string()).await?;
+        let commit = slate.tx.output_commitments().first().cloned();
+        let initialized_payout = fsm
+            .send(InitializePayout {
+                new_payout,
+                wallet_tx,
+                commit,
+            })
+            .await??;
+        let signed_slate = send_to_merchant_wallet(&wallet_url, &slate).await?;
+        let finalized_slate = wallet.finalize(&signed_slate).await?;
+        wallet.post_tx(&finalized_slate.tx, true).await?;
+        Ok::<_, Error>((initialized_payout, finalized_slate))
+    }
+    .await;
+
+    let (initialized_payout, finalized_slate) = match res {
+        Ok(posted) => posted,
+        Err(e) => {
+            reject_unposted_payout(&fsm, &merchant.id, payout_id).await?;
+            return Err(e);
+        }
+    };
+
+    // The transaction is on the network now, so the payout must not be
+    // rejected whatever happens next
+    let mut attempt = 1;
+    loop {
+        let res = fsm
+            .send(FinalizePayout {
+                initialized_payout: initialized_payout.clone(),
+                kernel_excess: finalized_slate.tx.kernel_excess().map(ser::to_hex),
+                input_commits: finalized_slate
+                    .tx
+                    .input_commitments()
+                    .into_iter()
+                    .map(ser::to_hex)
+                    .collect(),
+            })
+            .await
+            .map_err(|e| Error::General(s!(e)))
+            .and_then(|res| res);
+        match res {
+            Ok(pending_payout) => return Ok(pending_payout),
+            Err(e) if attempt < MAX_FINALIZE_ATTEMPTS => {
+                warn!(
+                    "Cannot finalize posted payout {}, attempt {}: {}",
+                    payout_id, attempt, e
+                );
+                attempt += 1;
+            }
+            Err(e) => {
+                error!(
+                    "Payout {} is posted but stays initialized, check it manually: {}",
+                    payout_id, e
+                );
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Rejects the auto payout whose transaction didn't reach the network, the
+/// amount returns to the balance
+async fn reject_unposted_payout(
+    fsm: &Addr<FsmPayout>,
+    merchant_id: &str,
+    payout_id: Uuid,
+) -> Result<(), Error> {
+    let payout = fsm
+        .send(GetPayout {
+            merchant_id: merchant_id.to_owned(),
+            transaction_id: payout_id,
+        })
+        .await??;
+    match payout.status {
+        TransactionStatus::New => {
+            fsm.send(RejectPayout {
+                payout: NewPayout(payout),
+            })
+            .await??;
+        }
+        TransactionStatus::Initialized => {
+            fsm.send(RejectPayout {
+                payout: InitializedPayout(payout),
+            })
+            .await??;
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
 impl Handler<GetExpiredNewPayouts> for FsmPayout {
     type Result = ResponseFuture<Result<Vec<NewPayout>, Error>>;
 
     fn handle(&mut self, _: GetExpiredNewPayouts, _: &mut Self::Context) -> Self::Result {
         block::<_, _, Error>({
             let pool = self.pool.clone();
+            let now = self.clock.now();
             move || {
                 use crate::schema::transactions::dsl::*;
                 let conn: &PgConnection = &pool.get().unwrap();
                 transactions
                     .filter(status.eq(TransactionStatus::New))
                     .filter(transaction_type.eq(TransactionType::Payout))
-                    .filter(
-                        created_at
-                            .lt(Utc::now().naive_utc() - Duration::seconds(NEW_PAYOUT_TTL_SECONDS)),
-                    )
+                    .filter(created_at.lt(now - Duration::seconds(NEW_PAYOUT_TTL_SECONDS)))
                     .load::<Transaction>(conn)
                     .map_err(|e| e.into())
                     .map(|txs| txs.into_iter().map(NewPayout).collect())
@@ -433,17 +935,14 @@ impl Handler<GetExpiredInitializedPayouts> for FsmPayout {
     fn handle(&mut self, _: GetExpiredInitializedPayouts, _: &mut Self::Context) -> Self::Result {
         block::<_, _, Error>({
             let pool = self.pool.clone();
+            let now = self.clock.now();
             move || {
                 use crate::schema::transactions::dsl::*;
                 let conn: &PgConnection = &pool.get().unwrap();
                 transactions
                     .filter(status.eq(TransactionStatus::Initialized))
                     .filter(transaction_type.eq(TransactionType::Payout))
-                    .filter(
-                        created_at
-                            .lt(Utc::now().naive_utc()
-                                - Duration::seconds(PENDING_PAYOUT_TTL_SECONDS)),
-                    )
+                    .filter(created_at.lt(now - Duration::seconds(PENDING_PAYOUT_TTL_SECONDS)))
                     .load::<Transaction>(conn)
                     .map_err(|e| e.into())
                     .map(|txs| txs.into_iter().map(InitializedPayout).collect())
@@ -460,27 +959,31 @@ impl Handler<RejectPayout<NewPayout>> for FsmPayout {
     fn handle(&mut self, msg: RejectPayout<NewPayout>, _: &mut Self::Context) -> Self::Result {
         block::<_, _, Error>({
             let pool = self.pool.clone();
+            let now = self.clock.now();
             move || {
                 let conn: &PgConnection = &pool.get().unwrap();
                 let payout = msg.payout;
                 warn!("Reject payout {:?}", payout);
                 use crate::schema::transactions::dsl::*;
-                let tx = diesel::update(
-                    transactions
-                        .filter(id.eq(payout.id.clone()))
-                        .filter(status.eq(TransactionStatus::New)),
-                )
-                .set((
-                    status.eq(TransactionStatus::Rejected),
-                    updated_at.eq(Utc::now().naive_utc()),
-                ))
-                .get_result(conn)?;
-
-                Ok(RejectedPayout(tx))
+                conn.transaction(|| {
+                    let tx = diesel::update(
+                        transactions
+                            .filter(id.eq(payout.id.clone()))
+                            .filter(status.eq(TransactionStatus::New)),
+                    )
+                    .set((
+                        status.eq(TransactionStatus::Rejected),
+                        updated_at.eq(now),
+                        reported.eq(false),
+                    ))
+                    .get_result(conn)?;
+                    record_transaction(&tx, now, conn)?;
+                    Ok(RejectedPayout(tx))
+                })
             }
         })
         .map_err(|e| e.into())
-        .boxed()
+        .track_transition("reject_new_payout")
     }
 }
 
@@ -494,6 +997,7 @@ impl Handler<RejectPayout<InitializedPayout>> for FsmPayout {
     ) -> Self::Result {
         let wallet = self.wallet.clone();
         let pool = self.pool.clone();
+        let now = self.clock.now();
         async move {
             wallet
                 .cancel_tx(&msg.payout.wallet_tx_slate_id.clone().unwrap())
@@ -502,93 +1006,261 @@ impl Handler<RejectPayout<InitializedPayout>> for FsmPayout {
                 move || {
                     let conn: &PgConnection = &pool.get().unwrap();
                     use crate::schema::transactions::dsl::*;
-                    let tx = diesel::update(
-                        transactions
-                            .filter(id.eq(msg.payout.id.clone()))
-                            .filter(status.eq(TransactionStatus::Initialized)),
-                    )
-                    .set((
-                        status.eq(TransactionStatus::Rejected),
-                        updated_at.eq(Utc::now().naive_utc()),
-                    ))
-                    .get_result(conn)?;
-
-                    Ok(RejectedPayout(tx))
+                    conn.transaction(|| {
+                        let tx = diesel::update(
+                            transactions
+                                .filter(id.eq(msg.payout.id.clone()))
+                                .filter(status.eq(TransactionStatus::Initialized)),
+                        )
+                        .set((
+                            status.eq(TransactionStatus::Rejected),
+                            updated_at.eq(now),
+                            reported.eq(false),
+                        ))
+                        .get_result(conn)?;
+                        record_transaction(&tx, now, conn)?;
+                        Ok(RejectedPayout(tx))
+                    })
                 }
             })
             .await
             .map_err(|e| e.into())
         }
-        .boxed_local()
+        .track_transition("reject_initialized_payout")
+    }
+}
+
+impl Handler<CancelPayoutTx> for FsmPayout {
+    type Result = ResponseFuture<Result<PendingPayout, Error>>;
+
+    fn handle(&mut self, msg: CancelPayoutTx, _: &mut Self::Context) -> Self::Result {
+        let wallet = self.wallet.clone();
+        let pool = self.pool.clone();
+        let now = self.clock.now();
+        async move {
+            let slate_id = msg
+                .payout
+                .wallet_tx_slate_id
+                .clone()
+                .ok_or(Error::General(s!("Pending payout has no wallet tx")))?;
+            wallet.cancel_tx(&slate_id).await?;
+            block::<_, _, Error>(move || {
+                use crate::schema::transactions::dsl::*;
+                let conn: &PgConnection = &pool.get().unwrap();
+                diesel::update(
+                    transactions
+                        .filter(id.eq(msg.payout.id))
+                        .filter(status.eq(TransactionStatus::Pending)),
+                )
+                .set((wallet_tx_cancelled.eq(true), updated_at.eq(now)))
+                .get_result(conn)
+                .map(PendingPayout)
+                .map_err(|e| e.into())
+            })
+            .await
+            .map_err(|e| e.into())
+        }
+        .track_transition("cancel_pending_payout_tx")
     }
 }
 
 impl Handler<RejectPayout<PendingPayout>> for FsmPayout {
     type Result = ResponseFuture<Result<RejectedPayout, Error>>;
 
+    /// Only a payout whose wallet tx is cancelled can be rejected, otherwise
+    /// the wallet may still get it into the chain
     fn handle(&mut self, msg: RejectPayout<PendingPayout>, _: &mut Self::Context) -> Self::Result {
+        let pool = self.pool.clone();
+        let now = self.clock.now();
+        block::<_, _, Error>(move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            let payout = msg.payout;
+            warn!("Reject payout {:?}", payout);
+            use crate::schema::transactions::dsl::*;
+            conn.transaction(|| {
+                let tx = diesel::update(
+                    transactions
+                        .filter(id.eq(payout.id.clone()))
+                        .filter(status.eq(TransactionStatus::Pending))
+                        .filter(wallet_tx_cancelled.eq(true)),
+                )
+                .set((
+                    status.eq(TransactionStatus::Rejected),
+                    updated_at.eq(now),
+                    reported.eq(false),
+                ))
+                .get_result(conn)?;
+                record_transaction(&tx, now, conn)?;
+                Ok(RejectedPayout(tx))
+            })
+        })
+        .map_err(|e| e.into())
+        .track_transition("reject_pending_payout")
+    }
+}
+
+impl Handler<CancelPayout> for FsmPayout {
+    type Result = ResponseFuture<Result<RejectedPayout, Error>>;
+
+    fn handle(&mut self, msg: CancelPayout, _: &mut Self::Context) -> Self::Result {
         let wallet = self.wallet.clone();
         let pool = self.pool.clone();
+        let now = self.clock.now();
         async move {
-            wallet
-                .cancel_tx(&msg.payout.wallet_tx_slate_id.clone().unwrap())
-                .await;
-            block::<_, _, Error>({
+            let payout: Transaction = block::<_, _, Error>({
+                let pool = pool.clone();
                 move || {
-                    let conn: &PgConnection = &pool.get().unwrap();
-                    let payout = msg.payout;
-                    warn!("Reject payout {:?}", payout);
                     use crate::schema::transactions::dsl::*;
-                    let tx = diesel::update(
+                    let conn: &PgConnection = &pool.get().unwrap();
+                    transactions
+                        .filter(id.eq(msg.transaction_id))
+                        .filter(merchant_id.eq(msg.merchant_id))
+                        .filter(transaction_type.eq(TransactionType::Payout))
+                        .first(conn)
+                        .map_err(|e| e.into())
+                }
+            })
+            .await?;
+            match payout.status {
+                TransactionStatus::New => {}
+                TransactionStatus::Initialized => {
+                    // Unlock outputs now instead of waiting for the payout to expire.
+                    // If wallet can't do it payout stays as is, cron rejects it later
+                    if let Some(slate_id) = payout.wallet_tx_slate_id.as_ref() {
+                        wallet.cancel_tx(slate_id).await?;
+                    }
+                }
+                payout_status => {
+                    return Err(Error::InvalidEntity(format!(
+                        "payout in status {} can't be cancelled",
+                        payout_status
+                    )))
+                }
+            }
+            let tx = block::<_, _, Error>(move || {
+                use crate::schema::transactions::dsl::*;
+                let conn: &PgConnection = &pool.get().unwrap();
+                warn!("Cancel payout {:?}", payout);
+                conn.transaction(|| {
+                    diesel::update(
                         transactions
-                            .filter(id.eq(payout.id.clone()))
-                            .filter(status.eq(TransactionStatus::Pending)),
+                            .filter(id.eq(payout.id))
+                            .filter(status.eq(payout.status)),
                     )
                     .set((
                         status.eq(TransactionStatus::Rejected),
-                        updated_at.eq(Utc::now().naive_utc()),
+                        updated_at.eq(now),
+                        reported.eq(false),
                     ))
-                    .get_result(conn)?;
-
-                    Ok(RejectedPayout(tx))
-                }
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or(Error::InvalidEntity(s!("payout has changed its status")))
+                    .and_then(|tx| {
+                        record_transaction(&tx, now, conn)?;
+                        Ok(tx)
+                    })
+                })
             })
-            .map_err(|e| e.into())
-            .await
+            .await?;
+            Ok(RejectedPayout(tx))
         }
-        .boxed_local()
+        .track_transition("cancel_payout")
+    }
+}
+/// Part of the charged transfer fee which the wallet didn't spend. If the real
+/// fee is higher than the charged one we pay the difference
+fn fee_overcharge(payout: &Transaction) -> Option<i64> {
+    let real_fee = payout.real_transfer_fee?;
+    let charged_fee = payout.transfer_fee();
+    if real_fee > charged_fee {
+        warn!(
+            "Real transfer fee {} of payout {} is higher than charged {}",
+            real_fee, payout.id, charged_fee
+        );
     }
+    Some((charged_fee - real_fee).max(0))
+}
+
+/// Asks the wallet for the network fee of a payout, if the wallet can't tell
+/// we charge `TRANSFER_FEE`
+pub async fn quote_payout(
+    wallet: &dyn WalletApi,
+    fee_schedule: &FeeSchedule,
+    amount: i64,
+) -> PayoutQuote {
+    let send_amount = PayoutQuote::new(fee_schedule, amount, TRANSFER_FEE)
+        .reminder()
+        .unwrap_or(amount)
+        .max(0) as u64;
+    let transfer_fee = match wallet.estimate_fee(send_amount).await {
+        Ok(fee) => fee as i64,
+        Err(e) => {
+            warn!(
+                "Cannot estimate transfer fee, charge {}: {}",
+                TRANSFER_FEE, e
+            );
+            TRANSFER_FEE
+        }
+    };
+    PayoutQuote::new(fee_schedule, amount, transfer_fee)
 }
+
 pub trait PayoutFees {
     fn transfer_fee(&self) -> i64;
     fn knockturn_fee(&self) -> i64;
     fn reminder(&self) -> Result<i64, Error>;
 }
 
-impl PayoutFees for i64 {
+/// Fees of a payout by merchant's fee schedule with the network fee quoted
+/// by the wallet
+#[derive(Debug, Clone, Copy)]
+pub struct PayoutQuote {
+    pub amount: i64,
+    pub transfer_fee: i64,
+    pub knockturn_fee: i64,
+}
+
+impl PayoutQuote {
+    pub fn new(fee_schedule: &FeeSchedule, amount: i64, transfer_fee: i64) -> Self {
+        PayoutQuote {
+            amount,
+            transfer_fee,
+            knockturn_fee: fee_schedule.payout_fee(amount),
+        }
+    }
+}
+
+impl PayoutFees for PayoutQuote {
     fn transfer_fee(&self) -> i64 {
-        TRANSFER_FEE
+        self.transfer_fee
     }
     fn knockturn_fee(&self) -> i64 {
-        (*self as f64 * KNOCKTURN_SHARE) as i64
+        self.knockturn_fee
     }
     fn reminder(&self) -> Result<i64, Error> {
-        if *self < self.transfer_fee() + self.knockturn_fee() {
+        if self.amount < self.transfer_fee() + self.knockturn_fee() {
             Err(Error::General(s!("fees are higher than amount")))
         } else {
-            Ok(*self - self.transfer_fee() - self.knockturn_fee())
+            Ok(self.amount - self.transfer_fee() - self.knockturn_fee())
         }
     }
 }
 
+/// Fees charged when the payout was created
 impl PayoutFees for Transaction {
     fn transfer_fee(&self) -> i64 {
-        TRANSFER_FEE
+        self.transfer_fee.unwrap_or(TRANSFER_FEE)
     }
     fn knockturn_fee(&self) -> i64 {
-        self.grin_amount.knockturn_fee()
+        self.knockturn_fee
+            .unwrap_or_else(|| FeeSchedule::default().payout_fee(self.grin_amount))
     }
     fn reminder(&self) -> Result<i64, Error> {
-        self.grin_amount.reminder()
+        PayoutQuote {
+            amount: self.grin_amount,
+            transfer_fee: self.transfer_fee(),
+            knockturn_fee: self.knockturn_fee(),
+        }
+        .reminder()
     }
 }
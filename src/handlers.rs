@@ -10,10 +10,15 @@ use askama::Template;
 use diesel::pg::PgConnection;
 use mime_guess::get_mime_type;
 
+pub mod admin;
+pub mod health;
+pub mod metrics;
 pub mod mfa;
 pub mod paginator;
 pub mod payment;
 pub mod payout;
+pub mod payout_api;
+pub mod statement;
 pub mod transaction;
 pub mod webui;
 
@@ -24,9 +29,10 @@ pub async fn create_merchant(
     let create_merchant = create_merchant.into_inner();
     let merchant = block::<_, _, Error>({
         let pool = state.pool.clone();
+        let now = state.clock.now();
         move || {
             let conn: &PgConnection = &pool.get().unwrap();
-            let merchant = db::create_merchant(create_merchant, conn)?;
+            let merchant = db::create_merchant(create_merchant, now, conn)?;
             Ok(merchant)
         }
     })
@@ -0,0 +1,343 @@
+use crate::app::AppState;
+use crate::errors::*;
+use crate::extractor::{BasicAuth, Operator, SimpleJson};
+use crate::fees::{self, FeeSchedule};
+use crate::fsm_payout::{
+    CancelPayout, CreatePayout, GetInitializedPayout, GetNewPayout, DEFAULT_PAYOUT_CONFIRMATIONS,
+};
+use crate::handlers::payout::{finalize_payout, initialize_payout};
+use crate::ledger;
+use crate::models::{Transaction, TransactionType};
+use crate::rescan::{apply_changes, find_changes, RescanChange};
+use crate::revenue::{get_revenue, reconcile, OPERATOR_ACCOUNT};
+use crate::wallet::Slate;
+use actix_web::web::{block, Data, Path, Query};
+use actix_web::HttpResponse;
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::{self, prelude::*};
+use log::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RescanRequest {
+    pub from_height: i64,
+    pub to_height: i64,
+    /// Without it we only report what would change
+    #[serde(default)]
+    pub apply: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RescanResponse {
+    pub from_height: i64,
+    pub to_height: i64,
+    pub changes: Vec<RescanChange>,
+}
+
+pub async fn rescan(
+    _: BasicAuth<Operator>,
+    rescan_req: SimpleJson<RescanRequest>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let rescan_req = rescan_req.into_inner();
+    info!(
+        "Operator requested rescan of blocks {}-{}, apply: {}",
+        rescan_req.from_height, rescan_req.to_height, rescan_req.apply
+    );
+    let mut changes = find_changes(
+        state.node.as_ref(),
+        &state.pool,
+        rescan_req.from_height,
+        rescan_req.to_height,
+    )
+    .await?;
+    if rescan_req.apply {
+        apply_changes(&state.fsm, &state.fsm_payout, &state.pool, &mut changes).await;
+    }
+    Ok(HttpResponse::Ok().json(RescanResponse {
+        from_height: rescan_req.from_height,
+        to_height: rescan_req.to_height,
+        changes,
+    }))
+}
+
+pub async fn get_jobs(
+    _: BasicAuth<Operator>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(state.jobs.statuses(state.clock.now())))
+}
+
+pub async fn pause_job(
+    _: BasicAuth<Operator>,
+    job: Path<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    info!("Operator paused job {}", job);
+    let status = block::<_, _, Error>({
+        let pool = state.pool.clone();
+        let jobs = state.jobs.clone();
+        let now = state.clock.now();
+        move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            jobs.pause(&job, now, conn)
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+pub async fn resume_job(
+    _: BasicAuth<Operator>,
+    job: Path<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    info!("Operator resumed job {}", job);
+    let status = block::<_, _, Error>({
+        let pool = state.pool.clone();
+        let jobs = state.jobs.clone();
+        move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            jobs.resume(&job, conn)
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+pub async fn get_fee_schedule(
+    _: BasicAuth<Operator>,
+    merchant_id: Path<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let schedule = block::<_, _, Error>({
+        let pool = state.pool.clone();
+        move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            fees::get_fee_schedule(&merchant_id, conn)
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(schedule))
+}
+
+pub async fn set_fee_schedule(
+    _: BasicAuth<Operator>,
+    merchant_id: Path<String>,
+    schedule: SimpleJson<FeeSchedule>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let schedule = schedule.into_inner();
+    info!(
+        "Operator set fee schedule of merchant {}: {:?}",
+        merchant_id, schedule
+    );
+    let schedule = block::<_, _, Error>({
+        let pool = state.pool.clone();
+        let now = state.clock.now();
+        move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            fees::set_fee_schedule(&merchant_id, &schedule, now, conn)?;
+            Ok(schedule)
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(schedule))
+}
+
+pub async fn get_merchant_balances(
+    _: BasicAuth<Operator>,
+    merchant_id: Path<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let balances = block::<_, _, Error>({
+        let pool = state.pool.clone();
+        move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            ledger::get_balances(&merchant_id, conn)
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(balances))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdjustmentRequest {
+    /// Negative amount takes money from merchant
+    pub amount: i64,
+    pub note: String,
+}
+
+pub async fn adjust_merchant_balance(
+    _: BasicAuth<Operator>,
+    merchant_id: Path<String>,
+    adjustment: SimpleJson<AdjustmentRequest>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let adjustment = adjustment.into_inner();
+    if adjustment.amount == 0 || adjustment.note.trim().is_empty() {
+        return Err(Error::InvalidEntity(s!(
+            "adjustment needs an amount and a note"
+        )));
+    }
+    info!(
+        "Operator adjusted balance of merchant {} by {}: {}",
+        merchant_id, adjustment.amount, adjustment.note
+    );
+    let balances = block::<_, _, Error>({
+        let pool = state.pool.clone();
+        let now = state.clock.now();
+        move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            ledger::adjust_balance(&merchant_id, adjustment.amount, &adjustment.note, now, conn)?;
+            ledger::get_balances(&merchant_id, conn)
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(balances))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevenueQuery {
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub merchant_id: Option<String>,
+}
+
+/// Fees earned in the period, by merchant
+pub async fn get_revenue_report(
+    _: BasicAuth<Operator>,
+    query: Query<RevenueQuery>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let report = block::<_, _, Error>({
+        let pool = state.pool.clone();
+        let query = query.into_inner();
+        move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            get_revenue(query.from, query.to, query.merchant_id.as_deref(), conn)
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+pub async fn get_balances(
+    _: BasicAuth<Operator>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let wallet_info = state.wallet.retrieve_summary_info(1).await?;
+    let reconciliation = block::<_, _, Error>({
+        let pool = state.pool.clone();
+        move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            reconcile(wallet_info.total as i64, conn)
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(reconciliation))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OperatorPayoutRequest {
+    pub amount: i64,
+    pub confirmations: Option<i64>,
+}
+
+/// Withdraws revenue from the operator account, the rest of the flow is the
+/// same as for merchants' payouts
+pub async fn create_operator_payout(
+    _: BasicAuth<Operator>,
+    payout_req: SimpleJson<OperatorPayoutRequest>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let payout_req = payout_req.into_inner();
+    let confirmations = payout_req
+        .confirmations
+        .unwrap_or(DEFAULT_PAYOUT_CONFIRMATIONS);
+    if confirmations < 1 {
+        return Err(Error::InvalidEntity(s!("confirmations must be positive")));
+    }
+    info!("Operator requested payout of {}", payout_req.amount);
+    let new_payout = state
+        .fsm_payout
+        .send(CreatePayout {
+            merchant_id: s!(OPERATOR_ACCOUNT),
+            amount: payout_req.amount,
+            confirmations,
+        })
+        .await??;
+    Ok(HttpResponse::Created().json(&*new_payout))
+}
+
+pub async fn get_operator_payouts(
+    _: BasicAuth<Operator>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let payouts = block::<_, _, Error>({
+        let pool = state.pool.clone();
+        move || {
+            use crate::schema::transactions::dsl::*;
+            let conn: &PgConnection = &pool.get().unwrap();
+            transactions
+                .filter(merchant_id.eq(OPERATOR_ACCOUNT))
+                .filter(transaction_type.eq(TransactionType::Payout))
+                .order(created_at.desc())
+                .load::<Transaction>(conn)
+                .map_err(|e| e.into())
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(payouts))
+}
+
+pub async fn get_operator_payout_slate(
+    _: BasicAuth<Operator>,
+    transaction_id: Path<Uuid>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let new_payout = state
+        .fsm_payout
+        .send(GetNewPayout {
+            merchant_id: s!(OPERATOR_ACCOUNT),
+            transaction_id: transaction_id.into_inner(),
+        })
+        .await??;
+    let slate = initialize_payout(&state, new_payout).await?;
+    Ok(HttpResponse::Ok().json(slate))
+}
+
+pub async fn accept_operator_payout_slate(
+    _: BasicAuth<Operator>,
+    transaction_id: Path<Uuid>,
+    slate: SimpleJson<Slate>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let transaction_id = transaction_id.into_inner();
+    let initialized_payout = state
+        .fsm_payout
+        .send(GetInitializedPayout { transaction_id })
+        .await??;
+    if initialized_payout.merchant_id != OPERATOR_ACCOUNT {
+        return Err(Error::EntityNotFound(format!("payout {}", transaction_id)));
+    }
+    let finalized_slate = finalize_payout(&state, initialized_payout, &slate).await?;
+    Ok(HttpResponse::Ok().json(finalized_slate))
+}
+
+pub async fn cancel_operator_payout(
+    _: BasicAuth<Operator>,
+    transaction_id: Path<Uuid>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let cancelled = state
+        .fsm_payout
+        .send(CancelPayout {
+            merchant_id: s!(OPERATOR_ACCOUNT),
+            transaction_id: transaction_id.into_inner(),
+        })
+        .await??;
+    Ok(HttpResponse::Ok().json(&*cancelled))
+}
@@ -0,0 +1,30 @@
+use crate::app::AppState;
+use crate::errors::*;
+use crate::health::{liveness, readiness, HealthReport};
+use actix_web::web::Data;
+use actix_web::HttpResponse;
+
+fn respond(report: HealthReport) -> HttpResponse {
+    if report.ok {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+pub async fn health(state: Data<AppState>) -> Result<HttpResponse, Error> {
+    let report = liveness(&state.pool, &state.jobs, state.clock.now()).await;
+    Ok(respond(report))
+}
+
+pub async fn ready(state: Data<AppState>) -> Result<HttpResponse, Error> {
+    let report = readiness(
+        &state.pool,
+        &state.jobs,
+        state.wallet.as_ref(),
+        state.node.as_ref(),
+        state.clock.now(),
+    )
+    .await;
+    Ok(respond(report))
+}
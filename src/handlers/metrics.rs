@@ -0,0 +1,13 @@
+use crate::app::AppState;
+use crate::errors::*;
+use crate::extractor::{BasicAuth, Operator};
+use crate::metrics::gather;
+use actix_web::web::Data;
+use actix_web::HttpResponse;
+
+pub async fn metrics(_: BasicAuth<Operator>, state: Data<AppState>) -> Result<HttpResponse, Error> {
+    let body = gather(&state.pool).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
@@ -1,5 +1,5 @@
 use crate::app::AppState;
-use crate::db::{get_current_height, get_transaction};
+use crate::db::{get_current_height, get_sync_status, get_transaction};
 use crate::errors::*;
 use crate::extractor::{BasicAuth, SimpleJson};
 use crate::filters::{self, ForHuman};
@@ -62,6 +62,10 @@ struct PaymentStatus {
     pub expired_in: Option<String>,
     pub current_confirmations: i64,
     pub required_confirmations: i64,
+    /// How many blocks we are behind the node, confirmations are counted with a delay
+    pub sync_lag: i64,
+    /// Pending transaction is in node's pool and waits for a block
+    pub in_mempool: bool,
 }
 
 pub async fn get_payment_status(
@@ -71,9 +75,10 @@ pub async fn get_payment_status(
     let payment_status = block::<_, _, Error>({
         let pool = state.pool.clone();
         let (merchant_id, transaction_id) = transaction_data.into_inner();
+        let now = state.clock.now();
         move || {
             let conn: &PgConnection = &pool.get().unwrap();
-            let current_height = get_current_height(conn)?;
+            let sync_status = get_sync_status(conn)?;
             let tx = get_transaction(transaction_id, conn)?;
             if tx.merchant_id != merchant_id {
                 return Err(Error::General(format!("Wrong merchant: {}", merchant_id)));
@@ -81,12 +86,14 @@ pub async fn get_payment_status(
             let payment_status = PaymentStatus {
                 transaction_id: tx.id.to_string(),
                 status: tx.status.to_string(),
-                seconds_until_expired: tx.time_until_expired().map(|d| d.num_seconds()),
+                seconds_until_expired: tx.time_until_expired_at(now).map(|d| d.num_seconds()),
 
-                expired_in: tx.time_until_expired().map(|d| d.for_human()),
-                current_confirmations: tx.current_confirmations(current_height),
+                expired_in: tx.time_until_expired_at(now).map(|d| d.for_human()),
+                current_confirmations: tx.current_confirmations(sync_status.height),
                 required_confirmations: tx.confirmations,
                 reported: tx.reported,
+                sync_lag: sync_status.lag(),
+                in_mempool: tx.is_in_mempool(),
             };
             Ok(payment_status)
         }
@@ -102,6 +109,7 @@ pub async fn get_payment(
     let html = block::<_, _, Error>({
         let pool = state.pool.clone();
         let (merchant_id, transaction_id) = transaction_data.into_inner();
+        let now = state.clock.now();
         move || {
             let conn: &PgConnection = &pool.get().unwrap();
             let current_height = get_current_height(conn)?;
@@ -123,6 +131,9 @@ pub async fn get_payment(
             );
             let html = PaymentTemplate {
                 payment: &transaction,
+                expired_in: transaction
+                    .time_until_expired_at(now)
+                    .map(|d| d.for_human()),
                 payment_url: payment_url,
                 current_height: current_height,
                 ironbelly_link: &ironbelly_link,
@@ -141,6 +152,7 @@ pub async fn get_payment(
 #[template(path = "payment.html")]
 struct PaymentTemplate<'a> {
     payment: &'a Transaction,
+    expired_in: Option<String>,
     payment_url: String,
     current_height: i64,
     ironbelly_link: &'a str,
@@ -158,11 +170,17 @@ pub async fn wallet_jsonrpc(
 
     let res: Result<jsonrpc::Response, jsonrpc::ErrorData> = match req.method.as_ref() {
         "receive_tx" => {
-            let params = req.params.clone();
-            if params.len() != 3 {
+            let slate = match &req.params {
+                serde_json::Value::Array(params) if params.len() == 3 => params[0].clone(),
+                serde_json::Value::Object(params) if params.contains_key("slate") => {
+                    params["slate"].clone()
+                }
+                _ => serde_json::Value::Null,
+            };
+            if slate.is_null() {
                 Err(jsonrpc::ErrorData::std(-32602))
             } else {
-                match serde_json::from_value(params[0].clone()) {
+                match serde_json::from_value(slate) {
                     Ok(slate) => pay_slate2(req, slate, merchant_id, payment_id, state)
                         .await
                         .map_err(|e| {
@@ -180,7 +198,7 @@ pub async fn wallet_jsonrpc(
                 }
             }
         }
-        _ => state.wallet.jsonrpc_request(req, false).await.map_err(|e| {
+        _ => state.wallet.foreign_request(req).await.map_err(|e| {
             error!("Error while proxying request {}", e);
             jsonrpc::ErrorData {
                 code: 32000,
@@ -241,7 +259,7 @@ pub async fn pay_slate2(
     let wallet = state.wallet.clone();
     let fsm = state.fsm.clone();
 
-    let resp = wallet.jsonrpc_request(req, false).await?;
+    let resp = wallet.foreign_request(req).await?;
     let commit = slate.tx.output_commitments()[0].clone();
     let wallet_tx = wallet.get_tx(&slate.id.hyphenated().to_string()).await?;
     fsm.send(MakePayment {
@@ -1,27 +1,38 @@
 use crate::app::AppState;
-use crate::db::get_balance;
+use crate::db::{get_balance, new_payout_key};
 use crate::errors::*;
 use crate::extractor::{SimpleJson, User};
+use crate::fees::{applied_fee_schedule, MINIMAL_WITHDRAW, TRANSFER_FEE};
 use crate::filters::{self, ForHuman};
-use crate::fsm::MINIMAL_WITHDRAW;
 use crate::fsm_payout::{
-    CreatePayout, FinalizePayout, GetInitializedPayout, GetNewPayout, GetPayout, InitializePayout,
-    PayoutFees,
+    quote_payout, CancelPayout, CreatePayout, FinalizePayout, GetInitializedPayout, GetNewPayout,
+    GetPayout, InitializePayout, InitializedPayout, NewPayout, PayoutFees, PayoutQuote,
+    DEFAULT_PAYOUT_CONFIRMATIONS,
 };
 use crate::handlers::check_2fa_code;
 use crate::handlers::BootstrapColor;
-use crate::models::{Merchant, Money, Transaction, TransactionStatus};
+use crate::models::{Merchant, Money, PayoutPolicy, Transaction, TransactionStatus};
+use crate::ser;
 use crate::wallet::Slate;
 use actix_identity::Identity;
 use actix_web::web::{block, Data, Form, Path};
 use actix_web::{HttpRequest, HttpResponse};
 use diesel::pg::PgConnection;
+use diesel::{self, prelude::*};
 
 use askama::Template;
 use futures::future::{ok, Either, Future};
 use serde::Deserialize;
 use uuid::Uuid;
 
+const PAYOUT_POLICIES: [PayoutPolicy; 5] = [
+    PayoutPolicy::Manual,
+    PayoutPolicy::Immediate,
+    PayoutPolicy::Daily,
+    PayoutPolicy::Weekly,
+    PayoutPolicy::Threshold,
+];
+
 #[derive(Template, Debug)]
 #[template(path = "withdraw.html")]
 struct WithdrawTemplate<'a> {
@@ -31,40 +42,77 @@ struct WithdrawTemplate<'a> {
     transfer_fee: Money,
     total: Money,
     url: &'a str,
+    merchant: &'a Merchant,
+    policies: Vec<PolicyOption>,
+    /// Shown only once, right after it's generated
+    payout_key: Option<String>,
+}
+
+#[derive(Debug)]
+struct PolicyOption {
+    policy: PayoutPolicy,
+    selected: bool,
 }
 
 pub async fn withdraw(
     merchant: User<Merchant>,
     data: Data<AppState>,
 ) -> Result<HttpResponse, Error> {
-    block::<_, _, Error>({
+    withdraw_page(merchant, data, None).await
+}
+
+async fn withdraw_page(
+    merchant: User<Merchant>,
+    data: Data<AppState>,
+    payout_key: Option<String>,
+) -> Result<HttpResponse, Error> {
+    let (balance, fee_schedule) = block::<_, _, Error>({
         let pool = data.pool.clone();
+        let merchant_id = merchant.id.clone();
+        let now = data.clock.now();
         move || {
             let conn: &PgConnection = &pool.get().unwrap();
-            let balance = get_balance(&merchant.id, conn)?;
-            let reminder = balance.reminder().unwrap_or(0);
-            let mut template = WithdrawTemplate {
-                error: None,
-                balance: balance.into(),
-                transfer_fee: balance.transfer_fee().into(),
-                knockturn_fee: balance.knockturn_fee().into(),
-                total: reminder.into(),
-                url: "http://localhost:6000/withdraw/confirm",
-            };
-
-            if balance < MINIMAL_WITHDRAW {
-                template.error = Some(format!(
-                    "You balance is too small. Minimal withdraw amount is {}",
-                    Money::from_grin(MINIMAL_WITHDRAW)
-                ));
-            }
-
-            template.render().map_err(|e| Error::from(e))
+            Ok((
+                get_balance(&merchant_id, conn)?,
+                applied_fee_schedule(&merchant_id, now, conn)?,
+            ))
         }
     })
-    .await
-    .map_err(|e| e.into())
-    .and_then(|html| Ok(HttpResponse::Ok().content_type("text/html").body(html)))
+    .await?;
+    // there is nothing to quote if merchant can't withdraw anyway
+    let quote = if balance < MINIMAL_WITHDRAW {
+        PayoutQuote::new(&fee_schedule, balance, TRANSFER_FEE)
+    } else {
+        quote_payout(&*data.wallet, &fee_schedule, balance).await
+    };
+    let reminder = quote.reminder().unwrap_or(0);
+    let mut template = WithdrawTemplate {
+        error: None,
+        balance: balance.into(),
+        transfer_fee: quote.transfer_fee().into(),
+        knockturn_fee: quote.knockturn_fee().into(),
+        total: reminder.into(),
+        url: "http://localhost:6000/withdraw/confirm",
+        merchant: &merchant.0,
+        policies: PAYOUT_POLICIES
+            .iter()
+            .map(|policy| PolicyOption {
+                policy: *policy,
+                selected: *policy == merchant.0.payout_policy,
+            })
+            .collect(),
+        payout_key,
+    };
+
+    if balance < MINIMAL_WITHDRAW {
+        template.error = Some(format!(
+            "You balance is too small. Minimal withdraw amount is {}",
+            Money::from_grin(MINIMAL_WITHDRAW)
+        ));
+    }
+
+    let html = template.render().map_err(|e| Error::from(e))?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
 }
 
 #[derive(Debug, Deserialize)]
@@ -90,7 +138,7 @@ pub async fn create_payout(
             .send(CreatePayout {
                 amount: form.amount,
                 merchant_id: merchant.id,
-                confirmations: 10,
+                confirmations: DEFAULT_PAYOUT_CONFIRMATIONS,
             })
             .await?;
 
@@ -101,10 +149,107 @@ pub async fn create_payout(
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct PayoutPolicyForm {
+    pub policy: PayoutPolicy,
+    pub wallet_url: String,
+    pub threshold: String,
+    pub code: String,
+}
+
+pub async fn set_payout_policy(
+    data: Data<AppState>,
+    form: Form<PayoutPolicyForm>,
+    identity_merchant: User<Merchant>,
+) -> Result<HttpResponse, Error> {
+    let merchant = identity_merchant.clone().into_inner();
+    if !check_2fa_code(&merchant, &form.code)? {
+        return withdraw(identity_merchant, data).await;
+    }
+    let form = form.into_inner();
+    let new_wallet_url = match form.wallet_url.trim() {
+        "" => None,
+        url if url.starts_with("http://") || url.starts_with("https://") => Some(url.to_owned()),
+        url => return Err(Error::InvalidEntity(format!("wrong wallet url {}", url))),
+    };
+    if form.policy != PayoutPolicy::Manual && new_wallet_url.is_none() {
+        return Err(Error::InvalidEntity(s!(
+            "automatic payouts need a listening wallet"
+        )));
+    }
+    let threshold = match form.threshold.trim() {
+        "" => None,
+        threshold => Some(
+            threshold
+                .parse::<i64>()
+                .map_err(|e| Error::InvalidEntity(format!("wrong threshold: {}", e)))?,
+        ),
+    };
+    let new_threshold = match (form.policy, threshold) {
+        (PayoutPolicy::Threshold, Some(threshold)) if threshold >= MINIMAL_WITHDRAW => {
+            Some(threshold)
+        }
+        (PayoutPolicy::Threshold, _) => {
+            return Err(Error::InvalidEntity(format!(
+                "threshold must be at least {}",
+                Money::from_grin(MINIMAL_WITHDRAW)
+            )))
+        }
+        (_, threshold) => threshold,
+    };
+    block::<_, _, Error>({
+        let pool = data.pool.clone();
+        move || {
+            use crate::schema::merchants::dsl::*;
+            let conn: &PgConnection = &pool.get().unwrap();
+            diesel::update(merchants.filter(id.eq(merchant.id)))
+                .set((
+                    payout_policy.eq(form.policy),
+                    wallet_url.eq(new_wallet_url),
+                    payout_threshold.eq(new_threshold),
+                    auto_payout_error.eq(None::<String>),
+                ))
+                .execute(conn)?;
+            Ok(())
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Found()
+        .header("location", "/withdraw")
+        .finish())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PayoutKeyForm {
+    pub code: String,
+}
+
+/// Generates a key which authorizes payouts through the API without 2fa code
+pub async fn generate_payout_key(
+    data: Data<AppState>,
+    form: Form<PayoutKeyForm>,
+    identity_merchant: User<Merchant>,
+) -> Result<HttpResponse, Error> {
+    if !check_2fa_code(&identity_merchant, &form.code)? {
+        return withdraw(identity_merchant, data).await;
+    }
+    let payout_key = block::<_, _, Error>({
+        let pool = data.pool.clone();
+        let merchant_id = identity_merchant.id.clone();
+        move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            new_payout_key(&merchant_id, conn)
+        }
+    })
+    .await?;
+    withdraw_page(identity_merchant, data, Some(payout_key)).await
+}
+
 #[derive(Template)]
 #[template(path = "payout.html")]
 struct PayoutTemplate<'a> {
     payout: &'a Transaction,
+    expired_in: Option<String>,
 }
 
 pub async fn get_payout(
@@ -129,12 +274,32 @@ pub async fn get_payout(
         .ok_or(Error::General(s!("Transaction doesn't have transfer_fee")))?;
     let html = PayoutTemplate {
         payout: &transaction,
+        expired_in: transaction
+            .time_until_expired_at(state.clock.now())
+            .map(|d| d.for_human()),
     }
     .render()
     .map_err(|e| Error::from(e))?;
     Ok(HttpResponse::Ok().content_type("text/html").body(html))
 }
 
+pub async fn cancel_payout(
+    transaction_id: Path<Uuid>,
+    state: Data<AppState>,
+    merchant: User<Merchant>,
+) -> Result<HttpResponse, Error> {
+    let cancelled = state
+        .fsm_payout
+        .send(CancelPayout {
+            merchant_id: merchant.id.clone(),
+            transaction_id: transaction_id.into_inner(),
+        })
+        .await??;
+    Ok(HttpResponse::Found()
+        .header("location", format!("/payouts/{}", cancelled.id))
+        .finish())
+}
+
 pub async fn generate_slate(
     transaction_id: Path<Uuid>,
     state: Data<AppState>,
@@ -153,23 +318,32 @@ pub async fn generate_slate(
         })
         .await??;
 
-    let real_payment = new_payout.grin_amount
-        - new_payout.transfer_fee.unwrap()
-        - new_payout.knockturn_fee.unwrap();
+    let slate = initialize_payout(&state, new_payout).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .json(slate))
+}
+
+/// Creates transaction in wallet, returns slate which merchant has to sign
+pub async fn initialize_payout(state: &AppState, new_payout: NewPayout) -> Result<Slate, Error> {
+    if new_payout.transfer_fee.is_none() || new_payout.knockturn_fee.is_none() {
+        return Err(Error::InvalidEntity(s!("payout has no fees")));
+    }
+    let real_payment = new_payout.reminder()?;
 
-    let resp = state
+    let slate = state
         .wallet
         .create_slate(real_payment as u64, new_payout.message.clone())
         .await?;
 
-    let slate = resp.into_result()?;
-
     let wallet_tx = state
         .wallet
         .get_tx(&slate.id.hyphenated().to_string())
         .await?;
 
-    let commit = slate.tx.output_commitments()[0].clone();
+    // it may be our change output, payouts are detected by the kernel
+    let commit = slate.tx.output_commitments().first().cloned();
 
     state
         .fsm_payout
@@ -178,11 +352,8 @@ pub async fn generate_slate(
             wallet_tx,
             commit,
         })
-        .await?;
-
-    Ok(HttpResponse::Ok()
-        .content_type("application/octet-stream")
-        .json(resp.into_inner()))
+        .await??;
+    Ok(slate)
 }
 
 pub async fn accept_slate(
@@ -196,14 +367,33 @@ pub async fn accept_slate(
             transaction_id: tx_id.clone(),
         })
         .await??;
-    let finalized_slate = state.wallet.finalize(&slate).await?;
-    state.wallet.post_tx().await?;
+    let finalized_slate = finalize_payout(&state, initialized_payout, &slate).await?;
+    Ok(HttpResponse::Ok().json(finalized_slate))
+}
+
+/// Finalizes slate signed by merchant and posts transaction to the chain
+pub async fn finalize_payout(
+    state: &AppState,
+    initialized_payout: InitializedPayout,
+    slate: &Slate,
+) -> Result<Slate, Error> {
+    let finalized_slate = state.wallet.finalize(slate).await?;
+    state.wallet.post_tx(&finalized_slate.tx, true).await?;
 
     state
         .fsm_payout
-        .send(FinalizePayout { initialized_payout })
+        .send(FinalizePayout {
+            initialized_payout,
+            kernel_excess: finalized_slate.tx.kernel_excess().map(ser::to_hex),
+            input_commits: finalized_slate
+                .tx
+                .input_commitments()
+                .into_iter()
+                .map(ser::to_hex)
+                .collect(),
+        })
         .await??;
-    Ok(HttpResponse::Ok().json(finalized_slate))
+    Ok(finalized_slate)
 }
 
 pub async fn withdraw_confirmation(_req: HttpRequest) -> Result<HttpResponse, Error> {
@@ -0,0 +1,165 @@
+//! API for merchants who automate their payouts. Endpoints accept the API
+//! token or the payout key, requesting a payout needs either the payout key
+//! or a 2fa code.
+use crate::app::AppState;
+use crate::errors::*;
+use crate::extractor::{BasicAuth, PayoutAuth, SimpleJson};
+use crate::fsm_payout::{
+    CancelPayout, CreatePayout, GetInitializedPayout, GetNewPayout, GetPayout,
+    DEFAULT_PAYOUT_CONFIRMATIONS,
+};
+use crate::handlers::check_2fa_code;
+use crate::handlers::payout::{finalize_payout, initialize_payout};
+use crate::models::{Transaction, TransactionType};
+use crate::wallet::Slate;
+use actix_web::web::{block, Data, Path};
+use actix_web::HttpResponse;
+use diesel::pg::PgConnection;
+use diesel::{self, prelude::*};
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub struct CreatePayoutRequest {
+    pub amount: i64,
+    pub confirmations: Option<i64>,
+    /// Not needed if the request is authorized with the payout key
+    pub code: Option<String>,
+}
+
+fn check_merchant(auth: &PayoutAuth, merchant_id: &str) -> Result<(), Error> {
+    if auth.merchant.id != merchant_id {
+        return Err(Error::InvalidEntity(s!("wrong merchant_id")));
+    }
+    Ok(())
+}
+
+pub async fn create_payout(
+    auth: BasicAuth<PayoutAuth>,
+    merchant_id: Path<String>,
+    payout_req: SimpleJson<CreatePayoutRequest>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    check_merchant(&auth, &merchant_id)?;
+    let payout_req = payout_req.into_inner();
+    if !auth.with_payout_key {
+        let code = payout_req.code.as_ref().ok_or(Error::NotAuthorized)?;
+        if !check_2fa_code(&auth.merchant, code)? {
+            return Err(Error::NotAuthorized);
+        }
+    }
+    let confirmations = payout_req
+        .confirmations
+        .unwrap_or(DEFAULT_PAYOUT_CONFIRMATIONS);
+    if confirmations < 1 {
+        return Err(Error::InvalidEntity(s!("confirmations must be positive")));
+    }
+    let new_payout = state
+        .fsm_payout
+        .send(CreatePayout {
+            merchant_id: auth.merchant.id.clone(),
+            amount: payout_req.amount,
+            confirmations,
+        })
+        .await??;
+    Ok(HttpResponse::Created().json(&*new_payout))
+}
+
+pub async fn get_payouts(
+    auth: BasicAuth<PayoutAuth>,
+    path: Path<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    check_merchant(&auth, &path)?;
+    let payouts = block::<_, _, Error>({
+        let pool = state.pool.clone();
+        let merch_id = auth.merchant.id.clone();
+        move || {
+            use crate::schema::transactions::dsl::*;
+            let conn: &PgConnection = &pool.get().unwrap();
+            transactions
+                .filter(merchant_id.eq(merch_id))
+                .filter(transaction_type.eq(TransactionType::Payout))
+                .order(created_at.desc())
+                .load::<Transaction>(conn)
+                .map_err(|e| e.into())
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(payouts))
+}
+
+pub async fn get_payout(
+    auth: BasicAuth<PayoutAuth>,
+    path: Path<(String, Uuid)>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let (merchant_id, transaction_id) = path.into_inner();
+    check_merchant(&auth, &merchant_id)?;
+    let payout = state
+        .fsm_payout
+        .send(GetPayout {
+            merchant_id,
+            transaction_id,
+        })
+        .await??;
+    Ok(HttpResponse::Ok().json(payout))
+}
+
+/// Creates transaction in our wallet, returns the slate which merchant's
+/// wallet has to sign
+pub async fn get_slate(
+    auth: BasicAuth<PayoutAuth>,
+    path: Path<(String, Uuid)>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let (merchant_id, transaction_id) = path.into_inner();
+    check_merchant(&auth, &merchant_id)?;
+    let new_payout = state
+        .fsm_payout
+        .send(GetNewPayout {
+            merchant_id,
+            transaction_id,
+        })
+        .await??;
+    let slate = initialize_payout(&state, new_payout).await?;
+    Ok(HttpResponse::Ok().json(slate))
+}
+
+/// Accepts the slate signed by merchant's wallet and posts the transaction
+pub async fn accept_slate(
+    auth: BasicAuth<PayoutAuth>,
+    path: Path<(String, Uuid)>,
+    slate: SimpleJson<Slate>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let (merchant_id, transaction_id) = path.into_inner();
+    check_merchant(&auth, &merchant_id)?;
+    let initialized_payout = state
+        .fsm_payout
+        .send(GetInitializedPayout { transaction_id })
+        .await??;
+    if initialized_payout.merchant_id != merchant_id {
+        return Err(Error::EntityNotFound(format!("payout {}", transaction_id)));
+    }
+    let finalized_slate = finalize_payout(&state, initialized_payout, &slate).await?;
+    Ok(HttpResponse::Ok().json(finalized_slate))
+}
+
+/// Payout can be cancelled until the transaction is posted
+pub async fn cancel_payout(
+    auth: BasicAuth<PayoutAuth>,
+    path: Path<(String, Uuid)>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let (merchant_id, transaction_id) = path.into_inner();
+    check_merchant(&auth, &merchant_id)?;
+    let cancelled = state
+        .fsm_payout
+        .send(CancelPayout {
+            merchant_id,
+            transaction_id,
+        })
+        .await??;
+    Ok(HttpResponse::Ok().json(&*cancelled))
+}
@@ -0,0 +1,92 @@
+use crate::app::AppState;
+use crate::errors::*;
+use crate::extractor::User;
+use crate::filters;
+use crate::models::Merchant;
+use crate::statement::{get_statement, Statement};
+use actix_web::web::{block, Data, Query};
+use actix_web::HttpResponse;
+use askama::Template;
+use chrono::{Datelike, Duration, NaiveDate};
+use diesel::pg::PgConnection;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementFormat {
+    Html,
+    Csv,
+    Pdf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatementQuery {
+    /// First day of the period, the current month by default
+    pub from: Option<NaiveDate>,
+    /// Last day of the period, inclusive
+    pub to: Option<NaiveDate>,
+    pub format: Option<StatementFormat>,
+}
+
+#[derive(Template)]
+#[template(path = "statement.html")]
+struct StatementTemplate<'a> {
+    statement: &'a Statement,
+    from: NaiveDate,
+    to: NaiveDate,
+}
+
+pub async fn get_merchant_statement(
+    merchant: User<Merchant>,
+    query: Query<StatementQuery>,
+    data: Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let merchant = merchant.into_inner();
+    let today = data.clock.now().date();
+    let from = query.from.unwrap_or_else(|| today.with_day(1).unwrap());
+    let to = query.to.unwrap_or(today);
+    if from > to {
+        return Err(Error::InvalidEntity(s!("statement period is empty")));
+    }
+    let statement = block::<_, _, Error>({
+        let pool = data.pool.clone();
+        move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            get_statement(
+                &merchant.id,
+                from.and_hms(0, 0, 0),
+                (to + Duration::days(1)).and_hms(0, 0, 0),
+                conn,
+            )
+        }
+    })
+    .await?;
+
+    let filename = format!("statement-{}-{}-{}", statement.merchant_id, from, to);
+    match query.format.unwrap_or(StatementFormat::Html) {
+        StatementFormat::Html => {
+            let html = StatementTemplate {
+                statement: &statement,
+                from,
+                to,
+            }
+            .render()
+            .map_err(|e| Error::from(e))?;
+            Ok(HttpResponse::Ok().content_type("text/html").body(html))
+        }
+        StatementFormat::Csv => Ok(HttpResponse::Ok()
+            .content_type("text/csv")
+            .header(
+                "content-disposition",
+                format!("attachment; filename=\"{}.csv\"", filename),
+            )
+            .body(statement.to_csv())),
+        StatementFormat::Pdf => Ok(HttpResponse::Ok()
+            .content_type("application/pdf")
+            .header(
+                "content-disposition",
+                format!("attachment; filename=\"{}.pdf\"", filename),
+            )
+            .body(statement.to_pdf())),
+    }
+}
@@ -1,11 +1,11 @@
 use crate::app::AppState;
-use crate::db::{get_balance, GetMerchant};
+use crate::db::{get_balance, get_sync_status, GetMerchant};
 use crate::errors::*;
 use crate::extractor::User;
 use crate::filters;
 use crate::handlers::BootstrapColor;
 use crate::handlers::TemplateIntoResponse;
-use crate::models::{Merchant, Transaction, TransactionType};
+use crate::models::{Merchant, Transaction, TransactionStatus, TransactionType};
 use actix_identity::Identity;
 use actix_session::Session;
 use actix_web::web::{block, Data, Form};
@@ -23,6 +23,9 @@ struct IndexTemplate<'a> {
     transactions: Vec<Transaction>,
     last_payout: &'a Option<Transaction>,
     current_height: i64,
+    node_height: i64,
+    sync_lag: i64,
+    stuck_transactions: i64,
 }
 
 pub async fn index(merchant: User<Merchant>, data: Data<AppState>) -> Result<HttpResponse, Error> {
@@ -53,19 +56,26 @@ pub async fn index(merchant: User<Merchant>, data: Data<AppState>) -> Result<Htt
                     .optional()
                     .map_err::<Error, _>(|e| e.into())
             }?;
-            let current_height = {
-                use crate::schema::current_height::dsl::*;
-                current_height
-                    .select(height)
-                    .first(conn)
+            let stuck_transactions = {
+                use crate::schema::transactions::dsl::*;
+                transactions
+                    .filter(merchant_id.eq(&merchant.id))
+                    .filter(status.eq(TransactionStatus::Pending))
+                    .filter(stuck_since.is_not_null())
+                    .count()
+                    .get_result(conn)
                     .map_err::<Error, _>(|e| e.into())
             }?;
+            let sync_status = get_sync_status(conn)?;
             IndexTemplate {
                 merchant: &merchant,
                 balance: balance,
                 transactions: txs,
                 last_payout: &last_payout,
-                current_height: current_height,
+                current_height: sync_status.height,
+                node_height: sync_status.node_height,
+                sync_lag: sync_status.lag(),
+                stuck_transactions,
             }
             .render()
             .map_err(|e| Error::from(e))
@@ -96,6 +106,8 @@ pub async fn login(
         Ok(res) => {
             if res {
                 session.set("merchant", merchant.id)?;
+                // set() marks the session as changed, renew after it
+                session.renew();
                 if merchant.confirmed_2fa {
                     Ok(HttpResponse::Found().header("location", "/2fa").finish())
                 } else {
@@ -121,6 +133,6 @@ pub async fn login_form(_: HttpRequest) -> Result<HttpResponse, Error> {
 
 pub async fn logout(identity: Identity, session: Session) -> Result<HttpResponse, Error> {
     identity.forget();
-    session.clear();
+    session.purge();
     Ok(HttpResponse::Found().header("location", "/login").finish())
 }
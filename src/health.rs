@@ -0,0 +1,184 @@
+//! Health of knockturn for an orchestrator.
+//!
+//! `/health` tells if the process itself works: the database is usable and
+//! background jobs keep running. It doesn't call the wallet or the node, so a
+//! dead dependency doesn't get knockturn restarted. `/ready` additionally checks
+//! that the wallet and the node are reachable, we keep up with the chain and
+//! exchange rates are fresh, i.e. payments can be accepted right now.
+use crate::db::get_sync_status;
+use crate::errors::Error;
+use crate::jsonrpc;
+use crate::node::NodeApi;
+use crate::scheduler::{JobRegistry, JobStatus};
+use crate::wallet::WalletApi;
+use crate::Pool;
+use actix_web::web::block;
+use chrono::NaiveDateTime;
+use diesel::dsl::min;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+pub const MAX_SYNC_LAG: i64 = 10; // blocks we may be behind the node
+pub const MAX_RATES_AGE_SECONDS: i64 = 60 * 60;
+
+/// Result of a single check
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub details: Map<String, Value>,
+}
+
+impl Check {
+    fn passed(details: Value) -> Self {
+        Check::new(true, None, details)
+    }
+
+    fn failed(error: String, details: Value) -> Self {
+        Check::new(false, Some(error), details)
+    }
+
+    fn new(ok: bool, error: Option<String>, details: Value) -> Self {
+        let details = match details {
+            Value::Object(details) => details,
+            _ => Map::new(),
+        };
+        Check { ok, error, details }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub ok: bool,
+    /// Only the leader runs background jobs
+    #[serde(default)]
+    pub leader: bool,
+    pub database: Check,
+    pub sync: Check,
+    pub rates: Check,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wallet: Option<Check>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<Check>,
+    pub jobs: BTreeMap<String, JobStatus>,
+}
+
+/// Checks which don't leave the process: database, what we know about the
+/// chain and rates, background jobs. `ok` depends on the database and jobs only
+pub async fn liveness(pool: &Pool, jobs: &JobRegistry, now: NaiveDateTime) -> HealthReport {
+    local_checks(pool, jobs, now).await.0
+}
+
+async fn local_checks(
+    pool: &Pool,
+    jobs: &JobRegistry,
+    now: NaiveDateTime,
+) -> (HealthReport, Option<i64>) {
+    let db_report = block::<_, _, Error>({
+        let pool = pool.clone();
+        move || {
+            let state = pool.state();
+            let details = json!({
+                "connections": state.connections,
+                "idle_connections": state.idle_connections,
+            });
+            let conn = match pool.get() {
+                Ok(conn) => conn,
+                Err(e) => return Ok((Check::failed(e.to_string(), details), None, None)),
+            };
+            let conn: &PgConnection = &conn;
+            let sync_status = get_sync_status(conn)?;
+            let rates_updated_at: Option<NaiveDateTime> = {
+                use crate::schema::rates::dsl::*;
+                rates.select(min(updated_at)).get_result(conn)?
+            };
+            Ok((Check::passed(details), Some(sync_status), rates_updated_at))
+        }
+    })
+    .await;
+    let (database, sync_status, rates_updated_at) = match db_report {
+        Ok(report) => report,
+        Err(e) => (Check::failed(e.to_string(), json!({})), None, None),
+    };
+
+    let current_height = sync_status.as_ref().map(|sync_status| sync_status.height);
+    let sync = match sync_status {
+        Some(sync_status) if sync_status.lag() > MAX_SYNC_LAG => Check::failed(
+            format!("{} blocks behind the node", sync_status.lag()),
+            json!(sync_status),
+        ),
+        Some(sync_status) => Check::passed(json!(sync_status)),
+        None => Check::failed(s!("unknown"), json!({})),
+    };
+    let rates = match rates_updated_at {
+        Some(updated_at) => {
+            let age = (now - updated_at).num_seconds();
+            let details = json!({"updated_at": updated_at, "age_seconds": age});
+            if age > MAX_RATES_AGE_SECONDS {
+                Check::failed(s!("exchange rates are outdated"), details)
+            } else {
+                Check::passed(details)
+            }
+        }
+        None => Check::failed(s!("no exchange rates"), json!({})),
+    };
+    let leader = jobs.is_leader();
+    let jobs = jobs.statuses(now);
+    let report = HealthReport {
+        ok: database.ok && jobs.values().all(|job| job.ok),
+        leader,
+        database,
+        sync,
+        rates,
+        wallet: None,
+        node: None,
+        jobs,
+    };
+    (report, current_height)
+}
+
+/// All checks including calls to the wallet and the node. `ok` only if all of them pass
+pub async fn readiness(
+    pool: &Pool,
+    jobs: &JobRegistry,
+    wallet: &dyn WalletApi,
+    node: &dyn NodeApi,
+    now: NaiveDateTime,
+) -> HealthReport {
+    let (mut report, current_height) = local_checks(pool, jobs, now).await;
+
+    let wallet_check = match wallet
+        .foreign_request(jsonrpc::Request::new("check_version", json!([])))
+        .await
+    {
+        Ok(resp) => match resp.error {
+            None => Check::passed(json!({})),
+            Some(e) => Check::failed(e.to_string(), json!({})),
+        },
+        Err(e) => Check::failed(e.to_string(), json!({})),
+    };
+    // lag against the node's tip right now, the one in `sync` is as of the last sync
+    let node_check = match (node.current_height().await, current_height) {
+        (Ok(tip), Some(current_height)) => {
+            let lag = tip - current_height;
+            let details = json!({"height": tip, "lag": lag});
+            if lag > MAX_SYNC_LAG {
+                Check::failed(format!("{} blocks behind the node", lag), details)
+            } else {
+                Check::passed(details)
+            }
+        }
+        (Ok(tip), None) => Check::passed(json!({ "height": tip })),
+        (Err(e), _) => Check::failed(e.to_string(), json!({})),
+    };
+
+    report.ok = report.ok && report.sync.ok && report.rates.ok && wallet_check.ok && node_check.ok;
+    report.wallet = Some(wallet_check);
+    report.node = Some(node_check);
+    report
+}
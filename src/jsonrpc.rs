@@ -94,7 +94,8 @@ pub struct Request {
 
     /// A Structured value that holds the parameter values to be used during the invocation of the method. This member
     /// MAY be omitted.
-    pub params: Vec<Value>,
+    #[serde(default)]
+    pub params: Value,
 
     /// An identifier established by the Client that MUST contain a String, Number, or NULL value if included. If it is
     /// not included it is assumed to be a notification. The value SHOULD normally not be Null [1] and Numbers SHOULD
@@ -103,7 +104,8 @@ pub struct Request {
 }
 
 impl Request {
-    pub fn new(method: &str, params: Vec<Value>) -> Self {
+    /// Creates a request with either positional (array) or named (object) params.
+    pub fn new(method: &str, params: Value) -> Self {
         Request {
             jsonrpc: JSONRPC_VERSION.into(),
             method: method.to_owned(),
@@ -128,6 +130,7 @@ pub struct Response {
     /// This member is REQUIRED on success.
     /// This member MUST NOT exist if there was an error invoking the method.
     /// The value of this member is determined by the method invoked on the Server.
+    #[serde(default)]
     pub result: Value,
 
     // This member is REQUIRED on error.
@@ -187,24 +190,27 @@ where
         self.response
     }
     pub fn into_result(&self) -> Result<T, errors::Error> {
+        self.into_result_with(errors::Error::WalletAPIError)
+    }
+    /// Like `into_result`, errors are reported as `api_error` of the API we called
+    pub fn into_result_with(
+        &self,
+        api_error: fn(String) -> errors::Error,
+    ) -> Result<T, errors::Error> {
         if let Some(e) = &self.response.error {
-            return Err(errors::Error::WalletAPIError(s!(e)));
+            return Err(api_error(s!(e)));
         }
         serde_json::from_value::<Result<T, _>>(self.response.result.clone())
-            .map_err(|e| e.into())
-            .and_then(|res: Result<T, String>| match res {
-                Ok(r) => Ok(r),
-                Err(e) => Err(errors::Error::WalletAPIError(format!(
-                    "Wallet return an error {}",
-                    e
-                ))),
-            })
             .map_err(|e| {
                 error!(
                     "Cannot decode json {:?}:\n with error {} ",
                     self.response.result, e
                 );
-                errors::Error::WalletAPIError(format!("Cannot decode json {}", e))
+                api_error(format!("Cannot decode json {}", e))
+            })
+            .and_then(|res: Result<T, Value>| match res {
+                Ok(r) => Ok(r),
+                Err(e) => Err(api_error(format!("API returned an error {}", e))),
             })
     }
 }
@@ -0,0 +1,193 @@
+//! Leader election between several instances of knockturn.
+//!
+//! All instances serve http, but only one of them may run background jobs,
+//! otherwise payments are rejected and reported twice and instances race on
+//! the current height. The leader holds a lease in the `leases` table and
+//! renews it every `LEASE_RENEW_SECONDS`. If the leader dies its lease expires
+//! and another instance takes it over. With every renewal instances reload
+//! jobs paused by an operator on any of them.
+use crate::clock::Clock;
+use crate::errors::Error;
+use crate::scheduler::JobRegistry;
+use crate::Pool;
+use actix::prelude::*;
+use actix_web::web::block;
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Int8, Text};
+use log::*;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const CRON_LEASE: &'static str = "cron";
+pub const LEASE_TTL_SECONDS: i64 = 30;
+pub const LEASE_RENEW_SECONDS: u64 = 10;
+
+/// Whether this instance is the leader, shared between crons and http workers
+#[derive(Clone)]
+pub struct Leadership {
+    instance_id: String,
+    since: Arc<Mutex<Option<NaiveDateTime>>>,
+}
+
+impl Leadership {
+    /// Leadership which is decided by `LeaderElection`
+    pub fn new(instance_id: &str) -> Self {
+        Leadership {
+            instance_id: instance_id.to_owned(),
+            since: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The only instance, always the leader
+    pub fn single(now: NaiveDateTime) -> Self {
+        Leadership {
+            instance_id: s!("single"),
+            since: Arc::new(Mutex::new(Some(now))),
+        }
+    }
+
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.since.lock().is_some()
+    }
+
+    /// When this instance became the leader
+    pub fn since(&self) -> Option<NaiveDateTime> {
+        *self.since.lock()
+    }
+
+    fn set_leader(&self, leader: bool, now: NaiveDateTime) {
+        let mut since = self.since.lock();
+        match (since.is_some(), leader) {
+            (false, true) => {
+                info!("Instance {} became the leader", self.instance_id);
+                *since = Some(now);
+            }
+            (true, false) => {
+                warn!("Instance {} is not the leader anymore", self.instance_id);
+                *since = None;
+            }
+            _ => (),
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+struct LeaseHolder {
+    #[sql_type = "Text"]
+    holder: String,
+}
+
+/// Takes the lease or prolongs it if `holder` already has it. Returns false if
+/// somebody else holds an unexpired lease
+pub fn acquire_lease(
+    name: &str,
+    holder: &str,
+    ttl_seconds: i64,
+    conn: &PgConnection,
+) -> Result<bool, Error> {
+    let acquired: Vec<LeaseHolder> = sql_query(
+        "INSERT INTO leases (name, holder, expires_at)
+         VALUES ($1, $2, (now() at time zone 'utc') + $3 * interval '1 second')
+         ON CONFLICT (name) DO UPDATE
+         SET holder = excluded.holder, expires_at = excluded.expires_at
+         WHERE leases.holder = excluded.holder
+            OR leases.expires_at < (now() at time zone 'utc')
+         RETURNING holder",
+    )
+    .bind::<Text, _>(name)
+    .bind::<Text, _>(holder)
+    .bind::<Int8, _>(ttl_seconds)
+    .load(conn)?;
+    Ok(acquired.iter().any(|lease| lease.holder == holder))
+}
+
+/// Gives the lease up so that another instance doesn't wait for it to expire
+pub fn release_lease(name: &str, holder: &str, conn: &PgConnection) -> Result<(), Error> {
+    use crate::schema::leases;
+    diesel::delete(
+        leases::table
+            .filter(leases::name.eq(name))
+            .filter(leases::holder.eq(holder)),
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Keeps trying to become the leader and renews the lease once it is
+pub struct LeaderElection {
+    pub pool: Pool,
+    pub leadership: Leadership,
+    pub jobs: JobRegistry,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Actor for LeaderElection {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!(
+            "Starting leader election as instance {}",
+            self.leadership.instance_id()
+        );
+        self.renew(ctx);
+        ctx.run_interval(Duration::from_secs(LEASE_RENEW_SECONDS), |election, ctx| {
+            election.renew(ctx)
+        });
+    }
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
+        if self.leadership.is_leader() {
+            let res = self
+                .pool
+                .get()
+                .map_err(|e| Error::General(s!(e)))
+                .and_then(|conn| release_lease(CRON_LEASE, self.leadership.instance_id(), &conn));
+            if let Err(e) = res {
+                error!("Cannot release lease: {}", e);
+            }
+            self.leadership.set_leader(false, self.clock.now());
+        }
+        Running::Stop
+    }
+}
+
+impl LeaderElection {
+    fn renew(&mut self, ctx: &mut Context<Self>) {
+        let pool = self.pool.clone();
+        let leadership = self.leadership.clone();
+        let jobs = self.jobs.clone();
+        let clock = self.clock.clone();
+        ctx.spawn(
+            async move {
+                let res = block::<_, _, Error>({
+                    let instance_id = leadership.instance_id().to_owned();
+                    move || {
+                        let conn: &PgConnection = &pool.get().unwrap();
+                        let leader =
+                            acquire_lease(CRON_LEASE, &instance_id, LEASE_TTL_SECONDS, conn)?;
+                        jobs.load_paused(conn)?;
+                        Ok(leader)
+                    }
+                })
+                .await;
+                match res {
+                    Ok(leader) => leadership.set_leader(leader, clock.now()),
+                    Err(e) => {
+                        // we can't tell if the lease is still ours, better stop
+                        error!("Cannot renew lease: {}", e);
+                        leadership.set_leader(false, clock.now());
+                    }
+                }
+            }
+            .into_actor(self),
+        );
+    }
+}
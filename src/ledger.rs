@@ -0,0 +1,342 @@
+//! Double-entry ledger of merchants' money.
+//!
+//! Every move of money is a posting: a few immutable entries whose amounts sum
+//! up to zero. Accounts are merchants (the operator is a merchant too) and two
+//! accounts of the outside world: `EXTERNAL_ACCOUNT` which customers pay from
+//! and payouts go to, and `NETWORK_ACCOUNT` which gets transfer fees. Balance
+//! of an account is split into available, pending and locked parts, their
+//! running totals are kept in `balances` table.
+//!
+//! Entries of a transaction follow from its state. When the state changes we
+//! compare entries the transaction should have with the recorded ones and
+//! write the difference, so a rejected payout or a rolled back payment get
+//! reversing entries. It must be done in the same DB transaction which
+//! changed the state.
+use crate::errors::Error;
+use crate::fsm_payout::PayoutFees;
+use crate::models::{
+    BalanceBucket, LedgerEntry, LedgerEntryKind, StatusChange, Transaction, TransactionStatus,
+    TransactionType,
+};
+use crate::revenue::OPERATOR_ACCOUNT;
+use bigdecimal::{BigDecimal, ToPrimitive};
+use chrono::NaiveDateTime;
+use diesel::dsl::sum;
+use diesel::pg::PgConnection;
+use diesel::upsert::excluded;
+use diesel::{self, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// Customers who pay and merchants' wallets which get payouts
+pub const EXTERNAL_ACCOUNT: &str = "external";
+/// Miners who get transfer fees
+pub const NETWORK_ACCOUNT: &str = "network";
+/// Merchants can't have these ids and their balances can't be adjusted
+pub const RESERVED_ACCOUNTS: &[&str] = &[EXTERNAL_ACCOUNT, NETWORK_ACCOUNT, OPERATOR_ACCOUNT];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct Balances {
+    /// May be withdrawn
+    pub available: i64,
+    /// Payments which are not confirmed yet
+    pub pending: i64,
+    /// Payouts which didn't leave our wallet yet
+    pub locked: i64,
+}
+
+type EntryKey = (LedgerEntryKind, String, BalanceBucket);
+
+/// Entries the transaction should have in its current state
+fn expected_entries(tx: &Transaction) -> Vec<(EntryKey, i64)> {
+    use BalanceBucket::*;
+    use LedgerEntryKind::*;
+    let merchant = tx.merchant_id.clone();
+    let external = s!(EXTERNAL_ACCOUNT);
+    let network = s!(NETWORK_ACCOUNT);
+    let operator = s!(OPERATOR_ACCOUNT);
+    let mut entries = vec![];
+    match tx.transaction_type {
+        TransactionType::Payment => {
+            let amount = tx.grin_amount;
+            let fee = tx.knockturn_fee.unwrap_or(0);
+            // confirmed payment is available to merchant once reported, so
+            // merchant's customer got the goods
+            let (received, cleared, refunded) = match tx.status {
+                TransactionStatus::Pending | TransactionStatus::InChain => (true, false, false),
+                TransactionStatus::Confirmed => (true, tx.reported, false),
+                TransactionStatus::Refund | TransactionStatus::RefundedManually => {
+                    (false, false, true)
+                }
+                _ => (false, false, false),
+            };
+            if received {
+                entries.push(((PaymentReceived, external.clone(), Available), -amount));
+                entries.push(((PaymentReceived, merchant.clone(), Pending), amount));
+            }
+            if cleared {
+                entries.push(((PaymentCleared, merchant.clone(), Pending), -amount));
+                entries.push(((PaymentCleared, merchant.clone(), Available), amount));
+            }
+            if refunded {
+                entries.push(((Refund, external, Available), -amount));
+                entries.push(((Refund, merchant.clone(), Available), amount));
+            }
+            if cleared || refunded {
+                entries.push(((Fee, merchant, Available), -fee));
+                entries.push(((Fee, operator, Available), fee));
+            }
+        }
+        TransactionType::Payout => {
+            let sent = match tx.status {
+                TransactionStatus::New | TransactionStatus::Initialized => false,
+                TransactionStatus::Pending
+                | TransactionStatus::InChain
+                | TransactionStatus::Confirmed => true,
+                _ => return entries,
+            };
+            let fee = tx.knockturn_fee();
+            let transfer_fee = tx.transfer_fee();
+            let to_send = tx.grin_amount - fee;
+            entries.push(((Payout, merchant.clone(), Available), -to_send));
+            entries.push(((Payout, merchant.clone(), Locked), to_send));
+            entries.push(((Fee, merchant.clone(), Available), -fee));
+            entries.push(((Fee, operator.clone(), Available), fee));
+            if !sent {
+                return entries;
+            }
+            entries.push((
+                (PayoutSent, merchant.clone(), Locked),
+                -(to_send - transfer_fee),
+            ));
+            entries.push(((PayoutSent, external, Available), to_send - transfer_fee));
+            entries.push(((NetworkFee, merchant.clone(), Locked), -transfer_fee));
+            entries.push(((NetworkFee, network.clone(), Available), transfer_fee));
+            if let Some(credit) = tx.transfer_fee_credit {
+                entries.push(((FeeCredit, network.clone(), Available), -credit));
+                entries.push(((FeeCredit, merchant, Available), credit));
+            }
+            let loss = tx.real_transfer_fee.unwrap_or(transfer_fee) - transfer_fee;
+            if loss > 0 {
+                entries.push(((FeeLoss, operator, Available), -loss));
+                entries.push(((FeeLoss, network, Available), loss));
+            }
+        }
+    }
+    entries
+}
+
+/// Writes entries which bring the ledger in line with the transaction's
+/// state. The caller must hold a lock on the transaction's row, i.e. call
+/// it in the DB transaction which updated it.
+pub fn record_transaction(
+    tx: &Transaction,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<(), Error> {
+    let recorded = {
+        use crate::schema::ledger_entries::dsl::*;
+        ledger_entries
+            .filter(transaction_id.eq(tx.id))
+            .load::<LedgerEntry>(conn)?
+    };
+    let mut diff: BTreeMap<EntryKey, i64> = BTreeMap::new();
+    for (key, amount) in expected_entries(tx) {
+        *diff.entry(key).or_insert(0) += amount;
+    }
+    for entry in recorded {
+        *diff
+            .entry((entry.kind, entry.account, entry.bucket))
+            .or_insert(0) -= entry.amount;
+    }
+    let posting_id = Uuid::new_v4();
+    let entries: Vec<LedgerEntry> = diff
+        .into_iter()
+        .filter(|(_, amount)| *amount != 0)
+        .map(|((kind, account, bucket), amount)| LedgerEntry {
+            id: Uuid::new_v4(),
+            posting_id,
+            transaction_id: Some(tx.id),
+            kind,
+            account,
+            bucket,
+            amount,
+            note: None,
+            created_at: now,
+        })
+        .collect();
+    post(&entries, now, conn)
+}
+
+/// Manual correction of merchant's available balance, the counterparty is
+/// the outside world
+pub fn adjust_balance(
+    account: &str,
+    amount: i64,
+    note: &str,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<(), Error> {
+    if RESERVED_ACCOUNTS.contains(&account) {
+        return Err(Error::InvalidEntity(format!(
+            "{} can't be adjusted",
+            account
+        )));
+    }
+    {
+        use crate::schema::merchants::dsl::*;
+        merchants
+            .find(account)
+            .select(id)
+            .first::<String>(conn)
+            .optional()?
+            .ok_or(Error::EntityNotFound(format!("merchant {}", account)))?;
+    }
+    let posting_id = Uuid::new_v4();
+    let entry = |account: &str, amount: i64| LedgerEntry {
+        id: Uuid::new_v4(),
+        posting_id,
+        transaction_id: None,
+        kind: LedgerEntryKind::Adjustment,
+        account: account.to_owned(),
+        bucket: BalanceBucket::Available,
+        amount,
+        note: Some(note.to_owned()),
+        created_at: now,
+    };
+    conn.transaction(|| {
+        post(
+            &[entry(EXTERNAL_ACCOUNT, -amount), entry(account, amount)],
+            now,
+            conn,
+        )
+    })
+}
+
+fn post(entries: &[LedgerEntry], now: NaiveDateTime, conn: &PgConnection) -> Result<(), Error> {
+    use crate::schema::balances::dsl::*;
+    if entries.is_empty() {
+        return Ok(());
+    }
+    if entries.iter().map(|entry| entry.amount).sum::<i64>() != 0 {
+        return Err(Error::General(format!("Unbalanced posting {:?}", entries)));
+    }
+    diesel::insert_into(crate::schema::ledger_entries::table)
+        .values(entries)
+        .execute(conn)?;
+    for entry in entries {
+        diesel::insert_into(balances)
+            .values((
+                account.eq(&entry.account),
+                bucket.eq(entry.bucket),
+                amount.eq(entry.amount),
+                updated_at.eq(now),
+            ))
+            .on_conflict((account, bucket))
+            .do_update()
+            .set((amount.eq(amount + excluded(amount)), updated_at.eq(now)))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+pub fn get_balances(acc: &str, conn: &PgConnection) -> Result<Balances, Error> {
+    use crate::schema::balances::dsl::*;
+    let mut result = Balances::default();
+    let parts = balances
+        .filter(account.eq(acc))
+        .select((bucket, amount))
+        .load::<(BalanceBucket, i64)>(conn)?;
+    for (part, value) in parts {
+        match part {
+            BalanceBucket::Available => result.available = value,
+            BalanceBucket::Pending => result.pending = value,
+            BalanceBucket::Locked => result.locked = value,
+        }
+    }
+    Ok(result)
+}
+
+/// Whether a payment to the account was cleared, i.e. confirmed and
+/// reported, since `since`
+pub fn payment_cleared_since(
+    acc: &str,
+    since: Option<NaiveDateTime>,
+    conn: &PgConnection,
+) -> Result<bool, Error> {
+    use crate::schema::ledger_entries::dsl::*;
+    let mut query = ledger_entries
+        .filter(account.eq(acc))
+        .filter(kind.eq(LedgerEntryKind::PaymentCleared))
+        .filter(bucket.eq(BalanceBucket::Available))
+        .filter(amount.gt(0))
+        .select(id)
+        .into_boxed();
+    if let Some(since) = since {
+        query = query.filter(created_at.ge(since));
+    }
+    let entry = query.first::<Uuid>(conn).optional()?;
+    Ok(entry.is_some())
+}
+
+/// Records transactions which have no ledger entries, e.g. created before
+/// the ledger. Their history is replayed from `status_changes`, so entries
+/// are dated when the status changed rather than when the ledger appeared.
+/// Returns how many of them got entries.
+pub fn sync_ledger(conn: &PgConnection) -> Result<usize, Error> {
+    use crate::schema::transactions::dsl::*;
+    use crate::schema::{ledger_entries, status_changes};
+    conn.transaction(|| {
+        let recorded: Vec<Uuid> = ledger_entries::table
+            .select(ledger_entries::transaction_id)
+            .filter(ledger_entries::transaction_id.is_not_null())
+            .distinct()
+            .load::<Option<Uuid>>(conn)?
+            .into_iter()
+            .flatten()
+            .collect();
+        let txs = transactions
+            .filter(diesel::dsl::not(id.eq_any(recorded)))
+            .filter(status.ne(TransactionStatus::New))
+            .filter(status.ne(TransactionStatus::Rejected))
+            .for_update()
+            .load::<Transaction>(conn)?;
+        for tx in &txs {
+            let changes = status_changes::table
+                .filter(status_changes::transaction_id.eq(tx.id))
+                .order(status_changes::updated_at.asc())
+                .load::<StatusChange>(conn)?;
+            for change in changes {
+                let mut past = tx.clone();
+                past.status = change.status;
+                record_transaction(&past, change.updated_at, conn)?;
+            }
+            // the current state, in case some changes weren't recorded
+            record_transaction(tx, tx.updated_at, conn)?;
+        }
+        Ok(txs.len())
+    })
+}
+
+/// Accounts whose running totals differ from the sum of their entries
+pub fn check_balances(conn: &PgConnection) -> Result<Vec<String>, Error> {
+    use crate::schema::{balances, ledger_entries};
+    let totals = balances::table
+        .select((balances::account, balances::bucket, balances::amount))
+        .load::<(String, BalanceBucket, i64)>(conn)?;
+    let mut wrong = vec![];
+    for (acc, part, total) in totals {
+        let entries_sum = ledger_entries::table
+            .filter(ledger_entries::account.eq(&acc))
+            .filter(ledger_entries::bucket.eq(part))
+            .select(sum(ledger_entries::amount))
+            .first::<Option<BigDecimal>>(conn)?
+            .and_then(|value| value.to_i64())
+            .unwrap_or(0);
+        if entries_sum != total {
+            wrong.push(format!("{} {}", acc, part));
+        }
+    }
+    Ok(wrong)
+}
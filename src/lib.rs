@@ -2,23 +2,39 @@
 mod macros;
 
 pub mod app;
+pub mod clock;
 pub mod cron;
 pub mod cron_payout;
 pub mod db;
 pub mod errors;
 pub mod extractor;
+#[cfg(any(test, feature = "fakes"))]
+pub mod fakes;
+pub mod fees;
 pub mod filters;
 pub mod fsm;
 pub mod fsm_payout;
 pub mod handlers;
+pub mod health;
 pub mod jsonrpc;
+pub mod leader;
+pub mod ledger;
+pub mod metrics;
 pub mod models;
 pub mod node;
+pub mod node_pool;
+pub mod node_v2;
 pub mod qrcode;
 pub mod rates;
+pub mod rescan;
+pub mod revenue;
+pub mod scheduler;
 #[allow(unused_imports)]
 pub mod schema;
+pub mod secure_api;
 mod ser;
+pub mod session;
+pub mod statement;
 #[cfg(test)]
 pub mod test_utils;
 pub mod totp;
@@ -1,18 +1,24 @@
 use actix::prelude::*;
-use actix_identity::{CookieIdentityPolicy, IdentityService};
-use actix_session::CookieSession;
+use actix_identity::IdentityService;
 use actix_web::{middleware, App, HttpServer};
 use diesel::pg::PgConnection;
 use diesel::r2d2::ConnectionManager;
 use dotenv::dotenv;
 use env_logger;
 use knockturn::app::{check_node_horizon, routing, AppCfg, AppState};
+use knockturn::clock::{Clock, SystemClock};
 use knockturn::db::DbExecutor;
 use knockturn::errors::Error;
 use knockturn::fsm::Fsm;
 use knockturn::fsm_payout::FsmPayout;
-use knockturn::node::Node;
-use knockturn::wallet::Wallet;
+use knockturn::leader::{LeaderElection, Leadership};
+use knockturn::ledger::sync_ledger;
+use knockturn::metrics::HttpMetrics;
+use knockturn::node::{NodeApi, NodeApiVersion};
+use knockturn::node_pool::NodePool;
+use knockturn::scheduler::JobRegistry;
+use knockturn::session::{PgSession, SessionIdentityPolicy};
+use knockturn::wallet::{Wallet, WalletApi};
 use knockturn::{cron, cron_payout};
 use log::*;
 use rustls::internal::pemfile::{certs, pkcs8_private_keys};
@@ -23,6 +29,7 @@ use futures::future::{lazy, ok, TryFutureExt};
 use std::env;
 use std::fs::File;
 use std::io::BufReader;
+use std::sync::Arc;
 
 #[macro_use]
 extern crate diesel_migrations;
@@ -35,7 +42,6 @@ async fn main() -> std::io::Result<()> {
 
     env_logger::init();
 
-    let cookie_secret = env::var("COOKIE_SECRET").expect("COOKIE_SECRET must be set");
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let host = env::var("HOST").unwrap_or("0.0.0.0:3000".to_owned());
     let _ = env::var("DOMAIN").expect("DOMAIN must be set");
@@ -43,11 +49,29 @@ async fn main() -> std::io::Result<()> {
     let wallet_url = env::var("WALLET_URL").expect("WALLET_URL must be set");
     let wallet_user = env::var("WALLET_USER").expect("WALLET_USER must be set");
     let wallet_pass = env::var("WALLET_PASS").expect("WALLET_PASS must be set");
+    let wallet_password = env::var("WALLET_PASSWORD").expect("WALLET_PASSWORD must be set");
 
     let node_url = env::var("NODE_URL").expect("NODE_URL must be set");
     let node_user = env::var("NODE_USER").expect("NODE_USER must be set");
     let node_pass = env::var("NODE_PASS").expect("NODE_PASS must be set");
+    let node_api_version = env::var("NODE_API_VERSION")
+        .map(|v| v.parse().expect("NODE_API_VERSION must be v1 or v2"))
+        .unwrap_or(NodeApiVersion::V1);
+    let node_quorum = env::var("NODE_QUORUM")
+        .map(|q| q.parse().expect("NODE_QUORUM must be a number"))
+        .unwrap_or(1);
+    let job_schedule = env::var("JOB_INTERVALS")
+        .map(|s| s.parse().expect("JOB_INTERVALS must be job=seconds pairs"))
+        .unwrap_or_default();
     let sentry_url = env::var("SENTRY_URL").unwrap_or("".to_owned());
+    let operator_token = env::var("OPERATOR_TOKEN").ok();
+    let instance_id = env::var("INSTANCE_ID").unwrap_or_else(|_| {
+        format!(
+            "{}-{}",
+            env::var("HOSTNAME").unwrap_or("knockturn".to_owned()),
+            std::process::id()
+        )
+    });
 
     if sentry_url != "" {
         let _ = sentry::init("https://3a46c4de68e54de9ab7e86e7547a4073@sentry.io/1464519");
@@ -59,10 +83,15 @@ async fn main() -> std::io::Result<()> {
         node_url,
         node_user,
         node_pass,
+        node_api_version,
+        node_quorum,
         wallet_url,
         wallet_user,
         wallet_pass,
+        wallet_password,
         database_url,
+        operator_token,
+        job_schedule,
     };
 
     info!("Starting");
@@ -80,12 +109,38 @@ async fn main() -> std::io::Result<()> {
         let pool = pool.clone();
         move || DbExecutor(pool.clone())
     });
-    let wallet = Wallet::new(&cfg.wallet_url, &cfg.wallet_user, &cfg.wallet_pass);
-    let node = Node::new(&cfg.node_url, &cfg.node_user, &cfg.node_pass);
+    let wallet: Arc<dyn WalletApi> = Arc::new(Wallet::new(
+        &cfg.wallet_url,
+        &cfg.wallet_user,
+        &cfg.wallet_pass,
+        &cfg.wallet_password,
+    ));
+    let node: Arc<dyn NodeApi> = Arc::new(NodePool::connect(
+        &cfg.node_url,
+        &cfg.node_user,
+        &cfg.node_pass,
+        cfg.node_api_version,
+        cfg.node_quorum,
+    ));
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let synced = sync_ledger(conn).expect("Failed to sync ledger");
+    if synced > 0 {
+        info!("Recorded {} transactions in the ledger", synced);
+    }
+    let leadership = Leadership::new(&instance_id);
+    let jobs = JobRegistry::new(clock.now(), cfg.job_schedule.clone(), leadership.clone());
+    LeaderElection {
+        pool: pool.clone(),
+        leadership,
+        jobs: jobs.clone(),
+        clock: clock.clone(),
+    }
+    .start();
     let fsm: Addr<Fsm> = Fsm {
         db: db.clone(),
         wallet: wallet.clone(),
         pool: pool.clone(),
+        clock: clock.clone(),
     }
     .start();
 
@@ -93,20 +148,38 @@ async fn main() -> std::io::Result<()> {
         db: db.clone(),
         wallet: wallet.clone(),
         pool: pool.clone(),
+        clock: clock.clone(),
     }
     .start();
 
-    cron::Cron::new(db.clone(), fsm.clone(), node.clone(), pool.clone()).start();
-    cron_payout::CronPayout::new(fsm_payout.clone(), pool.clone()).start();
+    cron::Cron::new(
+        db.clone(),
+        fsm.clone(),
+        node.clone(),
+        pool.clone(),
+        clock.clone(),
+        jobs.clone(),
+    )
+    .start();
+    cron_payout::CronPayout::new(
+        fsm_payout.clone(),
+        node.clone(),
+        clock.clone(),
+        jobs.clone(),
+    )
+    .start();
 
-    check_node_horizon(&node, &pool).await.map_err(|e: Error| {
-        error!("Cannot check horizon: {}", e);
-        System::current().stop();
-        e
-    })?;
+    check_node_horizon(node.as_ref(), &pool)
+        .await
+        .map_err(|e: Error| {
+            error!("Cannot check horizon: {}", e);
+            System::current().stop();
+            e
+        })?;
 
     let srv = HttpServer::new({
         let pool = pool.clone();
+        let cfg = cfg.clone();
         move || {
             let app = App::new()
                 .data(AppState {
@@ -115,15 +188,16 @@ async fn main() -> std::io::Result<()> {
                     pool: pool.clone(),
                     fsm: fsm.clone(),
                     fsm_payout: fsm_payout.clone(),
+                    clock: clock.clone(),
+                    node: node.clone(),
+                    operator_token: cfg.operator_token.clone(),
+                    jobs: jobs.clone(),
                 })
                 .configure(routing)
                 .wrap(middleware::Logger::new("\"%r\" %s %b %Dms"))
-                .wrap(IdentityService::new(
-                    CookieIdentityPolicy::new(cookie_secret.as_bytes())
-                        .name("auth-example")
-                        .secure(false),
-                ))
-                .wrap(CookieSession::private(cookie_secret.as_bytes()).secure(false));
+                .wrap(HttpMetrics)
+                .wrap(IdentityService::new(SessionIdentityPolicy))
+                .wrap(PgSession::new(pool.clone(), clock.clone()).secure(false));
 
             /*
              * doesn't work yet with actix 1.0
@@ -0,0 +1,306 @@
+//! Prometheus metrics, exposed at `/metrics`.
+//!
+//! Latencies and outcomes are recorded when things happen. Numbers which are
+//! already in the database (transactions per merchant, payout volume, sync lag)
+//! and DB pool usage are read on every scrape, so they survive restarts and
+//! agree between several instances.
+use crate::db::get_sync_status;
+use crate::errors::Error;
+use crate::models::{TransactionStatus, TransactionType};
+use crate::Pool;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::block;
+use bigdecimal::{BigDecimal, ToPrimitive};
+use diesel::dsl::{count_star, sum};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use futures::future::{ok, FutureExt, LocalBoxFuture, Ready};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::future::Future;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+lazy_static! {
+    static ref TRANSACTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "knockturn_transactions",
+        "Number of transactions by merchant, type and status",
+        &["merchant", "type", "status"]
+    )
+    .unwrap();
+    static ref PAYOUT_VOLUME: IntGaugeVec = register_int_gauge_vec!(
+        "knockturn_payout_volume_nanogrins",
+        "Sum of payouts by merchant and status",
+        &["merchant", "status"]
+    )
+    .unwrap();
+    static ref FSM_TRANSITION_SECONDS: HistogramVec = register_histogram_vec!(
+        "knockturn_fsm_transition_seconds",
+        "Time to make a state transition of a payment or a payout",
+        &["transition"]
+    )
+    .unwrap();
+    static ref FSM_TRANSITIONS: IntCounterVec = register_int_counter_vec!(
+        "knockturn_fsm_transitions_total",
+        "State transitions by outcome",
+        &["transition", "result"]
+    )
+    .unwrap();
+    static ref CALLBACKS: IntCounterVec = register_int_counter_vec!(
+        "knockturn_callbacks_total",
+        "Callbacks to merchants by outcome",
+        &["result"]
+    )
+    .unwrap();
+    static ref CALLBACK_ATTEMPTS: HistogramVec = register_histogram_vec!(
+        "knockturn_callback_attempts",
+        "Number of the attempt to report a transaction to the merchant",
+        &["result"],
+        vec![1.0, 2.0, 3.0, 5.0, 8.0, 13.0, 21.0]
+    )
+    .unwrap();
+    static ref API_SECONDS: HistogramVec = register_histogram_vec!(
+        "knockturn_api_call_seconds",
+        "Latency of calls to the wallet and the node",
+        &["api", "method"]
+    )
+    .unwrap();
+    static ref API_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "knockturn_api_call_errors_total",
+        "Failed calls to the wallet and the node",
+        &["api", "method"]
+    )
+    .unwrap();
+    static ref CURRENT_HEIGHT: IntGauge =
+        register_int_gauge!("knockturn_current_height", "Height we synced up to").unwrap();
+    static ref SYNC_LAG: IntGauge =
+        register_int_gauge!("knockturn_sync_lag_blocks", "Blocks we are behind the node").unwrap();
+    static ref DB_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "knockturn_db_pool_connections",
+        "Connections of DB pool by state",
+        &["state"]
+    )
+    .unwrap();
+    static ref HTTP_SECONDS: HistogramVec = register_histogram_vec!(
+        "knockturn_http_request_seconds",
+        "Latency of HTTP requests by route",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+}
+
+fn outcome<T>(res: &Result<T, Error>) -> &'static str {
+    match res {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    }
+}
+
+/// Measures duration and outcome of an FSM transition
+pub trait TrackTransition<T>: Future<Output = Result<T, Error>> + Sized + 'static {
+    fn track_transition(
+        self,
+        transition: &'static str,
+    ) -> LocalBoxFuture<'static, Result<T, Error>> {
+        async move {
+            let timer = FSM_TRANSITION_SECONDS
+                .with_label_values(&[transition])
+                .start_timer();
+            let res = self.await;
+            timer.observe_duration();
+            FSM_TRANSITIONS
+                .with_label_values(&[transition, outcome(&res)])
+                .inc();
+            res
+        }
+        .boxed_local()
+    }
+}
+
+impl<T, F> TrackTransition<T> for F where F: Future<Output = Result<T, Error>> + Sized + 'static {}
+
+/// Measures latency and errors of a call to the wallet or the node
+pub async fn api_call<T, F>(api: &'static str, method: &'static str, call: F) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    let timer = API_SECONDS.with_label_values(&[api, method]).start_timer();
+    let res = call.await;
+    timer.observe_duration();
+    if res.is_err() {
+        API_ERRORS.with_label_values(&[api, method]).inc();
+    }
+    res
+}
+
+/// Records a callback to a merchant, `attempt` starts from 1
+pub fn callback(res: &Result<(), Error>, attempt: i32) {
+    let result = outcome(res);
+    CALLBACKS.with_label_values(&[result]).inc();
+    CALLBACK_ATTEMPTS
+        .with_label_values(&[result])
+        .observe(attempt as f64);
+}
+
+/// Refreshes metrics which are read from the database and returns all of them
+/// in the text format
+pub async fn gather(pool: &Pool) -> Result<String, Error> {
+    block::<_, _, Error>({
+        let pool = pool.clone();
+        move || {
+            let state = pool.state();
+            let conn: &PgConnection = &pool.get().unwrap();
+            DB_CONNECTIONS
+                .with_label_values(&["idle"])
+                .set(state.idle_connections as i64);
+            DB_CONNECTIONS
+                .with_label_values(&["active"])
+                .set((state.connections - state.idle_connections) as i64);
+            update_db_metrics(conn)
+        }
+    })
+    .await?;
+
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|e| Error::General(format!("cannot encode metrics: {}", e)))?;
+    String::from_utf8(buffer).map_err(|e| Error::General(s!(e)))
+}
+
+fn update_db_metrics(conn: &PgConnection) -> Result<(), Error> {
+    use crate::schema::transactions::dsl::*;
+    let counts: Vec<(String, TransactionType, TransactionStatus, i64)> = transactions
+        .group_by((merchant_id, transaction_type, status))
+        .select((merchant_id, transaction_type, status, count_star()))
+        .load(conn)?;
+    // forget merchants and statuses which are gone
+    TRANSACTIONS.reset();
+    for (merchant, tx_type, tx_status, count) in counts {
+        TRANSACTIONS
+            .with_label_values(&[&merchant, &tx_type.to_string(), &tx_status.to_string()])
+            .set(count);
+    }
+
+    let volumes: Vec<(String, TransactionStatus, Option<BigDecimal>)> = transactions
+        .filter(transaction_type.eq(TransactionType::Payout))
+        .group_by((merchant_id, status))
+        .select((merchant_id, status, sum(grin_amount)))
+        .load(conn)?;
+    PAYOUT_VOLUME.reset();
+    for (merchant, tx_status, volume) in volumes {
+        PAYOUT_VOLUME
+            .with_label_values(&[&merchant, &tx_status.to_string()])
+            .set(volume.and_then(|v| v.to_i64()).unwrap_or(0));
+    }
+
+    let sync_status = get_sync_status(conn)?;
+    CURRENT_HEIGHT.set(sync_status.height);
+    SYNC_LAG.set(sync_status.lag());
+    Ok(())
+}
+
+/// Route pattern of the matched resource, e.g. `/merchants/{merchant_id}`,
+/// so that label values don't grow with the number of merchants and transactions
+fn route(res: &ServiceResponse) -> String {
+    let req = res.request();
+    let params = req.match_info();
+    if params.iter().next().is_none() && res.status().as_u16() == 404 {
+        return s!("unmatched");
+    }
+    let mut route = req.path().to_owned();
+    for (name, value) in params.iter() {
+        if !value.is_empty() {
+            route = route.replacen(&format!("/{}", value), &format!("/{{{}}}", name), 1);
+        }
+    }
+    route
+}
+
+/// Middleware which measures latencies of HTTP requests
+pub struct HttpMetrics;
+
+impl<S, B> Transform<S> for HttpMetrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = HttpMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(HttpMetricsMiddleware { service })
+    }
+}
+
+pub struct HttpMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for HttpMetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let fut = self.service.call(req);
+        async move {
+            let res = fut.await?;
+            HTTP_SECONDS
+                .with_label_values(&[&method, &route(&res), res.status().as_str()])
+                .observe(started.elapsed().as_secs_f64());
+            Ok(res)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use actix_web::HttpResponse;
+
+    #[test]
+    fn route_test() {
+        let res = TestRequest::with_uri("/merchants/shop/payments/42/status")
+            .param("merchant_id", "shop")
+            .param("transaction_id", "42")
+            .to_srv_request()
+            .into_response(HttpResponse::Ok().finish());
+        assert_eq!(
+            route(&res),
+            "/merchants/{merchant_id}/payments/{transaction_id}/status"
+        );
+
+        let res = TestRequest::with_uri("/login")
+            .to_srv_request()
+            .into_response(HttpResponse::Ok().finish());
+        assert_eq!(route(&res), "/login");
+
+        let res = TestRequest::with_uri("/no/such/page")
+            .to_srv_request()
+            .into_response(HttpResponse::NotFound().finish());
+        assert_eq!(route(&res), "unmatched");
+    }
+}
@@ -1,5 +1,8 @@
-use crate::schema::{current_height, merchants, rates, status_changes, transactions};
-use chrono::{Duration, NaiveDateTime, Utc};
+use crate::fees::FeeSchedule;
+use crate::schema::{
+    blocks, current_height, ledger_entries, merchants, rates, status_changes, transactions,
+};
+use chrono::{Duration, NaiveDateTime};
 use diesel::deserialize::{self, FromSql};
 use diesel::pg::Pg;
 use diesel::serialize::{self, Output, ToSql};
@@ -17,6 +20,8 @@ pub const NEW_PAYOUT_TTL_SECONDS: i64 = 5 * 60; //5  minutes since creation time
 pub const INITIALIZED_PAYOUT_TTL_SECONDS: i64 = 5 * 60; //5  minutes since creation time
 pub const PENDING_PAYOUT_TTL_SECONDS: i64 = 15 * 60; //15 minutes since became pending
 
+pub const MEMPOOL_GRACE_SECONDS: i64 = 3 * 60; //3 minutes to appear in node's pool since became pending or left it
+
 pub const WAIT_PER_CONFIRMATION_SECONDS: i64 = 5 * 60; // How long we wait per confirmation. E.g. if payment requires 5 confirmations we will wail 5 * WAIT_PER_CONFIRMATION_SECONDS
 
 #[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Identifiable, Clone)]
@@ -33,6 +38,64 @@ pub struct Merchant {
     pub token_2fa: Option<String>,
     #[serde(skip_serializing)]
     pub confirmed_2fa: bool,
+    pub payout_policy: PayoutPolicy,
+    /// Balance which triggers a payout for `PayoutPolicy::Threshold`
+    pub payout_threshold: Option<i64>,
+    pub last_auto_payout_at: Option<NaiveDateTime>,
+    /// Why automatic payouts were switched off
+    pub auto_payout_error: Option<String>,
+    /// Hash of the key which allows to request payouts via API without 2fa code
+    #[serde(skip_serializing)]
+    pub payout_key_hash: Option<String>,
+}
+
+impl Merchant {
+    /// Whether it's time to send `balance` to merchant's wallet automatically,
+    /// `payment_cleared` tells if a payment was cleared since the last payout
+    pub fn is_auto_payout_due(
+        &self,
+        balance: i64,
+        payment_cleared: bool,
+        now: NaiveDateTime,
+    ) -> bool {
+        let since_last_payout = |period: Duration| match self.last_auto_payout_at {
+            Some(last_payout) => now - last_payout >= period,
+            None => true,
+        };
+        match self.payout_policy {
+            PayoutPolicy::Manual => false,
+            PayoutPolicy::Immediate => payment_cleared,
+            PayoutPolicy::Daily => since_last_payout(Duration::days(1)),
+            PayoutPolicy::Weekly => since_last_payout(Duration::weeks(1)),
+            PayoutPolicy::Threshold => match self.payout_threshold {
+                Some(threshold) => balance >= threshold,
+                None => false,
+            },
+        }
+    }
+}
+
+/*
+ * How merchant's balance is withdrawn:
+ * Manual - merchant requests a payout in web UI and signs the slate
+ * Immediate - balance is sent to merchant's wallet after each confirmed and reported payment
+ * Daily, Weekly - balance is sent to merchant's wallet once a day or a week
+ * Threshold - balance is sent to merchant's wallet when it reaches payout_threshold
+ */
+#[derive(Debug, PartialEq, DbEnum, Serialize, Deserialize, Clone, Copy, EnumString, Display)]
+#[DieselType = "Payout_policy"]
+pub enum PayoutPolicy {
+    Manual,
+    Immediate,
+    Daily,
+    Weekly,
+    Threshold,
+}
+
+impl Default for PayoutPolicy {
+    fn default() -> Self {
+        PayoutPolicy::Manual
+    }
 }
 
 /*
@@ -115,18 +178,48 @@ pub struct Transaction {
     #[serde(skip_serializing)]
     pub commit: Option<String>,
     pub redirect_url: Option<String>,
+    #[serde(skip_serializing)]
+    pub wallet_tx_cancelled: bool,
+    #[serde(skip_serializing)]
+    pub cancel_attempts: i32,
+    #[serde(skip_serializing)]
+    pub next_cancel_attempt: Option<NaiveDateTime>,
+    #[serde(skip_serializing)]
+    pub kernel_excess: Option<String>,
+    #[serde(skip_serializing)]
+    pub in_mempool: bool,
+    /// When the transaction appeared in or disappeared from node's pool
+    #[serde(skip_serializing)]
+    pub mempool_changed_at: Option<NaiveDateTime>,
+    /// When we raised an alert that the transaction is stuck
+    #[serde(skip_serializing)]
+    pub stuck_since: Option<NaiveDateTime>,
+    /// Part of the charged transfer fee returned to the merchant because the
+    /// real fee was lower
+    pub transfer_fee_credit: Option<i64>,
+    /// Fee schedule applied to the transaction
+    #[serde(skip_serializing)]
+    pub fee_schedule: Option<FeeSchedule>,
+    /// Commitments of outputs spent by the payout, to check whether they
+    /// are still unspent before the payout expires
+    #[serde(skip_serializing)]
+    pub input_commits: Option<Vec<String>>,
 }
 
 impl Transaction {
-    pub fn is_expired(&self) -> bool {
-        match self.time_until_expired() {
+    pub fn is_expired(&self, now: NaiveDateTime) -> bool {
+        match self.time_until_expired_at(now) {
             Some(time) => time < Duration::zero(),
             None => false,
         }
     }
 
-    pub fn time_until_expired(&self) -> Option<Duration> {
-        let expiration_time = match (self.transaction_type, self.status) {
+    pub fn time_until_expired_at(&self, now: NaiveDateTime) -> Option<Duration> {
+        self.expires_at().map(|exp_time| exp_time - now)
+    }
+
+    pub fn expires_at(&self) -> Option<NaiveDateTime> {
+        match (self.transaction_type, self.status) {
             (TransactionType::Payment, TransactionStatus::New) => {
                 Some(self.created_at + Duration::seconds(NEW_PAYMENT_TTL_SECONDS))
             }
@@ -147,8 +240,7 @@ impl Transaction {
                     + Duration::seconds(self.confirmations * WAIT_PER_CONFIRMATION_SECONDS),
             ),
             (_, _) => None,
-        };
-        expiration_time.map(|exp_time| exp_time - Utc::now().naive_utc())
+        }
     }
 
     pub fn grins(&self) -> Money {
@@ -162,6 +254,29 @@ impl Transaction {
         }
     }
 
+    /// Pending transaction which waits in node's pool for a block
+    pub fn is_in_mempool(&self) -> bool {
+        self.status == TransactionStatus::Pending && self.in_mempool
+    }
+
+    /// Pending transaction which we reported as stuck
+    pub fn is_stuck(&self) -> bool {
+        self.status == TransactionStatus::Pending && self.stuck_since.is_some()
+    }
+
+    /// Pending transaction which is neither in node's pool nor in chain for longer
+    /// than MEMPOOL_GRACE_SECONDS. Probably it was never broadcasted or was evicted
+    pub fn is_missing_from_mempool(&self, now: NaiveDateTime) -> bool {
+        if self.status != TransactionStatus::Pending || self.in_mempool {
+            return false;
+        }
+        let since = match self.mempool_changed_at {
+            Some(changed_at) if changed_at > self.updated_at => changed_at,
+            _ => self.updated_at,
+        };
+        now - since > Duration::seconds(MEMPOOL_GRACE_SECONDS)
+    }
+
     pub fn is_invalid_amount(&self, payment_amount: u64) -> bool {
         let amount = self.grin_amount as u64;
         (payment_amount < amount) || (payment_amount - amount > 1_000_000)
@@ -310,6 +425,24 @@ pub struct Rate {
 #[table_name = "current_height"]
 pub struct CurrentHeight {
     pub height: i64,
+    /// Height of the node's tip when we synced last time
+    pub node_height: i64,
+}
+
+impl CurrentHeight {
+    /// How many blocks we are behind the node
+    pub fn lag(&self) -> i64 {
+        (self.node_height - self.height).max(0)
+    }
+}
+
+/// Recent block of the chain we synced with, used to detect forks
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "blocks"]
+pub struct Block {
+    pub height: i64,
+    pub hash: String,
+    pub previous: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Queryable, Identifiable)]
@@ -321,10 +454,95 @@ pub struct StatusChange {
     pub updated_at: NaiveDateTime,
 }
 
+/*
+ * Why money moved in the ledger:
+ * PaymentReceived - customer sent a payment, it's pending until confirmed and reported
+ * PaymentCleared - confirmed payment became available to merchant
+ * Refund - payment we got although couldn't report to merchant, available to merchant
+ * Fee - knockturn fee of a payment or a payout, goes to the operator
+ * Payout - merchant's money locked for a payout
+ * PayoutSent - payout left our wallet
+ * NetworkFee - transfer fee charged for a payout
+ * FeeCredit - part of transfer fee the wallet didn't spend, returned to merchant
+ * FeeLoss - real transfer fee above the charged one, paid by the operator
+ * Adjustment - manual correction by the operator
+ */
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    DbEnum,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    EnumString,
+    Display,
+)]
+#[DieselType = "Ledger_entry_kind"]
+pub enum LedgerEntryKind {
+    PaymentReceived,
+    PaymentCleared,
+    Refund,
+    Fee,
+    Payout,
+    PayoutSent,
+    NetworkFee,
+    FeeCredit,
+    FeeLoss,
+    Adjustment,
+}
+
+/*
+ * Parts of an account's balance:
+ * Available - may be withdrawn
+ * Pending - payments which are not confirmed yet
+ * Locked - payouts which didn't leave our wallet yet
+ */
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    DbEnum,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    EnumString,
+    Display,
+)]
+#[DieselType = "Balance_bucket"]
+pub enum BalanceBucket {
+    Available,
+    Pending,
+    Locked,
+}
+
+/// Immutable record of money moved to or from an account, positive amount
+/// is received by the account
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Identifiable, Clone)]
+#[table_name = "ledger_entries"]
+pub struct LedgerEntry {
+    pub id: Uuid,
+    pub posting_id: Uuid,
+    pub transaction_id: Option<Uuid>,
+    pub kind: LedgerEntryKind,
+    pub account: String,
+    pub bucket: BalanceBucket,
+    pub amount: i64,
+    pub note: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
 #[cfg(test)]
 mod tests {
 
     use crate::models::*;
+    use chrono::Utc;
     fn create_tx() -> Transaction {
         Transaction {
             id: Uuid::new_v4(),
@@ -351,48 +569,125 @@ mod tests {
             height: None,
             commit: None,
             redirect_url: Some(s!("https://store.cycle42.com")),
+            wallet_tx_cancelled: false,
+            cancel_attempts: 0,
+            next_cancel_attempt: None,
+            kernel_excess: None,
+            in_mempool: false,
+            mempool_changed_at: None,
+            stuck_since: None,
+            transfer_fee_credit: None,
+            fee_schedule: None,
+            input_commits: None,
         }
     }
 
-    fn approximately(expect: i64, real: i64) -> bool {
-        let ratio = expect as f64 / real as f64;
-        ratio > 0.99 && ratio < 1.01
-    }
     #[test]
     fn test_expiration_date() {
         let mut tx = create_tx();
+        let now = tx.created_at;
         tx.status = TransactionStatus::New;
-        assert!(approximately(
-            tx.time_until_expired().unwrap().num_seconds(),
+        assert_eq!(
+            tx.time_until_expired_at(now).unwrap().num_seconds(),
             NEW_PAYMENT_TTL_SECONDS
-        ));
+        );
         tx.status = TransactionStatus::Pending;
-        assert!(approximately(
-            tx.time_until_expired().unwrap().num_seconds(),
+        assert_eq!(
+            tx.time_until_expired_at(now).unwrap().num_seconds(),
             PENDING_PAYMENT_TTL_SECONDS
-        ));
+        );
 
         tx.status = TransactionStatus::Confirmed;
-        assert!(tx.time_until_expired() == None);
+        assert!(tx.time_until_expired_at(now) == None);
 
         tx.transaction_type = TransactionType::Payout;
         tx.status = TransactionStatus::New;
-        assert!(approximately(
-            tx.time_until_expired().unwrap().num_seconds(),
+        assert_eq!(
+            tx.time_until_expired_at(now).unwrap().num_seconds(),
             NEW_PAYOUT_TTL_SECONDS
-        ));
+        );
         tx.status = TransactionStatus::Initialized;
-        assert!(approximately(
-            tx.time_until_expired().unwrap().num_seconds(),
+        assert_eq!(
+            tx.time_until_expired_at(now).unwrap().num_seconds(),
             INITIALIZED_PAYOUT_TTL_SECONDS
-        ));
+        );
         tx.status = TransactionStatus::Pending;
-        assert!(approximately(
-            tx.time_until_expired().unwrap().num_seconds(),
+        assert_eq!(
+            tx.time_until_expired_at(now).unwrap().num_seconds(),
             PENDING_PAYOUT_TTL_SECONDS
-        ));
+        );
         tx.status = TransactionStatus::Confirmed;
-        assert!(tx.time_until_expired() == None);
+        assert!(tx.time_until_expired_at(now) == None);
+    }
+
+    #[test]
+    fn test_is_expired() {
+        let tx = create_tx();
+        let now = tx.created_at;
+        assert!(!tx.is_expired(now));
+        assert!(!tx.is_expired(now + Duration::seconds(NEW_PAYMENT_TTL_SECONDS)));
+        assert!(tx.is_expired(now + Duration::seconds(NEW_PAYMENT_TTL_SECONDS + 1)));
+    }
+
+    #[test]
+    fn test_is_missing_from_mempool() {
+        let mut tx = create_tx();
+        let now = tx.updated_at;
+        let grace = Duration::seconds(MEMPOOL_GRACE_SECONDS + 1);
+        assert!(!tx.is_missing_from_mempool(now + grace));
+        tx.status = TransactionStatus::Pending;
+        assert!(!tx.is_missing_from_mempool(now));
+        assert!(tx.is_missing_from_mempool(now + grace));
+        tx.in_mempool = true;
+        assert!(!tx.is_missing_from_mempool(now + grace));
+        // left the pool, but not in chain yet
+        tx.in_mempool = false;
+        tx.mempool_changed_at = Some(now + grace);
+        assert!(!tx.is_missing_from_mempool(now + grace));
+        assert!(tx.is_missing_from_mempool(now + grace + grace));
+    }
+
+    #[test]
+    fn test_is_auto_payout_due() {
+        let now = Utc::now().naive_utc();
+        let mut merchant = Merchant {
+            id: s!("merchant"),
+            email: s!("merchant@example.com"),
+            password: s!(""),
+            wallet_url: Some(s!("http://wallet.example.com")),
+            created_at: now,
+            token: s!(""),
+            callback_url: None,
+            token_2fa: None,
+            confirmed_2fa: false,
+            payout_policy: PayoutPolicy::Manual,
+            payout_threshold: None,
+            last_auto_payout_at: None,
+            auto_payout_error: None,
+            payout_key_hash: None,
+        };
+        assert!(!merchant.is_auto_payout_due(1_000_000_000, true, now));
+
+        merchant.payout_policy = PayoutPolicy::Immediate;
+        assert!(merchant.is_auto_payout_due(1_000_000_000, true, now));
+        // e.g. a refund or a fee credit
+        assert!(!merchant.is_auto_payout_due(1_000_000_000, false, now));
+
+        merchant.payout_policy = PayoutPolicy::Daily;
+        assert!(merchant.is_auto_payout_due(1_000_000_000, false, now));
+        merchant.last_auto_payout_at = Some(now - Duration::hours(23));
+        assert!(!merchant.is_auto_payout_due(1_000_000_000, false, now));
+        assert!(merchant.is_auto_payout_due(1_000_000_000, false, now + Duration::hours(1)));
+
+        merchant.payout_policy = PayoutPolicy::Weekly;
+        assert!(!merchant.is_auto_payout_due(1_000_000_000, false, now + Duration::days(1)));
+        assert!(merchant.is_auto_payout_due(1_000_000_000, false, now + Duration::days(7)));
+
+        merchant.payout_policy = PayoutPolicy::Threshold;
+        assert!(!merchant.is_auto_payout_due(1_000_000_000, false, now));
+        merchant.payout_threshold = Some(5_000_000_000);
+        assert!(!merchant.is_auto_payout_due(1_000_000_000, false, now));
+        assert!(merchant.is_auto_payout_due(5_000_000_000, false, now));
     }
 
     #[test]
@@ -1,17 +1,58 @@
 use crate::errors::Error;
+use crate::metrics;
 use actix_web::client::{Client, Connector};
+use actix_web::http::StatusCode;
+use futures::future::FutureExt;
 use log::{debug, error};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::from_slice;
-use std::str::from_utf8;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::{from_utf8, FromStr};
 use std::time::Duration;
 
+pub type NodeFuture<T> = Pin<Box<dyn Future<Output = Result<T, Error>>>>;
+
+/// Operations we need from a grin node. Implemented by `Node` and by
+/// `fakes::FakeNode` which is used in tests.
+pub trait NodeApi: Send + Sync {
+    /// Blocks with their outputs in range [start, end]
+    fn blocks(&self, start: i64, end: i64) -> NodeFuture<Vec<Block>>;
+    fn current_height(&self) -> NodeFuture<i64>;
+    /// Height of the block in range [min_height, max_height] which contains kernel with `excess`
+    fn kernel(&self, excess: &str, min_height: i64, max_height: i64) -> NodeFuture<Option<i64>>;
+    /// Unspent outputs with given commitments and heights of blocks which contain them
+    fn outputs(&self, commits: &[String]) -> NodeFuture<Vec<LocatedOutput>>;
+    /// Transactions in the node's pool, None if the API doesn't expose them
+    fn unconfirmed_transactions(&self) -> NodeFuture<Option<Vec<PoolTransaction>>>;
+}
+
+/// Which API of the node to use: legacy REST (v1) or foreign JSON-RPC (v2)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeApiVersion {
+    V1,
+    V2,
+}
+
+impl FromStr for NodeApiVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "v1" | "1" => Ok(NodeApiVersion::V1),
+            "v2" | "2" => Ok(NodeApiVersion::V2),
+            _ => Err(Error::General(format!("Unknown node API version {}", s))),
+        }
+    }
+}
+
 const CHAIN_OUTPUTS_BY_HEIGHT: &'static str = "v1/chain/outputs/byheight";
 const GET_STATUS_URL: &'static str = "v1/status";
+const CHAIN_KERNELS: &'static str = "v1/chain/kernels";
+const CHAIN_OUTPUTS_BY_IDS: &'static str = "v1/chain/outputs/byids";
 
 #[derive(Clone)]
 pub struct Node {
-    client: Client,
     username: String,
     password: String,
     url: String,
@@ -19,18 +60,22 @@ pub struct Node {
 
 impl Node {
     pub fn new(url: &str, username: &str, password: &str) -> Self {
-        let connector = Connector::new()
-            .conn_lifetime(Duration::from_secs(300))
-            .conn_keep_alive(Duration::from_secs(300))
-            .finish();
         Node {
             url: url.trim_end_matches('/').to_owned(),
             username: username.to_owned(),
             password: password.to_owned(),
-            client: Client::build().connector(connector).finish(),
         }
     }
 
+    /// Client can't be shared between threads, every request gets its own
+    fn client(&self) -> Client {
+        let connector = Connector::new()
+            .conn_lifetime(Duration::from_secs(300))
+            .conn_keep_alive(Duration::from_secs(300))
+            .finish();
+        Client::build().connector(connector).finish()
+    }
+
     pub async fn blocks(&self, start: i64, end: i64) -> Result<Vec<Block>, Error> {
         let url = format!(
             "{}/{}?start_height={}&end_height={}",
@@ -38,7 +83,7 @@ impl Node {
         );
         debug!("Get latest blocks from node {}", url);
         let mut resp = self
-            .client
+            .client()
             .get(&url) // <- Create request builder
             .basic_auth(&self.username, Some(&self.password))
             .send() // <- Send http request
@@ -71,7 +116,7 @@ impl Node {
         debug!("Get current height from the node {}", url);
 
         let mut resp = self
-            .client
+            .client()
             .get(&url) // <- Create request builder
             .basic_auth(&self.username, Some(&self.password))
             .send() // <- Send http request
@@ -99,6 +144,132 @@ impl Node {
         })?;
         Ok(status.tip.height)
     }
+
+    pub async fn kernel(
+        &self,
+        excess: &str,
+        min_height: i64,
+        max_height: i64,
+    ) -> Result<Option<i64>, Error> {
+        let url = format!(
+            "{}/{}/{}?min_height={}&max_height={}",
+            self.url, CHAIN_KERNELS, excess, min_height, max_height
+        );
+        debug!("Look up kernel on the node {}", url);
+
+        let mut resp = self
+            .client()
+            .get(&url)
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .await
+            .map_err(|e| Error::NodeAPIError(s!(e)))?;
+
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(Error::NodeAPIError(format!("Error status: {:?}", resp)));
+        }
+
+        let bytes = resp
+            .body()
+            .limit(10 * 1024 * 1024)
+            .await
+            .map_err(|e| Error::NodeAPIError(s!(e)))?;
+
+        let kernel: Option<LocatedTxKernel> = from_slice(&bytes).map_err(|e| {
+            error!(
+                "Cannot decode json {:?}:\n with error {} ",
+                from_utf8(&bytes),
+                e
+            );
+            Error::NodeAPIError(format!("Cannot decode json {}", e))
+        })?;
+        Ok(kernel.map(|kernel| kernel.height as i64))
+    }
+
+    pub async fn outputs(&self, commits: &[String]) -> Result<Vec<LocatedOutput>, Error> {
+        if commits.is_empty() {
+            return Ok(vec![]);
+        }
+        let url = format!(
+            "{}/{}?id={}",
+            self.url,
+            CHAIN_OUTPUTS_BY_IDS,
+            commits.join(",")
+        );
+        debug!("Look up {} outputs on the node", commits.len());
+
+        let mut resp = self
+            .client()
+            .get(&url)
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .await
+            .map_err(|e| Error::NodeAPIError(s!(e)))?;
+
+        if !resp.status().is_success() {
+            return Err(Error::NodeAPIError(format!("Error status: {:?}", resp)));
+        }
+
+        let bytes = resp
+            .body()
+            .limit(10 * 1024 * 1024)
+            .await
+            .map_err(|e| Error::NodeAPIError(s!(e)))?;
+
+        let outputs: Vec<LocatedOutput> = from_slice(&bytes).map_err(|e| {
+            error!(
+                "Cannot decode json {:?}:\n with error {} ",
+                from_utf8(&bytes),
+                e
+            );
+            Error::NodeAPIError(format!("Cannot decode json {}", e))
+        })?;
+        Ok(outputs)
+    }
+}
+
+impl NodeApi for Node {
+    fn blocks(&self, start: i64, end: i64) -> NodeFuture<Vec<Block>> {
+        let node = self.clone();
+        metrics::api_call("node", "blocks", async move {
+            Node::blocks(&node, start, end).await
+        })
+        .boxed_local()
+    }
+
+    fn current_height(&self) -> NodeFuture<i64> {
+        let node = self.clone();
+        metrics::api_call("node", "current_height", async move {
+            Node::current_height(&node).await
+        })
+        .boxed_local()
+    }
+
+    fn kernel(&self, excess: &str, min_height: i64, max_height: i64) -> NodeFuture<Option<i64>> {
+        let node = self.clone();
+        let excess = excess.to_owned();
+        metrics::api_call("node", "kernel", async move {
+            Node::kernel(&node, &excess, min_height, max_height).await
+        })
+        .boxed_local()
+    }
+
+    fn outputs(&self, commits: &[String]) -> NodeFuture<Vec<LocatedOutput>> {
+        let node = self.clone();
+        let commits = commits.to_vec();
+        metrics::api_call("node", "outputs", async move {
+            Node::outputs(&node, &commits).await
+        })
+        .boxed_local()
+    }
+
+    /// REST API v1 tells only size of the pool
+    fn unconfirmed_transactions(&self) -> NodeFuture<Option<Vec<PoolTransaction>>> {
+        async { Ok(None) }.boxed_local()
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -111,18 +282,42 @@ pub struct Status {
     pub tip: Tip,
 }
 
+/// Kernel found in the chain
 #[derive(Deserialize, Debug)]
+pub struct LocatedTxKernel {
+    pub height: u64,
+    pub mmr_index: u64,
+}
+
+/// Unspent output found in the chain
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocatedOutput {
+    pub commit: String,
+    pub height: u64,
+    pub mmr_index: u64,
+}
+
+/// Transaction waiting in the node's pool
+#[derive(Debug, Clone)]
+pub struct PoolTransaction {
+    pub kernels: Vec<String>,
+    pub outputs: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
     pub header: Header,
     pub outputs: Vec<Output>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header {
+    pub hash: String,
     pub height: u64,
+    pub previous: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Output {
     pub output_type: String,
     pub commit: String,
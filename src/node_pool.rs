@@ -0,0 +1,313 @@
+//! Several grin nodes behind one `NodeApi`.
+//!
+//! Requests go to the healthiest node and fail over to the next one on error.
+//! A node is considered stale if its tip is too far behind the others. With
+//! quorum greater than one the current height is the highest one which enough
+//! nodes agree on, both by height and by block hash, so a single misbehaving
+//! node can't move `current_height` forward on its own.
+use crate::errors::Error;
+use crate::node::{
+    Block, LocatedOutput, Node, NodeApi, NodeApiVersion, NodeFuture, PoolTransaction,
+};
+use crate::node_v2::NodeV2;
+use futures::future::{join_all, FutureExt};
+use log::*;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const STALE_TIP_BLOCKS: i64 = 5; // node is stale if its tip is that many blocks behind the best one
+
+#[derive(Debug, Default)]
+struct Health {
+    /// Failed requests in a row
+    failures: u32,
+    tip: Option<i64>,
+    stale: bool,
+}
+
+struct Endpoint {
+    url: String,
+    node: Arc<dyn NodeApi>,
+    health: Mutex<Health>,
+}
+
+#[derive(Clone)]
+pub struct NodePool {
+    endpoints: Arc<Vec<Endpoint>>,
+    quorum: usize,
+}
+
+impl NodePool {
+    pub fn new(nodes: Vec<(String, Arc<dyn NodeApi>)>, quorum: usize) -> Self {
+        assert!(!nodes.is_empty(), "at least one node is required");
+        assert!(
+            quorum >= 1 && quorum <= nodes.len(),
+            "quorum must be between 1 and number of nodes"
+        );
+        let endpoints = nodes
+            .into_iter()
+            .map(|(url, node)| Endpoint {
+                url,
+                node,
+                health: Mutex::new(Health::default()),
+            })
+            .collect();
+        NodePool {
+            endpoints: Arc::new(endpoints),
+            quorum,
+        }
+    }
+
+    /// Pool of nodes with comma separated `urls` and the same credentials
+    pub fn connect(
+        urls: &str,
+        username: &str,
+        password: &str,
+        version: NodeApiVersion,
+        quorum: usize,
+    ) -> Self {
+        let nodes = urls
+            .split(',')
+            .map(|url| url.trim())
+            .filter(|url| !url.is_empty())
+            .map(|url| {
+                let node: Arc<dyn NodeApi> = match version {
+                    NodeApiVersion::V1 => Arc::new(Node::new(url, username, password)),
+                    NodeApiVersion::V2 => Arc::new(NodeV2::new(url, username, password)),
+                };
+                (url.to_owned(), node)
+            })
+            .collect();
+        NodePool::new(nodes, quorum)
+    }
+
+    /// Indexes of endpoints, the healthiest first. Nodes listed first
+    /// in configuration are preferred among equally healthy ones
+    fn ordered(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.endpoints.len()).collect();
+        order.sort_by_key(|i| {
+            let health = self.endpoints[*i].health.lock();
+            (health.stale, health.failures)
+        });
+        order
+    }
+
+    /// Runs request on the healthiest node, on error tries the next one
+    async fn with_failover<T, F>(self, request: F) -> Result<T, Error>
+    where
+        F: Fn(&dyn NodeApi) -> NodeFuture<T>,
+    {
+        let mut last_error = None;
+        for i in self.ordered() {
+            let endpoint = &self.endpoints[i];
+            match request(endpoint.node.as_ref()).await {
+                Ok(result) => {
+                    endpoint.health.lock().failures = 0;
+                    return Ok(result);
+                }
+                Err(e) => {
+                    warn!("Node {} failed, try the next one: {}", endpoint.url, e);
+                    endpoint.health.lock().failures += 1;
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    async fn quorum_height(self) -> Result<i64, Error> {
+        let tips = join_all(self.endpoints.iter().map(|e| e.node.current_height())).await;
+        for (endpoint, tip) in self.endpoints.iter().zip(&tips) {
+            let mut health = endpoint.health.lock();
+            match tip {
+                Ok(tip) => {
+                    health.failures = 0;
+                    health.tip = Some(*tip);
+                }
+                Err(e) => {
+                    warn!("Cannot get tip of node {}: {}", endpoint.url, e);
+                    health.failures += 1;
+                    health.tip = None;
+                }
+            }
+        }
+        let mut heights: Vec<i64> = tips
+            .iter()
+            .filter_map(|tip| tip.as_ref().ok())
+            .cloned()
+            .collect();
+        heights.sort_by(|a, b| b.cmp(a));
+        let best = heights.first().cloned();
+        for endpoint in self.endpoints.iter() {
+            let mut health = endpoint.health.lock();
+            health.stale = match (health.tip, best) {
+                (Some(tip), Some(best)) => best - tip > STALE_TIP_BLOCKS,
+                _ => true,
+            };
+        }
+        if heights.len() < self.quorum {
+            return Err(Error::NodeAPIError(format!(
+                "only {} of {} nodes respond, quorum is {}",
+                heights.len(),
+                self.endpoints.len(),
+                self.quorum
+            )));
+        }
+        let height = heights[self.quorum - 1];
+        if self.quorum > 1 {
+            self.check_hashes(height).await?;
+        }
+        Ok(height)
+    }
+
+    /// Checks that at least quorum of nodes have the same block at `height`.
+    /// Nodes on another branch are marked stale
+    async fn check_hashes(&self, height: i64) -> Result<(), Error> {
+        let candidates: Vec<&Endpoint> = self
+            .endpoints
+            .iter()
+            .filter(|e| e.health.lock().tip.map(|tip| tip >= height) == Some(true))
+            .collect();
+        let blocks = join_all(candidates.iter().map(|e| e.node.blocks(height, height))).await;
+        let hashes: Vec<Option<String>> = blocks
+            .into_iter()
+            .map(|res| {
+                res.ok()
+                    .and_then(|blocks| blocks.into_iter().next())
+                    .map(|b| b.header.hash)
+            })
+            .collect();
+        let mut votes: HashMap<&String, usize> = HashMap::new();
+        for hash in hashes.iter().flatten() {
+            *votes.entry(hash).or_insert(0) += 1;
+        }
+        let (agreed, count) = match votes.into_iter().max_by_key(|(_, count)| *count) {
+            Some(vote) => vote,
+            None => {
+                return Err(Error::NodeAPIError(format!(
+                    "no node returned block {}",
+                    height
+                )))
+            }
+        };
+        if count < self.quorum {
+            return Err(Error::NodeAPIError(format!(
+                "nodes disagree on block {}, only {} of them agree",
+                height, count
+            )));
+        }
+        for (endpoint, hash) in candidates.iter().zip(&hashes) {
+            if hash.as_ref() != Some(agreed) {
+                warn!(
+                    "Node {} is on another branch at height {}",
+                    endpoint.url, height
+                );
+                endpoint.health.lock().stale = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl NodeApi for NodePool {
+    fn blocks(&self, start: i64, end: i64) -> NodeFuture<Vec<Block>> {
+        self.clone()
+            .with_failover(move |node| node.blocks(start, end))
+            .boxed_local()
+    }
+
+    /// Height which quorum of nodes reached
+    fn current_height(&self) -> NodeFuture<i64> {
+        self.clone().quorum_height().boxed_local()
+    }
+
+    fn kernel(&self, excess: &str, min_height: i64, max_height: i64) -> NodeFuture<Option<i64>> {
+        let excess = excess.to_owned();
+        self.clone()
+            .with_failover(move |node| node.kernel(&excess, min_height, max_height))
+            .boxed_local()
+    }
+
+    fn outputs(&self, commits: &[String]) -> NodeFuture<Vec<LocatedOutput>> {
+        let commits = commits.to_vec();
+        self.clone()
+            .with_failover(move |node| node.outputs(&commits))
+            .boxed_local()
+    }
+
+    fn unconfirmed_transactions(&self) -> NodeFuture<Option<Vec<PoolTransaction>>> {
+        self.clone()
+            .with_failover(|node| node.unconfirmed_transactions())
+            .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fakes::FakeNode;
+    use futures::executor::block_on;
+
+    fn pool(nodes: &[FakeNode], quorum: usize) -> NodePool {
+        let nodes = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let node: Arc<dyn NodeApi> = Arc::new(node.clone());
+                (format!("node{}", i), node)
+            })
+            .collect();
+        NodePool::new(nodes, quorum)
+    }
+
+    #[test]
+    fn failover_test() {
+        let primary = FakeNode::new(10);
+        let backup = FakeNode::new(10);
+        let pool = pool(&[primary.clone(), backup.clone()], 1);
+        primary.mine_blocks(2);
+        backup.mine_blocks(1);
+        assert_eq!(block_on(pool.current_height()).unwrap(), 12);
+        assert_eq!(block_on(pool.blocks(12, 12)).unwrap().len(), 1);
+
+        primary.set_offline(true);
+        assert_eq!(block_on(pool.current_height()).unwrap(), 11);
+        assert_eq!(block_on(pool.blocks(11, 12)).unwrap().len(), 1);
+
+        backup.set_offline(true);
+        assert!(block_on(pool.current_height()).is_err());
+        assert!(block_on(pool.blocks(11, 12)).is_err());
+    }
+
+    #[test]
+    fn stale_node_test() {
+        let primary = FakeNode::new(10);
+        let backup = FakeNode::new(10);
+        let pool = pool(&[primary.clone(), backup.clone()], 1);
+        backup.mine_blocks(STALE_TIP_BLOCKS + 1);
+        assert_eq!(block_on(pool.current_height()).unwrap(), 16);
+        // primary is stale, so blocks come from backup
+        assert_eq!(block_on(pool.blocks(11, 16)).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn quorum_test() {
+        let nodes = [FakeNode::new(10), FakeNode::new(10), FakeNode::new(10)];
+        let pool = pool(&nodes, 2);
+        assert_eq!(block_on(pool.current_height()).unwrap(), 10);
+
+        // a single node can't move height forward
+        nodes[0].mine_blocks(3);
+        assert_eq!(block_on(pool.current_height()).unwrap(), 10);
+
+        nodes[1].set_offline(true);
+        nodes[2].set_offline(true);
+        assert!(block_on(pool.current_height()).is_err());
+
+        // nodes mined different blocks at the same height
+        nodes[1].set_offline(false);
+        nodes[1].mine_blocks(3);
+        assert!(block_on(pool.current_height()).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+//! Client of the foreign JSON-RPC v2 API of grin node.
+//! See: https://docs.rs/grin_api/latest/grin_api/foreign_rpc/trait.ForeignRpc.html
+use crate::errors::Error;
+use crate::jsonrpc;
+use crate::metrics;
+use crate::node::{Block, LocatedOutput, LocatedTxKernel, NodeApi, NodeFuture, PoolTransaction};
+use crate::ser;
+use crate::wallet::Transaction;
+use actix_web::client::{Client, Connector};
+use chrono::{DateTime, Utc};
+use futures::future::FutureExt;
+use log::{debug, error};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{from_slice, json, Value};
+use std::str::from_utf8;
+use std::time::Duration;
+
+const JSONRPC_FOREIGN_URL: &'static str = "v2/foreign";
+const MAX_BLOCKS_PER_REQUEST: i64 = 1000; // node refuses to return more at once
+
+#[derive(Clone)]
+pub struct NodeV2 {
+    username: String,
+    password: String,
+    url: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Tip {
+    pub height: i64,
+    pub last_block_pushed: String,
+    pub prev_block_to_last: String,
+    pub total_difficulty: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BlockListing {
+    pub last_retrieved_height: u64,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OutputPrintable {
+    pub commit: String,
+    pub block_height: Option<u64>,
+    pub mmr_index: u64,
+}
+
+/// Transaction in the node's pool
+#[derive(Deserialize, Debug)]
+pub struct PoolEntry {
+    pub src: Value,
+    pub tx_at: DateTime<Utc>,
+    pub tx: Transaction,
+}
+
+impl NodeV2 {
+    pub fn new(url: &str, username: &str, password: &str) -> Self {
+        NodeV2 {
+            url: url.trim_end_matches('/').to_owned(),
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+    }
+
+    /// Client can't be shared between threads, every request gets its own
+    fn client(&self) -> Client {
+        let connector = Connector::new()
+            .conn_lifetime(Duration::from_secs(300))
+            .conn_keep_alive(Duration::from_secs(300))
+            .finish();
+        Client::build().connector(connector).finish()
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<jsonrpc::Response, Error> {
+        let url = format!("{}/{}", self.url, JSONRPC_FOREIGN_URL);
+        debug!("Send jsonrpc request {} to node {}", method, url);
+        let req = jsonrpc::Request::new(method, params);
+
+        let mut resp = self
+            .client()
+            .post(&url)
+            .basic_auth(&self.username, Some(&self.password))
+            .send_json(&req)
+            .await
+            .map_err(|e| Error::NodeAPIError(s!(e)))?;
+
+        if !resp.status().is_success() {
+            return Err(Error::NodeAPIError(format!("Error status: {:?}", resp)));
+        }
+
+        let bytes = resp
+            .body()
+            .limit(10 * 1024 * 1024)
+            .await
+            .map_err(|e| Error::NodeAPIError(s!(e)))?;
+
+        from_slice(&bytes).map_err(|e| {
+            error!(
+                "Cannot decode json {:?}:\n with error {} ",
+                from_utf8(&bytes),
+                e
+            );
+            Error::NodeAPIError(format!("Cannot decode json {}", e))
+        })
+    }
+
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T, Error> {
+        let resp = self.request(method, params).await?;
+        jsonrpc::TypedResponse::new(resp).into_result_with(Error::NodeAPIError)
+    }
+
+    pub async fn get_tip(&self) -> Result<Tip, Error> {
+        self.call("get_tip", json!([])).await
+    }
+
+    /// Blocks in range [start, end]
+    pub async fn get_blocks(&self, start: i64, end: i64) -> Result<Vec<Block>, Error> {
+        let mut blocks = vec![];
+        let mut start = start.max(0);
+        while start <= end {
+            let listing: BlockListing = self
+                .call(
+                    "get_blocks",
+                    json!([start, end, MAX_BLOCKS_PER_REQUEST, false]),
+                )
+                .await?;
+            if listing.blocks.is_empty() {
+                break;
+            }
+            blocks.extend(listing.blocks);
+            start = listing.last_retrieved_height as i64 + 1;
+        }
+        Ok(blocks)
+    }
+
+    pub async fn get_kernel(
+        &self,
+        excess: &str,
+        min_height: i64,
+        max_height: i64,
+    ) -> Result<Option<LocatedTxKernel>, Error> {
+        let resp = self
+            .request("get_kernel", json!([excess, min_height, max_height]))
+            .await?;
+        if resp
+            .result
+            .get("Err")
+            .and_then(|e| e.get("NotFound"))
+            .is_some()
+        {
+            return Ok(None);
+        }
+        jsonrpc::TypedResponse::new(resp)
+            .into_result_with(Error::NodeAPIError)
+            .map(Some)
+    }
+
+    pub async fn get_outputs(&self, commits: &[String]) -> Result<Vec<OutputPrintable>, Error> {
+        if commits.is_empty() {
+            return Ok(vec![]);
+        }
+        self.call("get_outputs", json!([commits, null, null, false, false]))
+            .await
+    }
+
+    pub async fn get_unconfirmed_transactions(&self) -> Result<Vec<PoolEntry>, Error> {
+        self.call("get_unconfirmed_transactions", json!([])).await
+    }
+}
+
+impl NodeApi for NodeV2 {
+    fn blocks(&self, start: i64, end: i64) -> NodeFuture<Vec<Block>> {
+        let node = self.clone();
+        metrics::api_call("node", "blocks", async move {
+            node.get_blocks(start, end).await
+        })
+        .boxed_local()
+    }
+
+    fn current_height(&self) -> NodeFuture<i64> {
+        let node = self.clone();
+        metrics::api_call("node", "current_height", async move {
+            Ok(node.get_tip().await?.height)
+        })
+        .boxed_local()
+    }
+
+    fn kernel(&self, excess: &str, min_height: i64, max_height: i64) -> NodeFuture<Option<i64>> {
+        let node = self.clone();
+        let excess = excess.to_owned();
+        metrics::api_call("node", "kernel", async move {
+            let kernel = node.get_kernel(&excess, min_height, max_height).await?;
+            Ok(kernel.map(|kernel| kernel.height as i64))
+        })
+        .boxed_local()
+    }
+
+    fn outputs(&self, commits: &[String]) -> NodeFuture<Vec<LocatedOutput>> {
+        let node = self.clone();
+        let commits = commits.to_vec();
+        metrics::api_call("node", "outputs", async move {
+            let outputs = node.get_outputs(&commits).await?;
+            Ok(outputs
+                .into_iter()
+                .filter_map(|o| {
+                    o.block_height.map(|height| LocatedOutput {
+                        commit: o.commit,
+                        height,
+                        mmr_index: o.mmr_index,
+                    })
+                })
+                .collect())
+        })
+        .boxed_local()
+    }
+
+    fn unconfirmed_transactions(&self) -> NodeFuture<Option<Vec<PoolTransaction>>> {
+        let node = self.clone();
+        metrics::api_call("node", "unconfirmed_transactions", async move {
+            let entries = node.get_unconfirmed_transactions().await?;
+            Ok(Some(
+                entries
+                    .into_iter()
+                    .map(|entry| PoolTransaction {
+                        kernels: entry
+                            .tx
+                            .body
+                            .kernels
+                            .iter()
+                            .map(|kernel| ser::to_hex(kernel.excess.clone()))
+                            .collect(),
+                        outputs: entry
+                            .tx
+                            .output_commitments()
+                            .into_iter()
+                            .map(ser::to_hex)
+                            .collect(),
+                    })
+                    .collect(),
+            ))
+        })
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_responses_test() {
+        let resp: jsonrpc::Response = serde_json::from_value(json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "result": {
+                "Ok": {
+                    "height": 436944,
+                    "last_block_pushed": "00000b4a5b8a3fd0f84d0a7a3a78eee6ebf5b4f4ac5386cd66d1eba5c4cd5d28",
+                    "prev_block_to_last": "000033b8ee9cc5b5d9ba8fd7a9e96de1e7a71ae1ce3d6e1a5e8e2a3b6f0e3cd8",
+                    "total_difficulty": 1127628411943045u64
+                }
+            }
+        }))
+        .unwrap();
+        let tip: Tip = jsonrpc::TypedResponse::new(resp)
+            .into_result_with(Error::NodeAPIError)
+            .unwrap();
+        assert_eq!(tip.height, 436944);
+
+        let resp: jsonrpc::Response = serde_json::from_value(json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "result": {
+                "Ok": {
+                    "last_retrieved_height": 2,
+                    "blocks": [{
+                        "header": {
+                            "hash": "0e9e3f3d3b5fd6a5e1bc61a5a8df8f1b0a3a2bd4c65c07fa30e1a60a1d4a3b2e",
+                            "height": 2,
+                            "previous": "1d4ab68f8d7ae4e32116b0a84a2046a1f737f1f8431aa7b934248b4a58a0b14c",
+                            "version": 1
+                        },
+                        "inputs": [],
+                        "outputs": [{
+                            "output_type": "Coinbase",
+                            "commit": "0808d9594ac88429049238fcce5bf69944e4c05f87ad318c56b776076de630d46c",
+                            "spent": false,
+                            "proof": null,
+                            "proof_hash": "16519ea0616790dbe3c7acc6f3e40aa0e776106cad02e20f94a003a646d55bd9",
+                            "block_height": 2,
+                            "merkle_proof": null,
+                            "mmr_index": 3
+                        }],
+                        "kernels": []
+                    }]
+                }
+            }
+        }))
+        .unwrap();
+        let listing: BlockListing = jsonrpc::TypedResponse::new(resp)
+            .into_result_with(Error::NodeAPIError)
+            .unwrap();
+        assert_eq!(listing.blocks[0].header.height, 2);
+        assert!(listing.blocks[0].outputs[0].is_coinbase());
+
+        let resp: jsonrpc::Response = serde_json::from_value(json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "result": {"Err": {"Internal": "node is syncing"}}
+        }))
+        .unwrap();
+        match jsonrpc::TypedResponse::<Tip>::new(resp).into_result_with(Error::NodeAPIError) {
+            Err(Error::NodeAPIError(_)) => (),
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+}
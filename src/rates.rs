@@ -1,3 +1,4 @@
+use crate::clock::Clock;
 use crate::db::register_rate;
 use crate::errors::Error;
 use crate::Pool;
@@ -9,6 +10,7 @@ use serde::Deserialize;
 use serde_json;
 use std::collections::HashMap;
 use std::str;
+use std::sync::Arc;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Rates {
@@ -18,11 +20,12 @@ pub struct Rates {
 #[derive(Clone)]
 pub struct RatesFetcher {
     pool: Pool,
+    clock: Arc<dyn Clock>,
 }
 
 impl RatesFetcher {
-    pub fn new(pool: Pool) -> Self {
-        RatesFetcher { pool }
+    pub fn new(pool: Pool, clock: Arc<dyn Clock>) -> Self {
+        RatesFetcher { pool, clock }
     }
 
     pub async fn fetch(&self) -> Result<Rates, Error> {
@@ -48,9 +51,10 @@ impl RatesFetcher {
 
         block::<_, _, Error>({
             let rates = rates.clone();
+            let now = self.clock.now();
             move || {
                 let conn: &PgConnection = &pool.get().unwrap();
-                register_rate(rates.grin, conn)
+                register_rate(rates.grin, now, conn)
             }
         })
         .await
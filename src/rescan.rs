@@ -0,0 +1,187 @@
+//! Operator's tool to re-check a range of blocks.
+//!
+//! Regular sync only moves forward, so a transaction missed because of a
+//! misbehaving node stays pending or rejected forever. Rescan looks for
+//! pending and rejected transactions in the given blocks and reports which of
+//! them would change state. Transitions are applied through the state machines
+//! only when the operator confirms them.
+use crate::cron::{find_kernels, HORIZON_HEIGHT};
+use crate::db::get_watched_transactions;
+use crate::errors::Error;
+use crate::fsm::{Fsm, Payment, PendingPayment, RejectedPayment, SeenInChainPayment};
+use crate::fsm_payout::{FsmPayout, GetPendingPayouts, SeenInChainPayout};
+use crate::models::{Transaction, TransactionStatus, TransactionType};
+use crate::node::NodeApi;
+use crate::Pool;
+use actix::Addr;
+use actix_web::web::block;
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+pub const MAX_RESCAN_BLOCKS: i64 = HORIZON_HEIGHT;
+const BLOCKS_PER_REQUEST: i64 = 100;
+
+/// Transaction found in rescanned blocks
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RescanChange {
+    pub transaction_id: Uuid,
+    pub merchant_id: String,
+    pub transaction_type: TransactionType,
+    pub status: TransactionStatus,
+    /// None if there is no automatic transition, e.g. for a rejected payout
+    pub new_status: Option<TransactionStatus>,
+    pub height: i64,
+    pub applied: bool,
+}
+
+/// Looks up pending and rejected transactions in blocks [from_height, to_height]
+pub async fn find_changes(
+    node: &dyn NodeApi,
+    pool: &Pool,
+    from_height: i64,
+    to_height: i64,
+) -> Result<Vec<RescanChange>, Error> {
+    if from_height > to_height || from_height < 0 {
+        return Err(Error::InvalidEntity(format!(
+            "wrong height range {}-{}",
+            from_height, to_height
+        )));
+    }
+    if to_height - from_height >= MAX_RESCAN_BLOCKS {
+        return Err(Error::InvalidEntity(format!(
+            "can't rescan more than {} blocks at once",
+            MAX_RESCAN_BLOCKS
+        )));
+    }
+    let txs = block::<_, _, Error>({
+        let pool = pool.clone();
+        move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            // all rejected transactions, no matter how old
+            get_watched_transactions(NaiveDateTime::from_timestamp(0, 0), conn)
+        }
+    })
+    .await?;
+
+    let mut commits: HashMap<String, i64> = HashMap::new();
+    let mut start = from_height;
+    while start <= to_height {
+        let end = (start + BLOCKS_PER_REQUEST - 1).min(to_height);
+        for block in node.blocks(start, end).await? {
+            commits.extend(
+                block
+                    .outputs
+                    .into_iter()
+                    .filter(|o| !o.is_coinbase())
+                    .filter_map(|o| o.block_height.map(|h| (o.commit, h as i64))),
+            );
+        }
+        start = end + 1;
+    }
+    // kernel is unambiguous, an output could be sender's change
+    let mut found = find_kernels(node, &txs, from_height, to_height).await?;
+    for tx in &txs {
+        if let Some(height) = tx.commit.as_ref().and_then(|c| commits.get(c)) {
+            found.entry(tx.id).or_insert(*height);
+        }
+    }
+
+    let changes = txs
+        .into_iter()
+        .filter_map(|tx| {
+            let height = *found.get(&tx.id)?;
+            Some(RescanChange {
+                new_status: new_status(&tx),
+                transaction_id: tx.id,
+                merchant_id: tx.merchant_id,
+                transaction_type: tx.transaction_type,
+                status: tx.status,
+                height,
+                applied: false,
+            })
+        })
+        .collect();
+    Ok(changes)
+}
+
+fn new_status(tx: &Transaction) -> Option<TransactionStatus> {
+    match (tx.transaction_type, tx.status) {
+        (TransactionType::Payment, TransactionStatus::Pending) => Some(TransactionStatus::InChain),
+        (TransactionType::Payment, TransactionStatus::Rejected) => Some(TransactionStatus::Refund),
+        (TransactionType::Payout, TransactionStatus::Pending) => Some(TransactionStatus::InChain),
+        _ => None,
+    }
+}
+
+/// Applies transitions of `changes` through the state machines. A transaction
+/// which changed state since it was found is left as is
+pub async fn apply_changes(
+    fsm: &Addr<Fsm>,
+    fsm_payout: &Addr<FsmPayout>,
+    pool: &Pool,
+    changes: &mut [RescanChange],
+) {
+    for change in changes.iter_mut() {
+        if change.new_status.is_none() {
+            continue;
+        }
+        match apply_change(fsm, fsm_payout, pool, change).await {
+            Ok(()) => {
+                info!(
+                    "Rescan: transaction {} seen in block {}, {} -> {}",
+                    change.transaction_id,
+                    change.height,
+                    change.status,
+                    change.new_status.unwrap()
+                );
+                change.applied = true;
+            }
+            Err(e) => error!(
+                "Rescan: cannot change state of transaction {}: {}",
+                change.transaction_id, e
+            ),
+        }
+    }
+}
+
+async fn apply_change(
+    fsm: &Addr<Fsm>,
+    fsm_payout: &Addr<FsmPayout>,
+    pool: &Pool,
+    change: &RescanChange,
+) -> Result<(), Error> {
+    let tx_id = change.transaction_id;
+    let height = change.height;
+    match (change.transaction_type, change.status) {
+        (TransactionType::Payment, TransactionStatus::Pending) => {
+            let payment = PendingPayment::get(tx_id, pool.clone()).await?;
+            fsm.send(SeenInChainPayment { payment, height }).await??;
+        }
+        (TransactionType::Payment, TransactionStatus::Rejected) => {
+            let payment = RejectedPayment::get(tx_id, pool.clone()).await?;
+            fsm.send(SeenInChainPayment { payment, height }).await??;
+        }
+        (TransactionType::Payout, TransactionStatus::Pending) => {
+            let payout = fsm_payout
+                .send(GetPendingPayouts)
+                .await??
+                .into_iter()
+                .find(|payout| payout.id == tx_id)
+                .ok_or(Error::EntityNotFound(format!("pending payout {}", tx_id)))?;
+            fsm_payout
+                .send(SeenInChainPayout { payout, height })
+                .await??;
+        }
+        (transaction_type, status) => {
+            return Err(Error::General(format!(
+                "no transition for {} in status {}",
+                transaction_type, status
+            )))
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! Revenue of the operator.
+//!
+//! Knockturn earns fees charged on payments and payouts and pays the network
+//! fee of a payout when the real one is higher than the charged one. Earned
+//! fees accumulate on the operator account, a merchant reserved for that,
+//! which withdraws them with the usual payout flow.
+use crate::errors::Error;
+use crate::ledger::{check_balances, get_balances, RESERVED_ACCOUNTS};
+use crate::models::{BalanceBucket, LedgerEntryKind, TransactionType};
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::{self, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Id of the merchant which holds operator's revenue
+pub const OPERATOR_ACCOUNT: &str = "knockturn";
+
+/// Fees earned from one merchant
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct MerchantRevenue {
+    pub merchant_id: String,
+    pub payment_fees: i64,
+    pub payout_fees: i64,
+    /// Network fee of payouts above the charged one
+    pub transfer_fee_loss: i64,
+    pub total: i64,
+}
+
+impl MerchantRevenue {
+    /// Adds an entry of the operator account
+    fn add(&mut self, kind: LedgerEntryKind, tx_type: TransactionType, amount: i64) {
+        match (kind, tx_type) {
+            (LedgerEntryKind::Fee, TransactionType::Payment) => self.payment_fees += amount,
+            (LedgerEntryKind::Fee, TransactionType::Payout) => self.payout_fees += amount,
+            (LedgerEntryKind::FeeLoss, _) => self.transfer_fee_loss -= amount,
+            _ => return,
+        }
+        self.total = self.payment_fees + self.payout_fees - self.transfer_fee_loss;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RevenueReport {
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub merchants: Vec<MerchantRevenue>,
+    pub total: i64,
+}
+
+/// Balances KA keeps in its wallet
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Reconciliation {
+    pub wallet_total: i64,
+    /// Sum of available balances of all merchants except the operator
+    pub merchants: i64,
+    pub operator: i64,
+    /// Payments of all merchants which are not confirmed yet
+    pub pending: i64,
+    /// Payouts of all merchants which didn't leave the wallet yet
+    pub locked: i64,
+    /// Funds of payments which are not credited to merchants yet, of payouts
+    /// in flight and KA's own funds
+    pub unaccounted: i64,
+    /// Accounts whose balance differs from their ledger entries
+    pub ledger_errors: Vec<String>,
+}
+
+/// Fees the operator account got in `[from, to)`, by merchant. It's the sum
+/// of operator's ledger entries, so a fee returned to a merchant, e.g. of a
+/// rejected payout, cancels out.
+pub fn get_revenue(
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+    merch_id: Option<&str>,
+    conn: &PgConnection,
+) -> Result<RevenueReport, Error> {
+    use crate::schema::ledger_entries::dsl::*;
+    use crate::schema::transactions;
+    let mut query = ledger_entries
+        .inner_join(transactions::table)
+        .filter(account.eq(OPERATOR_ACCOUNT))
+        .filter(bucket.eq(BalanceBucket::Available))
+        .filter(
+            kind.eq(LedgerEntryKind::Fee)
+                .or(kind.eq(LedgerEntryKind::FeeLoss)),
+        )
+        .select((
+            transactions::merchant_id,
+            transactions::transaction_type,
+            kind,
+            amount,
+        ))
+        .into_boxed();
+    if let Some(from) = from {
+        query = query.filter(created_at.ge(from));
+    }
+    if let Some(to) = to {
+        query = query.filter(created_at.lt(to));
+    }
+    if let Some(merch_id) = merch_id {
+        query = query.filter(transactions::merchant_id.eq(merch_id.to_owned()));
+    }
+    let entries = query.load::<(String, TransactionType, LedgerEntryKind, i64)>(conn)?;
+
+    let mut revenues: BTreeMap<String, MerchantRevenue> = BTreeMap::new();
+    for (entry_merchant, tx_type, entry_kind, entry_amount) in entries {
+        revenues
+            .entry(entry_merchant.clone())
+            .or_insert_with(|| MerchantRevenue {
+                merchant_id: entry_merchant,
+                ..Default::default()
+            })
+            .add(entry_kind, tx_type, entry_amount);
+    }
+    let merchants: Vec<MerchantRevenue> = revenues.into_iter().map(|(_, r)| r).collect();
+    let total = merchants.iter().map(|r| r.total).sum();
+    Ok(RevenueReport {
+        from,
+        to,
+        merchants,
+        total,
+    })
+}
+
+/// Compares the wallet balance with what we owe to merchants and the operator
+pub fn reconcile(wallet_total: i64, conn: &PgConnection) -> Result<Reconciliation, Error> {
+    use crate::schema::balances::dsl::*;
+    let totals = balances
+        .select((account, bucket, amount))
+        .load::<(String, BalanceBucket, i64)>(conn)?;
+    let mut merchants = 0;
+    let mut pending = 0;
+    let mut locked = 0;
+    for (acc, part, value) in totals {
+        if RESERVED_ACCOUNTS.contains(&acc.as_str()) {
+            continue;
+        }
+        match part {
+            BalanceBucket::Available => merchants += value,
+            BalanceBucket::Pending => pending += value,
+            BalanceBucket::Locked => locked += value,
+        }
+    }
+    let operator = get_balances(OPERATOR_ACCOUNT, conn)?.available;
+    Ok(Reconciliation {
+        wallet_total,
+        merchants,
+        operator,
+        pending,
+        locked,
+        unaccounted: wallet_total - merchants - operator,
+        ledger_errors: check_balances(conn)?,
+    })
+}
@@ -0,0 +1,412 @@
+//! Background jobs of crons.
+//!
+//! A job runs again `interval` after its previous run has finished, so a slow
+//! run delays the next one instead of piling up. After failures the delay grows
+//! exponentially, with jitter, up to `MAX_BACKOFF_SECONDS`. Runs are recorded
+//! in a `JobRegistry` shared between crons and http workers: health checks
+//! read it and an operator may pause a job. Jobs run only on the leader, see
+//! `leader`. Paused jobs are kept in the `paused_jobs` table, so a pause made
+//! on any instance applies to the leader, whichever instance it is.
+use crate::clock::Clock;
+use crate::errors::Error;
+use crate::leader::Leadership;
+use actix::prelude::*;
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::*;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_INTERVAL_SECONDS: u64 = 5;
+pub const MAX_BACKOFF_SECONDS: u64 = 5 * 60;
+pub const MAX_JOB_SILENCE_SECONDS: i64 = 5 * 60;
+/// Jobs which shouldn't run every few seconds by default
+const DEFAULT_INTERVALS: &[(&str, u64)] = &[("fetch_rates", 60)];
+
+/// Intervals of jobs in seconds, parsed from `sync_with_node=10,fetch_rates=300`
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    intervals: HashMap<String, u64>,
+}
+
+impl Schedule {
+    pub fn interval(&self, job: &str) -> Duration {
+        let seconds = self.intervals.get(job).cloned().unwrap_or_else(|| {
+            DEFAULT_INTERVALS
+                .iter()
+                .find(|(name, _)| *name == job)
+                .map(|(_, seconds)| *seconds)
+                .unwrap_or(DEFAULT_INTERVAL_SECONDS)
+        });
+        Duration::from_secs(seconds)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut intervals = HashMap::new();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let mut parts = item.splitn(2, '=');
+            let job = parts.next().unwrap_or_default().trim();
+            let seconds = parts
+                .next()
+                .and_then(|seconds| seconds.trim().parse::<u64>().ok())
+                .filter(|seconds| *seconds > 0)
+                .ok_or_else(|| Error::General(format!("Expected job=seconds, got \"{}\"", item)))?;
+            intervals.insert(job.to_owned(), seconds);
+        }
+        Ok(Schedule { intervals })
+    }
+}
+
+/// State and last runs of a background job
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub ok: bool,
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub running: bool,
+    #[serde(default)]
+    pub interval_seconds: u64,
+    /// Failures in a row
+    #[serde(default)]
+    pub failures: u32,
+    pub last_success: Option<NaiveDateTime>,
+    pub last_failure: Option<NaiveDateTime>,
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub next_run: Option<NaiveDateTime>,
+}
+
+/// Registry of background jobs
+#[derive(Clone)]
+pub struct JobRegistry {
+    started_at: NaiveDateTime,
+    schedule: Schedule,
+    leadership: Leadership,
+    jobs: Arc<Mutex<BTreeMap<String, JobStatus>>>,
+}
+
+impl JobRegistry {
+    pub fn new(now: NaiveDateTime, schedule: Schedule, leadership: Leadership) -> Self {
+        JobRegistry {
+            started_at: now,
+            schedule,
+            leadership,
+            jobs: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    pub fn interval(&self, job: &str) -> Duration {
+        self.schedule.interval(job)
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leadership.is_leader()
+    }
+
+    pub fn register(&self, job: &str) -> Duration {
+        let interval = self.schedule.interval(job);
+        let mut jobs = self.jobs.lock();
+        let status = jobs.entry(job.to_owned()).or_default();
+        status.interval_seconds = interval.as_secs();
+        interval
+    }
+
+    /// Marks the job as running unless it's paused or still running, or
+    /// another instance is the leader
+    pub fn start(&self, job: &str) -> bool {
+        if !self.leadership.is_leader() {
+            return false;
+        }
+        let mut jobs = self.jobs.lock();
+        let status = jobs.entry(job.to_owned()).or_default();
+        if status.paused || status.running {
+            return false;
+        }
+        status.running = true;
+        true
+    }
+
+    /// Records the result of a run and returns how long to wait for the next one
+    pub fn finish<T>(&self, job: &str, result: &Result<T, Error>, now: NaiveDateTime) -> Duration {
+        let interval = self.schedule.interval(job);
+        let mut jobs = self.jobs.lock();
+        let status = jobs.entry(job.to_owned()).or_default();
+        status.running = false;
+        match result {
+            Ok(_) => {
+                status.last_success = Some(now);
+                status.failures = 0;
+            }
+            Err(e) => {
+                status.last_failure = Some(now);
+                status.last_error = Some(e.to_string());
+                status.failures += 1;
+            }
+        }
+        let delay = backoff(interval, status.failures);
+        status.next_run = chrono::Duration::from_std(delay)
+            .ok()
+            .map(|delay| now + delay);
+        delay
+    }
+
+    /// Pauses the job on all instances, the leader picks it up with `load_paused`
+    pub fn pause(
+        &self,
+        job: &str,
+        now: NaiveDateTime,
+        conn: &PgConnection,
+    ) -> Result<JobStatus, Error> {
+        use crate::schema::paused_jobs;
+        self.check_registered(job)?;
+        diesel::insert_into(paused_jobs::table)
+            .values((paused_jobs::job.eq(job), paused_jobs::paused_at.eq(now)))
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        self.set_paused(job, true)
+    }
+
+    pub fn resume(&self, job: &str, conn: &PgConnection) -> Result<JobStatus, Error> {
+        use crate::schema::paused_jobs;
+        self.check_registered(job)?;
+        diesel::delete(paused_jobs::table.filter(paused_jobs::job.eq(job))).execute(conn)?;
+        self.set_paused(job, false)
+    }
+
+    /// Reads jobs paused on any instance
+    pub fn load_paused(&self, conn: &PgConnection) -> Result<(), Error> {
+        use crate::schema::paused_jobs;
+        let paused: HashSet<String> = paused_jobs::table
+            .select(paused_jobs::job)
+            .load::<String>(conn)?
+            .into_iter()
+            .collect();
+        self.set_paused_jobs(&paused);
+        Ok(())
+    }
+
+    fn set_paused_jobs(&self, paused: &HashSet<String>) {
+        let mut jobs = self.jobs.lock();
+        for job in paused {
+            jobs.entry(job.clone()).or_default();
+        }
+        for (job, status) in jobs.iter_mut() {
+            status.paused = paused.contains(job);
+        }
+    }
+
+    fn check_registered(&self, job: &str) -> Result<(), Error> {
+        if !self.jobs.lock().contains_key(job) {
+            return Err(Error::EntityNotFound(format!("Job {}", job)));
+        }
+        Ok(())
+    }
+
+    fn set_paused(&self, job: &str, paused: bool) -> Result<JobStatus, Error> {
+        let mut jobs = self.jobs.lock();
+        let status = jobs
+            .get_mut(job)
+            .ok_or_else(|| Error::EntityNotFound(format!("Job {}", job)))?;
+        status.paused = paused;
+        Ok(status.clone())
+    }
+
+    /// A job is fine if it succeeded recently or was paused on purpose. Right
+    /// after start a job which hasn't run yet is fine too. Jobs of a standby
+    /// instance don't run at all
+    pub fn statuses(&self, now: NaiveDateTime) -> BTreeMap<String, JobStatus> {
+        let leader_since = self.leadership.since();
+        let mut jobs = self.jobs.lock().clone();
+        for status in jobs.values_mut() {
+            let silence = chrono::Duration::seconds(
+                MAX_JOB_SILENCE_SECONDS.max(3 * status.interval_seconds as i64),
+            );
+            status.ok = match leader_since {
+                Some(leader_since) => {
+                    let since = status
+                        .last_success
+                        .unwrap_or(self.started_at)
+                        .max(self.started_at)
+                        .max(leader_since);
+                    status.paused || now - since <= silence
+                }
+                None => true,
+            };
+        }
+        jobs
+    }
+}
+
+/// Delay before the next run: the interval, doubled with every failure in a
+/// row, randomized so that jobs failing for the same reason don't retry in sync
+fn backoff(interval: Duration, failures: u32) -> Duration {
+    if failures == 0 {
+        return interval;
+    }
+    let max = interval
+        .checked_mul(1 << failures.min(16))
+        .unwrap_or(Duration::from_secs(MAX_BACKOFF_SECONDS))
+        .min(Duration::from_secs(MAX_BACKOFF_SECONDS))
+        .max(interval);
+    let min = (max / 2).max(interval);
+    if min >= max {
+        return max;
+    }
+    let millis = rand::thread_rng().gen_range(min.as_millis() as u64, max.as_millis() as u64 + 1);
+    Duration::from_millis(millis)
+}
+
+/// Runs `job` of the actor now and then according to the registry
+pub fn schedule<A, F, Fut>(
+    ctx: &mut Context<A>,
+    registry: JobRegistry,
+    clock: Arc<dyn Clock>,
+    name: &'static str,
+    job: F,
+) where
+    A: Actor<Context = Context<A>> + Clone,
+    F: Fn(A) -> Fut + 'static,
+    Fut: Future<Output = Result<(), Error>> + 'static,
+{
+    registry.register(name);
+    run_later(
+        ctx,
+        Duration::from_secs(0),
+        registry,
+        clock,
+        name,
+        Rc::new(job),
+    );
+}
+
+fn run_later<A, F, Fut>(
+    ctx: &mut Context<A>,
+    delay: Duration,
+    registry: JobRegistry,
+    clock: Arc<dyn Clock>,
+    name: &'static str,
+    job: Rc<F>,
+) where
+    A: Actor<Context = Context<A>> + Clone,
+    F: Fn(A) -> Fut + 'static,
+    Fut: Future<Output = Result<(), Error>> + 'static,
+{
+    ctx.run_later(delay, move |actor, ctx| {
+        if !registry.start(name) {
+            let interval = registry.interval(name);
+            return run_later(ctx, interval, registry, clock, name, job);
+        }
+        debug!("run {}", name);
+        ctx.spawn(
+            job(actor.clone())
+                .into_actor(actor)
+                .map(move |res, _actor, ctx| {
+                    if let Err(e) = &res {
+                        error!("Job {} failed: {}", name, e);
+                    }
+                    let delay = registry.finish(name, &res, clock.now());
+                    run_later(ctx, delay, registry, clock, name, job);
+                }),
+        );
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn schedule_test() {
+        let schedule: Schedule = "sync_with_node=10, fetch_rates=300".parse().unwrap();
+        assert_eq!(schedule.interval("sync_with_node"), Duration::from_secs(10));
+        assert_eq!(schedule.interval("fetch_rates"), Duration::from_secs(300));
+        assert_eq!(
+            schedule.interval("watch_mempool"),
+            Duration::from_secs(DEFAULT_INTERVAL_SECONDS)
+        );
+        let schedule = Schedule::default();
+        assert_eq!(schedule.interval("fetch_rates"), Duration::from_secs(60));
+        assert!("sync_with_node".parse::<Schedule>().is_err());
+        assert!("sync_with_node=0".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn backoff_test() {
+        let interval = Duration::from_secs(5);
+        assert_eq!(backoff(interval, 0), interval);
+        let delay = backoff(interval, 1);
+        assert!(delay >= interval && delay <= interval * 2);
+        let delay = backoff(interval, 3);
+        assert!(delay >= interval * 4 && delay <= interval * 8);
+        let delay = backoff(interval, 30);
+        assert!(delay <= Duration::from_secs(MAX_BACKOFF_SECONDS));
+        assert!(delay >= Duration::from_secs(MAX_BACKOFF_SECONDS / 2));
+    }
+
+    #[test]
+    fn job_registry_test() {
+        let start = Utc::now().naive_utc();
+        let silence = chrono::Duration::seconds(MAX_JOB_SILENCE_SECONDS + 1);
+        let registry = JobRegistry::new(start, Schedule::default(), Leadership::single(start));
+        registry.register("sync_with_node");
+        registry.register("watch_mempool");
+
+        assert!(registry.start("sync_with_node"));
+        // the previous run hasn't finished yet
+        assert!(!registry.start("sync_with_node"));
+        let delay = registry.finish("sync_with_node", &Ok(()), start);
+        assert_eq!(delay, Duration::from_secs(DEFAULT_INTERVAL_SECONDS));
+        assert!(registry.start("watch_mempool"));
+        registry.finish::<()>(
+            "watch_mempool",
+            &Err(Error::NodeAPIError(s!("offline"))),
+            start,
+        );
+        let jobs = registry.statuses(start);
+        assert!(jobs["sync_with_node"].ok);
+        // never succeeded, but we've just started
+        assert!(jobs["watch_mempool"].ok);
+        assert_eq!(jobs["watch_mempool"].failures, 1);
+        assert!(jobs["watch_mempool"].last_error.is_some());
+
+        assert!(registry.start("sync_with_node"));
+        registry.finish("sync_with_node", &Ok(()), start + silence);
+        let jobs = registry.statuses(start + silence);
+        assert!(jobs["sync_with_node"].ok);
+        assert!(!jobs["watch_mempool"].ok);
+
+        let mut paused = HashSet::new();
+        paused.insert(s!("watch_mempool"));
+        registry.set_paused_jobs(&paused);
+        assert!(!registry.start("watch_mempool"));
+        assert!(registry.statuses(start + silence)["watch_mempool"].ok);
+        registry.set_paused_jobs(&HashSet::new());
+        assert!(registry.start("watch_mempool"));
+    }
+
+    #[test]
+    fn standby_test() {
+        let start = Utc::now().naive_utc();
+        let silence = chrono::Duration::seconds(MAX_JOB_SILENCE_SECONDS + 1);
+        let registry = JobRegistry::new(start, Schedule::default(), Leadership::new("standby"));
+        registry.register("sync_with_node");
+        assert!(!registry.is_leader());
+        assert!(!registry.start("sync_with_node"));
+        // standby doesn't run jobs, that's fine
+        assert!(registry.statuses(start + silence)["sync_with_node"].ok);
+    }
+}
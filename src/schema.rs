@@ -2,9 +2,59 @@ table! {
     use diesel::sql_types::*;
     use crate::models::Transaction_status;
     use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
+
+    balances (account, bucket) {
+        account -> Text,
+        bucket -> Balance_bucket,
+        amount -> Int8,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
+
+    blocks (height) {
+        height -> Int8,
+        hash -> Text,
+        previous -> Text,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
 
     current_height (height) {
         height -> Int8,
+        node_height -> Int8,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
+
+    fee_schedules (merchant_id) {
+        merchant_id -> Text,
+        schedule -> Jsonb,
+        updated_at -> Timestamp,
     }
 }
 
@@ -12,6 +62,45 @@ table! {
     use diesel::sql_types::*;
     use crate::models::Transaction_status;
     use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
+
+    leases (name) {
+        name -> Text,
+        holder -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
+
+    ledger_entries (id) {
+        id -> Uuid,
+        posting_id -> Uuid,
+        transaction_id -> Nullable<Uuid>,
+        kind -> Ledger_entry_kind,
+        account -> Text,
+        bucket -> Balance_bucket,
+        amount -> Int8,
+        note -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
 
     merchants (id) {
         id -> Text,
@@ -23,6 +112,11 @@ table! {
         callback_url -> Nullable<Text>,
         token_2fa -> Nullable<Varchar>,
         confirmed_2fa -> Bool,
+        payout_policy -> Payout_policy,
+        payout_threshold -> Nullable<Int8>,
+        last_auto_payout_at -> Nullable<Timestamp>,
+        auto_payout_error -> Nullable<Text>,
+        payout_key_hash -> Nullable<Text>,
     }
 }
 
@@ -30,6 +124,23 @@ table! {
     use diesel::sql_types::*;
     use crate::models::Transaction_status;
     use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
+
+    paused_jobs (job) {
+        job -> Text,
+        paused_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
 
     rates (id) {
         id -> Text,
@@ -42,6 +153,24 @@ table! {
     use diesel::sql_types::*;
     use crate::models::Transaction_status;
     use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
+
+    sessions (id) {
+        id -> Text,
+        state -> Jsonb,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
 
     status_changes (id) {
         id -> Uuid,
@@ -55,6 +184,9 @@ table! {
     use diesel::sql_types::*;
     use crate::models::Transaction_status;
     use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
 
     transactions (id) {
         id -> Uuid,
@@ -81,6 +213,16 @@ table! {
         height -> Nullable<Int8>,
         commit -> Nullable<Text>,
         redirect_url -> Nullable<Text>,
+        wallet_tx_cancelled -> Bool,
+        cancel_attempts -> Int4,
+        next_cancel_attempt -> Nullable<Timestamp>,
+        kernel_excess -> Nullable<Text>,
+        in_mempool -> Bool,
+        mempool_changed_at -> Nullable<Timestamp>,
+        stuck_since -> Nullable<Timestamp>,
+        transfer_fee_credit -> Nullable<Int8>,
+        fee_schedule -> Nullable<Jsonb>,
+        input_commits -> Nullable<Array<Text>>,
     }
 }
 
@@ -88,6 +230,9 @@ table! {
     use diesel::sql_types::*;
     use crate::models::Transaction_status;
     use crate::models::Transaction_type;
+    use crate::models::Payout_policy;
+    use crate::models::Ledger_entry_kind;
+    use crate::models::Balance_bucket;
 
     txs (slate_id) {
         slate_id -> Text,
@@ -104,14 +249,23 @@ table! {
     }
 }
 
+joinable!(fee_schedules -> merchants (merchant_id));
+joinable!(ledger_entries -> transactions (transaction_id));
 joinable!(status_changes -> transactions (transaction_id));
 joinable!(transactions -> merchants (merchant_id));
 joinable!(txs -> transactions (order_id));
 
 allow_tables_to_appear_in_same_query!(
+    balances,
+    blocks,
     current_height,
+    fee_schedules,
+    leases,
+    ledger_entries,
     merchants,
+    paused_jobs,
     rates,
+    sessions,
     status_changes,
     transactions,
     txs,
@@ -0,0 +1,137 @@
+//! Encryption helpers for the secure JSON-RPC owner API (v3) of grin-wallet.
+//!
+//! The client and the wallet exchange secp256k1 public keys via `init_secure_api`,
+//! both derive a shared key from the x coordinate of the ECDH point and then
+//! every request and response body is encrypted with AES-256-GCM.
+use crate::errors::Error;
+use crate::ser;
+use rand::{thread_rng, Rng};
+use ring::aead;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::str::FromStr;
+
+pub const ENCRYPTED_REQUEST_METHOD: &'static str = "encrypted_request_v3";
+
+/// Ephemeral key pair used to establish a shared key with the wallet
+pub struct EcdhKeypair {
+    secret: SecretKey,
+    pub public: PublicKey,
+}
+
+impl EcdhKeypair {
+    pub fn new() -> Result<Self, Error> {
+        let secp = Secp256k1::new();
+        let secret = SecretKey::from_slice(&thread_rng().gen::<[u8; 32]>())
+            .map_err(|e| Error::General(format!("Cannot generate secret key: {}", e)))?;
+        let public = PublicKey::from_secret_key(&secp, &secret);
+        Ok(EcdhKeypair { secret, public })
+    }
+
+    pub fn public_hex(&self) -> String {
+        ser::to_hex(self.public.serialize().to_vec())
+    }
+
+    /// Derive the shared key from the public key of the other side (hex encoded, compressed)
+    pub fn shared_key(&self, other_public_hex: &str) -> Result<SharedKey, Error> {
+        let secp = Secp256k1::new();
+        let mut point = PublicKey::from_str(other_public_hex)
+            .map_err(|e| Error::General(format!("Invalid ecdh public key: {}", e)))?;
+        point
+            .mul_assign(&secp, &self.secret[..])
+            .map_err(|e| Error::General(format!("Cannot derive shared key: {}", e)))?;
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&point.serialize()[1..]);
+        Ok(SharedKey(key))
+    }
+}
+
+#[derive(Clone)]
+pub struct SharedKey([u8; 32]);
+
+/// Encrypted payload of request or response
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EncryptedBody {
+    pub nonce: String,
+    pub body_enc: String,
+}
+
+impl EncryptedBody {
+    pub fn encrypt(value: &Value, key: &SharedKey) -> Result<Self, Error> {
+        let nonce: [u8; 12] = thread_rng().gen();
+        let mut buf = serde_json::to_vec(value)?;
+        let sealing_key = aead::LessSafeKey::new(
+            aead::UnboundKey::new(&aead::AES_256_GCM, &key.0)
+                .map_err(|_| Error::General(s!("Cannot create encryption key")))?,
+        );
+        sealing_key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                &mut buf,
+            )
+            .map_err(|_| Error::General(s!("Cannot encrypt request body")))?;
+        Ok(EncryptedBody {
+            nonce: ser::to_hex(nonce.to_vec()),
+            body_enc: base64::encode(&buf),
+        })
+    }
+
+    pub fn decrypt(&self, key: &SharedKey) -> Result<Value, Error> {
+        let nonce_bytes = ser::from_hex(self.nonce.clone())
+            .map_err(|e| Error::General(format!("Invalid nonce: {}", e)))?;
+        if nonce_bytes.len() != 12 {
+            return Err(Error::General(s!("Invalid nonce length")));
+        }
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(&nonce_bytes);
+        let mut buf = base64::decode(&self.body_enc)
+            .map_err(|e| Error::General(format!("Invalid encrypted body: {}", e)))?;
+        let opening_key = aead::LessSafeKey::new(
+            aead::UnboundKey::new(&aead::AES_256_GCM, &key.0)
+                .map_err(|_| Error::General(s!("Cannot create decryption key")))?,
+        );
+        let decrypted = opening_key
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                &mut buf,
+            )
+            .map_err(|_| Error::General(s!("Cannot decrypt body")))?;
+        Ok(serde_json::from_slice(decrypted)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn shared_key_test() {
+        let client = EcdhKeypair::new().unwrap();
+        let server = EcdhKeypair::new().unwrap();
+        let client_key = client.shared_key(&server.public_hex()).unwrap();
+        let server_key = server.shared_key(&client.public_hex()).unwrap();
+        assert_eq!(client_key.0, server_key.0);
+    }
+
+    #[test]
+    fn encrypt_decrypt_test() {
+        let client = EcdhKeypair::new().unwrap();
+        let server = EcdhKeypair::new().unwrap();
+        let body = json!({"jsonrpc": "2.0", "method": "open_wallet", "id": 1});
+        let enc = EncryptedBody::encrypt(&body, &client.shared_key(&server.public_hex()).unwrap())
+            .unwrap();
+        let dec = enc
+            .decrypt(&server.shared_key(&client.public_hex()).unwrap())
+            .unwrap();
+        assert_eq!(body, dec);
+
+        let other = EcdhKeypair::new().unwrap();
+        assert!(enc
+            .decrypt(&other.shared_key(&client.public_hex()).unwrap())
+            .is_err());
+    }
+}
@@ -0,0 +1,307 @@
+//! Sessions stored in the database.
+//!
+//! The cookie keeps only a random session id, the state lives in the
+//! `sessions` table. Identity of a logged in merchant is kept in the session
+//! too, so any instance behind a load balancer can serve any merchant and
+//! logging out ends the session everywhere.
+use crate::clock::Clock;
+use crate::errors::Error;
+use crate::Pool;
+use actix_identity::IdentityPolicy;
+use actix_session::{Session, SessionStatus, UserSession};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::Cookie;
+use actix_web::web::block;
+use actix_web::FromRequest;
+use chrono::{Duration, NaiveDateTime};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use futures::future::{ok, ready, FutureExt, LocalBoxFuture, Ready};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+pub const SESSION_TTL_SECONDS: i64 = 24 * 60 * 60;
+const SESSION_ID_LENGTH: usize = 32;
+const IDENTITY_KEY: &'static str = "identity";
+
+/// Middleware which keeps session state in the database
+#[derive(Clone)]
+pub struct PgSession {
+    pool: Pool,
+    clock: Arc<dyn Clock>,
+    name: String,
+    secure: bool,
+}
+
+impl PgSession {
+    pub fn new(pool: Pool, clock: Arc<dyn Clock>) -> Self {
+        PgSession {
+            pool,
+            clock,
+            name: s!("knockturn-session"),
+            secure: true,
+        }
+    }
+
+    /// Name of the cookie with session id
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_owned();
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    fn cookie(&self, value: String) -> Cookie<'static> {
+        Cookie::build(self.name.clone(), value)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .finish()
+    }
+
+    async fn load(&self, session_id: String) -> Result<Option<HashMap<String, String>>, Error> {
+        let pool = self.pool.clone();
+        let now = self.clock.now();
+        block::<_, _, Error>(move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            load_session(&session_id, now, conn)
+        })
+        .await
+        .map_err(Error::from)
+    }
+
+    async fn save(&self, session_id: String, state: HashMap<String, String>) -> Result<(), Error> {
+        let pool = self.pool.clone();
+        let now = self.clock.now();
+        block::<_, _, Error>(move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            save_session(&session_id, state, now, conn)
+        })
+        .await
+        .map_err(Error::from)
+    }
+
+    async fn delete(&self, session_id: String) -> Result<(), Error> {
+        let pool = self.pool.clone();
+        block::<_, _, Error>(move || {
+            let conn: &PgConnection = &pool.get().unwrap();
+            delete_session(&session_id, conn)
+        })
+        .await
+        .map_err(Error::from)
+    }
+}
+
+fn new_session_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SESSION_ID_LENGTH)
+        .collect()
+}
+
+pub fn load_session(
+    session_id: &str,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<Option<HashMap<String, String>>, Error> {
+    use crate::schema::sessions::dsl::*;
+    let session_state: Option<Value> = sessions
+        .filter(id.eq(session_id))
+        .filter(expires_at.gt(now))
+        .select(state)
+        .first(conn)
+        .optional()?;
+    Ok(session_state.map(|session_state| match session_state {
+        Value::Object(map) => map
+            .into_iter()
+            .filter_map(|(key, value)| value.as_str().map(|value| (key, value.to_owned())))
+            .collect(),
+        _ => HashMap::new(),
+    }))
+}
+
+/// Stores the state and prolongs the session
+pub fn save_session(
+    session_id: &str,
+    session_state: HashMap<String, String>,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<(), Error> {
+    use crate::schema::sessions::dsl::*;
+    let session_state = Value::Object(
+        session_state
+            .into_iter()
+            .map(|(key, value)| (key, Value::String(value)))
+            .collect::<Map<String, Value>>(),
+    );
+    let expires = now + Duration::seconds(SESSION_TTL_SECONDS);
+    diesel::insert_into(sessions)
+        .values((
+            id.eq(session_id),
+            state.eq(&session_state),
+            expires_at.eq(expires),
+        ))
+        .on_conflict(id)
+        .do_update()
+        .set((state.eq(&session_state), expires_at.eq(expires)))
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn delete_session(session_id: &str, conn: &PgConnection) -> Result<(), Error> {
+    use crate::schema::sessions::dsl::*;
+    diesel::delete(sessions.filter(id.eq(session_id))).execute(conn)?;
+    Ok(())
+}
+
+pub fn delete_expired_sessions(now: NaiveDateTime, conn: &PgConnection) -> Result<usize, Error> {
+    use crate::schema::sessions::dsl::*;
+    Ok(diesel::delete(sessions.filter(expires_at.le(now))).execute(conn)?)
+}
+
+impl<S, B> Transform<S> for PgSession
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>
+        + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = PgSessionMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(PgSessionMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            store: self.clone(),
+        })
+    }
+}
+
+pub struct PgSessionMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    store: PgSession,
+}
+
+impl<S, B> Service for PgSessionMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>
+        + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let store = self.store.clone();
+        let session_id = req.cookie(&store.name).map(|c| c.value().to_owned());
+        async move {
+            let loaded = match session_id {
+                Some(session_id) => store
+                    .load(session_id.clone())
+                    .await?
+                    .map(|session_state| (session_id, session_state)),
+                None => None,
+            };
+            let session_id = match loaded {
+                Some((session_id, session_state)) => {
+                    Session::set_session(session_state.into_iter(), &mut req);
+                    Some(session_id)
+                }
+                None => None,
+            };
+
+            let fut = service.borrow_mut().call(req);
+            let mut res = fut.await?;
+
+            match Session::get_changes(&mut res) {
+                (SessionStatus::Unchanged, _) => Ok(res),
+                (status, session_state) => {
+                    let session_state: HashMap<String, String> = match status {
+                        SessionStatus::Purged => HashMap::new(),
+                        _ => session_state.map(|s| s.collect()).unwrap_or_default(),
+                    };
+                    if session_state.is_empty() {
+                        if let Some(session_id) = session_id {
+                            store.delete(session_id).await?;
+                            let mut cookie = store.cookie(String::new());
+                            cookie.make_removal();
+                            res.response_mut().add_cookie(&cookie)?;
+                        }
+                        return Ok(res);
+                    }
+                    let session_id = match (session_id, status) {
+                        (Some(old_id), SessionStatus::Renewed) => {
+                            store.delete(old_id).await?;
+                            new_session_id()
+                        }
+                        (Some(session_id), _) => session_id,
+                        (None, _) => new_session_id(),
+                    };
+                    store.save(session_id.clone(), session_state).await?;
+                    res.response_mut().add_cookie(&store.cookie(session_id))?;
+                    Ok(res)
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Keeps identity of a logged in merchant in the session
+pub struct SessionIdentityPolicy;
+
+impl IdentityPolicy for SessionIdentityPolicy {
+    type Future = Ready<Result<Option<String>, actix_web::Error>>;
+    type ResponseFuture = LocalBoxFuture<'static, Result<(), actix_web::Error>>;
+
+    fn from_request(&self, req: &mut ServiceRequest) -> Self::Future {
+        ready(req.get_session().get::<String>(IDENTITY_KEY))
+    }
+
+    fn to_response<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        res: &mut ServiceResponse<B>,
+    ) -> Self::ResponseFuture {
+        if !changed {
+            return ok(()).boxed_local();
+        }
+        let session = Session::extract(res.request());
+        async move {
+            let session = session.await?;
+            match identity {
+                Some(identity) => {
+                    session.set(IDENTITY_KEY, identity)?;
+                    // new privileges, new session id, so a session id
+                    // planted before login is useless
+                    session.renew();
+                }
+                None => session.remove(IDENTITY_KEY),
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
@@ -0,0 +1,394 @@
+//! Account statements of merchants.
+//!
+//! A statement lists every change of merchant's available balance in a
+//! period, between the opening and the closing balance. Changes are ledger
+//! entries of the merchant's available balance at the time they were
+//! written, so a statement always adds up to the balance.
+use crate::errors::Error;
+use crate::models::{BalanceBucket, LedgerEntry, LedgerEntryKind};
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::{self, prelude::*};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StatementLine {
+    pub at: NaiveDateTime,
+    pub transaction_id: Option<Uuid>,
+    /// Merchant's order id of a payment
+    pub order_id: Option<String>,
+    pub kind: LedgerEntryKind,
+    pub amount: i64,
+    /// Balance after this line
+    pub balance: i64,
+    pub note: Option<String>,
+}
+
+impl StatementLine {
+    pub fn description(&self) -> String {
+        let what = match self.kind {
+            LedgerEntryKind::PaymentReceived | LedgerEntryKind::PaymentCleared => "Payment",
+            LedgerEntryKind::Refund => "Refund",
+            LedgerEntryKind::Fee => "Fee",
+            LedgerEntryKind::Payout | LedgerEntryKind::PayoutSent => "Payout",
+            LedgerEntryKind::NetworkFee | LedgerEntryKind::FeeLoss => "Network fee",
+            LedgerEntryKind::FeeCredit => "Network fee credit",
+            LedgerEntryKind::Adjustment => "Adjustment",
+        };
+        match (&self.order_id, &self.note) {
+            (_, Some(note)) => format!("{}: {}", what, note),
+            (Some(order_id), None) => format!("{} for order {}", what, order_id),
+            (None, None) => what.to_owned(),
+        }
+    }
+}
+
+/// Available balance of the merchant in `[from, to)`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Statement {
+    pub merchant_id: String,
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+    pub opening_balance: i64,
+    pub lines: Vec<StatementLine>,
+    pub closing_balance: i64,
+}
+
+/// Available balance of the merchant just before `at`
+pub fn balance_at(merch_id: &str, at: NaiveDateTime, conn: &PgConnection) -> Result<i64, Error> {
+    Ok(movements(merch_id, at, conn)?
+        .iter()
+        .map(|line| line.amount)
+        .sum())
+}
+
+pub fn get_statement(
+    merch_id: &str,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<Statement, Error> {
+    if from >= to {
+        return Err(Error::InvalidEntity(s!("statement period is empty")));
+    }
+    let mut opening_balance = 0;
+    let mut balance = 0;
+    let mut lines = vec![];
+    for mut line in movements(merch_id, to, conn)? {
+        balance += line.amount;
+        if line.at < from {
+            opening_balance = balance;
+            continue;
+        }
+        line.balance = balance;
+        lines.push(line);
+    }
+    Ok(Statement {
+        merchant_id: merch_id.to_owned(),
+        from,
+        to,
+        opening_balance,
+        lines,
+        closing_balance: balance,
+    })
+}
+
+/// Changes of merchant's available balance before `to` in time order, their
+/// `balance` isn't set
+fn movements(
+    merch_id: &str,
+    to: NaiveDateTime,
+    conn: &PgConnection,
+) -> Result<Vec<StatementLine>, Error> {
+    use crate::schema::ledger_entries::dsl::*;
+    use crate::schema::transactions;
+    let entries = ledger_entries
+        .left_join(transactions::table)
+        .filter(account.eq(merch_id))
+        .filter(bucket.eq(BalanceBucket::Available))
+        .filter(created_at.lt(to))
+        .order(created_at.asc())
+        .select((
+            crate::schema::ledger_entries::all_columns,
+            transactions::external_id.nullable(),
+        ))
+        .load::<(LedgerEntry, Option<String>)>(conn)?;
+    Ok(entries
+        .into_iter()
+        .map(|(entry, order_id)| StatementLine {
+            at: entry.created_at,
+            transaction_id: entry.transaction_id,
+            order_id,
+            kind: entry.kind,
+            amount: entry.amount,
+            balance: 0,
+            note: entry.note,
+        })
+        .collect())
+}
+
+/// Exact amount in grins, statements must add up to a nanogrin
+pub fn format_grin(nanogrins: i64) -> String {
+    let sign = if nanogrins < 0 { "-" } else { "" };
+    let abs = nanogrins.abs();
+    format!("{}{}.{:09}", sign, abs / 1_000_000_000, abs % 1_000_000_000)
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+impl Statement {
+    pub fn to_csv(&self) -> String {
+        let mut csv = s!("date,transaction_id,order_id,type,description,amount,balance\r\n");
+        csv.push_str(&format!(
+            "{},,,OpeningBalance,Opening balance,,{}\r\n",
+            self.from.format("%Y-%m-%d %H:%M:%S"),
+            format_grin(self.opening_balance)
+        ));
+        for line in &self.lines {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{}\r\n",
+                line.at.format("%Y-%m-%d %H:%M:%S"),
+                line.transaction_id
+                    .map(|id| id.to_string())
+                    .unwrap_or_default(),
+                csv_field(line.order_id.as_ref().map(String::as_str).unwrap_or("")),
+                line.kind,
+                csv_field(&line.description()),
+                format_grin(line.amount),
+                format_grin(line.balance)
+            ));
+        }
+        csv.push_str(&format!(
+            "{},,,ClosingBalance,Closing balance,,{}\r\n",
+            self.to.format("%Y-%m-%d %H:%M:%S"),
+            format_grin(self.closing_balance)
+        ));
+        csv
+    }
+
+    /// Plain text statement in a PDF with the standard Courier font
+    pub fn to_pdf(&self) -> Vec<u8> {
+        let mut text = vec![
+            format!("Statement of merchant {}", self.merchant_id),
+            format!(
+                "Period: {} - {} UTC",
+                self.from.format("%Y-%m-%d %H:%M"),
+                self.to.format("%Y-%m-%d %H:%M")
+            ),
+            String::new(),
+            format!(
+                "{:<45}{:>21}",
+                "Opening balance",
+                format_grin(self.opening_balance)
+            ),
+            String::new(),
+            format!(
+                "{:<17}{:<28}{:>21}{:>21}",
+                "Date", "Description", "Amount", "Balance"
+            ),
+        ];
+        for line in &self.lines {
+            let mut description = line.description();
+            if description.chars().count() > 27 {
+                description = description.chars().take(26).collect::<String>() + "~";
+            }
+            text.push(format!(
+                "{:<17}{:<28}{:>21}{:>21}",
+                line.at.format("%Y-%m-%d %H:%M"),
+                description,
+                format_grin(line.amount),
+                format_grin(line.balance)
+            ));
+        }
+        text.push(String::new());
+        text.push(format!(
+            "{:<45}{:>21}",
+            "Closing balance",
+            format_grin(self.closing_balance)
+        ));
+        pdf::render(&text)
+    }
+}
+
+mod pdf {
+    /// A4 in points
+    const WIDTH: usize = 595;
+    const HEIGHT: usize = 842;
+    const MARGIN: usize = 40;
+    const FONT_SIZE: usize = 8;
+    const LEADING: usize = 11;
+    const LINES_PER_PAGE: usize = (HEIGHT - 2 * MARGIN) / LEADING;
+
+    fn escape(line: &str) -> String {
+        line.chars()
+            .map(|c| match c {
+                '\\' | '(' | ')' => format!("\\{}", c),
+                c if c.is_ascii() && !c.is_ascii_control() => c.to_string(),
+                _ => s!("?"),
+            })
+            .collect()
+    }
+
+    /// Lays out lines of text on as many pages as needed
+    pub fn render(lines: &[String]) -> Vec<u8> {
+        let pages: Vec<&[String]> = if lines.is_empty() {
+            vec![&[]]
+        } else {
+            lines.chunks(LINES_PER_PAGE).collect()
+        };
+        // 1 is the catalog, 2 the page tree, 3 the font, then a page and its
+        // content for every page
+        let page_ids: Vec<usize> = (0..pages.len()).map(|n| 4 + 2 * n).collect();
+        let mut objects = vec![
+            s!("<< /Type /Catalog /Pages 2 0 R >>"),
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                page_ids
+                    .iter()
+                    .map(|id| format!("{} 0 R", id))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                pages.len()
+            ),
+            s!("<< /Type /Font /Subtype /Type1 /BaseFont /Courier >>"),
+        ];
+        for (page, page_id) in pages.iter().zip(&page_ids) {
+            let mut content = format!(
+                "BT /F1 {} Tf {} TL {} {} Td\n",
+                FONT_SIZE,
+                LEADING,
+                MARGIN,
+                HEIGHT - MARGIN
+            );
+            for line in page.iter() {
+                content.push_str(&format!("({}) '\n", escape(line)));
+            }
+            content.push_str("ET");
+            objects.push(format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+                 /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+                WIDTH,
+                HEIGHT,
+                page_id + 1
+            ));
+            objects.push(format!(
+                "<< /Length {} >>\nstream\n{}\nendstream",
+                content.len(),
+                content
+            ));
+        }
+
+        let mut out = s!("%PDF-1.4\n");
+        let mut offsets = vec![];
+        for (n, object) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.push_str(&format!("{} 0 obj\n{}\nendobj\n", n + 1, object));
+        }
+        let xref = out.len();
+        out.push_str(&format!(
+            "xref\n0 {}\n0000000000 65535 f \n",
+            objects.len() + 1
+        ));
+        for offset in offsets {
+            out.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        out.push_str(&format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref
+        ));
+        out.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn statement() -> Statement {
+        let from = NaiveDate::from_ymd(2020, 5, 1).and_hms(0, 0, 0);
+        Statement {
+            merchant_id: s!("merchant"),
+            from,
+            to: NaiveDate::from_ymd(2020, 6, 1).and_hms(0, 0, 0),
+            opening_balance: 1_000_000_000,
+            lines: vec![
+                StatementLine {
+                    at: NaiveDate::from_ymd(2020, 5, 2).and_hms(10, 0, 0),
+                    transaction_id: Some(Uuid::nil()),
+                    order_id: Some(s!("order, \"1\"")),
+                    kind: LedgerEntryKind::PaymentCleared,
+                    amount: 2_500_000_000,
+                    balance: 3_500_000_000,
+                    note: None,
+                },
+                StatementLine {
+                    at: NaiveDate::from_ymd(2020, 5, 3).and_hms(10, 0, 0),
+                    transaction_id: None,
+                    order_id: None,
+                    kind: LedgerEntryKind::Adjustment,
+                    amount: -1,
+                    balance: 3_499_999_999,
+                    note: Some(s!("Chargeback (order 1)")),
+                },
+            ],
+            closing_balance: 3_499_999_999,
+        }
+    }
+
+    #[test]
+    fn format_grin_test() {
+        assert_eq!(format_grin(0), "0.000000000");
+        assert_eq!(format_grin(1_500_000_001), "1.500000001");
+        assert_eq!(format_grin(-1), "-0.000000001");
+    }
+
+    #[test]
+    fn csv_test() {
+        let csv = statement().to_csv();
+        let rows: Vec<&str> = csv.split("\r\n").collect();
+        assert_eq!(rows.len(), 6);
+        assert_eq!(
+            rows[1],
+            "2020-05-01 00:00:00,,,OpeningBalance,Opening balance,,1.000000000"
+        );
+        assert_eq!(
+            rows[2],
+            "2020-05-02 10:00:00,00000000-0000-0000-0000-000000000000,\"order, \"\"1\"\"\",\
+             PaymentCleared,\"Payment for order order, \"\"1\"\"\",2.500000000,3.500000000"
+        );
+        assert_eq!(
+            rows[4],
+            "2020-06-01 00:00:00,,,ClosingBalance,Closing balance,,3.499999999"
+        );
+    }
+
+    #[test]
+    fn pdf_test() {
+        let pdf = String::from_utf8(statement().to_pdf()).unwrap();
+        assert!(pdf.starts_with("%PDF-1.4\n"));
+        assert!(pdf.ends_with("%%EOF\n"));
+        assert!(pdf.contains("Adjustment: Chargeback \\(or~"));
+        // xref points to the objects
+        let xref: usize = pdf.lines().rev().nth(1).unwrap().parse().unwrap();
+        assert!(pdf[xref..].starts_with("xref\n0 6\n"));
+        let first = &pdf[xref..].lines().nth(3).unwrap()[..10];
+        let offset: usize = first.parse().unwrap();
+        assert!(pdf[offset..].starts_with("1 0 obj"));
+    }
+
+    #[test]
+    fn pdf_pages_test() {
+        let lines: Vec<String> = (0..200).map(|n| format!("line {}", n)).collect();
+        let pdf = String::from_utf8(pdf::render(&lines)).unwrap();
+        assert!(pdf.contains("/Count 3"));
+    }
+}
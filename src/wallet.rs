@@ -1,32 +1,102 @@
 use crate::errors::Error;
 use crate::jsonrpc;
+use crate::metrics;
+use crate::secure_api::{EcdhKeypair, EncryptedBody, SharedKey, ENCRYPTED_REQUEST_METHOD};
 use crate::ser;
 use actix_web::client::Client;
-use chrono::{DateTime, Utc};
-use log::{debug, error, info};
+use futures::future::FutureExt;
+use log::{debug, error, info, warn};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use serde_json::from_slice;
-use std::iter::Iterator;
+use serde_json::{from_slice, json, Value};
+use std::future::Future;
+use std::pin::Pin;
 use std::str::from_utf8;
+use std::sync::Arc;
 use uuid::Uuid;
 
+pub type WalletFuture<T> = Pin<Box<dyn Future<Output = Result<T, Error>>>>;
+
+/// Operations we need from a grin wallet. Implemented by `Wallet` and by
+/// `fakes::FakeWallet` which is used in tests.
+pub trait WalletApi: Send + Sync {
+    /// Send request to the foreign API of the wallet as is
+    fn foreign_request(&self, req: jsonrpc::Request) -> WalletFuture<jsonrpc::Response>;
+    fn get_tx(&self, tx_id: &str) -> WalletFuture<TxLogEntry>;
+    fn receive(&self, slate: &Slate) -> WalletFuture<Slate>;
+    fn finalize(&self, slate: &Slate) -> WalletFuture<Slate>;
+    fn post_tx(&self, tx: &Transaction, fluff: bool) -> WalletFuture<()>;
+    fn cancel_tx(&self, tx_slate_id: &str) -> WalletFuture<()>;
+    fn retrieve_summary_info(&self, minimum_confirmations: u64) -> WalletFuture<WalletInfo>;
+    fn create_slate(&self, amount: u64, message: String) -> WalletFuture<Slate>;
+    /// Network fee of sending `amount` now, no outputs are locked
+    fn estimate_fee(&self, amount: u64) -> WalletFuture<u64>;
+}
+
+/// Failed call of the secure owner API
+enum OwnerCallError {
+    /// The wallet doesn't accept our session, the call wasn't executed
+    Session(Error),
+    /// The call failed or we don't know if it was executed
+    Call(Error),
+}
+
+impl From<Error> for OwnerCallError {
+    fn from(e: Error) -> Self {
+        OwnerCallError::Call(e)
+    }
+}
+
+impl From<OwnerCallError> for Error {
+    fn from(e: OwnerCallError) -> Self {
+        match e {
+            OwnerCallError::Session(e) | OwnerCallError::Call(e) => e,
+        }
+    }
+}
+
+/// Error codes of the secure owner API when the wallet has no shared key or
+/// a different one (e.g. it was restarted), the request wasn't decrypted
+const ENCRYPTION_NOT_STARTED_CODE: i32 = -32001;
+const DECRYPTION_ERROR_CODE: i32 = -32002;
+
+/// Errors which grin-wallet returns when it checks the token, before the
+/// method is executed
+const SESSION_ERRORS: &[&str] = &["InvalidKeychainMask", "KeychainDoesntExist"];
+
+/// The wallet didn't execute the call because it doesn't accept our session
+fn is_session_error(resp: &jsonrpc::Response) -> bool {
+    match &resp.error {
+        Some(e) => e.code == ENCRYPTION_NOT_STARTED_CODE || e.code == DECRYPTION_ERROR_CODE,
+        None => resp.result["Err"]
+            .as_str()
+            .map(|kind| SESSION_ERRORS.contains(&kind))
+            .unwrap_or(false),
+    }
+}
+
+/// Client of grin-wallet. Owner API calls go through the secure JSON-RPC v3 API,
+/// foreign API calls (which are also proxied for customers' wallets) through JSON-RPC v2.
 #[derive(Clone)]
 pub struct Wallet {
-    //    client: Client,
     username: String,
     password: String,
     url: String,
+    wallet_password: String,
+    session: Arc<Mutex<Option<OwnerSession>>>,
+}
+
+/// Shared encryption key and token of an opened wallet
+#[derive(Clone)]
+struct OwnerSession {
+    key: SharedKey,
+    token: String,
 }
 
-const RETRIEVE_TXS_URL: &'static str = "v1/wallet/owner/retrieve_txs";
-const RECEIVE_URL: &'static str = "v1/wallet/foreign/receive_tx";
-const SEND_URL: &'static str = "/v1/wallet/owner/issue_send_tx";
-const FINALIZE_URL: &'static str = "/v1/wallet/owner/finalize_tx";
-const CANCEL_TX_URL: &'static str = "/v1/wallet/owner/cancel_tx";
-const POST_TX_URL: &'static str = "/v1/wallet/owner/post_tx?fluff";
 const JSONRPC_FOREIGN_URL: &'static str = "v2/foreign";
-const JSONRPC_OWNER_URL: &'static str = "v2/owner";
+const JSONRPC_OWNER_URL: &'static str = "v3/owner";
 
 /// V2 Init / Send TX API Args
 #[derive(Clone, Serialize, Deserialize)]
@@ -80,16 +150,13 @@ pub struct InitTxArgs {
 }
 
 impl Wallet {
-    pub fn new(url: &str, username: &str, password: &str) -> Self {
-        //let connector = Connector::new()
-        //.conn_lifetime(Duration::from_secs(300))
-        //.conn_keep_alive(Duration::from_secs(300))
-        //.finish();
+    pub fn new(url: &str, username: &str, password: &str, wallet_password: &str) -> Self {
         Wallet {
             url: url.trim_end_matches('/').to_owned(),
             username: username.to_owned(),
             password: password.to_owned(),
-            //client: Client::build().connector(connector).finish(),
+            wallet_password: wallet_password.to_owned(),
+            session: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -97,35 +164,27 @@ impl Wallet {
         Client::new()
     }
 
-    pub async fn jsonrpc_request(
+    async fn post_jsonrpc(
         &self,
-        req: jsonrpc::Request,
-        owner: bool,
+        url: &str,
+        req: &jsonrpc::Request,
     ) -> Result<jsonrpc::Response, Error> {
-        let url: String;
-
-        if (owner) {
-            url = format!("{}/{}", self.url, JSONRPC_OWNER_URL);
-        } else {
-            url = format!("{}/{}", self.url, JSONRPC_FOREIGN_URL);
-        }
-
-        debug!("Send raw jsonrpc request {}", url);
+        debug!("Send jsonrpc request {} to {}", req.method, url);
 
         let mut resp = self
             .client()
-            .post(&url) // <- Create request builder
+            .post(url) // <- Create request builder
             .basic_auth(&self.username, Some(&self.password))
-            .send_json(&req)
+            .send_json(req)
             .await
             .map_err(|e| Error::WalletAPIError(s!(e)))?;
 
         if !resp.status().is_success() {
             return Err(Error::WalletAPIError(format!("Error status: {:?}", resp)));
         }
-        debug!("Response: {:?}", resp);
         let bytes = resp
             .body()
+            .limit(10 * 1024 * 1024)
             .await
             .map_err(|e| Error::WalletAPIError(s!(e)))?;
 
@@ -140,26 +199,123 @@ impl Wallet {
         Ok(jsresp)
     }
 
-    pub async fn get_tx(&self, tx_id: &str) -> Result<TxLogEntry, Error> {
-        debug!("Get transaction from wallet");
-        let tx_id = tx_id.to_owned();
+    /// Send request to the foreign API of the wallet as is
+    pub async fn foreign_request(&self, req: jsonrpc::Request) -> Result<jsonrpc::Response, Error> {
+        let url = format!("{}/{}", self.url, JSONRPC_FOREIGN_URL);
+        self.post_jsonrpc(&url, &req).await
+    }
+
+    /// Exchange keys with the wallet and open it.
+    async fn open_session(&self) -> Result<OwnerSession, Error> {
+        info!("Init secure owner API session with wallet");
+        let url = format!("{}/{}", self.url, JSONRPC_OWNER_URL);
+        let keypair = EcdhKeypair::new()?;
+        let req = jsonrpc::Request::new(
+            "init_secure_api",
+            json!({ "ecdh_pubkey": keypair.public_hex() }),
+        );
+        let resp = self.post_jsonrpc(&url, &req).await?;
+        let server_pubkey: String = jsonrpc::TypedResponse::new(resp).into_result()?;
+        let key = keypair.shared_key(&server_pubkey)?;
+
         let req = jsonrpc::Request::new(
-            "retrieve_txs",
-            vec![
-                serde_json::to_value(true).unwrap(),
-                serde_json::Value::Null,
-                serde_json::to_value(&tx_id).unwrap(),
-            ],
+            "open_wallet",
+            json!({
+                "name": null,
+                "password": self.wallet_password,
+            }),
         );
+        let token: String = self.encrypted_request(&key, &req).await?;
+        Ok(OwnerSession { key, token })
+    }
+
+    async fn encrypted_request<T: DeserializeOwned>(
+        &self,
+        key: &SharedKey,
+        req: &jsonrpc::Request,
+    ) -> Result<T, OwnerCallError> {
+        use OwnerCallError::{Call, Session};
+        let url = format!("{}/{}", self.url, JSONRPC_OWNER_URL);
+        let body = EncryptedBody::encrypt(&serde_json::to_value(req).map_err(Error::from)?, key)?;
+        let enc_req = jsonrpc::Request::new(
+            ENCRYPTED_REQUEST_METHOD,
+            serde_json::to_value(body).map_err(Error::from)?,
+        );
+        // the request may have reached the wallet, it's not safe to resend it
+        let resp = self.post_jsonrpc(&url, &enc_req).await?;
+        if is_session_error(&resp) {
+            return Err(Session(Error::WalletAPIError(format!(
+                "Secure API session is rejected {:?}",
+                resp.error
+            ))));
+        }
+        let enc_body: EncryptedBody = jsonrpc::TypedResponse::new(resp).into_result()?;
+        let resp = enc_body
+            .decrypt(key)
+            .map_err(|e| Error::WalletAPIError(format!("Cannot decrypt response {}", e)))?;
+        let resp: jsonrpc::Response = serde_json::from_value(resp).map_err(Error::from)?;
+        if is_session_error(&resp) {
+            return Err(Session(Error::WalletAPIError(format!(
+                "Token is rejected {}",
+                resp.result["Err"]
+            ))));
+        }
+        jsonrpc::TypedResponse::new(resp)
+            .into_result()
+            .map_err(Call)
+    }
+
+    /// Call method of the secure owner API. The session is established lazily
+    /// and reopened once if the wallet doesn't accept it anymore (e.g. it was restarted).
+    /// Other errors are returned as is, the call may have been executed by the wallet.
+    pub async fn owner_request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<T, Error> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let session = self.session.lock().clone();
+            let session = match session {
+                Some(session) => session,
+                None => {
+                    let session = self.open_session().await?;
+                    *self.session.lock() = Some(session.clone());
+                    session
+                }
+            };
+
+            let mut params = params.clone();
+            params["token"] = json!(session.token);
+            let req = jsonrpc::Request::new(method, params);
+
+            match self.encrypted_request(&session.key, &req).await {
+                Ok(res) => return Ok(res),
+                Err(OwnerCallError::Session(e)) => {
+                    *self.session.lock() = None;
+                    if attempt > 1 {
+                        return Err(e);
+                    }
+                    warn!("Owner API call {} failed, reopen session: {}", method, e);
+                }
+                Err(OwnerCallError::Call(e)) => return Err(e),
+            }
+        }
+    }
 
-        let resp = self.jsonrpc_request(req, true).await?;
-        debug!("Response: {:?}", resp);
-        let res: Result<(bool, Vec<TxLogEntry>), String> =
-            serde_json::from_value(resp.result.clone()).map_err(|e| {
-                error!("Cannot decode json {:?}:\n with error {} ", resp.result, e);
-                Error::WalletAPIError(format!("Cannot decode json {}", e))
-            })?;
-        let (updated, txs) = res.map_err(|e| Error::General(s!(e)))?;
+    pub async fn get_tx(&self, tx_id: &str) -> Result<TxLogEntry, Error> {
+        debug!("Get transaction from wallet");
+        let (_, txs): (bool, Vec<TxLogEntry>) = self
+            .owner_request(
+                "retrieve_txs",
+                json!({
+                    "refresh_from_node": true,
+                    "tx_id": null,
+                    "tx_slate_id": tx_id,
+                }),
+            )
+            .await?;
         if txs.len() == 0 {
             return Err(Error::WalletAPIError(format!(
                 "Transaction with slate_id {} not found",
@@ -177,164 +333,236 @@ impl Wallet {
     }
 
     pub async fn receive(&self, slate: &Slate) -> Result<Slate, Error> {
-        let url = format!("{}/{}", self.url, RECEIVE_URL);
-        debug!("Receive slate by wallet  {}", url);
-        let mut resp = self
-            .client()
-            .post(&url)
-            .basic_auth(&self.username, Some(&self.password))
-            .send_json(slate)
-            .await
-            .map_err(|e| Error::WalletAPIError(s!(e)))?;
-
-        if !resp.status().is_success() {
-            return Err(Error::WalletAPIError(format!("Error status: {:?}", resp)));
-        }
-
-        let bytes = resp
-            .body()
-            .await
-            .map_err(|e| Error::WalletAPIError(s!(e)))?;
-        let slate_resp: Slate = from_slice(&bytes).map_err(|e| {
-            error!(
-                "Cannot decode json {:?}:\n with error {} ",
-                from_utf8(&bytes),
-                e
-            );
-            Error::WalletAPIError(format!("Cannot decode json {}", e))
-        })?;
-        Ok(slate_resp)
+        debug!("Receive slate {} by wallet", slate.id);
+        let req = jsonrpc::Request::new(
+            "receive_tx",
+            json!([serde_json::to_value(slate)?, null, null]),
+        );
+        let resp = self.foreign_request(req).await?;
+        jsonrpc::TypedResponse::new(resp).into_result()
     }
 
     pub async fn finalize(&self, slate: &Slate) -> Result<Slate, Error> {
-        let url = format!("{}/{}", self.url, FINALIZE_URL);
-        debug!("Finalize slate by wallet {}", url);
-        let mut resp = self
-            .client()
-            .post(&url)
-            .basic_auth(&self.username, Some(&self.password))
-            .send_json(slate)
+        debug!("Finalize slate {} by wallet", slate.id);
+        self.owner_request("finalize_tx", json!({ "slate": slate }))
             .await
-            .map_err(|e| Error::WalletAPIError(s!(e)))?;
-
-        if !resp.status().is_success() {
-            return Err(Error::WalletAPIError(format!("Error status: {:?}", resp)));
-        }
-
-        let bytes = resp
-            .body()
-            .await
-            .map_err(|e| Error::WalletAPIError(s!(e)))?;
-
-        let slate_resp: Slate = from_slice(&bytes).map_err(|e| {
-            error!(
-                "Cannot decode json {:?}:\n with error {} ",
-                from_utf8(&bytes),
-                e
-            );
-            Error::WalletAPIError(format!("Cannot decode json {}", e))
-        })?;
-        Ok(slate_resp)
     }
+
     pub async fn cancel_tx(&self, tx_slate_id: &str) -> Result<(), Error> {
-        let tx_slate_id = tx_slate_id.to_owned();
-        let req = jsonrpc::Request::new(
+        self.owner_request(
             "cancel_tx",
-            vec![
-                serde_json::Value::Null,
-                serde_json::to_value(&tx_slate_id).unwrap(),
-            ],
-        );
-
-        let resp = self.jsonrpc_request(req, true).await?;
-        if let Some(err) = resp.error {
-            return Err(Error::WalletAPIError(format!(
-                "Cannot cancel tx {}: {}",
-                tx_slate_id, err
-            )));
-        }
-        let res = serde_json::from_value::<Result<(), String>>(resp.result)?;
-        if let Err(err) = res {
-            return Err(Error::WalletAPIError(format!(
-                "Cannot cancel tx {}: {}",
-                tx_slate_id, err
-            )));
-        }
-        Ok(())
+            json!({
+                "tx_id": null,
+                "tx_slate_id": tx_slate_id,
+            }),
+        )
+        .await
+        .map_err(|e| Error::WalletAPIError(format!("Cannot cancel tx {}: {}", tx_slate_id, e)))
     }
 
-    pub async fn post_tx(&self) -> Result<(), Error> {
-        let url = format!("{}/{}", self.url, POST_TX_URL);
-        debug!("Post transaction in chain by wallet as {}", url);
-        let resp = self
-            .client()
-            .post(&url)
-            .basic_auth(&self.username, Some(&self.password))
-            .send()
+    pub async fn post_tx(&self, tx: &Transaction, fluff: bool) -> Result<(), Error> {
+        debug!("Post transaction in chain by wallet");
+        self.owner_request("post_tx", json!({ "tx": tx, "fluff": fluff }))
             .await
-            .map_err(|e| Error::WalletAPIError(s!(e)))?;
-
-        if !resp.status().is_success() {
-            Err(Error::WalletAPIError(format!("Error status: {:?}", resp)))
-        } else {
-            Ok(())
-        }
     }
 
-    pub async fn create_slate(
+    pub async fn retrieve_summary_info(
         &self,
-        amount: u64,
-        message: String,
-    ) -> Result<jsonrpc::TypedResponse<Slate>, Error> {
-        let url = format!("{}/{}", self.url, SEND_URL);
-        info!("Try to create payout slate");
-        debug!("Receive as {} {}: {}", self.username, self.password, url);
-        let payment = InitTxArgs {
+        minimum_confirmations: u64,
+    ) -> Result<WalletInfo, Error> {
+        let (_, info): (bool, WalletInfo) = self
+            .owner_request(
+                "retrieve_summary_info",
+                json!({
+                    "refresh_from_node": true,
+                    "minimum_confirmations": minimum_confirmations,
+                }),
+            )
+            .await?;
+        Ok(info)
+    }
+
+    fn init_tx_args(amount: u64, message: Option<String>, estimate_only: bool) -> InitTxArgs {
+        InitTxArgs {
             src_acct_name: None,
             amount,
             minimum_confirmations: 10,
             max_outputs: 10,
             num_change_outputs: 1,
             selection_strategy_is_use_all: false,
-            message: Some(message),
+            message,
             target_slate_version: None,
-            estimate_only: None,
+            estimate_only: Some(estimate_only),
             send_args: None,
-        };
+        }
+    }
 
-        let req =
-            jsonrpc::Request::new("init_send_tx", vec![serde_json::to_value(payment).unwrap()]);
+    pub async fn estimate_fee(&self, amount: u64) -> Result<u64, Error> {
+        debug!("Estimate fee of sending {}", amount);
+        let args = Wallet::init_tx_args(amount, None, true);
+        let slate: Slate = self
+            .owner_request("init_send_tx", json!({ "args": args }))
+            .await
+            .map_err(|e| Error::WalletAPIError(format!("Cannot estimate fee: {}", e)))?;
+        Ok(slate.fee)
+    }
 
-        let resp = self
-            .jsonrpc_request(req, true)
+    pub async fn create_slate(&self, amount: u64, message: String) -> Result<Slate, Error> {
+        info!("Try to create payout slate");
+        let args = Wallet::init_tx_args(amount, Some(message), false);
+
+        let slate: Slate = self
+            .owner_request("init_send_tx", json!({ "args": args }))
             .await
             .map_err(|e| Error::WalletAPIError(format!("Cannot create slate in wallet: {}", e)))?;
 
-        if let Some(err) = resp.error.as_ref() {
-            error!("Cannot create slate in wallet: {}", err);
-            Ok(jsonrpc::TypedResponse::new(resp))
-        } else {
-            let val =
-                serde_json::from_value::<Result<serde_json::Value, String>>(resp.result.clone())
-                    .map_err(|e| Error::WalletAPIError(format!("Cannon parse slate: {}", e)))?;
-
-            let req =
-                jsonrpc::Request::new("tx_lock_outputs", vec![val.unwrap(), serde_json::json!(0)]);
-            let lock_resp = self.jsonrpc_request(req, true).await.map_err(|e| {
-                Error::WalletAPIError(format!("Cannon lock outputs in wallet: {}", e))
-            })?;
-
-            if let Some(err) = lock_resp.error {
-                error!("Cannot lock outputs in wallet: {}", err);
-                return Err(Error::WalletAPIError(format!(
-                    "Cannon lock outputs in wallet: {}",
-                    err
-                )));
-            }
-            Ok(jsonrpc::TypedResponse::new(resp))
+        self.owner_request::<()>(
+            "tx_lock_outputs",
+            json!({
+                "slate": slate,
+                "participant_id": 0,
+            }),
+        )
+        .await
+        .map_err(|e| {
+            error!("Cannot lock outputs in wallet: {}", e);
+            Error::WalletAPIError(format!("Cannon lock outputs in wallet: {}", e))
+        })?;
+        Ok(slate)
+    }
+}
+
+/// Send slate to the foreign API of merchant's listening wallet, returns the
+/// slate signed by merchant's wallet
+pub async fn send_to_merchant_wallet(wallet_url: &str, slate: &Slate) -> Result<Slate, Error> {
+    let url = format!(
+        "{}/{}",
+        wallet_url.trim_end_matches('/'),
+        JSONRPC_FOREIGN_URL
+    );
+    let req = jsonrpc::Request::new(
+        "receive_tx",
+        json!([serde_json::to_value(slate)?, null, null]),
+    );
+    metrics::api_call("merchant_wallet", "receive_tx", async move {
+        debug!("Send slate {} to merchant's wallet {}", slate.id, url);
+        let mut resp = Client::new()
+            .post(url.as_str())
+            .send_json(&req)
+            .await
+            .map_err(|e| Error::WalletAPIError(format!("Cannot reach {}: {}", url, e)))?;
+        if !resp.status().is_success() {
+            return Err(Error::WalletAPIError(format!(
+                "Wallet {} replied with status {}",
+                url,
+                resp.status()
+            )));
         }
+        let resp: jsonrpc::Response = resp
+            .json()
+            .limit(10 * 1024 * 1024)
+            .await
+            .map_err(|e| Error::WalletAPIError(format!("Cannot decode json {}", e)))?;
+        jsonrpc::TypedResponse::new(resp).into_result()
+    })
+    .await
+}
+
+impl WalletApi for Wallet {
+    fn foreign_request(&self, req: jsonrpc::Request) -> WalletFuture<jsonrpc::Response> {
+        let wallet = self.clone();
+        metrics::api_call("wallet", "foreign_request", async move {
+            Wallet::foreign_request(&wallet, req).await
+        })
+        .boxed_local()
+    }
+
+    fn get_tx(&self, tx_id: &str) -> WalletFuture<TxLogEntry> {
+        let (wallet, tx_id) = (self.clone(), tx_id.to_owned());
+        metrics::api_call("wallet", "get_tx", async move {
+            Wallet::get_tx(&wallet, &tx_id).await
+        })
+        .boxed_local()
+    }
+
+    fn receive(&self, slate: &Slate) -> WalletFuture<Slate> {
+        let (wallet, slate) = (self.clone(), slate.clone());
+        metrics::api_call("wallet", "receive", async move {
+            Wallet::receive(&wallet, &slate).await
+        })
+        .boxed_local()
+    }
+
+    fn finalize(&self, slate: &Slate) -> WalletFuture<Slate> {
+        let (wallet, slate) = (self.clone(), slate.clone());
+        metrics::api_call("wallet", "finalize", async move {
+            Wallet::finalize(&wallet, &slate).await
+        })
+        .boxed_local()
+    }
+
+    fn post_tx(&self, tx: &Transaction, fluff: bool) -> WalletFuture<()> {
+        let (wallet, tx) = (self.clone(), tx.clone());
+        metrics::api_call("wallet", "post_tx", async move {
+            Wallet::post_tx(&wallet, &tx, fluff).await
+        })
+        .boxed_local()
+    }
+
+    fn cancel_tx(&self, tx_slate_id: &str) -> WalletFuture<()> {
+        let (wallet, tx_slate_id) = (self.clone(), tx_slate_id.to_owned());
+        metrics::api_call("wallet", "cancel_tx", async move {
+            Wallet::cancel_tx(&wallet, &tx_slate_id).await
+        })
+        .boxed_local()
     }
+
+    fn retrieve_summary_info(&self, minimum_confirmations: u64) -> WalletFuture<WalletInfo> {
+        let wallet = self.clone();
+        metrics::api_call("wallet", "retrieve_summary_info", async move {
+            Wallet::retrieve_summary_info(&wallet, minimum_confirmations).await
+        })
+        .boxed_local()
+    }
+
+    fn create_slate(&self, amount: u64, message: String) -> WalletFuture<Slate> {
+        let wallet = self.clone();
+        metrics::api_call("wallet", "create_slate", async move {
+            Wallet::create_slate(&wallet, amount, message).await
+        })
+        .boxed_local()
+    }
+
+    fn estimate_fee(&self, amount: u64) -> WalletFuture<u64> {
+        let wallet = self.clone();
+        metrics::api_call("wallet", "estimate_fee", async move {
+            Wallet::estimate_fee(&wallet, amount).await
+        })
+        .boxed_local()
+    }
+}
+
+/// Summary of the wallet balances
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalletInfo {
+    #[serde(with = "ser::string_or_u64")]
+    pub last_confirmed_height: u64,
+    #[serde(with = "ser::string_or_u64")]
+    pub minimum_confirmations: u64,
+    #[serde(with = "ser::string_or_u64")]
+    pub total: u64,
+    #[serde(with = "ser::string_or_u64")]
+    pub amount_awaiting_finalization: u64,
+    #[serde(with = "ser::string_or_u64")]
+    pub amount_awaiting_confirmation: u64,
+    #[serde(with = "ser::string_or_u64")]
+    pub amount_immature: u64,
+    #[serde(with = "ser::string_or_u64")]
+    pub amount_currently_spendable: u64,
+    #[serde(with = "ser::string_or_u64")]
+    pub amount_locked: u64,
 }
+
 /// Optional transaction information, recorded when an event happens
 /// to add or remove funds from a wallet. One Transaction log entry
 /// maps to one or many outputs
@@ -349,6 +577,12 @@ pub struct TxLogEntry {
     pub fee: Option<u64>,
     /// Message data, stored as json
     pub messages: Option<ParticipantMessages>,
+    /// Excess of the transaction kernel, known when the slate has signatures of both parties
+    #[serde(default)]
+    pub kernel_excess: Option<String>,
+    /// Height of the chain when the transaction was created, kernel can't be below it
+    #[serde(default, with = "ser::opt_string_or_u64")]
+    pub kernel_lookup_min_height: Option<u64>,
 }
 
 /// Helper just to facilitate serialization
@@ -393,6 +627,9 @@ pub struct ParticipantData {
 /// the slate around by whatever means they choose, (but we can provide some
 /// binary or JSON serialization helpers here).
 
+///
+/// We model only fields we need, the rest is kept as is, so a slate can be
+/// passed back to the wallet without losing data.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Slate {
     /// Unique transaction ID, selected by sender
@@ -403,25 +640,78 @@ pub struct Slate {
     /// base amount (excluding fee)
     #[serde(with = "ser::string_or_u64")]
     pub amount: u64,
+    /// fee amount
+    #[serde(with = "ser::string_or_u64")]
+    pub fee: u64,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, Value>,
 }
 
 /// A transaction
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub body: TransactionBody,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, Value>,
 }
 
 impl Transaction {
     pub fn output_commitments(&self) -> Vec<Vec<u8>> {
         self.body.outputs.iter().map(|o| o.commit.clone()).collect()
     }
+
+    pub fn input_commitments(&self) -> Vec<Vec<u8>> {
+        self.body.inputs.iter().map(|i| i.commit.clone()).collect()
+    }
+
+    /// Excess of the kernel, it's zero until the transaction is finalized
+    pub fn kernel_excess(&self) -> Option<Vec<u8>> {
+        self.body
+            .kernels
+            .first()
+            .map(|kernel| kernel.excess.clone())
+            .filter(|excess| excess.iter().any(|b| *b != 0))
+    }
 }
 
 /// TransactionBody is a common abstraction for transaction and block
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TransactionBody {
+    /// List of inputs spent by the transaction.
+    #[serde(default)]
+    pub inputs: Vec<Input>,
     /// List of outputs the transaction produces.
     pub outputs: Vec<Output>,
+    /// List of kernels that make up this transaction (usually a single kernel).
+    #[serde(default)]
+    pub kernels: Vec<TxKernel>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, Value>,
+}
+
+/// A proof that a transaction sums to zero. Its excess is unique, so it's
+/// used to find the transaction in the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxKernel {
+    #[serde(
+        serialize_with = "ser::as_hex",
+        deserialize_with = "ser::commitment_from_hex"
+    )]
+    pub excess: Vec<u8>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, Value>,
+}
+
+/// A transaction input, it spends an output of an earlier transaction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Input {
+    #[serde(
+        serialize_with = "ser::as_hex",
+        deserialize_with = "ser::commitment_from_hex"
+    )]
+    pub commit: Vec<u8>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, Value>,
 }
 
 /// Output for a transaction, defining the new ownership of coins that are being
@@ -435,27 +725,69 @@ pub struct Output {
         deserialize_with = "ser::commitment_from_hex"
     )]
     pub commit: Vec<u8>,
-}
-
-#[derive(Debug, Serialize)]
-struct SendTx {
-    amount: u64,
-    minimum_confirmations: u64,
-    method: &'static str,
-    dest: &'static str,
-    max_outputs: u8,
-    num_change_outputs: u8,
-    selection_strategy_is_use_all: bool,
-    message: Option<String>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, Value>,
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    const SLATE: &'static str = r#"
+{
+  "version_info": {"version": 3, "orig_version": 3, "block_header_version": 2},
+  "num_participants": 2,
+  "id": "0436430c-2b02-624c-2032-570501212b00",
+  "tx": {
+    "offset": "d202964900000000d302964900000000d402964900000000d502964900000000",
+    "body": {
+      "inputs": [{"features": "Plain", "commit": "087df32304c5d4ae8b2af0bc31e700019d722910ef87dd4eec3197b80b207e3045"}],
+      "outputs": [{"features": "Plain", "commit": "099b48cfb1f80a2347dc89818449e68e76a3c6817a532a8e9ef2b4a5ccf4363850", "proof": "29701ceae2"}],
+      "kernels": [{"features": "Plain", "fee": "7000000", "lock_height": "0", "excess": "000000000000000000000000000000000000000000000000000000000000000000", "excess_sig": "00"}]
+    }
+  },
+  "amount": "60000000000",
+  "fee": "7000000",
+  "height": "5",
+  "lock_height": "0",
+  "ttl_cutoff_height": null,
+  "participant_data": [],
+  "payment_proof": null
+}"#;
 
     #[test]
-    fn wallet_get_tx_test() {
-        assert!(true);
+    fn session_error_test() {
+        let mut resp = jsonrpc::Response::default();
+        resp.error = Some(jsonrpc::ErrorData::new(
+            DECRYPTION_ERROR_CODE,
+            "Decryption error",
+        ));
+        assert!(is_session_error(&resp));
+        resp.error = Some(jsonrpc::ErrorData::std(-32603));
+        assert!(!is_session_error(&resp));
+
+        let mut resp = jsonrpc::Response::default();
+        resp.result = json!({ "Err": "InvalidKeychainMask" });
+        assert!(is_session_error(&resp));
+        // the message may mention a token, but the call failed on its own
+        resp.result = json!({ "Err": { "GenericError": "Wrong token in slate" } });
+        assert!(!is_session_error(&resp));
+        resp.result = json!({ "Ok": null });
+        assert!(!is_session_error(&resp));
     }
+
     #[test]
-    fn txs_read_test() {}
+    fn slate_roundtrip_test() {
+        let original: Value = serde_json::from_str(SLATE).unwrap();
+        let slate: Slate = serde_json::from_value(original.clone()).unwrap();
+        assert_eq!(slate.amount, 60_000_000_000);
+        assert_eq!(slate.tx.output_commitments().len(), 1);
+        assert_eq!(slate.tx.input_commitments().len(), 1);
+        // not finalized yet
+        assert_eq!(slate.tx.kernel_excess(), None);
+        let mut restored = serde_json::to_value(&slate).unwrap();
+        // amounts are always serialized as strings
+        restored["amount"] = original["amount"].clone();
+        assert_eq!(original, restored);
+    }
 }
@@ -0,0 +1,998 @@
+//! Test environment for integration tests: the knockturn app with all actors,
+//! a throwaway Postgres database from `TEST_DATABASE_URL` and local stand-in
+//! HTTP servers for grin wallet (owner API v3, foreign API v2), grin node and
+//! merchant's callback.
+#![allow(dead_code)]
+use actix::prelude::*;
+use actix_identity::IdentityService;
+use actix_web::client::{Client, ClientResponse};
+use actix_web::dev::Server;
+use actix_web::http::Method;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use diesel::pg::PgConnection;
+use diesel::r2d2::ConnectionManager;
+use diesel::{self, prelude::*};
+use knockturn::app::{routing, AppState};
+use knockturn::clock::{Clock, TestClock};
+use knockturn::db::{get_sync_status, register_rate, set_current_height, DbExecutor};
+use knockturn::fakes::{FakeNode, FakeWallet};
+use knockturn::fsm::Fsm;
+use knockturn::fsm_payout::FsmPayout;
+use knockturn::handlers::admin::{RescanRequest, RescanResponse};
+use knockturn::health::HealthReport;
+use knockturn::jsonrpc;
+use knockturn::leader::Leadership;
+use knockturn::metrics::HttpMetrics;
+use knockturn::models::{CurrentHeight, Merchant, Transaction, TransactionStatus, TransactionType};
+use knockturn::node::{Node, NodeApi};
+use knockturn::scheduler::{JobRegistry, JobStatus, Schedule};
+use knockturn::secure_api::{EcdhKeypair, EncryptedBody, SharedKey, ENCRYPTED_REQUEST_METHOD};
+use knockturn::session::{PgSession, SessionIdentityPolicy};
+use knockturn::totp::Totp;
+use knockturn::wallet::{InitTxArgs, Slate, Wallet, WalletApi};
+use knockturn::{cron, cron_payout, Error, Pool};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+pub const START_HEIGHT: i64 = 1000;
+pub const WALLET_BALANCE: u64 = 1_000_000_000_000;
+pub const MERCHANT_PASSWORD: &'static str = "password";
+const WALLET_PASSWORD: &'static str = "wallet password";
+const WALLET_TOKEN: &'static str = "wallet token";
+pub const OPERATOR_TOKEN: &'static str = "operator token";
+const WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    // All tests share the same database and current height, so they run one by one
+    static ref DB_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+}
+
+pub struct TestEnv {
+    _guard: std::sync::MutexGuard<'static, ()>,
+    pub pool: Pool,
+    pub url: String,
+    pub node: FakeNode,
+    pub wallet: FakeWallet,
+    pub callbacks: Callbacks,
+    pub clock: TestClock,
+    pub merchant: Merchant,
+    pub fsm_payout: Addr<FsmPayout>,
+    servers: Vec<Server>,
+}
+
+impl TestEnv {
+    pub async fn start() -> TestEnv {
+        let _ = env_logger::try_init();
+        let guard = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let pool = test_pool();
+        let clock = TestClock::default();
+        {
+            let conn: &PgConnection = &pool.get().unwrap();
+            diesel_migrations::run_pending_migrations(conn).unwrap();
+            let mut rates = HashMap::new();
+            rates.insert("grin".to_owned(), 1.0);
+            register_rate(rates, clock.now(), conn).unwrap();
+            set_current_height(START_HEIGHT, conn).unwrap();
+            // blocks of the chain from the previous test
+            diesel::delete(knockturn::schema::blocks::table)
+                .execute(conn)
+                .unwrap();
+        }
+
+        let node = FakeNode::new(START_HEIGHT);
+        let wallet = FakeWallet::with_node(WALLET_BALANCE, node.clone());
+        let callbacks = Callbacks::default();
+        let app_clock: Arc<dyn Clock> = Arc::new(clock.clone());
+        let jobs = JobRegistry::new(
+            clock.now(),
+            Schedule::default(),
+            Leadership::single(clock.now()),
+        );
+
+        let (wallet_srv, wallet_url) = start_wallet_server(wallet.clone());
+        let (node_srv, node_url) = start_node_server(node.clone());
+        let (callback_srv, callback_url) = start_callback_server(callbacks.clone());
+
+        let wallet_client: Arc<dyn WalletApi> = Arc::new(Wallet::new(
+            &wallet_url,
+            "grin",
+            "api secret",
+            WALLET_PASSWORD,
+        ));
+        let node_client: Arc<dyn NodeApi> = Arc::new(Node::new(&node_url, "grin", "api secret"));
+
+        let db: Addr<DbExecutor> = SyncArbiter::start(2, {
+            let pool = pool.clone();
+            move || DbExecutor(pool.clone())
+        });
+        let fsm = Fsm {
+            db: db.clone(),
+            wallet: wallet_client.clone(),
+            pool: pool.clone(),
+            clock: app_clock.clone(),
+        }
+        .start();
+        let fsm_payout = FsmPayout {
+            db: db.clone(),
+            wallet: wallet_client.clone(),
+            pool: pool.clone(),
+            clock: app_clock.clone(),
+        }
+        .start();
+        cron::Cron::new(
+            db.clone(),
+            fsm.clone(),
+            node_client.clone(),
+            pool.clone(),
+            app_clock.clone(),
+            jobs.clone(),
+        )
+        .start();
+        cron_payout::CronPayout::new(
+            fsm_payout.clone(),
+            node_client.clone(),
+            app_clock.clone(),
+            jobs.clone(),
+        )
+        .start();
+
+        let app_srv = HttpServer::new({
+            let pool = pool.clone();
+            let node_url = node_url.clone();
+            move || {
+                App::new()
+                    .data(AppState {
+                        db: db.clone(),
+                        wallet: wallet_client.clone(),
+                        pool: pool.clone(),
+                        fsm: fsm.clone(),
+                        fsm_payout: fsm_payout.clone(),
+                        clock: app_clock.clone(),
+                        node: node_client.clone(),
+                        operator_token: Some(OPERATOR_TOKEN.to_owned()),
+                        jobs: jobs.clone(),
+                    })
+                    .configure(routing)
+                    .wrap(HttpMetrics)
+                    .wrap(IdentityService::new(SessionIdentityPolicy))
+                    .wrap(PgSession::new(pool.clone(), app_clock.clone()).secure(false))
+            }
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("http://{}", app_srv.addrs()[0]);
+        let app_srv = app_srv.run();
+
+        let merchant_id = Uuid::new_v4().to_string();
+        let mut resp = client()
+            .post(format!("{}/merchants", url))
+            .send_json(&json!({
+                "id": merchant_id,
+                "email": format!("{}@example.com", merchant_id),
+                "password": MERCHANT_PASSWORD,
+                "wallet_url": null,
+                "callback_url": callback_url,
+            }))
+            .await
+            .unwrap();
+        assert!(resp.status().is_success(), "Cannot create merchant");
+        let _ = resp.body().await.unwrap();
+        let merchant = {
+            use knockturn::schema::merchants::dsl::*;
+            let conn: &PgConnection = &pool.get().unwrap();
+            merchants
+                .find(merchant_id)
+                .get_result::<Merchant>(conn)
+                .unwrap()
+        };
+
+        TestEnv {
+            _guard: guard,
+            pool,
+            url,
+            node,
+            wallet,
+            callbacks,
+            clock,
+            merchant,
+            fsm_payout,
+            servers: vec![app_srv, wallet_srv, node_srv, callback_srv],
+        }
+    }
+
+    pub async fn stop(self) {
+        for srv in &self.servers {
+            srv.stop(true).await;
+        }
+    }
+
+    pub async fn create_payment(&self, grin_amount: i64, confirmations: i64) -> Transaction {
+        let mut resp = client()
+            .post(format!(
+                "{}/merchants/{}/payments",
+                self.url, self.merchant.id
+            ))
+            .basic_auth(&self.merchant.id, Some(&self.merchant.token))
+            .send_json(&json!({
+                "order_id": Uuid::new_v4().to_string(),
+                "amount": {"amount": grin_amount, "currency": "GRIN"},
+                "confirmations": confirmations,
+                "email": null,
+                "message": "Test payment",
+                "redirect_url": null,
+            }))
+            .await
+            .unwrap();
+        assert!(resp.status().is_success(), "Cannot create payment");
+        let payment: Value = resp.json().await.unwrap();
+        let payment_id: Uuid = serde_json::from_value(payment["id"].clone()).unwrap();
+        self.transaction(payment_id)
+    }
+
+    /// Send slate to knockturn as customer's wallet does, returns slate signed by knockturn
+    pub async fn pay(&self, payment_id: Uuid, slate: &Slate) -> Result<Slate, String> {
+        let mut resp = client()
+            .post(format!(
+                "{}/merchants/{}/payments/{}",
+                self.url, self.merchant.id, payment_id
+            ))
+            .send_json(slate)
+            .await
+            .unwrap();
+        let body = resp.body().limit(10 * 1024 * 1024).await.unwrap();
+        if !resp.status().is_success() {
+            return Err(String::from_utf8_lossy(&body).to_string());
+        }
+        Ok(serde_json::from_slice(&body).unwrap())
+    }
+
+    /// Create a payment, pay it and wait until it's confirmed and reported
+    pub async fn confirmed_payment(&self, grin_amount: i64) -> Transaction {
+        let payment = self.create_payment(grin_amount, 1).await;
+        let slate = FakeWallet::payer_slate(grin_amount as u64);
+        let signed_slate = self.pay(payment.id, &slate).await.unwrap();
+        self.node.post_tx(&signed_slate.tx);
+        self.node.mine_blocks(3);
+        self.wait_for_status(payment.id, TransactionStatus::Confirmed)
+            .await;
+        self.wait_for(payment.id, "reported", |tx| tx.reported)
+            .await
+    }
+
+    /// Login in web UI with 2fa, returns cookies of the session
+    pub async fn login(&self) -> String {
+        let session = self.password_login().await;
+        self.pass_2fa(&session).await
+    }
+
+    /// First step of the login, returns cookies of the session waiting for 2fa
+    pub async fn password_login(&self) -> String {
+        let resp = client()
+            .post(format!("{}/login", self.url))
+            .send_form(&[
+                ("login", self.merchant.id.as_str()),
+                ("password", MERCHANT_PASSWORD),
+            ])
+            .await
+            .unwrap();
+        let session = cookies(&resp);
+        assert!(!session.is_empty(), "Cannot login");
+        session
+    }
+
+    /// Second step of the login, the session gets a new id
+    pub async fn pass_2fa(&self, session: &str) -> String {
+        let resp = client()
+            .post(format!("{}/2fa", self.url))
+            .header("Cookie", session)
+            .send_form(&[("code", self.totp_code())])
+            .await
+            .unwrap();
+        let identity = cookies(&resp);
+        assert!(!identity.is_empty(), "Cannot pass 2fa");
+        identity
+    }
+
+    /// Returns status code of a web UI page
+    pub async fn page_status(&self, cookie: &str, path: &str) -> u16 {
+        let resp = client()
+            .get(format!("{}{}", self.url, path))
+            .header("Cookie", cookie)
+            .send()
+            .await
+            .unwrap();
+        resp.status().as_u16()
+    }
+
+    /// Downloads merchant's statement, `query` is the query string of `/statement`
+    pub async fn statement(&self, cookie: &str, query: &str) -> (u16, String) {
+        let mut resp = client()
+            .get(format!("{}/statement?{}", self.url, query))
+            .header("Cookie", cookie)
+            .send()
+            .await
+            .unwrap();
+        let body = resp.body().limit(10 * 1024 * 1024).await.unwrap();
+        (
+            resp.status().as_u16(),
+            String::from_utf8_lossy(&body).to_string(),
+        )
+    }
+
+    pub async fn logout(&self, cookie: &str) {
+        let resp = client()
+            .post(format!("{}/logout", self.url))
+            .header("Cookie", cookie)
+            .send()
+            .await
+            .unwrap();
+        assert!(resp.status().is_redirection());
+    }
+
+    /// Set payout policy in web UI, returns status code of the response
+    pub async fn set_payout_policy(
+        &self,
+        cookie: &str,
+        policy: &str,
+        wallet_url: &str,
+        threshold: &str,
+    ) -> u16 {
+        let resp = client()
+            .post(format!("{}/withdraw/policy", self.url))
+            .header("Cookie", cookie)
+            .send_form(&[
+                ("policy", policy.to_owned()),
+                ("wallet_url", wallet_url.to_owned()),
+                ("threshold", threshold.to_owned()),
+                ("code", self.totp_code()),
+            ])
+            .await
+            .unwrap();
+        resp.status().as_u16()
+    }
+
+    /// Start listening wallet of the merchant, returns its url
+    pub fn start_merchant_wallet(&mut self, wallet: FakeWallet) -> String {
+        let (srv, url) = start_wallet_server(wallet);
+        self.servers.push(srv);
+        url
+    }
+
+    pub fn totp_code(&self) -> String {
+        Totp::new(
+            self.merchant.id.clone(),
+            self.merchant.token_2fa.clone().unwrap(),
+        )
+        .generate()
+        .unwrap()
+    }
+
+    /// Request withdrawal, returns id of the created payout
+    pub async fn withdraw(&self, cookie: &str, grin_amount: i64) -> Uuid {
+        let resp = client()
+            .post(format!("{}/withdraw", self.url))
+            .header("Cookie", cookie)
+            .send_form(&[
+                ("amount", grin_amount.to_string()),
+                ("code", self.totp_code()),
+            ])
+            .await
+            .unwrap();
+        let location = resp
+            .headers()
+            .get("location")
+            .expect("Withdraw should redirect to payout")
+            .to_str()
+            .unwrap();
+        location.trim_start_matches("/payouts/").parse().unwrap()
+    }
+
+    pub async fn payout_slate(&self, cookie: &str, payout_id: Uuid) -> Slate {
+        let mut resp = client()
+            .get(format!(
+                "{}/payouts/{}/knockturn-payout.grinslate",
+                self.url, payout_id
+            ))
+            .header("Cookie", cookie)
+            .send()
+            .await
+            .unwrap();
+        assert!(resp.status().is_success(), "Cannot get payout slate");
+        resp.json().limit(10 * 1024 * 1024).await.unwrap()
+    }
+
+    /// Return slate signed by merchant's wallet
+    pub async fn accept_payout_slate(&self, cookie: &str, payout_id: Uuid, slate: &Slate) {
+        let mut resp = client()
+            .post(format!("{}/payouts/{}", self.url, payout_id))
+            .header("Cookie", cookie)
+            .send_json(slate)
+            .await
+            .unwrap();
+        let body = resp.body().await.unwrap();
+        assert!(
+            resp.status().is_success(),
+            "Cannot accept slate: {}",
+            String::from_utf8_lossy(&body)
+        );
+    }
+
+    /// Cancel payout from its page, returns status of the response
+    pub async fn cancel_payout(&self, cookie: &str, payout_id: Uuid) -> u16 {
+        let resp = client()
+            .post(format!("{}/payouts/{}/cancel", self.url, payout_id))
+            .header("Cookie", cookie)
+            .send()
+            .await
+            .unwrap();
+        resp.status().as_u16()
+    }
+
+    /// Call payout API authorized with `password`, which is either the API
+    /// token or the payout key. `path` is relative to the merchant
+    pub async fn payout_api(
+        &self,
+        method: Method,
+        path: &str,
+        password: &str,
+        body: Option<Value>,
+    ) -> (u16, Value) {
+        let req = client()
+            .request(
+                method,
+                format!("{}/merchants/{}{}", self.url, self.merchant.id, path),
+            )
+            .basic_auth(&self.merchant.id, Some(password));
+        let mut resp = match body {
+            Some(body) => req.send_json(&body).await.unwrap(),
+            None => req.send().await.unwrap(),
+        };
+        let body = resp.body().limit(10 * 1024 * 1024).await.unwrap();
+        let value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        (resp.status().as_u16(), value)
+    }
+
+    /// Call admin API to rescan blocks
+    pub async fn rescan(
+        &self,
+        token: &str,
+        from_height: i64,
+        to_height: i64,
+        apply: bool,
+    ) -> Result<RescanResponse, u16> {
+        let mut resp = client()
+            .post(format!("{}/admin/rescan", self.url))
+            .basic_auth("operator", Some(token))
+            .send_json(&RescanRequest {
+                from_height,
+                to_height,
+                apply,
+            })
+            .await
+            .unwrap();
+        if !resp.status().is_success() {
+            return Err(resp.status().as_u16());
+        }
+        Ok(resp.json().await.unwrap())
+    }
+
+    /// Call admin API to list background jobs
+    pub async fn jobs(&self, token: &str) -> Result<BTreeMap<String, JobStatus>, u16> {
+        let mut resp = client()
+            .get(format!("{}/admin/jobs", self.url))
+            .basic_auth("operator", Some(token))
+            .send()
+            .await
+            .unwrap();
+        if !resp.status().is_success() {
+            return Err(resp.status().as_u16());
+        }
+        Ok(resp.json().await.unwrap())
+    }
+
+    /// Call admin API to pause or resume a background job
+    pub async fn set_job_paused(
+        &self,
+        token: &str,
+        job: &str,
+        paused: bool,
+    ) -> Result<JobStatus, u16> {
+        let action = if paused { "pause" } else { "resume" };
+        let mut resp = client()
+            .post(format!("{}/admin/jobs/{}/{}", self.url, job, action))
+            .basic_auth("operator", Some(token))
+            .send()
+            .await
+            .unwrap();
+        if !resp.status().is_success() {
+            return Err(resp.status().as_u16());
+        }
+        Ok(resp.json().await.unwrap())
+    }
+
+    /// Call admin API to set merchant's fee schedule
+    pub async fn set_fee_schedule(&self, token: &str, schedule: Value) -> u16 {
+        let resp = client()
+            .put(format!(
+                "{}/admin/merchants/{}/fee_schedule",
+                self.url, self.merchant.id
+            ))
+            .basic_auth("operator", Some(token))
+            .send_json(&schedule)
+            .await
+            .unwrap();
+        resp.status().as_u16()
+    }
+
+    /// Call admin API authorized with `token`, `path` starts with `/admin`
+    pub async fn admin_api(
+        &self,
+        method: Method,
+        path: &str,
+        token: &str,
+        body: Option<Value>,
+    ) -> (u16, Value) {
+        let req = client()
+            .request(method, format!("{}{}", self.url, path))
+            .basic_auth("operator", Some(token));
+        let mut resp = match body {
+            Some(body) => req.send_json(&body).await.unwrap(),
+            None => req.send().await.unwrap(),
+        };
+        let body = resp.body().limit(10 * 1024 * 1024).await.unwrap();
+        let value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        (resp.status().as_u16(), value)
+    }
+
+    /// Scrapes `/metrics` with operator's token
+    pub async fn metrics(&self, token: &str) -> Result<String, u16> {
+        let mut resp = client()
+            .get(format!("{}/metrics", self.url))
+            .basic_auth("operator", Some(token))
+            .send()
+            .await
+            .unwrap();
+        if !resp.status().is_success() {
+            return Err(resp.status().as_u16());
+        }
+        let body = resp.body().limit(10 * 1024 * 1024).await.unwrap();
+        Ok(String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// Calls `/health` or `/ready`, returns status code and the report
+    pub async fn health(&self, path: &str) -> (u16, HealthReport) {
+        let mut resp = client()
+            .get(format!("{}{}", self.url, path))
+            .send()
+            .await
+            .unwrap();
+        (resp.status().as_u16(), resp.json().await.unwrap())
+    }
+
+    /// Pretend we've already synced up to `height`
+    pub fn set_current_height(&self, height: i64) {
+        let conn: &PgConnection = &self.pool.get().unwrap();
+        set_current_height(height, conn).unwrap();
+    }
+
+    pub fn transaction(&self, transaction_id: Uuid) -> Transaction {
+        use knockturn::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.pool.get().unwrap();
+        transactions
+            .find(transaction_id)
+            .get_result::<Transaction>(conn)
+            .unwrap()
+    }
+
+    /// Merchant as it's stored now
+    pub fn reload_merchant(&self) -> Merchant {
+        use knockturn::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.pool.get().unwrap();
+        merchants
+            .find(&self.merchant.id)
+            .get_result::<Merchant>(conn)
+            .unwrap()
+    }
+
+    /// Payouts of the merchant, the latest first
+    pub fn payouts(&self) -> Vec<Transaction> {
+        use knockturn::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.pool.get().unwrap();
+        transactions
+            .filter(merchant_id.eq(&self.merchant.id))
+            .filter(transaction_type.eq(TransactionType::Payout))
+            .order(created_at.desc())
+            .load::<Transaction>(conn)
+            .unwrap()
+    }
+
+    pub fn balance(&self) -> i64 {
+        let conn: &PgConnection = &self.pool.get().unwrap();
+        knockturn::db::get_balance(&self.merchant.id, conn).unwrap()
+    }
+
+    pub fn sync_status(&self) -> CurrentHeight {
+        let conn: &PgConnection = &self.pool.get().unwrap();
+        get_sync_status(conn).unwrap()
+    }
+
+    /// Wait until cron syncs with the node's tip
+    pub async fn wait_for_sync(&self) -> CurrentHeight {
+        let started = Instant::now();
+        loop {
+            let status = self.sync_status();
+            if status.height == self.node.height() {
+                return status;
+            }
+            if started.elapsed() > WAIT_TIMEOUT {
+                panic!("Not synced with node: {:?}", status);
+            }
+            actix_rt::time::delay_for(Duration::from_millis(500)).await;
+        }
+    }
+
+    /// Move the clock of the app forward
+    pub fn advance_time(&self, seconds: i64) {
+        self.clock.advance(chrono::Duration::seconds(seconds));
+    }
+
+    /// Wait until crons bring transaction into a state satisfying `predicate`
+    pub async fn wait_for<F>(&self, transaction_id: Uuid, what: &str, predicate: F) -> Transaction
+    where
+        F: Fn(&Transaction) -> bool,
+    {
+        let started = Instant::now();
+        loop {
+            let tx = self.transaction(transaction_id);
+            if predicate(&tx) {
+                return tx;
+            }
+            if started.elapsed() > WAIT_TIMEOUT {
+                panic!("Transaction {} is not {}: {:?}", transaction_id, what, tx);
+            }
+            actix_rt::time::delay_for(Duration::from_millis(500)).await;
+        }
+    }
+
+    /// Wait until crons create a payout number `n` of the merchant
+    pub async fn wait_for_payout(&self, n: usize) -> Transaction {
+        let started = Instant::now();
+        loop {
+            let payouts = self.payouts();
+            if payouts.len() >= n {
+                return payouts[0].clone();
+            }
+            if started.elapsed() > WAIT_TIMEOUT {
+                panic!("Payout {} was not created", n);
+            }
+            actix_rt::time::delay_for(Duration::from_millis(500)).await;
+        }
+    }
+
+    pub async fn wait_for_status(
+        &self,
+        transaction_id: Uuid,
+        status: TransactionStatus,
+    ) -> Transaction {
+        self.wait_for(transaction_id, &status.to_string(), |tx| {
+            tx.status == status
+        })
+        .await
+    }
+}
+
+fn test_pool() -> Pool {
+    let database_url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+    let manager = ConnectionManager::<PgConnection>::new(database_url);
+    r2d2::Pool::builder()
+        .max_size(5)
+        .build(manager)
+        .expect("Failed to create pool.")
+}
+
+fn client() -> Client {
+    Client::build()
+        .disable_redirects()
+        .timeout(Duration::from_secs(30))
+        .finish()
+}
+
+/// `name=value` pairs of all cookies set by response
+fn cookies<S>(resp: &ClientResponse<S>) -> String {
+    resp.headers()
+        .get_all("set-cookie")
+        .filter_map(|v| v.to_str().ok())
+        .filter_map(|v| v.split(';').next())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Calls of merchant's callback, it can be switched to fail
+#[derive(Clone, Default)]
+pub struct Callbacks {
+    calls: Arc<Mutex<Vec<Value>>>,
+    failing: Arc<AtomicBool>,
+}
+
+impl Callbacks {
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::SeqCst);
+    }
+
+    /// Successful callbacks for transaction
+    pub fn received(&self, transaction_id: Uuid) -> Vec<Value> {
+        self.calls
+            .lock()
+            .iter()
+            .filter(|call| call["id"] == json!(transaction_id))
+            .cloned()
+            .collect()
+    }
+}
+
+fn start_callback_server(callbacks: Callbacks) -> (Server, String) {
+    let srv = HttpServer::new(move || {
+        App::new().data(callbacks.clone()).route(
+            "/callback",
+            web::post().to(
+                |body: web::Json<Value>, callbacks: web::Data<Callbacks>| async move {
+                    if callbacks.failing.load(Ordering::SeqCst) {
+                        return HttpResponse::InternalServerError().finish();
+                    }
+                    callbacks.calls.lock().push(body.into_inner());
+                    HttpResponse::Ok().finish()
+                },
+            ),
+        )
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let url = format!("http://{}/callback", srv.addrs()[0]);
+    (srv.run(), url)
+}
+
+#[derive(Deserialize)]
+struct HeightRange {
+    start_height: i64,
+    end_height: i64,
+}
+
+#[derive(Deserialize)]
+struct OutputsQuery {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct KernelQuery {
+    min_height: i64,
+    max_height: i64,
+}
+
+fn start_node_server(node: FakeNode) -> (Server, String) {
+    let srv = HttpServer::new(move || {
+        App::new()
+            .data(node.clone())
+            .route(
+                "/v1/status",
+                web::get().to(|node: web::Data<FakeNode>| async move {
+                    HttpResponse::Ok().json(json!({"tip": {"height": node.height()}}))
+                }),
+            )
+            .route(
+                "/v1/chain/outputs/byheight",
+                web::get().to(
+                    |range: web::Query<HeightRange>, node: web::Data<FakeNode>| async move {
+                        match node.blocks(range.start_height, range.end_height).await {
+                            Ok(blocks) => HttpResponse::Ok().json(blocks),
+                            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/v1/chain/outputs/byids",
+                web::get().to(
+                    |query: web::Query<OutputsQuery>, node: web::Data<FakeNode>| async move {
+                        let commits: Vec<String> =
+                            query.id.split(',').map(|id| id.to_owned()).collect();
+                        match node.outputs(&commits).await {
+                            Ok(outputs) => HttpResponse::Ok().json(outputs),
+                            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/v1/chain/kernels/{excess}",
+                web::get().to(
+                    |excess: web::Path<String>,
+                     query: web::Query<KernelQuery>,
+                     node: web::Data<FakeNode>| async move {
+                        match node
+                            .kernel(&excess, query.min_height, query.max_height)
+                            .await
+                        {
+                            Ok(Some(height)) => HttpResponse::Ok()
+                                .json(json!({"height": height, "mmr_index": height})),
+                            Ok(None) => HttpResponse::NotFound().finish(),
+                            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+                        }
+                    },
+                ),
+            )
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let url = format!("http://{}", srv.addrs()[0]);
+    (srv.run(), url)
+}
+
+/// Stand-in of grin-wallet listener, all calls are served by `FakeWallet`
+#[derive(Clone)]
+struct WalletServer {
+    wallet: FakeWallet,
+    key: Arc<Mutex<Option<SharedKey>>>,
+}
+
+fn start_wallet_server(wallet: FakeWallet) -> (Server, String) {
+    let state = WalletServer {
+        wallet,
+        key: Arc::new(Mutex::new(None)),
+    };
+    let srv = HttpServer::new(move || {
+        App::new()
+            .data(state.clone())
+            .route("/v3/owner", web::post().to(owner_api))
+            .route("/v2/foreign", web::post().to(foreign_api))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let url = format!("http://{}", srv.addrs()[0]);
+    (srv.run(), url)
+}
+
+fn rpc_response(id: Value, result: Result<Value, Error>) -> jsonrpc::Response {
+    let mut resp = jsonrpc::Response::with_id(id);
+    resp.result = match result {
+        Ok(value) => json!({ "Ok": value }),
+        Err(e) => json!({ "Err": { "message": e.to_string() } }),
+    };
+    resp
+}
+
+async fn foreign_api(
+    req: web::Json<jsonrpc::Request>,
+    state: web::Data<WalletServer>,
+) -> HttpResponse {
+    match state.wallet.foreign_request(req.into_inner()).await {
+        Ok(resp) => HttpResponse::Ok().json(resp),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+async fn owner_api(
+    req: web::Json<jsonrpc::Request>,
+    state: web::Data<WalletServer>,
+) -> HttpResponse {
+    let req = req.into_inner();
+    let resp = match req.method.as_str() {
+        "init_secure_api" => {
+            let keypair = EcdhKeypair::new().unwrap();
+            let client_key = req.params["ecdh_pubkey"].as_str().unwrap_or_default();
+            let result = keypair.shared_key(client_key).map(|key| {
+                *state.key.lock() = Some(key);
+                json!(keypair.public_hex())
+            });
+            rpc_response(req.id, result)
+        }
+        ENCRYPTED_REQUEST_METHOD => encrypted_request(&state, req.id, &req.params).await,
+        _ => rpc_response(
+            req.id,
+            Err(Error::General(format!(
+                "Method {} must be encrypted",
+                req.method
+            ))),
+        ),
+    };
+    HttpResponse::Ok().json(resp)
+}
+
+fn rpc_error(id: Value, code: i32, message: &str) -> jsonrpc::Response {
+    let mut resp = jsonrpc::Response::with_id(id);
+    resp.error = Some(jsonrpc::ErrorData::new(code, message));
+    resp
+}
+
+/// Errors are returned the way grin-wallet does, so the client can tell
+/// whether the method was executed
+async fn encrypted_request(state: &WalletServer, id: Value, params: &Value) -> jsonrpc::Response {
+    let key = match state.key.lock().clone() {
+        Some(key) => key,
+        None => return rpc_error(id, -32001, "Encryption must be enabled"),
+    };
+    let inner = serde_json::from_value::<EncryptedBody>(params.clone())
+        .map_err(Error::from)
+        .and_then(|body| body.decrypt(&key))
+        .and_then(|inner| serde_json::from_value::<jsonrpc::Request>(inner).map_err(Error::from));
+    let inner = match inner {
+        Ok(inner) => inner,
+        Err(e) => return rpc_error(id, -32002, &format!("Decryption error: {}", e)),
+    };
+    let resp = if inner.method != "open_wallet" && inner.params["token"] != json!(WALLET_TOKEN) {
+        let mut resp = jsonrpc::Response::with_id(inner.id.clone());
+        resp.result = json!({ "Err": "InvalidKeychainMask" });
+        resp
+    } else {
+        rpc_response(inner.id.clone(), owner_method(&state.wallet, &inner).await)
+    };
+    let result = serde_json::to_value(resp)
+        .map_err(Error::from)
+        .and_then(|resp| EncryptedBody::encrypt(&resp, &key))
+        .and_then(|body| serde_json::to_value(body).map_err(Error::from));
+    rpc_response(id, result)
+}
+
+async fn owner_method(wallet: &FakeWallet, req: &jsonrpc::Request) -> Result<Value, Error> {
+    let params = &req.params;
+    if req.method == "open_wallet" {
+        if params["password"] != json!(WALLET_PASSWORD) {
+            return Err(Error::General("Wrong wallet password".to_owned()));
+        }
+        return Ok(json!(WALLET_TOKEN));
+    }
+    match req.method.as_str() {
+        "retrieve_txs" => {
+            let slate_id = params["tx_slate_id"].as_str().unwrap_or_default();
+            let txs: Vec<_> = wallet.get_tx(slate_id).await.into_iter().collect();
+            Ok(json!([true, txs]))
+        }
+        "init_send_tx" => {
+            let args: InitTxArgs = serde_json::from_value(params["args"].clone())?;
+            if args.estimate_only == Some(true) {
+                // as grin-wallet does, the estimate has the total amount locked
+                let fee = wallet.estimate_fee(args.amount).await?;
+                let mut slate = FakeWallet::payer_slate(args.amount + fee);
+                slate.fee = fee;
+                return Ok(json!(slate));
+            }
+            let slate = wallet
+                .create_slate(args.amount, args.message.unwrap_or_default())
+                .await?;
+            Ok(json!(slate))
+        }
+        "tx_lock_outputs" => Ok(Value::Null),
+        "finalize_tx" => {
+            let slate: Slate = serde_json::from_value(params["slate"].clone())?;
+            Ok(json!(wallet.finalize(&slate).await?))
+        }
+        "post_tx" => {
+            let tx = serde_json::from_value(params["tx"].clone())?;
+            wallet
+                .post_tx(&tx, params["fluff"].as_bool().unwrap_or(false))
+                .await?;
+            Ok(Value::Null)
+        }
+        "cancel_tx" => {
+            let slate_id = params["tx_slate_id"].as_str().unwrap_or_default();
+            wallet.cancel_tx(slate_id).await?;
+            Ok(Value::Null)
+        }
+        "retrieve_summary_info" => {
+            let min_conf = params["minimum_confirmations"].as_u64().unwrap_or(10);
+            Ok(json!([true, wallet.retrieve_summary_info(min_conf).await?]))
+        }
+        method => Err(Error::General(format!("Unknown method {}", method))),
+    }
+}
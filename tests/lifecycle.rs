@@ -0,0 +1,1229 @@
+//! End-to-end tests of payment and payout lifecycles.
+//! They need a throwaway database in `TEST_DATABASE_URL`.
+mod common;
+
+use actix_web::http::Method;
+use chrono::Utc;
+use common::{TestEnv, OPERATOR_TOKEN, START_HEIGHT};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use knockturn::cron::HORIZON_HEIGHT;
+use knockturn::fakes::{FakeTxStatus, FakeWallet};
+use knockturn::fsm_payout::{FinalizePayout, GetInitializedPayout};
+use knockturn::health::MAX_RATES_AGE_SECONDS;
+use knockturn::leader::{acquire_lease, release_lease, Leadership};
+use knockturn::models::{PayoutPolicy, TransactionStatus, PENDING_PAYOUT_TTL_SECONDS};
+use knockturn::scheduler::{JobRegistry, Schedule, DEFAULT_INTERVAL_SECONDS};
+use knockturn::Error;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const PAYMENT_AMOUNT: i64 = 2_000_000_000;
+
+#[actix_rt::test]
+async fn payment_and_payout_lifecycle_test() {
+    let env = TestEnv::start().await;
+
+    let payment = env.create_payment(PAYMENT_AMOUNT, 2).await;
+    assert_eq!(payment.status, TransactionStatus::New);
+    assert_eq!(payment.grin_amount, PAYMENT_AMOUNT);
+
+    // wrong amount is refused
+    let wrong_slate = FakeWallet::payer_slate(PAYMENT_AMOUNT as u64 / 2);
+    assert!(env.pay(payment.id, &wrong_slate).await.is_err());
+
+    let slate = FakeWallet::payer_slate(PAYMENT_AMOUNT as u64);
+    let signed_slate = env.pay(payment.id, &slate).await.unwrap();
+    let payment = env.transaction(payment.id);
+    assert_eq!(payment.status, TransactionStatus::Pending);
+    assert_eq!(
+        payment.wallet_tx_slate_id,
+        Some(slate.id.hyphenated().to_string())
+    );
+    assert!(payment.kernel_excess.is_some());
+
+    // customer finalizes and posts transaction
+    env.node.post_tx(&signed_slate.tx);
+    let height = env.node.mine_block();
+    let payment = env
+        .wait_for_status(payment.id, TransactionStatus::InChain)
+        .await;
+    assert_eq!(payment.height, Some(height));
+    assert_eq!(env.balance(), 0);
+
+    env.node.mine_blocks(3);
+    env.wait_for_status(payment.id, TransactionStatus::Confirmed)
+        .await;
+    env.wait_for(payment.id, "reported", |tx| tx.reported).await;
+    let callbacks = env.callbacks.received(payment.id);
+    assert_eq!(callbacks.len(), 1);
+    assert_eq!(callbacks[0]["status"], json!(TransactionStatus::Confirmed));
+    assert_eq!(callbacks[0]["token"], json!(env.merchant.token));
+    assert_eq!(env.balance(), PAYMENT_AMOUNT);
+
+    // withdraw everything
+    let cookie = env.login().await;
+    let payout_id = env.withdraw(&cookie, PAYMENT_AMOUNT).await;
+    let payout = env.transaction(payout_id);
+    assert_eq!(payout.status, TransactionStatus::New);
+    assert_eq!(env.balance(), 0);
+
+    let payout_slate = env.payout_slate(&cookie, payout_id).await;
+    let payout = env.transaction(payout_id);
+    assert_eq!(payout.status, TransactionStatus::Initialized);
+    assert_eq!(
+        payout_slate.amount as i64,
+        PAYMENT_AMOUNT - payout.transfer_fee.unwrap() - payout.knockturn_fee.unwrap()
+    );
+
+    env.accept_payout_slate(&cookie, payout_id, &payout_slate)
+        .await;
+    let payout = env.transaction(payout_id);
+    assert_eq!(payout.status, TransactionStatus::Pending);
+    assert!(payout.kernel_excess.is_some());
+    assert_eq!(
+        env.wallet
+            .tx_status(&payout_slate.id.hyphenated().to_string()),
+        Some(FakeTxStatus::Posted)
+    );
+
+    let height = env.node.mine_block();
+    let payout = env
+        .wait_for_status(payout_id, TransactionStatus::InChain)
+        .await;
+    assert_eq!(payout.height, Some(height));
+    env.wait_for(payout_id, "reported", |tx| tx.reported).await;
+    env.node.mine_blocks(11);
+    env.wait_for_status(payout_id, TransactionStatus::Confirmed)
+        .await;
+    env.wait_for(payout_id, "reported", |tx| tx.reported).await;
+    let statuses: Vec<Value> = env
+        .callbacks
+        .received(payout_id)
+        .iter()
+        .map(|callback| callback["status"].clone())
+        .collect();
+    assert_eq!(
+        statuses,
+        vec![
+            json!(TransactionStatus::InChain),
+            json!(TransactionStatus::Confirmed)
+        ]
+    );
+    assert_eq!(env.balance(), 0);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn expired_payment_test() {
+    let env = TestEnv::start().await;
+
+    let payment = env.create_payment(PAYMENT_AMOUNT, 2).await;
+    env.advance_time(16 * 60);
+    env.wait_for_status(payment.id, TransactionStatus::Rejected)
+        .await;
+    env.wait_for(payment.id, "reported", |tx| tx.reported).await;
+    let callbacks = env.callbacks.received(payment.id);
+    assert_eq!(callbacks.len(), 1);
+    assert_eq!(callbacks[0]["status"], json!(TransactionStatus::Rejected));
+
+    // too late to pay
+    let slate = FakeWallet::payer_slate(PAYMENT_AMOUNT as u64);
+    assert!(env.pay(payment.id, &slate).await.is_err());
+    assert_eq!(env.balance(), 0);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn refund_test() {
+    let env = TestEnv::start().await;
+
+    let payment = env.create_payment(PAYMENT_AMOUNT, 2).await;
+    let slate = FakeWallet::payer_slate(PAYMENT_AMOUNT as u64);
+    let signed_slate = env.pay(payment.id, &slate).await.unwrap();
+
+    // customer didn't post transaction in time
+    env.advance_time(8 * 60);
+    env.wait_for_status(payment.id, TransactionStatus::Rejected)
+        .await;
+    env.wait_for(payment.id, "cancelled in wallet", |tx| {
+        tx.wallet_tx_cancelled
+    })
+    .await;
+    assert_eq!(
+        env.wallet.tx_status(&slate.id.hyphenated().to_string()),
+        Some(FakeTxStatus::Cancelled)
+    );
+
+    // but it got into chain anyway
+    env.node
+        .post_output(&signed_slate.tx.output_commitments()[0]);
+    env.node.mine_block();
+    env.wait_for_status(payment.id, TransactionStatus::Refund)
+        .await;
+    assert_eq!(env.balance(), PAYMENT_AMOUNT);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn callback_failure_test() {
+    let env = TestEnv::start().await;
+    env.callbacks.set_failing(true);
+
+    let payment = env.create_payment(PAYMENT_AMOUNT, 1).await;
+    let slate = FakeWallet::payer_slate(PAYMENT_AMOUNT as u64);
+    let signed_slate = env.pay(payment.id, &slate).await.unwrap();
+    env.node
+        .post_output(&signed_slate.tx.output_commitments()[0]);
+    env.node.mine_blocks(3);
+    env.wait_for_status(payment.id, TransactionStatus::Confirmed)
+        .await;
+
+    let payment = env
+        .wait_for(payment.id, "attempted to report", |tx| {
+            tx.report_attempts > 0
+        })
+        .await;
+    assert!(!payment.reported);
+    assert!(payment.next_report_attempt.is_some());
+    assert!(env.callbacks.received(payment.id).is_empty());
+    // unreported payment doesn't count in balance
+    assert_eq!(env.balance(), 0);
+
+    env.callbacks.set_failing(false);
+    // skip waiting for the next attempt to run callback
+    env.advance_time(60);
+    env.wait_for(payment.id, "reported", |tx| tx.reported).await;
+    assert_eq!(env.callbacks.received(payment.id).len(), 1);
+    assert_eq!(env.balance(), PAYMENT_AMOUNT);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn reorg_test() {
+    let env = TestEnv::start().await;
+
+    let payment = env.create_payment(PAYMENT_AMOUNT, 2).await;
+    let slate = FakeWallet::payer_slate(PAYMENT_AMOUNT as u64);
+    let signed_slate = env.pay(payment.id, &slate).await.unwrap();
+    let commit = signed_slate.tx.output_commitments()[0].clone();
+    env.node.post_output(&commit);
+    env.node.mine_blocks(4);
+    env.wait_for_status(payment.id, TransactionStatus::Confirmed)
+        .await;
+    env.wait_for(payment.id, "reported", |tx| tx.reported).await;
+    assert_eq!(env.balance(), PAYMENT_AMOUNT);
+
+    // block with the payment is orphaned
+    env.node.reorg(4);
+    let payment = env
+        .wait_for_status(payment.id, TransactionStatus::Pending)
+        .await;
+    assert_eq!(payment.height, None);
+    assert!(!payment.reported);
+    assert_eq!(env.balance(), 0);
+
+    // transaction gets into the new branch
+    env.node.post_output(&commit);
+    let height = env.node.mine_block();
+    let payment = env
+        .wait_for_status(payment.id, TransactionStatus::InChain)
+        .await;
+    assert_eq!(payment.height, Some(height));
+    env.node.mine_blocks(3);
+    env.wait_for_status(payment.id, TransactionStatus::Confirmed)
+        .await;
+    env.wait_for(payment.id, "reported", |tx| tx.reported).await;
+    assert_eq!(env.callbacks.received(payment.id).len(), 2);
+    assert_eq!(env.balance(), PAYMENT_AMOUNT);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn kernel_detection_test() {
+    let env = TestEnv::start().await;
+
+    let payment = env.create_payment(PAYMENT_AMOUNT, 2).await;
+    let slate = FakeWallet::payer_slate(PAYMENT_AMOUNT as u64);
+    let signed_slate = env.pay(payment.id, &slate).await.unwrap();
+
+    // none of the outputs we know about gets into chain, only the kernel
+    env.node
+        .post_kernel(&signed_slate.tx.kernel_excess().unwrap());
+    let height = env.node.mine_block();
+    let payment = env
+        .wait_for_status(payment.id, TransactionStatus::InChain)
+        .await;
+    assert_eq!(payment.height, Some(height));
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn catch_up_test() {
+    let env = TestEnv::start().await;
+
+    let payment = env.create_payment(PAYMENT_AMOUNT, 2).await;
+    let slate = FakeWallet::payer_slate(PAYMENT_AMOUNT as u64);
+    let signed_slate = env.pay(payment.id, &slate).await.unwrap();
+    env.node.post_tx(&signed_slate.tx);
+
+    // knockturn was down for a while
+    env.node.mine_blocks(1000);
+    let payment = env
+        .wait_for_status(payment.id, TransactionStatus::InChain)
+        .await;
+    assert_eq!(payment.height, Some(START_HEIGHT + 1));
+    let status = env.wait_for_sync().await;
+    assert_eq!(status.node_height, env.node.height());
+    assert_eq!(status.lag(), 0);
+    env.wait_for_status(payment.id, TransactionStatus::Confirmed)
+        .await;
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn beyond_horizon_test() {
+    let env = TestEnv::start().await;
+
+    let by_output = env.create_payment(PAYMENT_AMOUNT, 2).await;
+    let slate = FakeWallet::payer_slate(PAYMENT_AMOUNT as u64);
+    let signed_slate = env.pay(by_output.id, &slate).await.unwrap();
+    env.node
+        .post_output(&signed_slate.tx.output_commitments()[0]);
+
+    let by_kernel = env.create_payment(PAYMENT_AMOUNT, 2).await;
+    let slate = FakeWallet::payer_slate(PAYMENT_AMOUNT as u64);
+    let signed_slate = env.pay(by_kernel.id, &slate).await.unwrap();
+    env.node.post_tx(&signed_slate.tx);
+
+    // too many blocks to fetch them all, only open transactions are looked up
+    env.node.mine_blocks(HORIZON_HEIGHT + 10);
+    for payment_id in &[by_output.id, by_kernel.id] {
+        let payment = env
+            .wait_for_status(*payment_id, TransactionStatus::InChain)
+            .await;
+        assert_eq!(payment.height, Some(START_HEIGHT + 1));
+    }
+    env.wait_for_sync().await;
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn rescan_test() {
+    let env = TestEnv::start().await;
+
+    let payment = env.create_payment(PAYMENT_AMOUNT, 2).await;
+    let slate = FakeWallet::payer_slate(PAYMENT_AMOUNT as u64);
+    let signed_slate = env.pay(payment.id, &slate).await.unwrap();
+
+    // sync skipped the block with payment
+    env.set_current_height(START_HEIGHT + 10);
+    env.node.post_tx(&signed_slate.tx);
+    env.node.mine_blocks(11);
+    env.wait_for_sync().await;
+    assert_eq!(
+        env.transaction(payment.id).status,
+        TransactionStatus::Pending
+    );
+
+    assert_eq!(
+        env.rescan("wrong token", START_HEIGHT, START_HEIGHT + 11, true)
+            .await
+            .unwrap_err(),
+        403
+    );
+    let report = env
+        .rescan(OPERATOR_TOKEN, START_HEIGHT, START_HEIGHT + 11, false)
+        .await
+        .unwrap();
+    assert_eq!(report.changes.len(), 1);
+    let change = &report.changes[0];
+    assert_eq!(change.transaction_id, payment.id);
+    assert_eq!(change.new_status, Some(TransactionStatus::InChain));
+    assert_eq!(change.height, START_HEIGHT + 1);
+    assert!(!change.applied);
+    assert_eq!(
+        env.transaction(payment.id).status,
+        TransactionStatus::Pending
+    );
+
+    let report = env
+        .rescan(OPERATOR_TOKEN, START_HEIGHT, START_HEIGHT + 11, true)
+        .await
+        .unwrap();
+    assert!(report.changes[0].applied);
+    let payment = env.transaction(payment.id);
+    assert_eq!(payment.status, TransactionStatus::InChain);
+    assert_eq!(payment.height, Some(START_HEIGHT + 1));
+    env.wait_for_status(payment.id, TransactionStatus::Confirmed)
+        .await;
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn health_test() {
+    let env = TestEnv::start().await;
+    env.wait_for_sync().await;
+
+    let (status, report) = env.health("/health").await;
+    assert_eq!(status, 200, "{:?}", report);
+    assert!(report.database.ok);
+    assert!(report.wallet.is_none());
+    let (status, report) = env.health("/ready").await;
+    assert_eq!(status, 200, "{:?}", report);
+    assert!(report.wallet.unwrap().ok);
+    assert!(report.node.unwrap().ok);
+
+    // dead wallet makes us not ready, but still alive
+    env.wallet.set_offline(true);
+    let (status, report) = env.health("/ready").await;
+    assert_eq!(status, 503);
+    assert!(!report.wallet.unwrap().ok);
+    assert!(report.node.unwrap().ok);
+    let (status, _) = env.health("/health").await;
+    assert_eq!(status, 200);
+    env.wallet.set_offline(false);
+
+    env.node.set_offline(true);
+    let (status, report) = env.health("/ready").await;
+    assert_eq!(status, 503);
+    assert!(!report.node.unwrap().ok);
+    env.node.set_offline(false);
+
+    // rates registered at the start get outdated by our clock
+    env.advance_time(MAX_RATES_AGE_SECONDS + 60);
+    let (status, report) = env.health("/ready").await;
+    assert_eq!(status, 503);
+    assert!(!report.rates.ok);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn metrics_test() {
+    let env = TestEnv::start().await;
+    env.wait_for_sync().await;
+    env.create_payment(1, 1000).await;
+
+    assert_eq!(env.metrics("wrong token").await, Err(403));
+    let metrics = env.metrics(OPERATOR_TOKEN).await.unwrap();
+    assert!(metrics.contains(&format!(
+        "knockturn_transactions{{merchant=\"{}\",status=\"New\",type=\"Payment\"}} 1",
+        env.merchant.id
+    )));
+    assert!(metrics.contains("knockturn_sync_lag_blocks "));
+    assert!(metrics.contains(
+        "knockturn_http_request_seconds_count{method=\"POST\",route=\"/merchants/{merchant_id}/payments\",status=\"201\"}"
+    ));
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn pause_job_test() {
+    let env = TestEnv::start().await;
+    env.wait_for_sync().await;
+
+    assert_eq!(env.jobs("wrong token").await.unwrap_err(), 403);
+    let jobs = env.jobs(OPERATOR_TOKEN).await.unwrap();
+    assert!(jobs.contains_key("sync_with_node"));
+    assert!(jobs.contains_key("process_pending_payouts"));
+    assert_eq!(
+        env.set_job_paused(OPERATOR_TOKEN, "no_such_job", true)
+            .await
+            .unwrap_err(),
+        404
+    );
+
+    let status = env
+        .set_job_paused(OPERATOR_TOKEN, "sync_with_node", true)
+        .await
+        .unwrap();
+    assert!(status.paused);
+    // let the run which may be in progress finish
+    while env.jobs(OPERATOR_TOKEN).await.unwrap()["sync_with_node"].running {
+        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+    }
+    let height = env.sync_status().height;
+    env.node.mine_blocks(3);
+    actix_rt::time::delay_for(Duration::from_secs(2 * DEFAULT_INTERVAL_SECONDS)).await;
+    assert_eq!(env.sync_status().height, height);
+    // a paused job doesn't make us unhealthy
+    let (status, _) = env.health("/health").await;
+    assert_eq!(status, 200);
+
+    env.set_job_paused(OPERATOR_TOKEN, "sync_with_node", false)
+        .await
+        .unwrap();
+    env.wait_for_sync().await;
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn auto_payout_test() {
+    let mut env = TestEnv::start().await;
+    let merchant_wallet = FakeWallet::new(0);
+    let wallet_url = env.start_merchant_wallet(merchant_wallet.clone());
+    let cookie = env.login().await;
+
+    // automatic payouts need a listening wallet
+    assert_eq!(
+        env.set_payout_policy(&cookie, "Immediate", "", "").await,
+        400
+    );
+    assert_eq!(
+        env.set_payout_policy(&cookie, "Threshold", &wallet_url, "1")
+            .await,
+        400
+    );
+    assert_eq!(
+        env.set_payout_policy(&cookie, "Immediate", &wallet_url, "")
+            .await,
+        302
+    );
+
+    env.confirmed_payment(PAYMENT_AMOUNT).await;
+    let payout = env.wait_for_payout(1).await;
+    let payout = env
+        .wait_for_status(payout.id, TransactionStatus::Pending)
+        .await;
+    assert_eq!(payout.grin_amount, PAYMENT_AMOUNT);
+    assert_eq!(
+        merchant_wallet.tx_status(payout.wallet_tx_slate_id.as_ref().unwrap()),
+        Some(FakeTxStatus::Received)
+    );
+    assert_eq!(env.balance(), 0);
+    env.node.mine_blocks(12);
+    env.wait_for_status(payout.id, TransactionStatus::Confirmed)
+        .await;
+    let merchant = env.reload_merchant();
+    assert!(merchant.last_auto_payout_at.is_some());
+    assert_eq!(merchant.auto_payout_error, None);
+
+    // merchant's wallet is gone, fall back to manual payouts
+    merchant_wallet.set_offline(true);
+    env.confirmed_payment(PAYMENT_AMOUNT).await;
+    let payout = env.wait_for_payout(2).await;
+    env.wait_for_status(payout.id, TransactionStatus::Rejected)
+        .await;
+    env.wait_for(payout.id, "reported", |tx| tx.reported).await;
+    let callbacks = env.callbacks.received(payout.id);
+    assert_eq!(callbacks[0]["status"], json!(TransactionStatus::Rejected));
+    let merchant = env.reload_merchant();
+    assert_eq!(merchant.payout_policy, PayoutPolicy::Manual);
+    assert!(merchant.auto_payout_error.is_some());
+    assert_eq!(env.balance(), PAYMENT_AMOUNT);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn payout_api_test() {
+    let env = TestEnv::start().await;
+    env.confirmed_payment(2 * PAYMENT_AMOUNT).await;
+    let token = env.merchant.token.clone();
+    let payout_key = {
+        let conn: &PgConnection = &env.pool.get().unwrap();
+        knockturn::db::new_payout_key(&env.merchant.id, conn).unwrap()
+    };
+
+    // API token alone isn't enough to take money out
+    let (status, _) = env
+        .payout_api(
+            Method::POST,
+            "/payouts",
+            &token,
+            Some(json!({ "amount": PAYMENT_AMOUNT })),
+        )
+        .await;
+    assert_eq!(status, 403);
+    let (status, _) = env
+        .payout_api(
+            Method::POST,
+            "/payouts",
+            &token,
+            Some(json!({ "amount": PAYMENT_AMOUNT, "code": env.totp_code() })),
+        )
+        .await;
+    assert_eq!(status, 201);
+    let (status, created) = env
+        .payout_api(
+            Method::POST,
+            "/payouts",
+            &payout_key,
+            Some(json!({ "amount": PAYMENT_AMOUNT, "confirmations": 5 })),
+        )
+        .await;
+    assert_eq!(status, 201);
+    assert_eq!(created["confirmations"], json!(5));
+    assert_eq!(env.balance(), 0);
+
+    let (status, payouts) = env
+        .payout_api(Method::GET, "/payouts", &payout_key, None)
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(payouts.as_array().unwrap().len(), 2);
+    assert_eq!(payouts[0]["id"], created["id"]);
+
+    // cancel the first one, money is back
+    let cancelled_id = payouts[1]["id"].as_str().unwrap().to_owned();
+    let (status, cancelled) = env
+        .payout_api(
+            Method::POST,
+            &format!("/payouts/{}/cancel", cancelled_id),
+            &token,
+            None,
+        )
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(cancelled["status"], json!(TransactionStatus::Rejected));
+    assert_eq!(env.balance(), PAYMENT_AMOUNT);
+
+    let payout_id = created["id"].as_str().unwrap().to_owned();
+    let (status, slate) = env
+        .payout_api(
+            Method::GET,
+            &format!("/payouts/{}/slate", payout_id),
+            &token,
+            None,
+        )
+        .await;
+    assert_eq!(status, 200);
+    let (_, payout) = env
+        .payout_api(
+            Method::GET,
+            &format!("/payouts/{}", payout_id),
+            &token,
+            None,
+        )
+        .await;
+    assert_eq!(payout["status"], json!(TransactionStatus::Initialized));
+    let (status, _) = env
+        .payout_api(
+            Method::POST,
+            &format!("/payouts/{}/slate", payout_id),
+            &token,
+            Some(slate),
+        )
+        .await;
+    assert_eq!(status, 200);
+    let (_, payout) = env
+        .payout_api(
+            Method::GET,
+            &format!("/payouts/{}", payout_id),
+            &token,
+            None,
+        )
+        .await;
+    assert_eq!(payout["status"], json!(TransactionStatus::Pending));
+
+    // transaction is posted already
+    let (status, _) = env
+        .payout_api(
+            Method::POST,
+            &format!("/payouts/{}/cancel", payout_id),
+            &token,
+            None,
+        )
+        .await;
+    assert_eq!(status, 400);
+    assert_eq!(env.balance(), PAYMENT_AMOUNT);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn concurrent_payouts_test() {
+    let env = TestEnv::start().await;
+    env.confirmed_payment(PAYMENT_AMOUNT).await;
+    let payout_key = {
+        let conn: &PgConnection = &env.pool.get().unwrap();
+        knockturn::db::new_payout_key(&env.merchant.id, conn).unwrap()
+    };
+
+    // together they ask for more than the balance, only one gets it
+    let body = json!({ "amount": PAYMENT_AMOUNT });
+    let ((first, _), (second, _)) = futures::join!(
+        env.payout_api(Method::POST, "/payouts", &payout_key, Some(body.clone())),
+        env.payout_api(Method::POST, "/payouts", &payout_key, Some(body.clone())),
+    );
+    let mut statuses = vec![first, second];
+    statuses.sort();
+    assert_eq!(statuses[0], 201);
+    assert_ne!(statuses[1], 201);
+    assert_eq!(env.balance(), 0);
+    let (_, payouts) = env
+        .payout_api(Method::GET, "/payouts", &payout_key, None)
+        .await;
+    assert_eq!(payouts.as_array().unwrap().len(), 1);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn cancel_payout_test() {
+    let env = TestEnv::start().await;
+    env.confirmed_payment(PAYMENT_AMOUNT).await;
+    let wallet_balance = env.wallet.balance();
+    let cookie = env.login().await;
+
+    // merchant downloaded the slate but changed their mind
+    let payout_id = env.withdraw(&cookie, PAYMENT_AMOUNT).await;
+    let payout_slate = env.payout_slate(&cookie, payout_id).await;
+    assert_eq!(env.balance(), 0);
+    assert_eq!(env.cancel_payout(&cookie, payout_id).await, 302);
+    let payout = env.transaction(payout_id);
+    assert_eq!(payout.status, TransactionStatus::Rejected);
+    assert_eq!(
+        env.wallet
+            .tx_status(&payout_slate.id.hyphenated().to_string()),
+        Some(FakeTxStatus::Cancelled)
+    );
+    assert_eq!(env.wallet.balance(), wallet_balance);
+    assert_eq!(env.balance(), PAYMENT_AMOUNT);
+    env.wait_for(payout_id, "reported", |tx| tx.reported).await;
+    assert_eq!(env.cancel_payout(&cookie, payout_id).await, 400);
+
+    // the money can be withdrawn again right away
+    let payout_id = env.withdraw(&cookie, PAYMENT_AMOUNT).await;
+    assert_eq!(env.transaction(payout_id).status, TransactionStatus::New);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn cancel_during_finalize_test() {
+    let env = TestEnv::start().await;
+    env.confirmed_payment(PAYMENT_AMOUNT).await;
+    let cookie = env.login().await;
+
+    let payout_id = env.withdraw(&cookie, PAYMENT_AMOUNT).await;
+    env.payout_slate(&cookie, payout_id).await;
+    let initialized_payout = env
+        .fsm_payout
+        .send(GetInitializedPayout {
+            transaction_id: payout_id,
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+    // merchant cancels while their slate is being finalized and posted
+    assert_eq!(env.cancel_payout(&cookie, payout_id).await, 302);
+    let result = env
+        .fsm_payout
+        .send(FinalizePayout {
+            initialized_payout,
+            kernel_excess: None,
+            input_commits: vec![],
+        })
+        .await
+        .unwrap();
+    match result {
+        Err(Error::InvalidEntity(_)) => {}
+        other => panic!("Payout was finalized after cancel: {:?}", other),
+    }
+    assert_eq!(
+        env.transaction(payout_id).status,
+        TransactionStatus::Rejected
+    );
+    assert_eq!(env.balance(), PAYMENT_AMOUNT);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn expired_pending_payout_test() {
+    let env = TestEnv::start().await;
+    env.confirmed_payment(PAYMENT_AMOUNT).await;
+    let wallet_balance = env.wallet.balance();
+    let cookie = env.login().await;
+
+    let payout_id = env.withdraw(&cookie, PAYMENT_AMOUNT).await;
+    let payout_slate = env.payout_slate(&cookie, payout_id).await;
+    env.accept_payout_slate(&cookie, payout_id, &payout_slate)
+        .await;
+    let payout = env.transaction(payout_id);
+    assert_eq!(
+        payout.input_commits.as_ref().map(|inputs| inputs.len()),
+        Some(1)
+    );
+
+    // the transaction is dropped from mempool and never gets into chain
+    env.node.clear_mempool();
+    env.advance_time(PENDING_PAYOUT_TTL_SECONDS + 60);
+    let payout = env
+        .wait_for_status(payout_id, TransactionStatus::Rejected)
+        .await;
+    assert!(payout.wallet_tx_cancelled);
+    assert_eq!(
+        env.wallet
+            .tx_status(&payout_slate.id.hyphenated().to_string()),
+        Some(FakeTxStatus::Cancelled)
+    );
+    assert_eq!(env.wallet.balance(), wallet_balance);
+    assert_eq!(env.balance(), PAYMENT_AMOUNT);
+
+    // it's mined anyway, the amount is taken from the balance again
+    env.node.post_tx(&payout_slate.tx);
+    let height = env.node.mine_block();
+    let payout = env
+        .wait_for_status(payout_id, TransactionStatus::InChain)
+        .await;
+    assert_eq!(payout.height, Some(height));
+    assert_eq!(env.balance(), 0);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn legacy_pending_payout_test() {
+    let env = TestEnv::start().await;
+    env.confirmed_payment(PAYMENT_AMOUNT).await;
+    let cookie = env.login().await;
+
+    let payout_id = env.withdraw(&cookie, PAYMENT_AMOUNT).await;
+    let payout_slate = env.payout_slate(&cookie, payout_id).await;
+    env.accept_payout_slate(&cookie, payout_id, &payout_slate)
+        .await;
+    // posted before inputs of payouts were recorded
+    {
+        use knockturn::schema::transactions::dsl::*;
+        let conn: &PgConnection = &env.pool.get().unwrap();
+        diesel::update(transactions.find(payout_id))
+            .set(input_commits.eq(None::<Vec<String>>))
+            .execute(conn)
+            .unwrap();
+    }
+
+    // the transaction is dropped from mempool, its kernel is not in chain
+    env.node.clear_mempool();
+    env.advance_time(PENDING_PAYOUT_TTL_SECONDS + 60);
+    let payout = env
+        .wait_for_status(payout_id, TransactionStatus::Rejected)
+        .await;
+    assert!(payout.wallet_tx_cancelled);
+    assert_eq!(env.balance(), PAYMENT_AMOUNT);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn payout_fee_test() {
+    let env = TestEnv::start().await;
+    env.confirmed_payment(PAYMENT_AMOUNT).await;
+    let cookie = env.login().await;
+
+    // merchant is charged the fee quoted by the wallet
+    env.wallet.set_fee(6_000_000);
+    let payout_id = env.withdraw(&cookie, PAYMENT_AMOUNT).await;
+    let payout = env.transaction(payout_id);
+    assert_eq!(payout.transfer_fee, Some(6_000_000));
+
+    // the real fee turned out to be lower, the difference is credited back
+    env.wallet.set_fee(4_000_000);
+    let payout_slate = env.payout_slate(&cookie, payout_id).await;
+    assert_eq!(
+        payout_slate.amount as i64,
+        PAYMENT_AMOUNT - 6_000_000 - payout.knockturn_fee.unwrap()
+    );
+    assert_eq!(env.balance(), 0);
+    env.accept_payout_slate(&cookie, payout_id, &payout_slate)
+        .await;
+    let payout = env.transaction(payout_id);
+    assert_eq!(payout.real_transfer_fee, Some(4_000_000));
+    assert_eq!(payout.transfer_fee_credit, Some(2_000_000));
+    assert_eq!(env.balance(), 2_000_000);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn fee_schedule_test() {
+    let env = TestEnv::start().await;
+    let schedule = json!({
+        "payout": {"share": 0.02},
+        "payment": {"fixed": 10_000_000},
+        "tiers": [{"min_volume": PAYMENT_AMOUNT, "payout_share": 0.005}],
+    });
+    assert_eq!(
+        env.set_fee_schedule("wrong token", schedule.clone()).await,
+        403
+    );
+    assert_eq!(
+        env.set_fee_schedule(OPERATOR_TOKEN, json!({"payout": {"share": 1.5}}))
+            .await,
+        400
+    );
+    assert_eq!(env.set_fee_schedule(OPERATOR_TOKEN, schedule).await, 200);
+
+    // payments are charged by the schedule
+    let payment = env.confirmed_payment(PAYMENT_AMOUNT).await;
+    assert_eq!(payment.knockturn_fee, Some(10_000_000));
+    let balance = PAYMENT_AMOUNT - 10_000_000;
+    assert_eq!(env.balance(), balance);
+
+    // the payment reached the volume of the tier
+    let cookie = env.login().await;
+    let payout_id = env.withdraw(&cookie, balance).await;
+    let payout = env.transaction(payout_id);
+    assert_eq!(payout.knockturn_fee, Some((balance as f64 * 0.005) as i64));
+    assert!(payout.fee_schedule.is_some());
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn operator_revenue_test() {
+    let env = TestEnv::start().await;
+    let payment_fee = 100_000_000;
+    let schedule = json!({"payout": {"share": 0.01}, "payment": {"fixed": payment_fee}});
+    assert_eq!(env.set_fee_schedule(OPERATOR_TOKEN, schedule).await, 200);
+    let (status, _) = env
+        .admin_api(Method::GET, "/admin/balances", "wrong token", None)
+        .await;
+    assert_eq!(status, 403);
+    let (_, before) = env
+        .admin_api(Method::GET, "/admin/balances", OPERATOR_TOKEN, None)
+        .await;
+    let operator_balance = before["operator"].as_i64().unwrap();
+
+    env.confirmed_payment(PAYMENT_AMOUNT).await;
+    let (status, report) = env
+        .admin_api(
+            Method::GET,
+            &format!("/admin/revenue?merchant_id={}", env.merchant.id),
+            OPERATOR_TOKEN,
+            None,
+        )
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(report["merchants"][0]["payment_fees"], json!(payment_fee));
+    assert_eq!(report["total"], json!(payment_fee));
+    let (_, balances) = env
+        .admin_api(Method::GET, "/admin/balances", OPERATOR_TOKEN, None)
+        .await;
+    assert_eq!(balances["operator"], json!(operator_balance + payment_fee));
+    assert_eq!(
+        balances["merchants"].as_i64().unwrap(),
+        before["merchants"].as_i64().unwrap() + PAYMENT_AMOUNT - payment_fee
+    );
+
+    // operator withdraws the fee with the same slate flow
+    let (status, payout) = env
+        .admin_api(
+            Method::POST,
+            "/admin/payouts",
+            OPERATOR_TOKEN,
+            Some(json!({ "amount": payment_fee })),
+        )
+        .await;
+    assert_eq!(status, 201);
+    let payout_id = payout["id"].as_str().unwrap().to_owned();
+    let payout = env.transaction(payout_id.parse().unwrap());
+    assert_eq!(payout.knockturn_fee, Some(0));
+    let (status, slate) = env
+        .admin_api(
+            Method::GET,
+            &format!("/admin/payouts/{}/slate", payout_id),
+            OPERATOR_TOKEN,
+            None,
+        )
+        .await;
+    assert_eq!(status, 200);
+    let (status, _) = env
+        .admin_api(
+            Method::POST,
+            &format!("/admin/payouts/{}/slate", payout_id),
+            OPERATOR_TOKEN,
+            Some(slate),
+        )
+        .await;
+    assert_eq!(status, 200);
+    let payout = env.transaction(payout_id.parse().unwrap());
+    assert_eq!(payout.status, TransactionStatus::Pending);
+
+    // what left the wallet is what the operator withdrew
+    let (_, after) = env
+        .admin_api(Method::GET, "/admin/balances", OPERATOR_TOKEN, None)
+        .await;
+    assert_eq!(after["operator"], json!(operator_balance));
+    assert_eq!(after["merchants"], balances["merchants"]);
+    assert_eq!(after["unaccounted"], balances["unaccounted"]);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn ledger_test() {
+    let env = TestEnv::start().await;
+    let path = format!("/admin/merchants/{}/balances", env.merchant.id);
+    let (status, _) = env.admin_api(Method::GET, &path, "wrong token", None).await;
+    assert_eq!(status, 403);
+
+    // unconfirmed payment is pending
+    let payment = env.create_payment(PAYMENT_AMOUNT, 1).await;
+    let slate = FakeWallet::payer_slate(PAYMENT_AMOUNT as u64);
+    let signed_slate = env.pay(payment.id, &slate).await.unwrap();
+    let (status, balances) = env
+        .admin_api(Method::GET, &path, OPERATOR_TOKEN, None)
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(balances["pending"], json!(PAYMENT_AMOUNT));
+    assert_eq!(balances["available"], json!(0));
+
+    // and becomes available once confirmed and reported
+    env.node.post_tx(&signed_slate.tx);
+    env.node.mine_blocks(3);
+    let payment = env.wait_for(payment.id, "reported", |tx| tx.reported).await;
+    let available = PAYMENT_AMOUNT - payment.knockturn_fee.unwrap_or(0);
+    let (_, balances) = env
+        .admin_api(Method::GET, &path, OPERATOR_TOKEN, None)
+        .await;
+    assert_eq!(balances["pending"], json!(0));
+    assert_eq!(balances["available"], json!(available));
+    assert_eq!(env.balance(), available);
+
+    // payout locks the money until it leaves the wallet
+    let cookie = env.login().await;
+    let payout_id = env.withdraw(&cookie, available).await;
+    let payout = env.transaction(payout_id);
+    let (_, balances) = env
+        .admin_api(Method::GET, &path, OPERATOR_TOKEN, None)
+        .await;
+    assert_eq!(balances["available"], json!(0));
+    assert_eq!(
+        balances["locked"],
+        json!(available - payout.knockturn_fee.unwrap_or(0))
+    );
+    assert_eq!(env.cancel_payout(&cookie, payout_id).await, 302);
+    let (_, balances) = env
+        .admin_api(Method::GET, &path, OPERATOR_TOKEN, None)
+        .await;
+    assert_eq!(balances["available"], json!(available));
+    assert_eq!(balances["locked"], json!(0));
+
+    // operator corrects the balance by hand
+    let adjustments = format!("/admin/merchants/{}/adjustments", env.merchant.id);
+    let adjustment = json!({"amount": -1000, "note": "Chargeback"});
+    for reserved in &["network", "knockturn"] {
+        let (status, _) = env
+            .admin_api(
+                Method::POST,
+                &format!("/admin/merchants/{}/adjustments", reserved),
+                OPERATOR_TOKEN,
+                Some(adjustment.clone()),
+            )
+            .await;
+        assert_eq!(status, 400);
+    }
+    let (status, balances) = env
+        .admin_api(Method::POST, &adjustments, OPERATOR_TOKEN, Some(adjustment))
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(balances["available"], json!(available - 1000));
+    assert_eq!(env.balance(), available - 1000);
+
+    let (_, reconciliation) = env
+        .admin_api(Method::GET, "/admin/balances", OPERATOR_TOKEN, None)
+        .await;
+    assert_eq!(reconciliation["ledger_errors"], json!([]));
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn statement_test() {
+    let env = TestEnv::start().await;
+    let payment = env.confirmed_payment(PAYMENT_AMOUNT).await;
+    let fee = payment.knockturn_fee.unwrap_or(0);
+    let cookie = env.login().await;
+    let payout_id = env.withdraw(&cookie, PAYMENT_AMOUNT - fee).await;
+    let payout = env.transaction(payout_id);
+    let (status, _) = env
+        .admin_api(
+            Method::POST,
+            &format!("/admin/merchants/{}/adjustments", env.merchant.id),
+            OPERATOR_TOKEN,
+            Some(json!({"amount": 1000, "note": "Bonus"})),
+        )
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(env.balance(), 1000);
+
+    let today = Utc::now().naive_utc().date();
+    let period = format!(
+        "from={}&to={}",
+        today - chrono::Duration::days(1),
+        today + chrono::Duration::days(1)
+    );
+    let (status, csv) = env
+        .statement(&cookie, &format!("{}&format=csv", period))
+        .await;
+    assert_eq!(status, 200);
+    let rows: Vec<Vec<&str>> = csv
+        .lines()
+        .skip(1)
+        .map(|row| row.split(',').collect())
+        .collect();
+    let amounts: Vec<(&str, &str)> = rows[1..rows.len() - 1]
+        .iter()
+        .map(|row| (row[3], row[5]))
+        .collect();
+    let grins = |nanogrins: i64| {
+        format!(
+            "{}.{:09}",
+            nanogrins / 1_000_000_000,
+            nanogrins % 1_000_000_000
+        )
+    };
+    assert!(amounts.contains(&("PaymentCleared", grins(PAYMENT_AMOUNT).as_str())));
+    assert!(amounts.contains(&(
+        "Payout",
+        format!(
+            "-{}",
+            grins(PAYMENT_AMOUNT - fee - payout.knockturn_fee.unwrap())
+        )
+        .as_str()
+    )));
+    assert!(amounts.contains(&("Adjustment", "0.000001000")));
+    assert_eq!(rows[0][6], "0.000000000");
+    assert_eq!(rows[rows.len() - 1][6], "0.000001000");
+
+    // point-in-time balance: the day after opens with the closing balance
+    let (_, csv) = env
+        .statement(
+            &cookie,
+            &format!(
+                "from={}&to={}&format=csv",
+                today + chrono::Duration::days(1),
+                today + chrono::Duration::days(1)
+            ),
+        )
+        .await;
+    assert!(csv.contains("OpeningBalance,Opening balance,,0.000001000"));
+
+    // statements are built from the ledger, so they agree with the balance
+    {
+        let conn: &PgConnection = &env.pool.get().unwrap();
+        let statement = knockturn::statement::get_statement(
+            &env.merchant.id,
+            (today - chrono::Duration::days(1)).and_hms(0, 0, 0),
+            (today + chrono::Duration::days(2)).and_hms(0, 0, 0),
+            conn,
+        )
+        .unwrap();
+        let balances = knockturn::ledger::get_balances(&env.merchant.id, conn).unwrap();
+        assert_eq!(statement.closing_balance, balances.available);
+    }
+
+    let (status, pdf) = env
+        .statement(&cookie, &format!("{}&format=pdf", period))
+        .await;
+    assert_eq!(status, 200);
+    assert!(pdf.starts_with("%PDF-"));
+    assert_eq!(
+        env.page_status(&cookie, &format!("/statement?{}", period))
+            .await,
+        200
+    );
+    assert_eq!(
+        env.page_status(&cookie, "/statement?from=2020-05-02&to=2020-05-01")
+            .await,
+        400
+    );
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn session_test() {
+    let env = TestEnv::start().await;
+
+    let cookie = env.login().await;
+    assert_eq!(env.page_status(&cookie, "/").await, 200);
+    assert_eq!(env.page_status("", "/").await, 302);
+
+    // session id changes with privileges, an id planted before login is useless
+    let planted = env.password_login().await;
+    let session = env.pass_2fa(&planted).await;
+    assert_ne!(planted, session);
+    assert_eq!(env.page_status(&planted, "/").await, 302);
+    assert_eq!(env.page_status(&session, "/").await, 200);
+
+    // the session is gone on the server, an old cookie doesn't help
+    env.logout(&cookie).await;
+    assert_eq!(env.page_status(&cookie, "/").await, 302);
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn lease_test() {
+    let env = TestEnv::start().await;
+    let conn: &PgConnection = &env.pool.get().unwrap();
+    release_lease("test", "first", conn).unwrap();
+
+    assert!(acquire_lease("test", "first", 30, conn).unwrap());
+    assert!(!acquire_lease("test", "second", 30, conn).unwrap());
+    // the holder prolongs the lease
+    assert!(acquire_lease("test", "first", 30, conn).unwrap());
+
+    // the first instance died, its lease expired
+    {
+        use knockturn::schema::leases::dsl::*;
+        diesel::update(leases.filter(name.eq("test")))
+            .set(expires_at.eq(Utc::now().naive_utc() - chrono::Duration::seconds(1)))
+            .execute(conn)
+            .unwrap();
+    }
+    assert!(acquire_lease("test", "second", 30, conn).unwrap());
+    assert!(!acquire_lease("test", "first", 30, conn).unwrap());
+
+    // only the holder can release the lease
+    release_lease("test", "first", conn).unwrap();
+    assert!(!acquire_lease("test", "first", 30, conn).unwrap());
+    release_lease("test", "second", conn).unwrap();
+    assert!(acquire_lease("test", "first", 30, conn).unwrap());
+    release_lease("test", "first", conn).unwrap();
+
+    env.stop().await;
+}
+
+#[actix_rt::test]
+async fn shared_pause_test() {
+    let env = TestEnv::start().await;
+    let conn: &PgConnection = &env.pool.get().unwrap();
+    let now = Utc::now().naive_utc();
+    let leader = JobRegistry::new(now, Schedule::default(), Leadership::single(now));
+    let standby = JobRegistry::new(now, Schedule::default(), Leadership::new("standby"));
+    leader.register("test_job");
+    standby.register("test_job");
+
+    // operator's request reached the standby instance
+    assert!(standby.pause("test_job", now, conn).unwrap().paused);
+    leader.load_paused(conn).unwrap();
+    assert!(!leader.start("test_job"));
+
+    // the pause survives the lease moving to another instance
+    let next_leader = JobRegistry::new(now, Schedule::default(), Leadership::single(now));
+    next_leader.register("test_job");
+    next_leader.load_paused(conn).unwrap();
+    assert!(!next_leader.start("test_job"));
+
+    standby.resume("test_job", conn).unwrap();
+    next_leader.load_paused(conn).unwrap();
+    assert!(next_leader.start("test_job"));
+
+    env.stop().await;
+}